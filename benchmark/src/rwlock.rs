@@ -14,7 +14,7 @@ use args::ArgRange;
 
 use std::thread;
 use std::sync::{Arc, Barrier};
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
 #[cfg(unix)]
 use std::cell::UnsafeCell;
@@ -72,6 +72,34 @@ impl<T> RwLock<T> for parking_lot::RwLock<T> {
     }
 }
 
+// Optimistic reads are only sound for atomics, so the value is stored as its
+// bit pattern in an AtomicU64.
+impl RwLock<f64> for parking_lot::StampedRwLock<AtomicU64> {
+    fn new(v: f64) -> Self {
+        Self::new(AtomicU64::new(v.to_bits()))
+    }
+    fn read<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&f64) -> R,
+    {
+        let bits = unsafe { self.read_optimistic(|p| (*p).load(Ordering::Relaxed)) };
+        f(&f64::from_bits(bits))
+    }
+    fn write<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut f64) -> R,
+    {
+        let guard = (**self).write();
+        let mut value = f64::from_bits(guard.load(Ordering::Relaxed));
+        let result = f(&mut value);
+        guard.store(value.to_bits(), Ordering::Relaxed);
+        result
+    }
+    fn name() -> &'static str {
+        "parking_lot::StampedRwLock"
+    }
+}
+
 impl<T: Copy> RwLock<T> for seqlock::SeqLock<T> {
     fn new(v: T) -> Self {
         Self::new(v)
@@ -303,6 +331,14 @@ fn run_all(
         seconds_per_test,
         test_iterations,
     );
+    run_benchmark_iterations::<parking_lot::StampedRwLock<AtomicU64>>(
+        num_writer_threads,
+        num_reader_threads,
+        work_per_critical_section,
+        work_between_critical_sections,
+        seconds_per_test,
+        test_iterations,
+    );
     run_benchmark_iterations::<seqlock::SeqLock<f64>>(
         num_writer_threads,
         num_reader_threads,
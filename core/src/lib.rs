@@ -28,6 +28,10 @@
 //!
 //! See the documentation of the individual functions for more details.
 //!
+//! With the `async` feature, asynchronous tasks can also wait in these queues
+//! alongside threads by using an `AsyncWaiter`. This requires Rust 1.36 or
+//! later.
+//!
 //! # Building custom synchronization primitives
 //!
 //! Building custom synchronization primitives is very simple since the parking
@@ -76,6 +80,9 @@ mod parking_lot;
 
 pub use parking_lot::{FilterOp, ParkResult, ParkToken, RequeueOp, UnparkResult, UnparkToken};
 pub use parking_lot::{DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
-pub use parking_lot::{park, unpark_all, unpark_filter, unpark_one, unpark_requeue};
+pub use parking_lot::{park, unpark_all, unpark_filter, unpark_one, unpark_requeue,
+                      unpark_requeue_filter, unpark_requeue_multi};
+#[cfg(feature = "async")]
+pub use parking_lot::AsyncWaiter;
 pub use spinwait::SpinWait;
 pub use parking_lot::deadlock;
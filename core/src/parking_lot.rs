@@ -13,9 +13,11 @@ use std::mem;
 use std::thread::LocalKey;
 #[cfg(not(feature = "nightly"))]
 use std::panic;
+#[cfg(feature = "async")]
+use std::task::Waker;
 use smallvec::SmallVec;
 use rand::{self, Rng, XorShiftRng};
-use thread_parker::ThreadParker;
+use thread_parker::{self, ThreadParker};
 use word_lock::WordLock;
 use util::UncheckedOptionExt;
 
@@ -61,8 +63,8 @@ struct Bucket {
     mutex: WordLock,
 
     // Linked list of threads waiting on this bucket
-    queue_head: Cell<*const ThreadData>,
-    queue_tail: Cell<*const ThreadData>,
+    queue_head: Cell<*const QueueEntry>,
+    queue_tail: Cell<*const QueueEntry>,
 
     // Next time at which point be_fair should be set
     fair_timeout: UnsafeCell<FairTimeout>,
@@ -114,15 +116,16 @@ impl FairTimeout {
     }
 }
 
-struct ThreadData {
-    parker: ThreadParker,
-
+// Entry in the queue of a bucket. A parked thread is represented by the entry
+// at the start of its ThreadData, while an asynchronous task only has a
+// QueueEntry of its own.
+struct QueueEntry {
     // Key that this thread is sleeping on. This may change if the thread is
     // requeued to a different key.
     key: AtomicUsize,
 
     // Linked list of parked threads in a bucket
-    next_in_queue: Cell<*const ThreadData>,
+    next_in_queue: Cell<*const QueueEntry>,
 
     // UnparkToken passed to this thread when it is unparked
     unpark_token: Cell<UnparkToken>,
@@ -130,12 +133,62 @@ struct ThreadData {
     // ParkToken value set by the thread when it was parked
     park_token: Cell<ParkToken>,
 
+    // State of the asynchronous task waiting in the queue, if this entry
+    // belongs to an AsyncWaiter rather than to a thread.
+    #[cfg(feature = "async")]
+    task: Option<TaskData>,
+}
+
+impl QueueEntry {
+    fn new() -> QueueEntry {
+        QueueEntry {
+            key: AtomicUsize::new(0),
+            next_in_queue: Cell::new(ptr::null()),
+            unpark_token: Cell::new(DEFAULT_UNPARK_TOKEN),
+            park_token: Cell::new(DEFAULT_PARK_TOKEN),
+            #[cfg(feature = "async")]
+            task: None,
+        }
+    }
+
+    // Returns the ThreadData containing this entry, or None if the entry
+    // belongs to an asynchronous task.
+    #[inline]
+    unsafe fn thread(&self) -> Option<&ThreadData> {
+        #[cfg(feature = "async")]
+        {
+            if self.task.is_some() {
+                return None;
+            }
+        }
+        Some(&*(self as *const QueueEntry as *const ThreadData))
+    }
+}
+
+// The queue entry must be the first field so that a pointer to it can be cast
+// back to the ThreadData.
+#[repr(C)]
+struct ThreadData {
+    entry: QueueEntry,
+
+    parker: ThreadParker,
+
     // Is the thread parked with a timeout?
     parked_with_timeout: Cell<bool>,
 
-    // Extra data for deadlock detection
-    // TODO: once supported in stable replace with #[cfg...] & remove dummy struct/impl
-    #[allow(dead_code)] deadlock_data: deadlock::DeadlockData,
+    // Resources held by the thread, and extra data for deadlock detection
+    deadlock_data: deadlock::DeadlockData,
+}
+
+// Queue entry state for an asynchronous task. This is only accessed while
+// holding the queue lock.
+#[cfg(feature = "async")]
+struct TaskData {
+    // Waker to notify when the task is unparked
+    waker: UnsafeCell<Option<Waker>>,
+
+    // Is the task still in the queue?
+    parked: Cell<bool>,
 }
 
 impl ThreadData {
@@ -148,17 +201,55 @@ impl ThreadData {
         }
 
         ThreadData {
+            entry: QueueEntry::new(),
             parker: ThreadParker::new(),
-            key: AtomicUsize::new(0),
-            next_in_queue: Cell::new(ptr::null()),
-            unpark_token: Cell::new(DEFAULT_UNPARK_TOKEN),
-            park_token: Cell::new(DEFAULT_PARK_TOKEN),
             parked_with_timeout: Cell::new(false),
             deadlock_data: deadlock::DeadlockData::new(),
         }
     }
 }
 
+// Handle for a queue entry that is about to be unparked. Threads are woken up
+// through their ThreadParker while asynchronous tasks are notified through
+// their Waker.
+enum UnparkHandle {
+    Thread(thread_parker::UnparkHandle),
+    #[cfg(feature = "async")]
+    Task(Option<Waker>),
+}
+
+impl UnparkHandle {
+    // Wakes up the parked thread or task. This should be called after the
+    // queue lock is released to avoid blocking the queue for too long.
+    #[inline]
+    unsafe fn unpark(self) {
+        match self {
+            UnparkHandle::Thread(handle) => handle.unpark(),
+            #[cfg(feature = "async")]
+            UnparkHandle::Task(waker) => if let Some(waker) = waker {
+                waker.wake();
+            },
+        }
+    }
+}
+
+// Prepares an entry which was removed from the queue to be unparked. This
+// should be called while holding the queue lock, after its UnparkToken is set.
+#[inline]
+unsafe fn unpark_lock(entry: *const QueueEntry) -> UnparkHandle {
+    #[cfg(feature = "async")]
+    {
+        if let Some(ref task) = (*entry).task {
+            // Unlike a thread, the task checks its state while holding the
+            // queue lock, so it is safe to free the entry as soon as the lock
+            // is released.
+            task.parked.set(false);
+            return UnparkHandle::Task((*task.waker.get()).take());
+        }
+    }
+    UnparkHandle::Thread((*entry).thread().unchecked_unwrap().parker.unpark_lock())
+}
+
 // Returns a ThreadData structure for the current thread
 unsafe fn get_thread_data(local: &mut Option<ThreadData>) -> &ThreadData {
     // Try to read from thread-local storage, but return None if the TLS has
@@ -219,7 +310,9 @@ unsafe fn get_hashtable() -> *const HashTable {
 
 // Grow the hash table so that it is big enough for the given number of threads.
 // This isn't performance-critical since it is only done when a ThreadData is
-// created, which only happens once per thread.
+// created, which only happens once per thread. Asynchronous tasks don't have a
+// ThreadData and aren't counted, so the table doesn't grow with the number of
+// waiting tasks.
 unsafe fn grow_hashtable(num_threads: usize) {
     // If there is no table, create one
     if HASHTABLE.load(Ordering::Relaxed) == 0 {
@@ -248,7 +341,7 @@ unsafe fn grow_hashtable(num_threads: usize) {
         }
 
         // Lock all buckets in the old table
-        for b in &(*old_table).entries[..] {
+        for b in &(&(*old_table).entries)[..] {
             b.mutex.lock();
         }
 
@@ -260,7 +353,7 @@ unsafe fn grow_hashtable(num_threads: usize) {
         }
 
         // Unlock buckets and try again
-        for b in &(*old_table).entries[..] {
+        for b in &(&(*old_table).entries)[..] {
             b.mutex.unlock();
         }
     }
@@ -269,7 +362,7 @@ unsafe fn grow_hashtable(num_threads: usize) {
     let new_table = HashTable::new(num_threads, old_table);
 
     // Move the entries from the old table to the new one
-    for b in &(*old_table).entries[..] {
+    for b in &(&(*old_table).entries)[..] {
         let mut current = b.queue_head.get();
         while !current.is_null() {
             let next = (*current).next_in_queue.get();
@@ -293,7 +386,7 @@ unsafe fn grow_hashtable(num_threads: usize) {
     HASHTABLE.store(Box::into_raw(new_table) as usize, Ordering::Release);
 
     // Unlock all buckets in the old table
-    for b in &(*old_table).entries[..] {
+    for b in &(&(*old_table).entries)[..] {
         b.mutex.unlock();
     }
 }
@@ -399,6 +492,48 @@ unsafe fn lock_bucket_pair<'a>(key1: usize, key2: usize) -> (&'a Bucket, &'a Buc
     }
 }
 
+// Lock the buckets for all of the given keys. Like with lock_bucket_pair, they
+// are locked in increasing order so that this can't deadlock. Returns the hash
+// table and the sorted indices of the locked buckets.
+unsafe fn lock_bucket_set<'a>(keys: &[usize]) -> (&'a HashTable, SmallVec<[usize; 8]>) {
+    loop {
+        let hashtable = &*get_hashtable();
+
+        let mut buckets: SmallVec<[usize; 8]> = keys
+            .iter()
+            .map(|&key| hash(key, hashtable.hash_bits))
+            .collect();
+        buckets.sort_unstable();
+
+        // Lock the first bucket
+        hashtable.entries[buckets[0]].mutex.lock();
+
+        // If no other thread has rehashed the table before we grabbed the lock
+        // then we are good to go! The lock we grabbed prevents any rehashes.
+        if HASHTABLE.load(Ordering::Relaxed) == hashtable as *const _ as usize {
+            // Now lock the other buckets, skipping duplicates
+            for i in 1..buckets.len() {
+                if buckets[i] != buckets[i - 1] {
+                    hashtable.entries[buckets[i]].mutex.lock();
+                }
+            }
+            return (hashtable, buckets);
+        }
+
+        // Unlock the bucket and try again
+        hashtable.entries[buckets[0]].mutex.unlock();
+    }
+}
+
+// Unlock a set of buckets locked by lock_bucket_set
+unsafe fn unlock_bucket_set(hashtable: &HashTable, buckets: &[usize]) {
+    for i in (0..buckets.len()).rev() {
+        if i == 0 || buckets[i] != buckets[i - 1] {
+            hashtable.entries[buckets[i]].mutex.unlock();
+        }
+    }
+}
+
 // Unlock a pair of buckets
 unsafe fn unlock_bucket_pair(bucket1: &Bucket, bucket2: &Bucket) {
     if bucket1 as *const _ == bucket2 as *const _ {
@@ -442,6 +577,10 @@ pub struct UnparkResult {
     /// The number of threads that were unparked.
     pub unparked_threads: usize,
 
+    /// The number of threads that were requeued. This is only ever non-zero
+    /// for `unpark_requeue` and `unpark_requeue_filter`.
+    pub requeued_threads: usize,
+
     /// Whether there are any threads remaining in the queue. This only returns
     /// true if a thread was unparked.
     pub have_more_threads: bool,
@@ -570,17 +709,19 @@ unsafe fn park_internal(
     }
 
     // Append our thread data to the queue and unlock the bucket
+    let entry = &thread_data.entry;
     thread_data.parked_with_timeout.set(timeout.is_some());
-    thread_data.next_in_queue.set(ptr::null());
-    thread_data.key.store(key, Ordering::Relaxed);
-    thread_data.park_token.set(park_token);
+    entry.next_in_queue.set(ptr::null());
+    entry.key.store(key, Ordering::Relaxed);
+    entry.park_token.set(park_token);
+    deadlock::on_enqueue(thread_data);
     thread_data.parker.prepare_park();
     if !bucket.queue_head.get().is_null() {
-        (*bucket.queue_tail.get()).next_in_queue.set(thread_data);
+        (*bucket.queue_tail.get()).next_in_queue.set(entry);
     } else {
-        bucket.queue_head.set(thread_data);
+        bucket.queue_head.set(entry);
     }
-    bucket.queue_tail.set(thread_data);
+    bucket.queue_tail.set(entry);
     bucket.mutex.unlock();
 
     // Invoke the pre-sleep callback
@@ -592,6 +733,8 @@ unsafe fn park_internal(
     let unparked = match timeout {
         Some(timeout) => thread_data.parker.park_until(timeout),
         None => {
+            // call deadlock detection on_park hook
+            deadlock::on_park(thread_data);
             thread_data.parker.park();
             // call deadlock detection on_unpark hook
             deadlock::on_unpark(thread_data);
@@ -601,62 +744,73 @@ unsafe fn park_internal(
 
     // If we were unparked, return now
     if unparked {
-        return ParkResult::Unparked(thread_data.unpark_token.get());
+        return ParkResult::Unparked(entry.unpark_token.get());
     }
 
     // Lock our bucket again. Note that the hashtable may have been rehashed in
     // the meantime. Our key may also have changed if we were requeued.
-    let (key, bucket) = lock_bucket_checked(&thread_data.key);
+    let (key, bucket) = lock_bucket_checked(&entry.key);
 
     // Now we need to check again if we were unparked or timed out. Unlike the
     // last check this is precise because we hold the bucket lock.
     if !thread_data.parker.timed_out() {
         bucket.mutex.unlock();
-        return ParkResult::Unparked(thread_data.unpark_token.get());
+        return ParkResult::Unparked(entry.unpark_token.get());
     }
 
-    // We timed out, so we now need to remove our thread from the queue
+    // We timed out, so we now need to remove our thread from the queue.
+    let was_last_thread = remove_from_queue(bucket, key, entry);
+
+    // Callback to indicate that we timed out, and whether we were the last
+    // thread on the queue.
+    timed_out(key, was_last_thread);
+
+    // Unlock the bucket, we are done
+    bucket.mutex.unlock();
+    ParkResult::TimedOut
+}
+
+// Removes an entry from the queue of the given bucket, which must be locked.
+// Returns whether it was the last entry in the queue with the given key.
+unsafe fn remove_from_queue(bucket: &Bucket, key: usize, entry: *const QueueEntry) -> bool {
     let mut link = &bucket.queue_head;
     let mut current = bucket.queue_head.get();
     let mut previous = ptr::null();
+    let mut was_last_thread = true;
     while !current.is_null() {
-        if current == thread_data {
+        if current == entry {
             let next = (*current).next_in_queue.get();
             link.set(next);
-            let mut was_last_thread = true;
             if bucket.queue_tail.get() == current {
                 bucket.queue_tail.set(previous);
-            } else {
-                // Scan the rest of the queue to see if there are any other
-                // entries with the given key.
-                let mut scan = next;
-                while !scan.is_null() {
-                    if (*scan).key.load(Ordering::Relaxed) == key {
-                        was_last_thread = false;
-                        break;
-                    }
-                    scan = (*scan).next_in_queue.get();
-                }
+                return was_last_thread;
             }
 
-            // Callback to indicate that we timed out, and whether we were the
-            // last thread on the queue.
-            timed_out(key, was_last_thread);
-            break;
+            // Scan the rest of the queue to see if there are any other entries
+            // with the given key.
+            let mut scan = next;
+            while !scan.is_null() {
+                if (*scan).key.load(Ordering::Relaxed) == key {
+                    return false;
+                }
+                scan = (*scan).next_in_queue.get();
+            }
+            return was_last_thread;
         } else {
+            // Entries with the same key may also be ahead of us in the queue
+            if (*current).key.load(Ordering::Relaxed) == key {
+                was_last_thread = false;
+            }
             link = &(*current).next_in_queue;
             previous = current;
             current = link.get();
         }
     }
 
-    // There should be no way for our thread to have been removed from the queue
-    // if we timed out.
-    debug_assert!(!current.is_null());
-
-    // Unlock the bucket, we are done
-    bucket.mutex.unlock();
-    ParkResult::TimedOut
+    // There should be no way for the entry to have been removed from the queue
+    // if it wasn't unparked.
+    debug_assert!(false, "entry not found in queue");
+    true
 }
 
 /// Unparks one thread from the queue associated with the given key.
@@ -701,6 +855,7 @@ unsafe fn unpark_one_internal(
     let mut previous = ptr::null();
     let mut result = UnparkResult {
         unparked_threads: 0,
+        requeued_threads: 0,
         have_more_threads: false,
         be_fair: false,
     };
@@ -737,7 +892,7 @@ unsafe fn unpark_one_internal(
             // times out. Then we unlock the queue since we don't want to keep
             // the queue locked while we perform a system call. Finally we wake
             // up the parked thread.
-            let handle = (*current).parker.unpark_lock();
+            let handle = unpark_lock(current);
             bucket.mutex.unlock();
             handle.unpark();
 
@@ -790,7 +945,7 @@ pub unsafe fn unpark_all(key: usize, unpark_token: UnparkToken) -> usize {
             // Don't wake up threads while holding the queue lock. See comment
             // in unpark_one. For now just record which threads we need to wake
             // up.
-            threads.push((*current).parker.unpark_lock());
+            threads.push(unpark_lock(current));
             current = next;
         } else {
             link = &(*current).next_in_queue;
@@ -824,9 +979,9 @@ pub unsafe fn unpark_all(key: usize, unpark_token: UnparkToken) -> usize {
 ///
 /// The `callback` function is also called while both queues are locked. It is
 /// passed the `RequeueOp` returned by `validate` and an `UnparkResult`
-/// indicating whether a thread was unparked and whether there are threads still
-/// parked in the new queue. This `UnparkResult` value is also returned by
-/// `unpark_requeue`.
+/// indicating whether a thread was unparked, how many threads were requeued and
+/// whether there are threads still parked in the new queue. This `UnparkResult`
+/// value is also returned by `unpark_requeue`.
 ///
 /// The `callback` function should return an `UnparkToken` value which will be
 /// passed to the thread that is unparked. If no thread is unparked then the
@@ -874,6 +1029,7 @@ unsafe fn unpark_requeue_internal(
     // If the validation function fails, just return
     let mut result = UnparkResult {
         unparked_threads: 0,
+        requeued_threads: 0,
         have_more_threads: false,
         be_fair: false,
     };
@@ -887,8 +1043,8 @@ unsafe fn unpark_requeue_internal(
     let mut link = &bucket_from.queue_head;
     let mut current = bucket_from.queue_head.get();
     let mut previous = ptr::null();
-    let mut requeue_threads: *const ThreadData = ptr::null();
-    let mut requeue_threads_tail: *const ThreadData = ptr::null();
+    let mut requeue_threads: *const QueueEntry = ptr::null();
+    let mut requeue_threads_tail: *const QueueEntry = ptr::null();
     let mut wakeup_thread = None;
     while !current.is_null() {
         if (*current).key.load(Ordering::Relaxed) == key_from {
@@ -911,6 +1067,7 @@ unsafe fn unpark_requeue_internal(
                 }
                 requeue_threads_tail = current;
                 (*current).key.store(key_to, Ordering::Relaxed);
+                result.requeued_threads += 1;
                 result.have_more_threads = true;
             }
             current = next;
@@ -943,7 +1100,7 @@ unsafe fn unpark_requeue_internal(
     // See comment in unpark_one for why we mess with the locking
     if let Some(wakeup_thread) = wakeup_thread {
         (*wakeup_thread).unpark_token.set(token);
-        let handle = (*wakeup_thread).parker.unpark_lock();
+        let handle = unpark_lock(wakeup_thread);
         unlock_bucket_pair(bucket_from, bucket_to);
         handle.unpark();
     } else {
@@ -953,22 +1110,29 @@ unsafe fn unpark_requeue_internal(
     result
 }
 
-/// Unparks a number of threads from the front of the queue associated with
-/// `key` depending on the results of a filter function which inspects the
-/// `ParkToken` associated with each thread.
+/// Removes the threads selected by a filter function from the queue associated
+/// with `key_from`, optionally unparks the first one and requeues the rest onto
+/// the queue associated with `key_to`.
 ///
-/// The `filter` function is called for each thread in the queue or until
-/// `FilterOp::Stop` is returned. This function is passed the `ParkToken`
-/// associated with a particular thread, which is unparked if `FilterOp::Unpark`
-/// is returned.
+/// The `filter` function is called for each thread in the source queue or
+/// until `FilterOp::Stop` is returned. This function is passed the `ParkToken`
+/// associated with a particular thread, which is selected if `FilterOp::Unpark`
+/// is returned. Threads which are not selected are left in the source queue.
 ///
-/// The `callback` function is also called while both queues are locked. It is
-/// passed an `UnparkResult` indicating the number of threads that were unparked
-/// and whether there are still parked threads in the queue. This `UnparkResult`
-/// value is also returned by `unpark_filter`.
+/// The `validate` function is called while both queues are locked, but only if
+/// at least one thread was selected. It decides what happens to the selected
+/// threads in the same way as for `unpark_requeue`, and can abort the operation
+/// by returning `RequeueOp::Abort`.
+///
+/// The `callback` function is also called while both queues are locked, unless
+/// the operation was aborted. It is passed the `RequeueOp` returned by
+/// `validate` and an `UnparkResult` indicating how many threads were unparked
+/// and requeued. Unlike with `unpark_requeue`, `have_more_threads` indicates
+/// whether there are threads still parked in the source queue. This
+/// `UnparkResult` value is also returned by `unpark_requeue_filter`.
 ///
 /// The `callback` function should return an `UnparkToken` value which will be
-/// passed to all threads that are unparked. If no thread is unparked then the
+/// passed to the thread that is unparked. If no thread is unparked then the
 /// returned value is ignored.
 ///
 /// # Safety
@@ -977,65 +1141,116 @@ unsafe fn unpark_requeue_internal(
 /// you could otherwise interfere with the operation of other synchronization
 /// primitives.
 ///
-/// The `filter` and `callback` functions are called while the queue is locked
-/// and must not panic or call into any function in `parking_lot`.
+/// The `validate`, `filter` and `callback` functions are called while the
+/// queue is locked and must not panic or call into any function in
+/// `parking_lot`.
 #[inline]
-pub unsafe fn unpark_filter<F, C>(key: usize, mut filter: F, callback: C) -> UnparkResult
+pub unsafe fn unpark_requeue_filter<V, F, C>(
+    key_from: usize,
+    key_to: usize,
+    validate: V,
+    mut filter: F,
+    callback: C,
+) -> UnparkResult
 where
+    V: FnOnce() -> RequeueOp,
     F: FnMut(ParkToken) -> FilterOp,
-    C: FnOnce(UnparkResult) -> UnparkToken,
+    C: FnOnce(RequeueOp, UnparkResult) -> UnparkToken,
 {
+    let mut v = Some(validate);
     let mut c = Some(callback);
-    unpark_filter_internal(key, &mut filter, &mut |r| c.take().unchecked_unwrap()(r))
+    unpark_requeue_filter_internal(
+        key_from,
+        key_to,
+        &mut || v.take().unchecked_unwrap()(),
+        &mut filter,
+        &mut |op, r| c.take().unchecked_unwrap()(op, r),
+    )
 }
 
 // Non-generic version to reduce monomorphization cost
-unsafe fn unpark_filter_internal(
-    key: usize,
+unsafe fn unpark_requeue_filter_internal(
+    key_from: usize,
+    key_to: usize,
+    validate: &mut FnMut() -> RequeueOp,
     filter: &mut FnMut(ParkToken) -> FilterOp,
-    callback: &mut FnMut(UnparkResult) -> UnparkToken,
+    callback: &mut FnMut(RequeueOp, UnparkResult) -> UnparkToken,
 ) -> UnparkResult {
-    // Lock the bucket for the given key
-    let bucket = lock_bucket(key);
+    // Lock the two buckets for the given key
+    let (bucket_from, bucket_to) = lock_bucket_pair(key_from, key_to);
 
-    // Go through the queue looking for threads with a matching key
-    let mut link = &bucket.queue_head;
-    let mut current = bucket.queue_head.get();
-    let mut previous = ptr::null();
-    let mut threads = SmallVec::<[_; 8]>::new();
+    // Select threads with the given key in the source bucket. They are only
+    // removed from the queue once the validation function has run.
     let mut result = UnparkResult {
         unparked_threads: 0,
+        requeued_threads: 0,
         have_more_threads: false,
         be_fair: false,
     };
+    let mut selected = SmallVec::<[*const QueueEntry; 8]>::new();
+    let mut current = bucket_from.queue_head.get();
     while !current.is_null() {
-        if (*current).key.load(Ordering::Relaxed) == key {
-            // Call the filter function with the thread's ParkToken
-            let next = (*current).next_in_queue.get();
+        if (*current).key.load(Ordering::Relaxed) == key_from {
             match filter((*current).park_token.get()) {
-                FilterOp::Unpark => {
-                    // Remove the thread from the queue
-                    link.set(next);
-                    if bucket.queue_tail.get() == current {
-                        bucket.queue_tail.set(previous);
-                    }
-
-                    // Add the thread to our list of threads to unpark
-                    threads.push((current, None));
-
-                    current = next;
-                }
-                FilterOp::Skip => {
-                    result.have_more_threads = true;
-                    link = &(*current).next_in_queue;
-                    previous = current;
-                    current = link.get();
-                }
+                FilterOp::Unpark => selected.push(current),
+                FilterOp::Skip => result.have_more_threads = true,
                 FilterOp::Stop => {
                     result.have_more_threads = true;
                     break;
                 }
             }
+        }
+        current = (*current).next_in_queue.get();
+    }
+
+    // If no threads were selected or the validation function fails, just
+    // return
+    let op = if selected.is_empty() {
+        RequeueOp::Abort
+    } else {
+        validate()
+    };
+    if op == RequeueOp::Abort {
+        unlock_bucket_pair(bucket_from, bucket_to);
+        result.have_more_threads |= !selected.is_empty();
+        return result;
+    }
+
+    // Remove the selected threads from the source bucket, which appear in the
+    // same order as in the queue.
+    let mut link = &bucket_from.queue_head;
+    let mut current = bucket_from.queue_head.get();
+    let mut previous = ptr::null();
+    let mut requeue_threads: *const QueueEntry = ptr::null();
+    let mut requeue_threads_tail: *const QueueEntry = ptr::null();
+    let mut wakeup_thread = None;
+    let mut selected = selected.into_iter().peekable();
+    while !current.is_null() && selected.peek().is_some() {
+        if selected.peek() == Some(&current) {
+            selected.next();
+
+            // Remove the thread from the queue
+            let next = (*current).next_in_queue.get();
+            link.set(next);
+            if bucket_from.queue_tail.get() == current {
+                bucket_from.queue_tail.set(previous);
+            }
+
+            // Prepare the first thread for wakeup and requeue the rest.
+            if op == RequeueOp::UnparkOneRequeueRest && wakeup_thread.is_none() {
+                wakeup_thread = Some(current);
+                result.unparked_threads = 1;
+            } else {
+                if !requeue_threads.is_null() {
+                    (*requeue_threads_tail).next_in_queue.set(current);
+                } else {
+                    requeue_threads = current;
+                }
+                requeue_threads_tail = current;
+                (*current).key.store(key_to, Ordering::Relaxed);
+                result.requeued_threads += 1;
+            }
+            current = next;
         } else {
             link = &(*current).next_in_queue;
             previous = current;
@@ -1043,69 +1258,863 @@ unsafe fn unpark_filter_internal(
         }
     }
 
-    // Invoke the callback before waking up the threads
-    result.unparked_threads = threads.len();
-    if result.unparked_threads != 0 {
-        result.be_fair = (*bucket.fair_timeout.get()).should_timeout();
+    // Add the requeued threads to the destination bucket
+    if !requeue_threads.is_null() {
+        (*requeue_threads_tail).next_in_queue.set(ptr::null());
+        if !bucket_to.queue_head.get().is_null() {
+            (*bucket_to.queue_tail.get())
+                .next_in_queue
+                .set(requeue_threads);
+        } else {
+            bucket_to.queue_head.set(requeue_threads);
+        }
+        bucket_to.queue_tail.set(requeue_threads_tail);
     }
-    let token = callback(result);
 
-    // Pass the token to all threads that are going to be unparked and prepare
-    // them for unparking.
-    for t in threads.iter_mut() {
-        (*t.0).unpark_token.set(token);
-        t.1 = Some((*t.0).parker.unpark_lock());
+    // Invoke the callback before waking up the thread
+    if result.unparked_threads != 0 {
+        result.be_fair = (*bucket_from.fair_timeout.get()).should_timeout();
     }
+    let token = callback(op, result);
 
-    bucket.mutex.unlock();
-
-    // Now that we are outside the lock, wake up all the threads that we removed
-    // from the queue.
-    for (_, handle) in threads.into_iter() {
-        handle.unchecked_unwrap().unpark();
+    // See comment in unpark_one for why we mess with the locking
+    if let Some(wakeup_thread) = wakeup_thread {
+        (*wakeup_thread).unpark_token.set(token);
+        let handle = unpark_lock(wakeup_thread);
+        unlock_bucket_pair(bucket_from, bucket_to);
+        handle.unpark();
+    } else {
+        unlock_bucket_pair(bucket_from, bucket_to);
     }
 
     result
 }
 
-/// [Experimental] Deadlock detection
+/// Removes all threads from the queue associated with `key_from` and either
+/// unparks them or requeues them onto a queue chosen separately for each
+/// thread.
 ///
-/// Enabled via the `deadlock_detection` feature flag.
-pub mod deadlock {
-    #[cfg(feature = "deadlock_detection")]
-    use super::deadlock_impl;
-
-    #[cfg(feature = "deadlock_detection")]
-    pub(super) use super::deadlock_impl::DeadlockData;
-
-    #[cfg(not(feature = "deadlock_detection"))]
-    pub(super) struct DeadlockData {}
-
-    #[cfg(not(feature = "deadlock_detection"))]
-    impl DeadlockData {
-        pub(super) fn new() -> Self {
-            DeadlockData {}
-        }
-    }
-
-    /// Acquire a resource identified by key in the deadlock detector
-    /// Noop if deadlock_detection feature isn't enabled.
-    /// Note: Call after the resource is acquired
-    #[inline]
-    pub unsafe fn acquire_resource(_key: usize) {
-        #[cfg(feature = "deadlock_detection")]
-        deadlock_impl::acquire_resource(_key);
-    }
-
-    /// Release a resource identified by key in the deadlock detector.
-    /// Noop if deadlock_detection feature isn't enabled.
-    /// Note: Call before the resource is released
-    /// # Panics
-    /// Panics if the resource was already released or wasn't acquired in this thread.
-    #[inline]
-    pub unsafe fn release_resource(_key: usize) {
+/// The `destination` function is called with the `ParkToken` of each thread
+/// and returns the key of the queue that the thread should be requeued onto,
+/// or `None` if the thread should be unparked. Threads with the same
+/// destination form a group. This function may be called more than once for
+/// the same thread, so its result should only depend on the token.
+///
+/// The `validate` function is called once for each group while all the queues
+/// involved are locked. It is passed the destination key and the number of
+/// threads in the group, and decides what happens to the group in the same way
+/// as for `unpark_requeue`. Returning `RequeueOp::Abort` leaves the threads of
+/// the group in the source queue.
+///
+/// The `callback` function is called once all threads have been moved, while
+/// the queues are still locked. It is passed an `UnparkResult` indicating the
+/// total number of threads that were unparked and requeued. As with
+/// `unpark_requeue_filter`, `have_more_threads` indicates whether there are
+/// threads still parked in the source queue. This `UnparkResult` value is also
+/// returned by `unpark_requeue_multi`.
+///
+/// The `callback` function should return an `UnparkToken` value which will be
+/// passed to all threads that are unparked. If no thread is unparked then the
+/// returned value is ignored.
+///
+/// # Safety
+///
+/// You should only call this function with an address that you control, since
+/// you could otherwise interfere with the operation of other synchronization
+/// primitives.
+///
+/// The `destination`, `validate` and `callback` functions are called while the
+/// queue is locked and must not panic or call into any function in
+/// `parking_lot`.
+#[inline]
+pub unsafe fn unpark_requeue_multi<D, V, C>(
+    key_from: usize,
+    mut destination: D,
+    mut validate: V,
+    callback: C,
+) -> UnparkResult
+where
+    D: FnMut(ParkToken) -> Option<usize>,
+    V: FnMut(usize, usize) -> RequeueOp,
+    C: FnOnce(UnparkResult) -> UnparkToken,
+{
+    let mut c = Some(callback);
+    unpark_requeue_multi_internal(
+        key_from,
+        &mut destination,
+        &mut validate,
+        &mut |r| c.take().unchecked_unwrap()(r),
+    )
+}
+
+// Threads selected by unpark_requeue_multi with the same destination
+struct RequeueGroup {
+    num_threads: usize,
+    op: RequeueOp,
+    unparked: bool,
+    head: *const QueueEntry,
+    tail: *const QueueEntry,
+}
+
+// Non-generic version to reduce monomorphization cost
+unsafe fn unpark_requeue_multi_internal(
+    key_from: usize,
+    destination: &mut FnMut(ParkToken) -> Option<usize>,
+    validate: &mut FnMut(usize, usize) -> RequeueOp,
+    callback: &mut FnMut(UnparkResult) -> UnparkToken,
+) -> UnparkResult {
+    // Find the destinations of the threads in the source queue. Since they
+    // can't be locked yet, threads may come and go until they are.
+    let mut keys = SmallVec::<[usize; 8]>::new();
+    let bucket = lock_bucket(key_from);
+    let mut current = bucket.queue_head.get();
+    while !current.is_null() {
+        if (*current).key.load(Ordering::Relaxed) == key_from {
+            if let Some(key_to) = destination((*current).park_token.get()) {
+                keys.push(key_to);
+            }
+        }
+        current = (*current).next_in_queue.get();
+    }
+    bucket.mutex.unlock();
+    keys.sort_unstable();
+    let mut len = 0;
+    for i in 0..keys.len() {
+        if i == 0 || keys[i] != keys[len - 1] {
+            keys[len] = keys[i];
+            len += 1;
+        }
+    }
+    keys.truncate(len);
+
+    // Lock the source bucket along with all the destination buckets
+    keys.push(key_from);
+    let (hashtable, buckets) = lock_bucket_set(&keys);
+    keys.pop();
+    let bucket_from = &hashtable.entries[hash(key_from, hashtable.hash_bits)];
+
+    // Select the threads to move. Threads whose destination wasn't seen above
+    // are left in the queue, since its bucket may not be locked.
+    let mut result = UnparkResult {
+        unparked_threads: 0,
+        requeued_threads: 0,
+        have_more_threads: false,
+        be_fair: false,
+    };
+    let mut groups: SmallVec<[RequeueGroup; 8]> = keys
+        .iter()
+        .map(|_| RequeueGroup {
+            num_threads: 0,
+            op: RequeueOp::Abort,
+            unparked: false,
+            head: ptr::null(),
+            tail: ptr::null(),
+        })
+        .collect();
+    let mut selected = SmallVec::<[(*const QueueEntry, Option<usize>); 8]>::new();
+    let mut current = bucket_from.queue_head.get();
+    while !current.is_null() {
+        if (*current).key.load(Ordering::Relaxed) == key_from {
+            match destination((*current).park_token.get()) {
+                Some(key_to) => match keys.binary_search(&key_to) {
+                    Ok(group) => {
+                        groups[group].num_threads += 1;
+                        selected.push((current, Some(group)));
+                    }
+                    Err(_) => result.have_more_threads = true,
+                },
+                None => selected.push((current, None)),
+            }
+        }
+        current = (*current).next_in_queue.get();
+    }
+
+    // Decide what to do with each group
+    for (group, &key_to) in groups.iter_mut().zip(keys.iter()) {
+        if group.num_threads != 0 {
+            group.op = validate(key_to, group.num_threads);
+        }
+    }
+
+    // Remove the selected threads from the source bucket, which appear in the
+    // same order as in the queue.
+    let mut link = &bucket_from.queue_head;
+    let mut current = bucket_from.queue_head.get();
+    let mut previous = ptr::null();
+    let mut threads = SmallVec::<[_; 8]>::new();
+    let mut selected = selected.into_iter().peekable();
+    while !current.is_null() && selected.peek().is_some() {
+        let mut remove = false;
+        if selected.peek().map(|x| x.0) == Some(current) {
+            let (_, group) = selected.next().unchecked_unwrap();
+            match group {
+                Some(group) => {
+                    let group = &mut groups[group];
+                    if group.op == RequeueOp::Abort {
+                        result.have_more_threads = true;
+                    } else if group.op == RequeueOp::UnparkOneRequeueRest && !group.unparked {
+                        group.unparked = true;
+                        threads.push((current, None));
+                        remove = true;
+                    } else {
+                        if !group.head.is_null() {
+                            (*group.tail).next_in_queue.set(current);
+                        } else {
+                            group.head = current;
+                        }
+                        group.tail = current;
+                        result.requeued_threads += 1;
+                        remove = true;
+                    }
+                }
+                None => {
+                    threads.push((current, None));
+                    remove = true;
+                }
+            }
+        }
+
+        if remove {
+            // Remove the thread from the queue
+            let next = (*current).next_in_queue.get();
+            link.set(next);
+            if bucket_from.queue_tail.get() == current {
+                bucket_from.queue_tail.set(previous);
+            }
+            current = next;
+        } else {
+            link = &(*current).next_in_queue;
+            previous = current;
+            current = link.get();
+        }
+    }
+
+    // Add the requeued threads to their destination buckets
+    for (group, &key_to) in groups.iter().zip(keys.iter()) {
+        if group.head.is_null() {
+            continue;
+        }
+        let bucket_to = &hashtable.entries[hash(key_to, hashtable.hash_bits)];
+        let mut current = group.head;
+        while current != group.tail {
+            (*current).key.store(key_to, Ordering::Relaxed);
+            current = (*current).next_in_queue.get();
+        }
+        (*group.tail).key.store(key_to, Ordering::Relaxed);
+        (*group.tail).next_in_queue.set(ptr::null());
+        if !bucket_to.queue_head.get().is_null() {
+            (*bucket_to.queue_tail.get()).next_in_queue.set(group.head);
+        } else {
+            bucket_to.queue_head.set(group.head);
+        }
+        bucket_to.queue_tail.set(group.tail);
+    }
+
+    // Invoke the callback before waking up the threads
+    result.unparked_threads = threads.len();
+    if result.unparked_threads != 0 {
+        result.be_fair = (*bucket_from.fair_timeout.get()).should_timeout();
+    }
+    let token = callback(result);
+
+    // Pass the token to all threads that are going to be unparked and prepare
+    // them for unparking.
+    for t in threads.iter_mut() {
+        (*t.0).unpark_token.set(token);
+        t.1 = Some(unpark_lock(t.0));
+    }
+
+    unlock_bucket_set(hashtable, &buckets);
+
+    // Now that we are outside the lock, wake up all the threads that we removed
+    // from the queue.
+    for (_, handle) in threads.into_iter() {
+        handle.unchecked_unwrap().unpark();
+    }
+
+    result
+}
+
+/// Unparks a number of threads from the front of the queue associated with
+/// `key` depending on the results of a filter function which inspects the
+/// `ParkToken` associated with each thread.
+///
+/// The `filter` function is called for each thread in the queue or until
+/// `FilterOp::Stop` is returned. This function is passed the `ParkToken`
+/// associated with a particular thread, which is unparked if `FilterOp::Unpark`
+/// is returned.
+///
+/// The `callback` function is also called while both queues are locked. It is
+/// passed an `UnparkResult` indicating the number of threads that were unparked
+/// and whether there are still parked threads in the queue. This `UnparkResult`
+/// value is also returned by `unpark_filter`.
+///
+/// The `callback` function should return an `UnparkToken` value which will be
+/// passed to all threads that are unparked. If no thread is unparked then the
+/// returned value is ignored.
+///
+/// # Safety
+///
+/// You should only call this function with an address that you control, since
+/// you could otherwise interfere with the operation of other synchronization
+/// primitives.
+///
+/// The `filter` and `callback` functions are called while the queue is locked
+/// and must not panic or call into any function in `parking_lot`.
+#[inline]
+pub unsafe fn unpark_filter<F, C>(key: usize, mut filter: F, callback: C) -> UnparkResult
+where
+    F: FnMut(ParkToken) -> FilterOp,
+    C: FnOnce(UnparkResult) -> UnparkToken,
+{
+    let mut c = Some(callback);
+    unpark_filter_internal(key, &mut filter, &mut |r| c.take().unchecked_unwrap()(r))
+}
+
+// Non-generic version to reduce monomorphization cost
+unsafe fn unpark_filter_internal(
+    key: usize,
+    filter: &mut FnMut(ParkToken) -> FilterOp,
+    callback: &mut FnMut(UnparkResult) -> UnparkToken,
+) -> UnparkResult {
+    // Lock the bucket for the given key
+    let bucket = lock_bucket(key);
+
+    // Go through the queue looking for threads with a matching key
+    let mut link = &bucket.queue_head;
+    let mut current = bucket.queue_head.get();
+    let mut previous = ptr::null();
+    let mut threads = SmallVec::<[_; 8]>::new();
+    let mut result = UnparkResult {
+        unparked_threads: 0,
+        requeued_threads: 0,
+        have_more_threads: false,
+        be_fair: false,
+    };
+    while !current.is_null() {
+        if (*current).key.load(Ordering::Relaxed) == key {
+            // Call the filter function with the thread's ParkToken
+            let next = (*current).next_in_queue.get();
+            match filter((*current).park_token.get()) {
+                FilterOp::Unpark => {
+                    // Remove the thread from the queue
+                    link.set(next);
+                    if bucket.queue_tail.get() == current {
+                        bucket.queue_tail.set(previous);
+                    }
+
+                    // Add the thread to our list of threads to unpark
+                    threads.push((current, None));
+
+                    current = next;
+                }
+                FilterOp::Skip => {
+                    result.have_more_threads = true;
+                    link = &(*current).next_in_queue;
+                    previous = current;
+                    current = link.get();
+                }
+                FilterOp::Stop => {
+                    result.have_more_threads = true;
+                    break;
+                }
+            }
+        } else {
+            link = &(*current).next_in_queue;
+            previous = current;
+            current = link.get();
+        }
+    }
+
+    // Invoke the callback before waking up the threads
+    result.unparked_threads = threads.len();
+    if result.unparked_threads != 0 {
+        result.be_fair = (*bucket.fair_timeout.get()).should_timeout();
+    }
+    let token = callback(result);
+
+    // Pass the token to all threads that are going to be unparked and prepare
+    // them for unparking.
+    for t in threads.iter_mut() {
+        (*t.0).unpark_token.set(token);
+        t.1 = Some(unpark_lock(t.0));
+    }
+
+    bucket.mutex.unlock();
+
+    // Now that we are outside the lock, wake up all the threads that we removed
+    // from the queue.
+    for (_, handle) in threads.into_iter() {
+        handle.unchecked_unwrap().unpark();
+    }
+
+    result
+}
+
+/// A queue entry which allows an asynchronous task to wait in the queue
+/// associated with a key, in the same way that `park` does for threads.
+///
+/// Waiting tasks are treated exactly like parked threads by `unpark_one`,
+/// `unpark_all`, `unpark_requeue`, `unpark_filter` and `unpark_requeue_filter`,
+/// so threads and tasks can wait on the same key. Instead of being woken up,
+/// an unparked task is notified through the `Waker` it most recently
+/// registered.
+///
+/// This type is only available with the `async` feature.
+#[cfg(feature = "async")]
+pub struct AsyncWaiter {
+    // The queue entry is boxed so that its address doesn't change when the
+    // waiter is moved.
+    entry: Box<QueueEntry>,
+}
+
+#[cfg(feature = "async")]
+unsafe impl Send for AsyncWaiter {}
+#[cfg(feature = "async")]
+unsafe impl Sync for AsyncWaiter {}
+
+#[cfg(feature = "async")]
+impl AsyncWaiter {
+    /// Creates a new waiter which isn't in any queue.
+    pub fn new() -> AsyncWaiter {
+        let mut entry = Box::new(QueueEntry::new());
+        entry.task = Some(TaskData {
+            waker: UnsafeCell::new(None),
+            parked: Cell::new(false),
+        });
+        AsyncWaiter { entry }
+    }
+
+    #[inline]
+    fn task(&self) -> &TaskData {
+        unsafe { self.entry.task.as_ref().unchecked_unwrap() }
+    }
+
+    /// Returns whether the waiter is in a queue, waiting to be unparked.
+    ///
+    /// This is true after a successful call to `park`, until `poll` or
+    /// `cancel` return the token passed to the waiter when it was unparked,
+    /// or until `cancel` removes it from the queue.
+    #[inline]
+    pub fn is_parked(&self) -> bool {
+        self.entry.key.load(Ordering::Relaxed) != 0
+    }
+
+    /// Adds the waiter to the queue associated with the given key.
+    ///
+    /// The `validate` function is called while the queue is locked and can
+    /// abort the operation by returning false, in which case this function
+    /// also returns false. Otherwise the waiter is appended to the queue and
+    /// the given `Waker` is registered to be notified when it is unparked.
+    ///
+    /// # Safety
+    ///
+    /// You should only call this function with an address that you control,
+    /// since you could otherwise interfere with the operation of other
+    /// synchronization primitives.
+    ///
+    /// The `validate` function is called while the queue is locked and must
+    /// not panic or call into any function in `parking_lot`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the waiter is already parked.
+    #[inline]
+    pub unsafe fn park<V>(
+        &mut self,
+        key: usize,
+        validate: V,
+        park_token: ParkToken,
+        waker: &Waker,
+    ) -> bool
+    where
+        V: FnOnce() -> bool,
+    {
+        let mut v = Some(validate);
+        self.park_internal(key, &mut || v.take().unchecked_unwrap()(), park_token, waker)
+    }
+
+    // Non-generic version to reduce monomorphization cost
+    unsafe fn park_internal(
+        &mut self,
+        key: usize,
+        validate: &mut FnMut() -> bool,
+        park_token: ParkToken,
+        waker: &Waker,
+    ) -> bool {
+        assert!(!self.is_parked(), "AsyncWaiter is already parked");
+        debug_assert!(key != 0, "AsyncWaiter can't be parked on key 0");
+
+        // Lock the bucket for the given key
+        let bucket = lock_bucket(key);
+
+        // If the validation function fails, just return
+        if !validate() {
+            bucket.mutex.unlock();
+            return false;
+        }
+
+        // Append the waiter to the queue and unlock the bucket
+        let entry = &*self.entry;
+        let task = self.task();
+        *task.waker.get() = Some(waker.clone());
+        task.parked.set(true);
+        entry.next_in_queue.set(ptr::null());
+        entry.key.store(key, Ordering::Relaxed);
+        entry.park_token.set(park_token);
+        if !bucket.queue_head.get().is_null() {
+            (*bucket.queue_tail.get()).next_in_queue.set(entry);
+        } else {
+            bucket.queue_head.set(entry);
+        }
+        bucket.queue_tail.set(entry);
+        bucket.mutex.unlock();
+        true
+    }
+
+    /// Checks whether the waiter has been unparked.
+    ///
+    /// If the waiter was unparked, this returns the `UnparkToken` passed to it
+    /// and the waiter is no longer parked. Otherwise this returns `None` and
+    /// registers the given `Waker` to be notified instead of the previous one.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the waiter isn't parked.
+    pub fn poll(&mut self, waker: &Waker) -> Option<UnparkToken> {
+        assert!(self.is_parked(), "AsyncWaiter is not parked");
+        unsafe {
+            // Lock our bucket. Note that our key may have changed if we were
+            // requeued.
+            let (_, bucket) = lock_bucket_checked(&self.entry.key);
+            let task = self.task();
+            let result = if task.parked.get() {
+                let current = &mut *task.waker.get();
+                let stale = match *current {
+                    Some(ref current) => !current.will_wake(waker),
+                    None => true,
+                };
+                if stale {
+                    *current = Some(waker.clone());
+                }
+                None
+            } else {
+                Some(self.entry.unpark_token.get())
+            };
+            bucket.mutex.unlock();
+            if result.is_some() {
+                self.entry.key.store(0, Ordering::Relaxed);
+            }
+            result
+        }
+    }
+
+    /// Stops waiting, removing the waiter from its queue.
+    ///
+    /// If the waiter was still in the queue then it is removed and the
+    /// `timed_out` function is called while the queue is locked, in the same
+    /// way as for a thread whose `park` timed out. It is passed the key of the
+    /// queue, which may be different from the original key if the waiter was
+    /// requeued, and whether the waiter was the last one in the queue. This
+    /// function then returns `None`.
+    ///
+    /// If the waiter had already been unparked, this returns the key of the
+    /// queue it was unparked from along with the `UnparkToken` passed to it.
+    /// The caller is then responsible for anything the unparker expected it to
+    /// do, such as releasing a lock which was handed off to it.
+    ///
+    /// # Safety
+    ///
+    /// The `timed_out` function is called while the queue is locked and must
+    /// not panic or call into any function in `parking_lot`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the waiter isn't parked.
+    #[inline]
+    pub unsafe fn cancel<T>(&mut self, timed_out: T) -> Option<(usize, UnparkToken)>
+    where
+        T: FnOnce(usize, bool),
+    {
+        let mut t = Some(timed_out);
+        self.cancel_internal(&mut |key, was_last_thread| {
+            t.take().unchecked_unwrap()(key, was_last_thread)
+        })
+    }
+
+    // Non-generic version to reduce monomorphization cost
+    unsafe fn cancel_internal(
+        &mut self,
+        timed_out: &mut FnMut(usize, bool),
+    ) -> Option<(usize, UnparkToken)> {
+        assert!(self.is_parked(), "AsyncWaiter is not parked");
+
+        // Lock our bucket. Note that our key may have changed if we were
+        // requeued.
+        let (key, bucket) = lock_bucket_checked(&self.entry.key);
+        let task = self.task();
+        let result = if task.parked.get() {
+            let was_last_thread = remove_from_queue(bucket, key, &*self.entry);
+            task.parked.set(false);
+            *task.waker.get() = None;
+            timed_out(key, was_last_thread);
+            None
+        } else {
+            Some((key, self.entry.unpark_token.get()))
+        };
+        bucket.mutex.unlock();
+        self.entry.key.store(0, Ordering::Relaxed);
+        result
+    }
+}
+
+#[cfg(feature = "async")]
+impl Default for AsyncWaiter {
+    #[inline]
+    fn default() -> AsyncWaiter {
+        AsyncWaiter::new()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Drop for AsyncWaiter {
+    fn drop(&mut self) {
+        // Make sure the queue never refers to a freed entry. Primitives should
+        // call cancel themselves so that they can update their state.
+        if self.is_parked() {
+            unsafe {
+                self.cancel(|_, _| {});
+            }
+        }
+    }
+}
+
+/// [Experimental] Deadlock detection
+///
+/// Tracking of the resources held by each thread is always compiled in, but it
+/// is disabled by default. It can be enabled at runtime with `set_enabled`, or
+/// at startup by setting the `PARKING_LOT_DEADLOCK_DETECTION` environment
+/// variable to `1`.
+///
+/// The detector itself, which looks for cycles among the threads waiting on
+/// tracked resources, is enabled via the `deadlock_detection` feature flag. This
+/// feature also enables tracking by default, in which case it can be disabled by
+/// setting the environment variable to `0`.
+pub mod deadlock {
+    use super::get_thread_data;
+    use std::cell::{Cell, UnsafeCell};
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    #[cfg(feature = "deadlock_detection")]
+    use super::deadlock_impl;
+
+    #[cfg(feature = "deadlock_detection")]
+    pub use super::deadlock_impl::{DeadlockedThread, LockHolder, LongWait};
+
+    #[cfg(feature = "deadlock_detection")]
+    use std::time::Duration;
+
+    pub(super) struct DeadlockData {
+        // Currently owned resources (keys)
+        resources: UnsafeCell<Vec<usize>>,
+
+        // Detection epoch in which the resources were recorded
+        epoch: Cell<usize>,
+
+        // Extra data used by the deadlock detector
+        #[cfg(feature = "deadlock_detection")]
+        pub(super) detector: deadlock_impl::DetectorData,
+    }
+
+    impl DeadlockData {
+        pub(super) fn new() -> Self {
+            DeadlockData {
+                resources: UnsafeCell::new(Vec::new()),
+                epoch: Cell::new(0),
+                #[cfg(feature = "deadlock_detection")]
+                detector: deadlock_impl::DetectorData::new(),
+            }
+        }
+
+        // Returns the resources held by this thread, ignoring any which were
+        // recorded before tracking was last enabled.
+        pub(super) unsafe fn resources(&self, epoch: usize) -> &[usize] {
+            if self.epoch.get() == epoch {
+                &*self.resources.get()
+            } else {
+                &[]
+            }
+        }
+
+        // Discards resources recorded before tracking was last enabled. Must
+        // only be called from the owning thread before updating its resources.
+        unsafe fn update_epoch(&self, epoch: usize) {
+            if self.epoch.get() != epoch {
+                (*self.resources.get()).clear();
+                self.epoch.set(epoch);
+            }
+        }
+    }
+
+    // Runtime tracking state. The lowest bit is set while tracking is enabled
+    // and the remaining bits hold an epoch which is incremented every time
+    // tracking is enabled. Zero means that the state hasn't been initialized
+    // yet.
+    static DETECTION_STATE: AtomicUsize = ATOMIC_USIZE_INIT;
+    const ENABLED_BIT: usize = 1;
+    const EPOCH_SHIFT: usize = 1;
+
+    // Returns the current epoch if tracking is enabled
+    #[inline]
+    pub(super) fn current_epoch() -> Option<usize> {
+        let mut state = DETECTION_STATE.load(Ordering::Acquire);
+        if state == 0 {
+            state = init_detection_state();
+        }
+        if state & ENABLED_BIT != 0 {
+            Some(state >> EPOCH_SHIFT)
+        } else {
+            None
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn init_detection_state() -> usize {
+        let enabled = match env::var_os("PARKING_LOT_DEADLOCK_DETECTION") {
+            Some(value) => value.to_str() != Some("0"),
+            None => cfg!(feature = "deadlock_detection"),
+        };
+        let state = (1 << EPOCH_SHIFT) | if enabled { ENABLED_BIT } else { 0 };
+        match DETECTION_STATE.compare_exchange(0, state, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => state,
+            Err(x) => x,
+        }
+    }
+
+    /// Acquire a resource identified by key in the deadlock detector
+    /// Noop while tracking is disabled, other than for the lock watchdog.
+    /// Note: Call after the resource is acquired
+    #[inline]
+    pub unsafe fn acquire_resource(key: usize) {
+        #[cfg(feature = "deadlock_detection")]
+        deadlock_impl::on_acquire(key);
+        if let Some(epoch) = current_epoch() {
+            track_acquire(key, epoch);
+        }
+    }
+
+    /// Release a resource identified by key in the deadlock detector.
+    /// Noop while tracking is disabled, other than for the lock watchdog.
+    /// Note: Call before the resource is released
+    ///
+    /// Releasing a resource which isn't tracked for this thread is ignored,
+    /// since it may have been acquired while tracking was disabled.
+    #[inline]
+    pub unsafe fn release_resource(key: usize) {
         #[cfg(feature = "deadlock_detection")]
-        deadlock_impl::release_resource(_key);
+        deadlock_impl::on_release(key);
+        if let Some(epoch) = current_epoch() {
+            track_release(key, epoch);
+        }
+    }
+
+    #[inline(never)]
+    unsafe fn track_acquire(key: usize, epoch: usize) {
+        let mut thread_data = None;
+        let thread_data = get_thread_data(&mut thread_data);
+        thread_data.deadlock_data.update_epoch(epoch);
+        (*thread_data.deadlock_data.resources.get()).push(key);
+    }
+
+    #[inline(never)]
+    unsafe fn track_release(key: usize, epoch: usize) {
+        let mut thread_data = None;
+        let thread_data = get_thread_data(&mut thread_data);
+        thread_data.deadlock_data.update_epoch(epoch);
+        let resources = &mut (*thread_data.deadlock_data.resources.get());
+        // The resource may have been acquired while tracking was disabled
+        if let Some(p) = resources.iter().rposition(|x| *x == key) {
+            resources.swap_remove(p);
+        }
+    }
+
+    /// Returns the keys of all resources currently held by the current thread.
+    ///
+    /// A resource which was acquired multiple times appears once for each
+    /// acquisition. Returns an empty vector while tracking is disabled.
+    pub fn held_resources() -> Vec<usize> {
+        let epoch = match current_epoch() {
+            Some(epoch) => epoch,
+            None => return Vec::new(),
+        };
+        unsafe {
+            let mut thread_data = None;
+            let thread_data = get_thread_data(&mut thread_data);
+            thread_data.deadlock_data.resources(epoch).to_vec()
+        }
+    }
+
+    /// Returns whether the resource identified by key is held by the current
+    /// thread.
+    ///
+    /// While tracking is disabled ownership can't be determined, so this always
+    /// returns `true`.
+    pub fn is_resource_held(key: usize) -> bool {
+        let epoch = match current_epoch() {
+            Some(epoch) => epoch,
+            None => return true,
+        };
+        unsafe {
+            let mut thread_data = None;
+            let thread_data = get_thread_data(&mut thread_data);
+            thread_data.deadlock_data.resources(epoch).contains(&key)
+        }
+    }
+
+    /// Enables or disables tracking of the resources held by each thread at
+    /// runtime.
+    ///
+    /// Tracking is disabled by default unless the `deadlock_detection` feature
+    /// is enabled, and the default can be overridden by setting the
+    /// `PARKING_LOT_DEADLOCK_DETECTION` environment variable to `0` or `1`
+    /// before the first resource is acquired. While disabled, acquiring and
+    /// releasing resources only costs an atomic load.
+    ///
+    /// Resources acquired while tracking was disabled are not tracked, even
+    /// after it is enabled again, and so they will not be part of any detected
+    /// deadlock.
+    pub fn set_enabled(enabled: bool) {
+        let mut state = DETECTION_STATE.load(Ordering::Acquire);
+        if state == 0 {
+            state = init_detection_state();
+        }
+        loop {
+            if (state & ENABLED_BIT != 0) == enabled {
+                return;
+            }
+            let new_state = if enabled {
+                (((state >> EPOCH_SHIFT) + 1) << EPOCH_SHIFT) | ENABLED_BIT
+            } else {
+                state & !ENABLED_BIT
+            };
+            match DETECTION_STATE.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    /// Returns whether tracking of held resources is currently enabled.
+    #[inline]
+    pub fn is_enabled() -> bool {
+        current_epoch().is_some()
     }
 
     /// Returns all deadlocks detected *since* the last call.
@@ -1116,6 +2125,77 @@ pub mod deadlock {
         deadlock_impl::check_deadlock()
     }
 
+    /// Registers a function which is called whenever a thread completes a
+    /// deadlock cycle by parking, or unregisters it if `None` is passed.
+    ///
+    /// While a handler is registered, every thread which parks without a
+    /// timeout checks whether it is waiting, through a chain of parked threads,
+    /// on a resource that it holds itself. When that is the case the deadlock is
+    /// confirmed in the same way as `check_deadlock`, and the handler is called
+    /// from a dedicated reporter thread with the same results `check_deadlock`
+    /// would return.
+    /// Deadlocks reported this way are not reported again by `check_deadlock`.
+    ///
+    /// While a handler is registered, a thread which parks while holding
+    /// resources looks at the threads parked on those resources, and
+    /// transitively on the resources they hold, which adds some overhead to
+    /// park operations.
+    #[cfg(feature = "deadlock_detection")]
+    #[inline]
+    pub fn set_deadlock_handler(handler: Option<fn(Vec<Vec<DeadlockedThread>>)>) {
+        deadlock_impl::set_deadlock_handler(handler)
+    }
+
+    /// Enables or disables the lock watchdog.
+    ///
+    /// While the watchdog is enabled, every acquired resource is recorded along
+    /// with the time and a backtrace of its acquisition, and every thread
+    /// records when it was parked. This information is used by
+    /// `check_long_holds` and `check_long_waits`.
+    ///
+    /// Capturing a backtrace on every acquisition is expensive, so the watchdog
+    /// is disabled by default. Resources acquired before the watchdog was
+    /// enabled are not recorded. The watchdog works independently of deadlock
+    /// detection being enabled.
+    #[cfg(feature = "deadlock_detection")]
+    #[inline]
+    pub fn set_watchdog_enabled(enabled: bool) {
+        deadlock_impl::set_watchdog_enabled(enabled)
+    }
+
+    /// Returns all resources which have been held for at least `threshold`.
+    ///
+    /// Only resources acquired while the watchdog was enabled are reported.
+    #[cfg(feature = "deadlock_detection")]
+    #[inline]
+    pub fn check_long_holds(threshold: Duration) -> Vec<LockHolder> {
+        deadlock_impl::check_long_holds(threshold)
+    }
+
+    /// Returns all threads which have been parked on a held resource for at
+    /// least `threshold`, along with the threads holding that resource.
+    ///
+    /// Threads parked on a key which isn't a resource recorded by the watchdog,
+    /// such as a condition variable, are not reported.
+    #[cfg(feature = "deadlock_detection")]
+    #[inline]
+    pub fn check_long_waits(threshold: Duration) -> Vec<LongWait> {
+        deadlock_impl::check_long_waits(threshold)
+    }
+
+    // Called when a thread is added to a queue, with the bucket locked
+    #[inline]
+    pub(super) unsafe fn on_enqueue(_td: &super::ThreadData) {
+        #[cfg(feature = "deadlock_detection")]
+        deadlock_impl::on_enqueue(_td);
+    }
+
+    #[inline]
+    pub(super) unsafe fn on_park(_td: &super::ThreadData) {
+        #[cfg(feature = "deadlock_detection")]
+        deadlock_impl::on_park(_td);
+    }
+
     #[inline]
     pub(super) unsafe fn on_unpark(_td: &super::ThreadData) {
         #[cfg(feature = "deadlock_detection")]
@@ -1125,11 +2205,17 @@ pub mod deadlock {
 
 #[cfg(feature = "deadlock_detection")]
 mod deadlock_impl {
-    use super::{get_hashtable, get_thread_data, lock_bucket, ThreadData, NUM_THREADS};
+    use super::{get_hashtable, get_thread_data, lock_bucket, QueueEntry, ThreadData,
+                NUM_THREADS};
+    use super::deadlock::current_epoch;
+    use word_lock::WordLock;
     use std::cell::{Cell, UnsafeCell};
-    use std::sync::mpsc;
-    use std::sync::atomic::Ordering;
-    use std::collections::HashSet;
+    use std::sync::{mpsc, Mutex, Once, ONCE_INIT};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                            ATOMIC_USIZE_INIT};
+    use std::time::{Duration, Instant};
+    use std::collections::{HashMap, HashSet};
+    use std::{ptr, thread};
     use thread_id;
     use backtrace::Backtrace;
     use petgraph;
@@ -1153,10 +2239,68 @@ mod deadlock_impl {
         }
     }
 
-    pub struct DeadlockData {
-        // Currently owned resources (keys)
-        resources: UnsafeCell<Vec<usize>>,
+    /// A resource held by a thread, as reported by the lock watchdog
+    #[derive(Clone)]
+    pub struct LockHolder {
+        key: usize,
+        thread_id: usize,
+        held_for: Duration,
+        backtrace: Backtrace,
+    }
+
+    impl LockHolder {
+        /// The key of the held resource
+        pub fn key(&self) -> usize {
+            self.key
+        }
+
+        /// The system thread id of the holder
+        pub fn thread_id(&self) -> usize {
+            self.thread_id
+        }
+
+        /// How long the resource has been held for
+        pub fn held_for(&self) -> Duration {
+            self.held_for
+        }
+
+        /// The backtrace of the holder at the time the resource was acquired
+        pub fn backtrace(&self) -> &Backtrace {
+            &self.backtrace
+        }
+    }
+
+    /// A thread parked on a held resource, as reported by the lock watchdog
+    pub struct LongWait {
+        key: usize,
+        thread_id: usize,
+        waited_for: Duration,
+        holders: Vec<LockHolder>,
+    }
+
+    impl LongWait {
+        /// The key of the resource the thread is waiting for
+        pub fn key(&self) -> usize {
+            self.key
+        }
+
+        /// The system thread id of the waiting thread
+        pub fn thread_id(&self) -> usize {
+            self.thread_id
+        }
+
+        /// How long the thread has been parked for
+        pub fn waited_for(&self) -> Duration {
+            self.waited_for
+        }
 
+        /// The threads currently holding the resource
+        pub fn holders(&self) -> &[LockHolder] {
+            &self.holders
+        }
+    }
+
+    pub struct DetectorData {
         // Set when there's a pending callstack request
         deadlocked: Cell<bool>,
 
@@ -1165,25 +2309,367 @@ mod deadlock_impl {
 
         // System thread id
         thread_id: usize,
+
+        // Time at which the thread was last parked, if the watchdog was enabled
+        parked_at: Cell<Option<Instant>>,
+
+        // Resources acquired while the watchdog was enabled. These are read by
+        // other threads in check_long_holds and check_long_waits, so they are
+        // protected by watchdog_lock.
+        acquisitions: UnsafeCell<Vec<Acquisition>>,
+        watchdog_lock: WordLock,
+
+        // Whether this thread is in the watchdog registry
+        registered: Cell<bool>,
     }
 
-    impl DeadlockData {
+    impl DetectorData {
         pub fn new() -> Self {
-            DeadlockData {
-                resources: UnsafeCell::new(Vec::new()),
+            DetectorData {
                 deadlocked: Cell::new(false),
                 backtrace_sender: UnsafeCell::new(None),
                 thread_id: thread_id::get(),
+                parked_at: Cell::new(None),
+                acquisitions: UnsafeCell::new(Vec::new()),
+                watchdog_lock: WordLock::new(),
+                registered: Cell::new(false),
+            }
+        }
+
+        // Adds this thread to the watchdog registry so that its acquisitions
+        // can be found by other threads. The data must not move afterwards.
+        unsafe fn register(&self) {
+            if !self.registered.get() {
+                let registry = watchdog_registry();
+                registry.lock.lock();
+                (*registry.threads.get()).push(self);
+                registry.lock.unlock();
+                self.registered.set(true);
+            }
+        }
+
+        // Returns the acquisitions of this thread which have been held for at
+        // least threshold. Backtraces are left unresolved.
+        unsafe fn long_holds(
+            &self,
+            now: Instant,
+            threshold: Duration,
+            results: &mut Vec<LockHolder>,
+        ) {
+            self.watchdog_lock.lock();
+            for a in &*self.acquisitions.get() {
+                let held_for = now.duration_since(a.acquired_at);
+                if held_for >= threshold {
+                    results.push(LockHolder {
+                        key: a.key,
+                        thread_id: self.thread_id,
+                        held_for,
+                        backtrace: a.backtrace.clone(),
+                    });
+                }
+            }
+            self.watchdog_lock.unlock();
+        }
+    }
+
+    impl Drop for DetectorData {
+        fn drop(&mut self) {
+            if self.registered.get() {
+                unsafe {
+                    let registry = watchdog_registry();
+                    registry.lock.lock();
+                    let threads = &mut *registry.threads.get();
+                    if let Some(p) = threads.iter().position(|&t| ptr::eq(t, self)) {
+                        threads.swap_remove(p);
+                    }
+                    registry.lock.unlock();
+                }
+            }
+        }
+    }
+
+    // A resource acquisition recorded by the watchdog
+    struct Acquisition {
+        key: usize,
+        acquired_at: Instant,
+        backtrace: Backtrace,
+    }
+
+    static WATCHDOG_ENABLED: AtomicBool = ATOMIC_BOOL_INIT;
+
+    // List of the threads which have recorded acquisitions for the watchdog.
+    // A thread is only removed from it when it exits, and the lock is held
+    // while looking at the acquisitions of other threads so that they aren't
+    // freed in the meantime.
+    struct WatchdogRegistry {
+        lock: WordLock,
+        threads: UnsafeCell<Vec<*const DetectorData>>,
+    }
+
+    unsafe impl Sync for WatchdogRegistry {}
+
+    // Pointer to the watchdog registry. It is allocated the first time a
+    // thread is registered and never freed.
+    static WATCHDOG_REGISTRY: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    fn watchdog_registry() -> &'static WatchdogRegistry {
+        let mut registry = WATCHDOG_REGISTRY.load(Ordering::Acquire);
+        if registry == 0 {
+            let new = Box::into_raw(Box::new(WatchdogRegistry {
+                lock: WordLock::new(),
+                threads: UnsafeCell::new(Vec::new()),
+            })) as usize;
+            registry = match WATCHDOG_REGISTRY.compare_exchange(
+                0,
+                new,
+                Ordering::Release,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => new,
+                Err(x) => {
+                    drop(unsafe { Box::from_raw(new as *mut WatchdogRegistry) });
+                    x
+                }
+            };
+        }
+        unsafe { &*(registry as *const WatchdogRegistry) }
+    }
+
+    // Calls f on the watchdog data of every registered thread
+    unsafe fn for_each_registered<F: FnMut(&DetectorData)>(mut f: F) {
+        let registry = watchdog_registry();
+        registry.lock.lock();
+        for &data in &*registry.threads.get() {
+            f(&*data);
+        }
+        registry.lock.unlock();
+    }
+
+    pub fn set_watchdog_enabled(enabled: bool) {
+        WATCHDOG_ENABLED.store(enabled, Ordering::Relaxed);
+
+        // Resources released while the watchdog is disabled aren't removed
+        // from the records, so discard them all. A thread which saw the
+        // watchdog as enabled checks again while holding its own lock before
+        // adding a record, so no record can be added after this.
+        if !enabled {
+            unsafe {
+                for_each_registered(|data| {
+                    data.watchdog_lock.lock();
+                    (*data.acquisitions.get()).clear();
+                    data.watchdog_lock.unlock();
+                });
+            }
+        }
+    }
+
+    unsafe fn watchdog_acquire(key: usize) {
+        let mut thread_data = None;
+        let thread_data = get_thread_data(&mut thread_data);
+        let data = &thread_data.deadlock_data.detector;
+        data.register();
+
+        // Capture the backtrace before taking the lock, since this is slow
+        let acquisition = Acquisition {
+            key,
+            acquired_at: Instant::now(),
+            backtrace: Backtrace::new_unresolved(),
+        };
+        data.watchdog_lock.lock();
+        if WATCHDOG_ENABLED.load(Ordering::Relaxed) {
+            (*data.acquisitions.get()).push(acquisition);
+        }
+        data.watchdog_lock.unlock();
+    }
+
+    unsafe fn watchdog_release(key: usize) {
+        let mut thread_data = None;
+        let thread_data = get_thread_data(&mut thread_data);
+        let data = &thread_data.deadlock_data.detector;
+        if !data.registered.get() {
+            return;
+        }
+        data.watchdog_lock.lock();
+        let acquisitions = &mut *data.acquisitions.get();
+        // The resource may have been acquired before the watchdog was enabled
+        if let Some(p) = acquisitions.iter().rposition(|a| a.key == key) {
+            acquisitions.swap_remove(p);
+        }
+        data.watchdog_lock.unlock();
+    }
+
+    pub fn check_long_holds(threshold: Duration) -> Vec<LockHolder> {
+        let now = Instant::now();
+        let mut results = Vec::new();
+        unsafe {
+            for_each_registered(|data| data.long_holds(now, threshold, &mut results));
+        }
+
+        // Resolving backtraces is slow, so do it after releasing the locks
+        for holder in &mut results {
+            holder.backtrace.resolve();
+        }
+        results
+    }
+
+    pub fn check_long_waits(threshold: Duration) -> Vec<LongWait> {
+        let now = Instant::now();
+
+        // Collect the threads which have been parked for long enough
+        let mut waiters = Vec::new();
+        unsafe {
+            let table = get_hashtable();
+            for b in &(&(*table).entries)[..] {
+                b.mutex.lock();
+                let mut current = b.queue_head.get();
+                while !current.is_null() {
+                    let detector = match (*current).thread() {
+                        Some(td) => &td.deadlock_data.detector,
+                        None => {
+                            current = (*current).next_in_queue.get();
+                            continue;
+                        }
+                    };
+                    if let Some(parked_at) = detector.parked_at.get() {
+                        let waited_for = now.duration_since(parked_at);
+                        if waited_for >= threshold {
+                            waiters.push((
+                                (*current).key.load(Ordering::Relaxed),
+                                detector.thread_id,
+                                waited_for,
+                            ));
+                        }
+                    }
+                    current = (*current).next_in_queue.get();
+                }
+                b.mutex.unlock();
+            }
+        }
+        if waiters.is_empty() {
+            return Vec::new();
+        }
+
+        // Find the holders of the resources they are waiting on
+        let mut holders = HashMap::<usize, Vec<LockHolder>>::new();
+        unsafe {
+            let mut all = Vec::new();
+            for_each_registered(|data| data.long_holds(now, Duration::from_secs(0), &mut all));
+            for holder in all {
+                if waiters.iter().any(|w| w.0 == holder.key) {
+                    holders.entry(holder.key).or_insert_with(Vec::new).push(holder);
+                }
+            }
+        }
+
+        let mut results = Vec::with_capacity(waiters.len());
+        for (key, thread_id, waited_for) in waiters {
+            if let Some(holders) = holders.get(&key) {
+                let mut holders = holders.clone();
+                for holder in &mut holders {
+                    holder.backtrace.resolve();
+                }
+                results.push(LongWait {
+                    key,
+                    thread_id,
+                    waited_for,
+                    holders,
+                });
+            }
+        }
+        results
+    }
+
+    pub(super) unsafe fn on_enqueue(td: &ThreadData) {
+        let parked_at = if WATCHDOG_ENABLED.load(Ordering::Relaxed) {
+            Some(Instant::now())
+        } else {
+            None
+        };
+        td.deadlock_data.detector.parked_at.set(parked_at);
+    }
+
+    // Messages sent to the deadlock reporter thread
+    enum ReporterMessage {
+        SetHandler(Option<fn(Vec<Vec<DeadlockedThread>>)>),
+        Check(usize),
+    }
+
+    // Whether a deadlock handler is registered, so that parking threads don't
+    // need to look for cycles otherwise.
+    static HANDLER_REGISTERED: AtomicBool = ATOMIC_BOOL_INIT;
+
+    // Pointer to the sender for the reporter thread. The thread is started the
+    // first time a handler is registered and is never stopped.
+    static REPORTER: AtomicUsize = ATOMIC_USIZE_INIT;
+    static REPORTER_INIT: Once = ONCE_INIT;
+
+    fn reporter() -> &'static Mutex<mpsc::Sender<ReporterMessage>> {
+        REPORTER_INIT.call_once(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::Builder::new()
+                .name("parking_lot deadlock reporter".to_string())
+                .spawn(move || run_reporter(receiver))
+                .expect("failed to spawn the deadlock reporter thread");
+            let sender = Box::into_raw(Box::new(Mutex::new(sender)));
+            REPORTER.store(sender as usize, Ordering::Release);
+        });
+        unsafe { &*(REPORTER.load(Ordering::Acquire) as *const _) }
+    }
+
+    fn send_to_reporter(message: ReporterMessage) {
+        // Errors are ignored: the reporter only stops if a handler panicked
+        if let Ok(sender) = reporter().lock() {
+            let _ = sender.send(message);
+        }
+    }
+
+    // The precise check unparks every deadlocked thread to collect its
+    // backtrace, including the one which found the cycle, so it has to run on
+    // another thread. This also allows the handler to use parking_lot
+    // primitives.
+    fn run_reporter(receiver: mpsc::Receiver<ReporterMessage>) {
+        let mut handler = None;
+        for message in receiver {
+            match message {
+                ReporterMessage::SetHandler(h) => handler = h,
+                ReporterMessage::Check(epoch) => {
+                    if let Some(handler) = handler {
+                        let deadlocks = unsafe { check_wait_graph_slow(epoch) };
+                        if !deadlocks.is_empty() {
+                            handler(deadlocks);
+                        }
+                    }
+                }
             }
         }
     }
 
+    pub fn set_deadlock_handler(handler: Option<fn(Vec<Vec<DeadlockedThread>>)>) {
+        if handler.is_some() || REPORTER.load(Ordering::Acquire) != 0 {
+            send_to_reporter(ReporterMessage::SetHandler(handler));
+        }
+        HANDLER_REGISTERED.store(handler.is_some(), Ordering::Release);
+    }
+
+    pub(super) unsafe fn on_park(td: &ThreadData) {
+        if !HANDLER_REGISTERED.load(Ordering::Acquire) {
+            return;
+        }
+        let epoch = match current_epoch() {
+            Some(epoch) => epoch,
+            None => return,
+        };
+        if closes_wait_cycle(td, epoch) {
+            send_to_reporter(ReporterMessage::Check(epoch));
+        }
+    }
+
     pub(super) unsafe fn on_unpark(td: &ThreadData) {
-        if td.deadlock_data.deadlocked.get() {
-            let sender = (*td.deadlock_data.backtrace_sender.get()).take().unwrap();
+        if td.deadlock_data.detector.deadlocked.get() {
+            let sender = (*td.deadlock_data.detector.backtrace_sender.get()).take().unwrap();
             sender
                 .send(DeadlockedThread {
-                    thread_id: td.deadlock_data.thread_id,
+                    thread_id: td.deadlock_data.detector.thread_id,
                     backtrace: Backtrace::new(),
                 })
                 .unwrap();
@@ -1197,51 +2683,60 @@ mod deadlock_impl {
         }
     }
 
-    pub unsafe fn acquire_resource(key: usize) {
-        let mut thread_data = None;
-        let thread_data = get_thread_data(&mut thread_data);
-        (*thread_data.deadlock_data.resources.get()).push(key);
+    pub unsafe fn on_acquire(key: usize) {
+        if WATCHDOG_ENABLED.load(Ordering::Relaxed) {
+            watchdog_acquire(key);
+        }
     }
 
-    pub unsafe fn release_resource(key: usize) {
-        let mut thread_data = None;
-        let thread_data = get_thread_data(&mut thread_data);
-        let resources = &mut (*thread_data.deadlock_data.resources.get());
-        match resources.iter().rposition(|x| *x == key) {
-            Some(p) => resources.swap_remove(p),
-            None => panic!("key {} not found in thread resources", key),
-        };
+    pub unsafe fn on_release(key: usize) {
+        if WATCHDOG_ENABLED.load(Ordering::Relaxed) {
+            watchdog_release(key);
+        }
     }
 
     pub fn check_deadlock() -> Vec<Vec<DeadlockedThread>> {
+        let epoch = match current_epoch() {
+            Some(epoch) => epoch,
+            None => return Vec::new(),
+        };
         unsafe {
             // fast pass
-            if check_wait_graph_fast() {
+            if check_wait_graph_fast(epoch) {
                 // double check
-                check_wait_graph_slow()
+                check_wait_graph_slow(epoch)
             } else {
                 Vec::new()
             }
         }
     }
 
+    // Returns the thread in the given queue entry if it belongs in the wait
+    // graph. Asynchronous tasks and threads parked with a timeout can't be
+    // deadlocked, and deadlocked threads have already been reported.
+    unsafe fn waiting_thread<'a>(entry: *const QueueEntry) -> Option<&'a ThreadData> {
+        match (*entry).thread() {
+            Some(td) if !td.parked_with_timeout.get()
+                && !td.deadlock_data.detector.deadlocked.get() => Some(td),
+            _ => None,
+        }
+    }
+
     // Simple algorithm that builds a wait graph f the threads and the resources,
     // then checks for the presence of cycles (deadlocks).
     // This variant isn't precise as it doesn't lock the entire table before checking
-    unsafe fn check_wait_graph_fast() -> bool {
+    unsafe fn check_wait_graph_fast(epoch: usize) -> bool {
         let table = get_hashtable();
         let thread_count = NUM_THREADS.load(Ordering::Relaxed);
         let mut graph = DiGraphMap::<usize, ()>::with_capacity(thread_count * 2, thread_count * 2);
 
-        for b in &(*table).entries[..] {
+        for b in &(&(*table).entries)[..] {
             b.mutex.lock();
             let mut current = b.queue_head.get();
             while !current.is_null() {
-                if !(*current).parked_with_timeout.get()
-                    && !(*current).deadlock_data.deadlocked.get()
-                {
+                if let Some(td) = waiting_thread(current) {
                     // .resources are waiting for their owner
-                    for &resource in &(*(*current).deadlock_data.resources.get()) {
+                    for &resource in td.deadlock_data.resources(epoch) {
                         graph.add_edge(resource, current as usize, ());
                     }
                     // owner waits for resource .key
@@ -1255,6 +2750,40 @@ mod deadlock_impl {
         petgraph::algo::is_cyclic_directed(&graph)
     }
 
+    // Follows the wait edges backwards from the given thread, which has just
+    // been added to the queue for its key, and returns whether they lead to a
+    // thread holding that key. Only the buckets of the resources reachable this
+    // way are locked, one at a time, so like the _fast variant this isn't
+    // precise.
+    unsafe fn closes_wait_cycle(td: &ThreadData, epoch: usize) -> bool {
+        let key = td.entry.key.load(Ordering::Relaxed);
+        let mut pending = td.deadlock_data.resources(epoch).to_vec();
+        let mut visited = HashSet::new();
+        while let Some(resource) = pending.pop() {
+            if resource == key {
+                return true;
+            }
+            if !visited.insert(resource) {
+                continue;
+            }
+
+            // Threads parked on this resource are waiting for its owner, and
+            // so are the resources they hold.
+            let bucket = lock_bucket(resource);
+            let mut current = bucket.queue_head.get();
+            while !current.is_null() {
+                if (*current).key.load(Ordering::Relaxed) == resource {
+                    if let Some(td) = waiting_thread(current) {
+                        pending.extend_from_slice(td.deadlock_data.resources(epoch));
+                    }
+                }
+                current = (*current).next_in_queue.get();
+            }
+            bucket.mutex.unlock();
+        }
+        false
+    }
+
     #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Copy, Clone)]
     enum WaitGraphNode {
         Thread(*const ThreadData),
@@ -1266,11 +2795,11 @@ mod deadlock_impl {
     // Contrary to the _fast variant this locks the entrie table before looking for cycles.
     // Returns all detected thread wait cycles.
     // Note that once a cycle is reported it's never reported again.
-    unsafe fn check_wait_graph_slow() -> Vec<Vec<DeadlockedThread>> {
+    unsafe fn check_wait_graph_slow(epoch: usize) -> Vec<Vec<DeadlockedThread>> {
         let mut table = get_hashtable();
         loop {
             // Lock all buckets in the old table
-            for b in &(*table).entries[..] {
+            for b in &(&(*table).entries)[..] {
                 b.mutex.lock();
             }
 
@@ -1282,7 +2811,7 @@ mod deadlock_impl {
             }
 
             // Unlock buckets and try again
-            for b in &(*table).entries[..] {
+            for b in &(&(*table).entries)[..] {
                 b.mutex.unlock();
             }
 
@@ -1293,19 +2822,17 @@ mod deadlock_impl {
         let mut graph =
             DiGraphMap::<WaitGraphNode, ()>::with_capacity(thread_count * 2, thread_count * 2);
 
-        for b in &(*table).entries[..] {
+        for b in &(&(*table).entries)[..] {
             let mut current = b.queue_head.get();
             while !current.is_null() {
-                if !(*current).parked_with_timeout.get()
-                    && !(*current).deadlock_data.deadlocked.get()
-                {
+                if let Some(td) = waiting_thread(current) {
                     // .resources are waiting for their owner
-                    for &resource in &(*(*current).deadlock_data.resources.get()) {
-                        graph.add_edge(Resource(resource), Thread(current), ());
+                    for &resource in td.deadlock_data.resources(epoch) {
+                        graph.add_edge(Resource(resource), Thread(td), ());
                     }
                     // owner waits for resource .key
                     graph.add_edge(
-                        Thread(current),
+                        Thread(td),
                         Resource((*current).key.load(Ordering::Relaxed)),
                         (),
                     );
@@ -1314,7 +2841,7 @@ mod deadlock_impl {
             }
         }
 
-        for b in &(*table).entries[..] {
+        for b in &(&(*table).entries)[..] {
             b.mutex.unlock();
         }
 
@@ -1326,9 +2853,9 @@ mod deadlock_impl {
         for cycle in cycles {
             let (sender, receiver) = mpsc::channel();
             for td in cycle {
-                let bucket = lock_bucket((*td).key.load(Ordering::Relaxed));
-                (*td).deadlock_data.deadlocked.set(true);
-                *(*td).deadlock_data.backtrace_sender.get() = Some(sender.clone());
+                let bucket = lock_bucket((*td).entry.key.load(Ordering::Relaxed));
+                (*td).deadlock_data.detector.deadlocked.set(true);
+                *(*td).deadlock_data.detector.backtrace_sender.get() = Some(sender.clone());
                 let handle = (*td).parker.unpark_lock();
                 bucket.mutex.unlock();
                 // unpark the deadlocked thread!
@@ -1387,3 +2914,118 @@ mod deadlock_impl {
         cycles.iter().cloned().collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_BOOL_INIT,
+                            ATOMIC_USIZE_INIT};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use super::{park, unpark_all, unpark_requeue_multi, ParkResult, ParkToken, RequeueOp,
+                DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
+
+    #[test]
+    fn timed_out_tail_is_not_last_thread() {
+        static KEY: AtomicBool = ATOMIC_BOOL_INIT;
+        static QUEUED: AtomicBool = ATOMIC_BOOL_INIT;
+        let key = &KEY as *const _ as usize;
+
+        // Queue a thread with a long timeout first
+        let t = thread::spawn(move || unsafe {
+            park(
+                key,
+                || true,
+                || QUEUED.store(true, Ordering::SeqCst),
+                |_, _| {},
+                DEFAULT_PARK_TOKEN,
+                Some(Instant::now() + Duration::from_secs(30)),
+            )
+        });
+        while !QUEUED.load(Ordering::SeqCst) {
+            thread::yield_now();
+        }
+
+        // Our entry is behind it in the queue, so when we time out there is
+        // still a thread parked on the same key.
+        let mut was_last = None;
+        let result = unsafe {
+            park(
+                key,
+                || true,
+                || {},
+                |_, was_last_thread| was_last = Some(was_last_thread),
+                DEFAULT_PARK_TOKEN,
+                Some(Instant::now() + Duration::from_millis(10)),
+            )
+        };
+        assert_eq!(result, ParkResult::TimedOut);
+        assert_eq!(was_last, Some(false));
+
+        assert_eq!(unsafe { unpark_all(key, DEFAULT_UNPARK_TOKEN) }, 1);
+        assert_eq!(t.join().unwrap(), ParkResult::Unparked(DEFAULT_UNPARK_TOKEN));
+    }
+
+    #[test]
+    fn requeue_multi_groups_by_destination() {
+        static KEY: AtomicBool = ATOMIC_BOOL_INIT;
+        static DEST: AtomicBool = ATOMIC_BOOL_INIT;
+        static QUEUED: AtomicUsize = ATOMIC_USIZE_INIT;
+        let key = &KEY as *const _ as usize;
+        let dest = &DEST as *const _ as usize;
+
+        // Two threads want to be requeued onto DEST and one doesn't
+        let threads: Vec<_> = [dest, 0, dest]
+            .iter()
+            .map(|&token| {
+                thread::spawn(move || unsafe {
+                    park(
+                        key,
+                        || true,
+                        || {
+                            QUEUED.fetch_add(1, Ordering::SeqCst);
+                        },
+                        |_, _| {},
+                        ParkToken(token),
+                        None,
+                    )
+                })
+            })
+            .collect();
+        while QUEUED.load(Ordering::SeqCst) != threads.len() {
+            thread::yield_now();
+        }
+
+        let mut groups = Vec::new();
+        let result = unsafe {
+            unpark_requeue_multi(
+                key,
+                |token| if token.0 == 0 { None } else { Some(token.0) },
+                |key_to, num_threads| {
+                    groups.push((key_to, num_threads));
+                    RequeueOp::UnparkOneRequeueRest
+                },
+                |_| DEFAULT_UNPARK_TOKEN,
+            )
+        };
+        assert_eq!(groups, vec![(dest, 2)]);
+        assert_eq!(result.unparked_threads, 2);
+        assert_eq!(result.requeued_threads, 1);
+        assert!(!result.have_more_threads);
+
+        assert_eq!(unsafe { unpark_all(dest, DEFAULT_UNPARK_TOKEN) }, 1);
+        for t in threads {
+            assert_eq!(t.join().unwrap(), ParkResult::Unparked(DEFAULT_UNPARK_TOKEN));
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn async_waiters_are_not_threads() {
+        use super::{AsyncWaiter, NUM_THREADS};
+
+        // Other tests may start threads concurrently, but not this many
+        let before = NUM_THREADS.load(Ordering::Relaxed);
+        let waiters: Vec<_> = (0..1000).map(|_| AsyncWaiter::new()).collect();
+        assert!(NUM_THREADS.load(Ordering::Relaxed) < before + waiters.len());
+    }
+}
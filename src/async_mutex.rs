@@ -0,0 +1,386 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::marker::PhantomData;
+use parking_lot_core::AsyncWaiter;
+use raw_mutex::RawMutex;
+use lock_api::RawMutex as RawMutexTrait;
+
+/// A mutual exclusion primitive for asynchronous code.
+///
+/// This type is the asynchronous equivalent of `Mutex`: `lock` returns a
+/// future which resolves to a guard instead of blocking the current thread.
+/// The guard can be held across `.await` points and sent to other threads.
+///
+/// `AsyncMutex` is built on the same lock as `Mutex` and has the same
+/// characteristics. Its state is a single byte (or word on stable Rust), tasks
+/// waiting for the lock are queued in the parking lot alongside blocked
+/// threads, and unlocking uses the same eventual fairness, directly handing the
+/// lock off to the next waiting task from time to time.
+///
+/// A future returned by `lock` can be dropped at any time. If it was waiting
+/// for the lock then it is removed from the queue, and if the lock was already
+/// handed off to it then the lock is passed on to the next waiter.
+///
+/// This type is only available with the `async` feature, which requires Rust
+/// 1.36 or later.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate parking_lot;
+/// # #[cfg(feature = "async")]
+/// # fn main() {
+/// use parking_lot::AsyncMutex;
+///
+/// let mutex = AsyncMutex::new(0);
+///
+/// // In an async function this would be `mutex.lock().await`
+/// let future = mutex.lock();
+/// # drop(future);
+///
+/// // The mutex can also be locked without waiting
+/// *mutex.try_lock().unwrap() += 1;
+/// assert_eq!(mutex.into_inner(), 1);
+/// # }
+/// # #[cfg(not(feature = "async"))]
+/// # fn main() {}
+/// ```
+pub struct AsyncMutex<T: ?Sized> {
+    raw: RawMutex,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for AsyncMutex<T> {}
+
+/// An RAII implementation of a "scoped lock" of an `AsyncMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+#[must_use]
+pub struct AsyncMutexGuard<'a, T: ?Sized + 'a> {
+    mutex: &'a AsyncMutex<T>,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Send + 'a> Send for AsyncMutexGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for AsyncMutexGuard<'a, T> {}
+
+/// A future which resolves to an `AsyncMutexGuard` once the mutex is locked,
+/// returned by `AsyncMutex::lock`.
+#[must_use]
+pub struct AsyncMutexLockFuture<'a, T: ?Sized + 'a> {
+    mutex: &'a AsyncMutex<T>,
+
+    // Queue entry, only created if the task actually needs to wait
+    waiter: Option<AsyncWaiter>,
+}
+
+impl<T> AsyncMutex<T> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> AsyncMutex<T> {
+        AsyncMutex {
+            data: UnsafeCell::new(val),
+            raw: <RawMutex as RawMutexTrait>::INIT,
+        }
+    }
+
+    /// Creates a new mutex in an unlocked state ready for use.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> AsyncMutex<T> {
+        AsyncMutex {
+            data: UnsafeCell::new(val),
+            raw: <RawMutex as RawMutexTrait>::INIT,
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        unsafe { self.data.into_inner() }
+    }
+}
+
+impl<T: ?Sized> AsyncMutex<T> {
+    #[inline]
+    pub(crate) fn raw(&self) -> &RawMutex {
+        &self.raw
+    }
+
+    // The lock must be held and detached from the current thread
+    #[inline]
+    pub(crate) fn guard(&self) -> AsyncMutexGuard<T> {
+        AsyncMutexGuard {
+            mutex: self,
+            marker: PhantomData,
+        }
+    }
+
+    /// Acquires the mutex asynchronously.
+    ///
+    /// The returned future resolves to an RAII guard once the mutex has been
+    /// locked. The mutex will be unlocked when the guard is dropped.
+    ///
+    /// Unlike `Mutex::lock`, this never blocks the current thread. Locking the
+    /// mutex again while a guard is held by the same task will never complete.
+    #[inline]
+    pub fn lock(&self) -> AsyncMutexLockFuture<T> {
+        AsyncMutexLockFuture {
+            mutex: self,
+            waiter: None,
+        }
+    }
+
+    /// Acquires the mutex, blocking the current thread until it is able to do
+    /// so.
+    ///
+    /// This allows synchronous code to share an `AsyncMutex` with asynchronous
+    /// tasks. It should not be called from an asynchronous task since it would
+    /// block the executor thread.
+    #[inline]
+    pub fn blocking_lock(&self) -> AsyncMutexGuard<T> {
+        self.raw.lock();
+        unsafe { self.raw.detach() };
+        self.guard()
+    }
+
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned. The lock will be unlocked when the
+    /// guard is dropped.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_lock(&self) -> Option<AsyncMutexGuard<T>> {
+        if self.raw.try_lock() {
+            // The guard isn't tied to the current thread
+            unsafe { self.raw.detach() };
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `AsyncMutex` mutably, no actual locking
+    /// needs to take place---the mutable borrow statically guarantees no locks
+    /// exist.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for AsyncMutex<T> {
+    #[inline]
+    fn default() -> AsyncMutex<T> {
+        AsyncMutex::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AsyncMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_struct("AsyncMutex")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.pad("AsyncMutex { <locked> }"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Future for AsyncMutexLockFuture<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncMutexGuard<'a, T>> {
+        let this = self.get_mut();
+        match this.mutex.raw.poll_lock(&mut this.waiter, cx.waker()) {
+            Poll::Ready(()) => Poll::Ready(this.mutex.guard()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncMutexLockFuture<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.mutex.raw.cancel_lock(&mut self.waiter);
+    }
+}
+
+impl<'a, T: ?Sized + 'a> fmt::Debug for AsyncMutexLockFuture<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("AsyncMutexLockFuture { .. }")
+    }
+}
+
+// Returns the mutex locked by an `AsyncMutexGuard`, used by `Condvar`.
+#[inline]
+pub(crate) fn guard_mutex<'a, T: ?Sized>(guard: &AsyncMutexGuard<'a, T>) -> &'a AsyncMutex<T> {
+    guard.mutex
+}
+
+impl<'a, T: ?Sized + 'a> Deref for AsyncMutexGuard<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for AsyncMutexGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncMutexGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        // Attribute the lock to the unlocking thread first so that the release
+        // is balanced for the deadlock detector.
+        unsafe {
+            self.mutex.raw.attach();
+            self.mutex.raw.unlock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::thread;
+    use util::tests::{block_on, poll_once};
+    use AsyncMutex;
+
+    #[test]
+    fn smoke() {
+        let m = AsyncMutex::new(());
+        drop(block_on(m.lock()));
+        drop(block_on(m.lock()));
+    }
+
+    #[test]
+    fn lots_and_lots() {
+        const J: u32 = 1000;
+        const K: u32 = 3;
+
+        let m = Arc::new(AsyncMutex::new(0));
+        let threads: Vec<_> = (0..K)
+            .map(|_| {
+                let m = m.clone();
+                thread::spawn(move || {
+                    for _ in 0..J {
+                        *block_on(m.lock()) += 1;
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*block_on(m.lock()), J * K);
+    }
+
+    #[test]
+    fn try_lock() {
+        let m = AsyncMutex::new(());
+        let g = m.try_lock().unwrap();
+        assert!(m.try_lock().is_none());
+        drop(g);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn wait_for_unlock() {
+        let m = AsyncMutex::new(1);
+        let mut g = m.try_lock().unwrap();
+        let mut f = Box::pin(m.lock());
+        assert!(poll_once(f.as_mut()).is_none());
+        *g = 2;
+        drop(g);
+        assert_eq!(*poll_once(f.as_mut()).unwrap(), 2);
+    }
+
+    #[test]
+    fn cancel() {
+        let m = AsyncMutex::new(());
+        let g = m.try_lock().unwrap();
+        let mut f1 = Box::pin(m.lock());
+        let mut f2 = Box::pin(m.lock());
+        assert!(poll_once(f1.as_mut()).is_none());
+        assert!(poll_once(f2.as_mut()).is_none());
+
+        // Dropping a queued future removes it from the queue
+        drop(f1);
+        drop(g);
+        drop(poll_once(f2.as_mut()).unwrap());
+        drop(f2);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn cancel_after_wakeup() {
+        let m = AsyncMutex::new(());
+        let g = m.try_lock().unwrap();
+        let mut f1 = Box::pin(m.lock());
+        let mut f2 = Box::pin(m.lock());
+        assert!(poll_once(f1.as_mut()).is_none());
+        assert!(poll_once(f2.as_mut()).is_none());
+
+        // The first future was woken up but is dropped without being polled,
+        // so the wakeup must be passed on to the second one.
+        drop(g);
+        drop(f1);
+        assert!(poll_once(f2.as_mut()).is_some());
+    }
+
+    #[test]
+    fn blocking_lock() {
+        let m = Arc::new(AsyncMutex::new(0));
+        let g = block_on(m.lock());
+        let m2 = m.clone();
+        let t = thread::spawn(move || *m2.blocking_lock() += 1);
+        drop(g);
+        t.join().unwrap();
+        assert_eq!(*m.blocking_lock(), 1);
+    }
+
+    #[test]
+    fn guard_is_send() {
+        fn assert_send<F: Future + Send>(_: &F) {}
+        let m: &'static AsyncMutex<i32> = Box::leak(Box::new(AsyncMutex::new(0)));
+        let f = m.lock();
+        assert_send(&f);
+        let g = block_on(f);
+        thread::spawn(move || drop(g)).join().unwrap();
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_debug_async_mutex() {
+        let m = AsyncMutex::new(vec![0u8, 10]);
+        assert_eq!(format!("{:?}", m), "AsyncMutex { data: [0, 10] }");
+        let _lock = m.try_lock();
+        assert_eq!(format!("{:?}", m), "AsyncMutex { <locked> }");
+    }
+}
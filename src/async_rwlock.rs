@@ -0,0 +1,457 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::UnsafeCell;
+use std::ops::{Deref, DerefMut};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::marker::PhantomData;
+use parking_lot_core::AsyncWaiter;
+use raw_rwlock::RawRwLock;
+use lock_api::RawRwLock as RawRwLockTrait;
+
+/// A reader-writer lock for asynchronous code.
+///
+/// This type is the asynchronous equivalent of `RwLock`: `read` and `write`
+/// return futures which resolve to guards instead of blocking the current
+/// thread. The guards can be held across `.await` points and sent to other
+/// threads.
+///
+/// `AsyncRwLock` is built on the same lock as `RwLock` and has the same
+/// characteristics. Its state is a single word, tasks waiting for the lock are
+/// queued in the parking lot alongside blocked threads, and the lock uses the
+/// same task-fair locking policy and eventual fairness.
+///
+/// A future returned by `read` or `write` can be dropped at any time. If it was
+/// waiting for the lock then it is removed from the queue, and if the lock was
+/// already handed off to it then the lock is passed on to the next waiters.
+///
+/// This type is only available with the `async` feature, which requires Rust
+/// 1.36 or later.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate parking_lot;
+/// # #[cfg(feature = "async")]
+/// # fn main() {
+/// use parking_lot::AsyncRwLock;
+///
+/// let lock = AsyncRwLock::new(5);
+///
+/// // In an async function this would be `lock.write().await`
+/// let future = lock.write();
+/// # drop(future);
+///
+/// // The lock can also be acquired without waiting
+/// {
+///     let r1 = lock.try_read().unwrap();
+///     let r2 = lock.try_read().unwrap();
+///     assert_eq!(*r1 + *r2, 10);
+///     assert!(lock.try_write().is_none());
+/// }
+/// *lock.try_write().unwrap() += 1;
+/// assert_eq!(lock.into_inner(), 6);
+/// # }
+/// # #[cfg(not(feature = "async"))]
+/// # fn main() {}
+/// ```
+pub struct AsyncRwLock<T: ?Sized> {
+    raw: RawRwLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for AsyncRwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for AsyncRwLock<T> {}
+
+/// RAII structure used to release the shared read access of an `AsyncRwLock`
+/// when dropped.
+#[must_use]
+pub struct AsyncRwLockReadGuard<'a, T: ?Sized + 'a> {
+    rwlock: &'a AsyncRwLock<T>,
+    marker: PhantomData<&'a T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a> Send for AsyncRwLockReadGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for AsyncRwLockReadGuard<'a, T> {}
+
+/// RAII structure used to release the exclusive write access of an
+/// `AsyncRwLock` when dropped.
+#[must_use]
+pub struct AsyncRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    rwlock: &'a AsyncRwLock<T>,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Send + 'a> Send for AsyncRwLockWriteGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for AsyncRwLockWriteGuard<'a, T> {}
+
+/// A future which resolves to an `AsyncRwLockReadGuard` once shared access has
+/// been acquired, returned by `AsyncRwLock::read`.
+#[must_use]
+pub struct AsyncRwLockReadFuture<'a, T: ?Sized + 'a> {
+    rwlock: &'a AsyncRwLock<T>,
+
+    // Queue entry, only created if the task actually needs to wait
+    waiter: Option<AsyncWaiter>,
+}
+
+/// A future which resolves to an `AsyncRwLockWriteGuard` once exclusive access
+/// has been acquired, returned by `AsyncRwLock::write`.
+#[must_use]
+pub struct AsyncRwLockWriteFuture<'a, T: ?Sized + 'a> {
+    rwlock: &'a AsyncRwLock<T>,
+
+    // Queue entry, only created if the task actually needs to wait
+    waiter: Option<AsyncWaiter>,
+}
+
+impl<T> AsyncRwLock<T> {
+    /// Creates a new instance of an `AsyncRwLock<T>` which is unlocked.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> AsyncRwLock<T> {
+        AsyncRwLock {
+            data: UnsafeCell::new(val),
+            raw: <RawRwLock as RawRwLockTrait>::INIT,
+        }
+    }
+
+    /// Creates a new instance of an `AsyncRwLock<T>` which is unlocked.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> AsyncRwLock<T> {
+        AsyncRwLock {
+            data: UnsafeCell::new(val),
+            raw: <RawRwLock as RawRwLockTrait>::INIT,
+        }
+    }
+
+    /// Consumes this `AsyncRwLock`, returning the underlying data.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        unsafe { self.data.into_inner() }
+    }
+}
+
+impl<T: ?Sized> AsyncRwLock<T> {
+    #[inline]
+    fn read_guard(&self) -> AsyncRwLockReadGuard<T> {
+        AsyncRwLockReadGuard {
+            rwlock: self,
+            marker: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn write_guard(&self) -> AsyncRwLockWriteGuard<T> {
+        AsyncRwLockWriteGuard {
+            rwlock: self,
+            marker: PhantomData,
+        }
+    }
+
+    /// Locks this rwlock with shared read access asynchronously.
+    ///
+    /// The returned future resolves once there are no more writers which hold
+    /// the lock. There may be other readers currently inside the lock at that
+    /// point.
+    ///
+    /// The future resolves to an RAII guard which will release the shared
+    /// access once it is dropped.
+    #[inline]
+    pub fn read(&self) -> AsyncRwLockReadFuture<T> {
+        AsyncRwLockReadFuture {
+            rwlock: self,
+            waiter: None,
+        }
+    }
+
+    /// Attempts to acquire this rwlock with shared read access.
+    ///
+    /// If the access could not be granted at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned which will release the shared access
+    /// when it is dropped.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_read(&self) -> Option<AsyncRwLockReadGuard<T>> {
+        if self.raw.try_lock_shared() {
+            // The guard isn't tied to the current thread
+            unsafe { self.raw.detach() };
+            Some(self.read_guard())
+        } else {
+            None
+        }
+    }
+
+    /// Locks this rwlock with exclusive write access asynchronously.
+    ///
+    /// The returned future resolves once no other writers or readers have
+    /// access to the lock.
+    ///
+    /// The future resolves to an RAII guard which will drop the write access of
+    /// this rwlock when dropped.
+    #[inline]
+    pub fn write(&self) -> AsyncRwLockWriteFuture<T> {
+        AsyncRwLockWriteFuture {
+            rwlock: self,
+            waiter: None,
+        }
+    }
+
+    /// Attempts to lock this rwlock with exclusive write access.
+    ///
+    /// If the lock could not be acquired at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned which will release the lock when
+    /// it is dropped.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_write(&self) -> Option<AsyncRwLockWriteGuard<T>> {
+        if self.raw.try_lock_exclusive() {
+            // The guard isn't tied to the current thread
+            unsafe { self.raw.detach() };
+            Some(self.write_guard())
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the `AsyncRwLock` mutably, no actual locking
+    /// needs to take place---the mutable borrow statically guarantees no locks
+    /// exist.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for AsyncRwLock<T> {
+    #[inline]
+    fn default() -> AsyncRwLock<T> {
+        AsyncRwLock::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AsyncRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("AsyncRwLock")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.pad("AsyncRwLock { <locked> }"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Future for AsyncRwLockReadFuture<'a, T> {
+    type Output = AsyncRwLockReadGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncRwLockReadGuard<'a, T>> {
+        let this = self.get_mut();
+        match this.rwlock.raw.poll_lock_shared(&mut this.waiter, cx.waker()) {
+            Poll::Ready(()) => Poll::Ready(this.rwlock.read_guard()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncRwLockReadFuture<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.cancel_lock_shared(&mut self.waiter);
+    }
+}
+
+impl<'a, T: ?Sized + 'a> fmt::Debug for AsyncRwLockReadFuture<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("AsyncRwLockReadFuture { .. }")
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Future for AsyncRwLockWriteFuture<'a, T> {
+    type Output = AsyncRwLockWriteGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncRwLockWriteGuard<'a, T>> {
+        let this = self.get_mut();
+        match this.rwlock.raw.poll_lock_exclusive(&mut this.waiter, cx.waker()) {
+            Poll::Ready(()) => Poll::Ready(this.rwlock.write_guard()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncRwLockWriteFuture<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        self.rwlock.raw.cancel_lock_exclusive(&mut self.waiter);
+    }
+}
+
+impl<'a, T: ?Sized + 'a> fmt::Debug for AsyncRwLockWriteFuture<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("AsyncRwLockWriteFuture { .. }")
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for AsyncRwLockReadGuard<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncRwLockReadGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        // Attribute the lock to the unlocking thread first so that the release
+        // is balanced for the deadlock detector.
+        unsafe {
+            self.rwlock.raw.attach();
+            self.rwlock.raw.unlock_shared();
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for AsyncRwLockWriteGuard<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for AsyncRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.data.get() }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for AsyncRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        // Attribute the lock to the unlocking thread first so that the release
+        // is balanced for the deadlock detector.
+        unsafe {
+            self.rwlock.raw.attach();
+            self.rwlock.raw.unlock_exclusive();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use util::tests::{block_on, poll_once};
+    use AsyncRwLock;
+
+    #[test]
+    fn smoke() {
+        let l = AsyncRwLock::new(());
+        drop(block_on(l.read()));
+        drop(block_on(l.write()));
+        drop((block_on(l.read()), block_on(l.read())));
+        drop(block_on(l.write()));
+    }
+
+    #[test]
+    fn frob() {
+        const N: u32 = 10;
+        const M: u32 = 1000;
+
+        let r = Arc::new(AsyncRwLock::new(0));
+        let threads: Vec<_> = (0..N)
+            .map(|i| {
+                let r = r.clone();
+                thread::spawn(move || {
+                    for _ in 0..M {
+                        if i % 2 == 0 {
+                            *block_on(r.write()) += 1;
+                        } else {
+                            drop(block_on(r.read()));
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(*block_on(r.read()), N / 2 * M);
+    }
+
+    #[test]
+    fn writer_waits_for_readers() {
+        let l = AsyncRwLock::new(0);
+        let r1 = l.try_read().unwrap();
+        let r2 = block_on(l.read());
+        let mut w = Box::pin(l.write());
+        assert!(poll_once(w.as_mut()).is_none());
+
+        // New readers queue up behind the waiting writer
+        let mut r3 = Box::pin(l.read());
+        assert!(poll_once(r3.as_mut()).is_none());
+
+        drop(r1);
+        assert!(poll_once(w.as_mut()).is_none());
+        drop(r2);
+        let mut g = poll_once(w.as_mut()).unwrap();
+        *g = 1;
+        assert!(poll_once(r3.as_mut()).is_none());
+        drop(g);
+        assert_eq!(*poll_once(r3.as_mut()).unwrap(), 1);
+    }
+
+    #[test]
+    fn cancel() {
+        let l = AsyncRwLock::new(());
+        let g = l.try_write().unwrap();
+        let mut w = Box::pin(l.write());
+        let mut r = Box::pin(l.read());
+        assert!(poll_once(w.as_mut()).is_none());
+        assert!(poll_once(r.as_mut()).is_none());
+
+        // Dropping a queued future removes it from the queue
+        drop(w);
+        drop(g);
+        drop(poll_once(r.as_mut()).unwrap());
+        drop(r);
+        assert!(l.try_write().is_some());
+    }
+
+    #[test]
+    fn cancel_after_wakeup() {
+        let l = AsyncRwLock::new(());
+        let g = l.try_write().unwrap();
+        let mut w1 = Box::pin(l.write());
+        let mut w2 = Box::pin(l.write());
+        assert!(poll_once(w1.as_mut()).is_none());
+        assert!(poll_once(w2.as_mut()).is_none());
+
+        // The first writer was woken up but is dropped without being polled,
+        // so the wakeup must be passed on to the second one.
+        drop(g);
+        drop(w1);
+        assert!(poll_once(w2.as_mut()).is_some());
+    }
+
+    #[test]
+    fn test_debug_async_rwlock() {
+        let x = AsyncRwLock::new(vec![0u8, 10]);
+        assert_eq!(format!("{:?}", x), "AsyncRwLock { data: [0, 10] }");
+        let _lock = x.try_write();
+        assert_eq!(format!("{:?}", x), "AsyncRwLock { <locked> }");
+    }
+}
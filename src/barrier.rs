@@ -0,0 +1,492 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{error, fmt};
+use parking_lot_core::{self, FilterOp, ParkResult, ParkToken, UnparkToken};
+
+// This bit is set once a wait on the barrier has timed out. It is never cleared.
+const BROKEN_BIT: usize = 1;
+
+// Generation counter, incremented every time all threads have reached the
+// barrier. Parked threads use their generation as park token, so that the
+// leader only wakes up the threads of its own generation. A woken thread learns
+// the outcome of its wait from the unpark token, so the counter is only re-read
+// by a thread whose generation ended before it could park. It just has to be
+// wide enough not to wrap around in that window.
+const GENERATION_ONE: usize = 2;
+const GENERATION_MASK: usize = (1 << COUNT_SHIFT) - GENERATION_ONE;
+
+// The number of threads waiting for the current generation is stored in the
+// remaining bits.
+#[cfg(target_pointer_width = "64")]
+const COUNT_SHIFT: usize = 32;
+#[cfg(not(target_pointer_width = "64"))]
+const COUNT_SHIFT: usize = 16;
+const COUNT_ONE: usize = 1 << COUNT_SHIFT;
+
+// Unpark tokens telling a woken thread how its wait ended.
+const TOKEN_COMPLETED: UnparkToken = UnparkToken(0);
+const TOKEN_BROKEN: UnparkToken = UnparkToken(1);
+
+/// A barrier enables multiple threads to synchronize the beginning of some
+/// computation.
+///
+/// A barrier is created for a fixed number of threads. Each thread calling
+/// `wait` blocks until that number of threads have called it, at which point
+/// they are all woken up and one of them is elected as the leader. The barrier
+/// can then be reused for the next round.
+///
+/// # Differences from the standard library `Barrier`
+///
+/// - Only requires 2 words of space, whereas the standard library uses a
+///   boxed mutex and condition variable.
+/// - Supports timed waits with `wait_for` and `wait_until`. A wait which times
+///   out breaks the barrier: all threads currently waiting are woken up, and
+///   all current and future waits return a `BarrierBrokenError`.
+/// - Can be statically constructed (requires the `const_fn` nightly feature).
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Barrier;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let barrier = Arc::new(Barrier::new(10));
+/// let threads: Vec<_> = (0..10)
+///     .map(|_| {
+///         let barrier = barrier.clone();
+///         // The same messages will be printed together. You will NOT see any
+///         // interleaving.
+///         thread::spawn(move || {
+///             println!("before wait");
+///             let result = barrier.wait().unwrap();
+///             println!("after wait");
+///             result.is_leader()
+///         })
+///     })
+///     .collect();
+///
+/// // Exactly one thread is elected as the leader
+/// let leaders = threads
+///     .into_iter()
+///     .map(|t| t.join().unwrap())
+///     .filter(|&is_leader| is_leader)
+///     .count();
+/// assert_eq!(leaders, 1);
+/// ```
+pub struct Barrier {
+    state: AtomicUsize,
+    num_threads: usize,
+}
+
+/// A `BarrierWaitResult` is returned by `wait` when all threads in the
+/// `Barrier` have rendezvoused.
+pub struct BarrierWaitResult(bool);
+
+/// An error returned by the wait functions of a `Barrier` which is broken,
+/// because a wait on it timed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierBrokenError;
+
+impl Barrier {
+    /// Creates a new barrier that can block a given number of threads.
+    ///
+    /// A barrier will block `n`-1 threads which call `wait` and then wake up
+    /// all threads at once when the `n`th thread calls `wait`. A barrier
+    /// created with `n` equal to 0 behaves like one created with 1.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(n: usize) -> Barrier {
+        Barrier {
+            state: AtomicUsize::new(0),
+            num_threads: n,
+        }
+    }
+
+    /// Creates a new barrier that can block a given number of threads.
+    ///
+    /// A barrier will block `n`-1 threads which call `wait` and then wake up
+    /// all threads at once when the `n`th thread calls `wait`. A barrier
+    /// created with `n` equal to 0 behaves like one created with 1.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(n: usize) -> Barrier {
+        Barrier {
+            state: AtomicUsize::new(0),
+            num_threads: n,
+        }
+    }
+
+    /// Blocks the current thread until all threads have rendezvoused here.
+    ///
+    /// Barriers are re-usable after all threads have rendezvoused once, and can
+    /// be used continuously.
+    ///
+    /// A single (arbitrary) thread will receive a `BarrierWaitResult` that
+    /// returns `true` from `is_leader` when returning from this function, and
+    /// all other threads will receive a result that will return `false` from
+    /// `is_leader`.
+    ///
+    /// An error is returned if the barrier is broken, either before this call
+    /// or while waiting, because a timed wait on it expired.
+    #[inline]
+    pub fn wait(&self) -> Result<BarrierWaitResult, BarrierBrokenError> {
+        self.wait_internal(None)
+    }
+
+    /// Blocks the current thread until all threads have rendezvoused here or
+    /// the specified time instant is reached.
+    ///
+    /// If the timeout expires before all threads have reached the barrier, the
+    /// barrier is broken: all waiting threads are woken up and an error is
+    /// returned from every current and future wait on it.
+    #[inline]
+    pub fn wait_until(&self, timeout: Instant) -> Result<BarrierWaitResult, BarrierBrokenError> {
+        self.wait_internal(Some(timeout))
+    }
+
+    /// Blocks the current thread until all threads have rendezvoused here or
+    /// the specified duration has elapsed.
+    ///
+    /// If the timeout expires before all threads have reached the barrier, the
+    /// barrier is broken: all waiting threads are woken up and an error is
+    /// returned from every current and future wait on it.
+    #[inline]
+    pub fn wait_for(&self, timeout: Duration) -> Result<BarrierWaitResult, BarrierBrokenError> {
+        self.wait_internal(Some(Instant::now() + timeout))
+    }
+
+    /// Returns whether the barrier was broken by a wait which timed out.
+    #[inline]
+    pub fn is_broken(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & BROKEN_BIT != 0
+    }
+
+    fn wait_internal(
+        &self,
+        timeout: Option<Instant>,
+    ) -> Result<BarrierWaitResult, BarrierBrokenError> {
+        // Register our arrival, or start the next generation if we are the
+        // last thread to arrive.
+        let mut state = self.state.load(Ordering::Relaxed);
+        let generation = loop {
+            if state & BROKEN_BIT != 0 {
+                return Err(BarrierBrokenError);
+            }
+            if (state >> COUNT_SHIFT) + 1 >= self.num_threads {
+                let next = state.wrapping_add(GENERATION_ONE) & GENERATION_MASK;
+                match self.state.compare_exchange_weak(
+                    state,
+                    next,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        // Wake up the other threads, if there are any
+                        if state >> COUNT_SHIFT != 0 {
+                            self.unpark_generation(state & GENERATION_MASK, TOKEN_COMPLETED);
+                        }
+                        return Ok(BarrierWaitResult(true));
+                    }
+                    Err(x) => state = x,
+                }
+            } else {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state + COUNT_ONE,
+                    Ordering::Release,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break state & GENERATION_MASK,
+                    Err(x) => state = x,
+                }
+            }
+        };
+
+        self.wait_slow(generation, timeout)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wait_slow(
+        &self,
+        generation: usize,
+        timeout: Option<Instant>,
+    ) -> Result<BarrierWaitResult, BarrierBrokenError> {
+        let addr = self as *const _ as usize;
+        let mut broke_barrier = false;
+        let park_result = unsafe {
+            let validate = || {
+                let state = self.state.load(Ordering::Relaxed);
+                state & GENERATION_MASK == generation && state & BROKEN_BIT == 0
+            };
+            let before_sleep = || {};
+            let timed_out = |_, _| {
+                // Break the barrier, unless our generation completed just as we
+                // timed out. This is done while the queue is locked so that no
+                // thread can start waiting on a barrier which is about to be
+                // broken.
+                let mut state = self.state.load(Ordering::Relaxed);
+                while state & GENERATION_MASK == generation && state & BROKEN_BIT == 0 {
+                    match self.state.compare_exchange_weak(
+                        state,
+                        state | BROKEN_BIT,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => {
+                            broke_barrier = true;
+                            break;
+                        }
+                        Err(x) => state = x,
+                    }
+                }
+            };
+            parking_lot_core::park(
+                addr,
+                validate,
+                before_sleep,
+                timed_out,
+                ParkToken(generation),
+                timeout,
+            )
+        };
+
+        match park_result {
+            // We were woken up by the leader of our generation, or by a thread
+            // which broke the barrier while we were waiting.
+            ParkResult::Unparked(TOKEN_COMPLETED) => Ok(BarrierWaitResult(false)),
+            ParkResult::Unparked(_) => Err(BarrierBrokenError),
+
+            // Wake up all the other waiting threads if we broke the barrier.
+            ParkResult::TimedOut if broke_barrier => {
+                self.unpark_generation(generation, TOKEN_BROKEN);
+                Err(BarrierBrokenError)
+            }
+
+            // Our generation completed or the barrier was broken before we
+            // could park, or just as we timed out.
+            ParkResult::Invalid | ParkResult::TimedOut => {
+                let state = self.state.load(Ordering::Acquire);
+                if state & GENERATION_MASK != generation {
+                    Ok(BarrierWaitResult(false))
+                } else {
+                    Err(BarrierBrokenError)
+                }
+            }
+        }
+    }
+
+    // Wakes up all the threads waiting for the given generation. Threads which
+    // already started waiting for the next one are left parked.
+    #[inline]
+    fn unpark_generation(&self, generation: usize, token: UnparkToken) {
+        unsafe {
+            let addr = self as *const _ as usize;
+            let filter = |ParkToken(park_token)| {
+                if park_token == generation {
+                    FilterOp::Unpark
+                } else {
+                    FilterOp::Skip
+                }
+            };
+            parking_lot_core::unpark_filter(addr, filter, |_| token);
+        }
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("Barrier { .. }")
+    }
+}
+
+impl BarrierWaitResult {
+    /// Returns whether this thread from `wait` is the "leader thread".
+    ///
+    /// Only one thread will have `true` returned from their result, all other
+    /// threads will have `false` returned.
+    #[inline]
+    pub fn is_leader(&self) -> bool {
+        self.0
+    }
+}
+
+impl fmt::Debug for BarrierWaitResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BarrierWaitResult")
+            .field("is_leader", &self.is_leader())
+            .finish()
+    }
+}
+
+impl fmt::Display for BarrierBrokenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("barrier was broken by a wait which timed out")
+    }
+}
+
+impl error::Error for BarrierBrokenError {
+    fn description(&self) -> &str {
+        "barrier was broken by a wait which timed out"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, TryRecvError};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use {Barrier, BarrierBrokenError};
+
+    #[test]
+    fn test_barrier() {
+        const N: usize = 10;
+
+        let barrier = Arc::new(Barrier::new(N));
+        let (tx, rx) = channel();
+
+        for _ in 0..N - 1 {
+            let c = barrier.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                tx.send(c.wait().unwrap().is_leader()).unwrap();
+            });
+        }
+
+        // At this point, all spawned threads should be blocked,
+        // so we shouldn't get anything from the port
+        assert!(match rx.try_recv() {
+            Err(TryRecvError::Empty) => true,
+            _ => false,
+        });
+
+        let mut leader_found = barrier.wait().unwrap().is_leader();
+
+        // Now, the barrier is cleared and we should get data.
+        for _ in 0..N - 1 {
+            if rx.recv().unwrap() {
+                assert!(!leader_found);
+                leader_found = true;
+            }
+        }
+        assert!(leader_found);
+    }
+
+    #[test]
+    fn reuse() {
+        const N: usize = 4;
+        const ROUNDS: usize = 100;
+
+        let barrier = Arc::new(Barrier::new(N));
+        let threads: Vec<_> = (0..N)
+            .map(|_| {
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    (0..ROUNDS)
+                        .filter(|_| barrier.wait().unwrap().is_leader())
+                        .count()
+                })
+            })
+            .collect();
+        let leaders: usize = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert_eq!(leaders, ROUNDS);
+    }
+
+    #[test]
+    fn more_threads_than_barrier() {
+        const N: usize = 2;
+        const THREADS: usize = 6;
+        const ROUNDS: usize = 1000;
+
+        // Threads of consecutive generations wait at the same time, and must
+        // only be woken up by the leader of their own generation. Once enough
+        // rounds completed, the barrier is broken to release the threads which
+        // are still waiting.
+        let barrier = Arc::new(Barrier::new(N));
+        let rounds = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..THREADS)
+            .map(|_| {
+                let barrier = barrier.clone();
+                let rounds = rounds.clone();
+                thread::spawn(move || {
+                    while rounds.load(Ordering::Relaxed) < ROUNDS {
+                        match barrier.wait() {
+                            Ok(result) => {
+                                if result.is_leader() {
+                                    rounds.fetch_add(1, Ordering::Relaxed);
+                                }
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                })
+            })
+            .collect();
+        while rounds.load(Ordering::Relaxed) < ROUNDS {
+            thread::yield_now();
+        }
+        while !barrier.is_broken() {
+            let _ = barrier.wait_for(Duration::from_millis(1));
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn single_thread() {
+        let barrier = Barrier::new(0);
+        assert!(barrier.wait().unwrap().is_leader());
+        let barrier = Barrier::new(1);
+        assert!(barrier.wait().unwrap().is_leader());
+        assert!(barrier.wait().unwrap().is_leader());
+    }
+
+    #[test]
+    fn timeout_breaks_barrier() {
+        let barrier = Arc::new(Barrier::new(3));
+        let b = barrier.clone();
+        let t = thread::spawn(move || b.wait());
+
+        assert_eq!(
+            barrier.wait_for(Duration::from_millis(50)).unwrap_err(),
+            BarrierBrokenError
+        );
+        assert_eq!(t.join().unwrap().unwrap_err(), BarrierBrokenError);
+        assert!(barrier.is_broken());
+        assert!(barrier.wait().is_err());
+    }
+
+    #[test]
+    fn timeout_completed() {
+        let barrier = Arc::new(Barrier::new(2));
+        let b = barrier.clone();
+        let t = thread::spawn(move || b.wait().unwrap().is_leader());
+        let leader = barrier
+            .wait_for(Duration::from_secs(10))
+            .unwrap()
+            .is_leader();
+        assert!(leader != t.join().unwrap());
+        assert!(!barrier.is_broken());
+    }
+
+    #[test]
+    fn test_debug_barrier() {
+        let barrier = Barrier::new(1);
+        assert_eq!(format!("{:?}", barrier), "Barrier { .. }");
+        assert_eq!(
+            format!("{:?}", barrier.wait().unwrap()),
+            "BarrierWaitResult { is_leader: true }"
+        );
+    }
+}
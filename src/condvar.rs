@@ -5,18 +5,35 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::any::TypeId;
+use std::ops::Deref;
 use std::sync::atomic::{AtomicPtr, Ordering};
 use std::time::{Duration, Instant};
 use std::{ptr, fmt};
-use parking_lot_core::{self, ParkResult, RequeueOp, UnparkResult, DEFAULT_PARK_TOKEN};
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll, Waker};
+use parking_lot_core::{self, FilterOp, ParkResult, RequeueOp, UnparkResult, DEFAULT_PARK_TOKEN};
+#[cfg(feature = "async")]
+use parking_lot_core::AsyncWaiter;
+#[cfg(feature = "async")]
+use async_mutex::{self, AsyncMutex, AsyncMutexGuard};
 use mutex::{guard_lock, MutexGuard};
+use remutex::{self, ReentrantMutexGuard};
+use rwlock::{read_guard_lock, upgradable_guard_lock, write_guard_lock, RwLockReadGuard,
+             RwLockUpgradableReadGuard, RwLockWriteGuard};
 use raw_mutex::{RawMutex, TOKEN_HANDOFF, TOKEN_NORMAL};
+use raw_remutex::RawReentrantMutex;
+use lock_api::{RawMutex as RawMutexTrait, RawRwLock as RawRwLockTrait, RawRwLockUpgrade};
 use deadlock;
 
 /// A type indicating whether a timed wait on a condition variable returned
 /// due to a time out or not.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-pub struct WaitTimeoutResult(bool);
+pub struct WaitTimeoutResult(pub(crate) bool);
 
 impl WaitTimeoutResult {
     /// Returns whether the wait was known to have timed out.
@@ -26,6 +43,225 @@ impl WaitTimeoutResult {
     }
 }
 
+/// A lock guard which can be used to wait on a `Condvar`.
+///
+/// This trait is implemented for `MutexGuard`, `ReentrantMutexGuard`,
+/// `RwLockReadGuard`, `RwLockWriteGuard` and `RwLockUpgradableReadGuard` with
+/// any raw lock (which must be `'static` for mutexes), including
+/// `StampedRwLock` guards, as well as `AsyncMutexGuard` with the `async`
+/// feature. It can't be implemented outside of this crate.
+pub trait CondvarGuard: WaitGuard {}
+
+// Internal part of `CondvarGuard`. This needs to be public since it appears in
+// the bounds of `CondvarGuard`, but it isn't exported from the crate.
+pub trait WaitGuard {
+    fn wait_lock(&self) -> WaitLock;
+}
+
+// The lock held by a guard which is waiting on a `Condvar`. The raw lock is
+// type-erased so that the wait functions don't need to be generic over it.
+#[derive(Copy, Clone)]
+pub struct WaitLock<'a> {
+    // The mutex which waiting threads can be requeued onto, if any. This is
+    // only the case for locks built on the `RawMutex` of this crate.
+    requeue_mutex: Option<&'a RawMutex>,
+    lock: *const (),
+    // Releases the lock before sleeping, and returns the recursion count of a
+    // reentrant mutex.
+    unlock: unsafe fn(*const ()) -> usize,
+    // Re-acquires the lock after waking up, once the requeue mutex has been
+    // re-acquired.
+    relock: unsafe fn(*const (), usize),
+}
+
+impl<'a> WaitLock<'a> {
+    #[inline]
+    fn new<L>(
+        lock: &'a L,
+        requeue_mutex: Option<&'a RawMutex>,
+        unlock: unsafe fn(*const ()) -> usize,
+        relock: unsafe fn(*const (), usize),
+    ) -> WaitLock<'a> {
+        WaitLock {
+            requeue_mutex,
+            lock: lock as *const L as *const (),
+            unlock,
+            relock,
+        }
+    }
+
+    // Returns the mutex which waiting threads can be requeued onto, if any.
+    #[inline]
+    pub(crate) fn requeue_mutex(&self) -> Option<&'a RawMutex> {
+        self.requeue_mutex
+    }
+
+    // Unlocks the lock before sleeping. Returns the recursion count of a
+    // reentrant mutex, which needs to be passed to `relock`.
+    #[inline]
+    pub(crate) unsafe fn unlock(&self) -> usize {
+        (self.unlock)(self.lock)
+    }
+
+    // Re-locks the lock after waking up. If `handoff` is true then the
+    // requeue mutex was handed off to the current thread directly.
+    #[inline]
+    pub(crate) unsafe fn relock(&self, lock_count: usize, handoff: bool) {
+        if let Some(mutex) = self.requeue_mutex {
+            if handoff {
+                deadlock::acquire_resource(mutex as *const _ as usize);
+            } else {
+                mutex.lock();
+            }
+        }
+        (self.relock)(self.lock, lock_count);
+    }
+}
+
+// Returns the mutex if it is the `RawMutex` of this crate, which waiting
+// threads can be requeued onto.
+#[inline]
+fn requeue_mutex<R: RawMutexTrait + 'static>(mutex: &R) -> Option<&RawMutex> {
+    if TypeId::of::<R>() == TypeId::of::<RawMutex>() {
+        Some(unsafe { &*(mutex as *const R as *const RawMutex) })
+    } else {
+        None
+    }
+}
+
+// Functions used to build a `WaitLock`. The erased lock is cast back to the
+// type of the raw lock, which the function is instantiated with.
+unsafe fn unlock_mutex<R: RawMutexTrait>(mutex: *const ()) -> usize {
+    (*(mutex as *const R)).unlock();
+    0
+}
+unsafe fn lock_mutex<R: RawMutexTrait>(mutex: *const (), _: usize) {
+    (*(mutex as *const R)).lock();
+}
+unsafe fn relock_requeue_mutex(_: *const (), _: usize) {}
+unsafe fn unlock_reentrant<R: RawMutexTrait>(mutex: *const ()) -> usize {
+    (*(mutex as *const RawReentrantMutex<R>)).unlock_all()
+}
+unsafe fn lock_reentrant<R: RawMutexTrait>(mutex: *const (), lock_count: usize) {
+    let mutex = &*(mutex as *const RawReentrantMutex<R>);
+    mutex.mutex().lock();
+    mutex.restore(lock_count);
+}
+unsafe fn restore_reentrant<R: RawMutexTrait>(mutex: *const (), lock_count: usize) {
+    (*(mutex as *const RawReentrantMutex<R>)).restore(lock_count);
+}
+unsafe fn unlock_shared<R: RawRwLockTrait>(rwlock: *const ()) -> usize {
+    (*(rwlock as *const R)).unlock_shared();
+    0
+}
+unsafe fn lock_shared<R: RawRwLockTrait>(rwlock: *const (), _: usize) {
+    (*(rwlock as *const R)).lock_shared();
+}
+unsafe fn unlock_exclusive<R: RawRwLockTrait>(rwlock: *const ()) -> usize {
+    (*(rwlock as *const R)).unlock_exclusive();
+    0
+}
+unsafe fn lock_exclusive<R: RawRwLockTrait>(rwlock: *const (), _: usize) {
+    (*(rwlock as *const R)).lock_exclusive();
+}
+unsafe fn unlock_upgradable<R: RawRwLockUpgrade>(rwlock: *const ()) -> usize {
+    (*(rwlock as *const R)).unlock_upgradable();
+    0
+}
+unsafe fn lock_upgradable<R: RawRwLockUpgrade>(rwlock: *const (), _: usize) {
+    (*(rwlock as *const R)).lock_upgradable();
+}
+#[cfg(feature = "async")]
+unsafe fn unlock_async_mutex(mutex: *const ()) -> usize {
+    // The guard isn't tied to a thread, see `AsyncMutexGuard`
+    let mutex = &*(mutex as *const RawMutex);
+    mutex.attach();
+    mutex.unlock();
+    0
+}
+#[cfg(feature = "async")]
+unsafe fn detach_async_mutex(mutex: *const (), _: usize) {
+    (*(mutex as *const RawMutex)).detach();
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'static> WaitGuard for MutexGuard<'a, T, R> {
+    #[inline]
+    fn wait_lock(&self) -> WaitLock {
+        let mutex = guard_lock(self);
+        match requeue_mutex(mutex) {
+            Some(mutex) => WaitLock::new(
+                mutex,
+                Some(mutex),
+                unlock_mutex::<RawMutex>,
+                relock_requeue_mutex,
+            ),
+            None => WaitLock::new(mutex, None, unlock_mutex::<R>, lock_mutex::<R>),
+        }
+    }
+}
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'static> CondvarGuard for MutexGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'static> WaitGuard
+    for ReentrantMutexGuard<'a, T, R> {
+    #[inline]
+    fn wait_lock(&self) -> WaitLock {
+        let mutex = remutex::guard_lock(self);
+        match requeue_mutex(mutex.mutex()) {
+            Some(requeue_mutex) => WaitLock::new(
+                mutex,
+                Some(requeue_mutex),
+                unlock_reentrant::<R>,
+                restore_reentrant::<R>,
+            ),
+            None => WaitLock::new(mutex, None, unlock_reentrant::<R>, lock_reentrant::<R>),
+        }
+    }
+}
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'static> CondvarGuard
+    for ReentrantMutexGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> WaitGuard for RwLockReadGuard<'a, T, R> {
+    #[inline]
+    fn wait_lock(&self) -> WaitLock {
+        WaitLock::new(read_guard_lock(self), None, unlock_shared::<R>, lock_shared::<R>)
+    }
+}
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> CondvarGuard for RwLockReadGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> WaitGuard for RwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn wait_lock(&self) -> WaitLock {
+        WaitLock::new(write_guard_lock(self), None, unlock_exclusive::<R>, lock_exclusive::<R>)
+    }
+}
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> CondvarGuard for RwLockWriteGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> WaitGuard
+    for RwLockUpgradableReadGuard<'a, T, R> {
+    #[inline]
+    fn wait_lock(&self) -> WaitLock {
+        WaitLock::new(
+            upgradable_guard_lock(self),
+            None,
+            unlock_upgradable::<R>,
+            lock_upgradable::<R>,
+        )
+    }
+}
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> CondvarGuard
+    for RwLockUpgradableReadGuard<'a, T, R> {}
+
+#[cfg(feature = "async")]
+impl<'a, T: ?Sized + 'a> WaitGuard for AsyncMutexGuard<'a, T> {
+    #[inline]
+    fn wait_lock(&self) -> WaitLock {
+        let mutex = async_mutex::guard_mutex(self).raw();
+        WaitLock::new(mutex, Some(mutex), unlock_async_mutex, detach_async_mutex)
+    }
+}
+#[cfg(feature = "async")]
+impl<'a, T: ?Sized + 'a> CondvarGuard for AsyncMutexGuard<'a, T> {}
+
 /// A Condition Variable
 ///
 /// Condition variables represent the ability to block a thread such that it
@@ -34,12 +270,28 @@ impl WaitTimeoutResult {
 /// and a mutex. The predicate is always verified inside of the mutex before
 /// determining that thread must block.
 ///
+/// Besides `MutexGuard`, waits also accept `ReentrantMutexGuard` and the
+/// `RwLock` read, write and upgradable read guards, with any raw lock (see
+/// `CondvarGuard`). When waiting with a `ReentrantMutexGuard`, the mutex is
+/// fully released for the duration of the wait even if it was locked
+/// recursively, and the recursion count is restored afterwards.
+///
 /// Note that this module places one additional restriction over the system
 /// condition variables: each condvar can be used with only one mutex at a
 /// time. Any attempt to use multiple mutexes on the same condition variable
 /// simultaneously will result in a runtime panic. However it is possible to
 /// switch to a different mutex if there are no threads currently waiting on
-/// the condition variable.
+/// the condition variable. Waiters using `RwLock` guards, or guards of locks
+/// built on a raw mutex other than `RawMutex`, are never requeued, so any
+/// number of such locks may share a condvar, but they can't be mixed with
+/// mutexes using `RawMutex` at the same time.
+///
+/// With the `async` feature, asynchronous tasks can wait on a `Condvar` with an
+/// `AsyncMutexGuard` using `wait_async`. Tasks and threads share the same
+/// queue, so notifications wake up whichever waited first, and `notify_all`
+/// requeues waiting tasks onto the `AsyncMutex` just like threads. Threads can
+/// wait on the same `Condvar` by passing an `AsyncMutexGuard` obtained from
+/// `AsyncMutex::blocking_lock` to `wait`.
 ///
 /// # Differences from the standard library `Condvar`
 ///
@@ -47,7 +299,9 @@ impl WaitTimeoutResult {
 ///   was woken up by `notify_one` or `notify_all`.
 /// - `Condvar::notify_all` will only wake up a single thread, the rest are
 ///   requeued to wait for the `Mutex` to be unlocked by the thread that was
-///   woken up.
+///   woken up. Threads waiting with a `RwLock` guard are all woken up, since
+///   they may be able to acquire the `RwLock` concurrently, and so are
+///   threads waiting on locks built on other raw mutexes.
 /// - Only requires 1 word of space, whereas the standard library boxes the
 ///   `Condvar` due to platform limitations.
 /// - Can be statically constructed (requires the `const_fn` nightly feature).
@@ -110,20 +364,22 @@ impl Condvar {
     /// be woken up from its call to `wait` or `wait_timeout`. Calls to
     /// `notify_one` are not buffered in any way.
     ///
+    /// Returns whether a thread was woken up.
+    ///
     /// To wake up all threads, see `notify_all()`.
     #[inline]
-    pub fn notify_one(&self) {
+    pub fn notify_one(&self) -> bool {
         // Nothing to do if there are no waiting threads
         if self.state.load(Ordering::Relaxed).is_null() {
-            return;
+            return false;
         }
 
-        self.notify_one_slow();
+        self.notify_one_slow()
     }
 
     #[cold]
     #[inline(never)]
-    fn notify_one_slow(&self) {
+    fn notify_one_slow(&self) -> bool {
         unsafe {
             // Unpark one thread
             let addr = self as *const _ as usize;
@@ -134,7 +390,9 @@ impl Condvar {
                 }
                 TOKEN_NORMAL
             };
-            parking_lot_core::unpark_one(addr, callback);
+            let res = parking_lot_core::unpark_one(addr, callback);
+
+            res.unparked_threads != 0
         }
     }
 
@@ -144,21 +402,36 @@ impl Condvar {
     /// variable are awoken. Calls to `notify_all()` are not buffered in any
     /// way.
     ///
+    /// Returns the number of threads which were woken up or requeued onto the
+    /// mutex.
+    ///
+    /// Only threads waiting with a guard of a mutex using `RawMutex` are
+    /// requeued. All other threads are woken up at once, including threads
+    /// waiting with a `RwLockWriteGuard` or `RwLockUpgradableReadGuard`. Those
+    /// can only acquire the `RwLock` one at a time, so they will contend for
+    /// it and most of them will go back to sleep on it. If many threads wait
+    /// for exclusive access, waking them up one by one with `notify_one()`
+    /// avoids this.
+    ///
     /// To wake up only one thread, see `notify_one()`.
     #[inline]
-    pub fn notify_all(&self) {
+    pub fn notify_all(&self) -> usize {
         // Nothing to do if there are no waiting threads
         let state = self.state.load(Ordering::Relaxed);
         if state.is_null() {
-            return;
+            return 0;
         }
 
-        self.notify_all_slow(state);
+        self.notify_all_slow(state)
     }
 
     #[cold]
     #[inline(never)]
-    fn notify_all_slow(&self, mutex: *mut RawMutex) {
+    fn notify_all_slow(&self, mutex: *mut RawMutex) -> usize {
+        if mutex == self.no_requeue_state() {
+            return self.notify_all_no_requeue();
+        }
+
         unsafe {
             // Unpark one thread and requeue the rest onto the mutex
             let from = self as *const _ as usize;
@@ -197,26 +470,169 @@ impl Condvar {
                 }
                 TOKEN_NORMAL
             };
-            parking_lot_core::unpark_requeue(from, to, validate, callback);
+            let res = parking_lot_core::unpark_requeue(from, to, validate, callback);
+
+            res.unparked_threads + res.requeued_threads
+        }
+    }
+
+    /// Wakes up at most `n` blocked threads on this condvar.
+    ///
+    /// Threads are woken up in the order in which they started waiting. Like
+    /// with `notify_all()`, threads waiting with a guard of a mutex using
+    /// `RawMutex` are requeued onto the mutex instead of all being woken up at
+    /// once, while all other threads are woken up. Calls to `notify_n()` are
+    /// not buffered in any way.
+    ///
+    /// Returns the number of threads which were woken up or requeued onto the
+    /// mutex, which is less than `n` if fewer threads were waiting.
+    #[inline]
+    pub fn notify_n(&self, n: usize) -> usize {
+        // Nothing to do if there are no waiting threads
+        let state = self.state.load(Ordering::Relaxed);
+        if n == 0 || state.is_null() {
+            return 0;
+        }
+
+        self.notify_n_slow(state, n)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn notify_n_slow(&self, mutex: *mut RawMutex, n: usize) -> usize {
+        if mutex == self.no_requeue_state() {
+            return self.notify_n_no_requeue(n);
+        }
+
+        unsafe {
+            // Unpark the first thread and requeue up to n - 1 more onto the
+            // mutex
+            let from = self as *const _ as usize;
+            let to = mutex as usize;
+            let mut remaining = n;
+            let filter = |_| {
+                if remaining == 0 {
+                    return FilterOp::Stop;
+                }
+                remaining -= 1;
+                FilterOp::Unpark
+            };
+            let validate = || {
+                // Make sure that our atomic state still points to the same
+                // mutex, as in notify_all_slow.
+                if self.state.load(Ordering::Relaxed) != mutex {
+                    return RequeueOp::Abort;
+                }
+
+                // Unpark one thread if the mutex is unlocked, otherwise just
+                // requeue the selected threads to the mutex.
+                if (*mutex).mark_parked_if_locked() {
+                    RequeueOp::RequeueAll
+                } else {
+                    RequeueOp::UnparkOneRequeueRest
+                }
+            };
+            let callback = |op, result: UnparkResult| {
+                // Clear our state if there are no more waiting threads
+                if !result.have_more_threads {
+                    self.state.store(ptr::null_mut(), Ordering::Relaxed);
+                }
+
+                // If we requeued threads to the mutex, mark it as having
+                // parked threads. The RequeueAll case is already handled above.
+                if op == RequeueOp::UnparkOneRequeueRest && result.requeued_threads != 0 {
+                    (*mutex).mark_parked();
+                }
+                TOKEN_NORMAL
+            };
+            let res = parking_lot_core::unpark_requeue_filter(from, to, validate, filter, callback);
+
+            res.unparked_threads + res.requeued_threads
+        }
+    }
+
+    fn notify_n_no_requeue(&self, n: usize) -> usize {
+        unsafe {
+            // Unpark the first n threads
+            let addr = self as *const _ as usize;
+            let mut remaining = n;
+            let filter = |_| {
+                if remaining == 0 {
+                    return FilterOp::Stop;
+                }
+                remaining -= 1;
+                FilterOp::Unpark
+            };
+            let callback = |result: UnparkResult| {
+                // Clear our state if there are no more waiting threads
+                if !result.have_more_threads {
+                    self.state.store(ptr::null_mut(), Ordering::Relaxed);
+                }
+                TOKEN_NORMAL
+            };
+            let res = parking_lot_core::unpark_filter(addr, filter, callback);
+
+            res.unparked_threads
+        }
+    }
+
+    // Threads waiting with a `RwLock` guard can't be requeued onto the
+    // `RwLock`, since its queue uses park tokens to tell apart the kinds of
+    // lock that threads are waiting for. Neither can threads waiting on a lock
+    // built on another raw mutex, whose queue we know nothing about. Instead
+    // of the address of the lock, the state then holds an address inside the
+    // `Condvar` itself, which can never be the address of a `RawMutex`.
+    #[inline]
+    fn no_requeue_state(&self) -> *mut RawMutex {
+        (self as *const _ as usize + 1) as *mut RawMutex
+    }
+
+    fn notify_all_no_requeue(&self) -> usize {
+        unsafe {
+            // Unpark all threads, since they can't be requeued and readers may
+            // all be able to lock a RwLock at the same time.
+            let addr = self as *const _ as usize;
+            let no_requeue_state = self.no_requeue_state();
+            let filter = |_| {
+                // Make sure that our atomic state still indicates waiters
+                // which can't be requeued. If not then it means that all
+                // threads were woken up and a new waiting thread switched to a
+                // mutex. In that case we can get away with doing nothing.
+                if self.state.load(Ordering::Relaxed) != no_requeue_state {
+                    FilterOp::Stop
+                } else {
+                    FilterOp::Unpark
+                }
+            };
+            let callback = |result: UnparkResult| {
+                // Clear our state since we unparked all threads.
+                if result.unparked_threads != 0 {
+                    self.state.store(ptr::null_mut(), Ordering::Relaxed);
+                }
+                TOKEN_NORMAL
+            };
+            let res = parking_lot_core::unpark_filter(addr, filter, callback);
+
+            res.unparked_threads
         }
     }
 
     /// Blocks the current thread until this condition variable receives a
     /// notification.
     ///
-    /// This function will atomically unlock the mutex specified (represented by
-    /// `mutex_guard`) and block the current thread. This means that any calls
-    /// to `notify_*()` which happen logically after the mutex is unlocked are
-    /// candidates to wake this thread up. When this function call returns, the
-    /// lock specified will have been re-acquired.
+    /// This function will atomically unlock the lock held by `guard` and block
+    /// the current thread. This means that any calls to `notify_*()` which
+    /// happen logically after the lock is unlocked are candidates to wake this
+    /// thread up. When this function call returns, the lock specified will have
+    /// been re-acquired.
     ///
     /// # Panics
     ///
     /// This function will panic if another thread is waiting on the `Condvar`
     /// with a different `Mutex` object.
     #[inline]
-    pub fn wait<T: ?Sized>(&self, mutex_guard: &mut MutexGuard<T>) {
-        self.wait_until_internal(guard_lock(mutex_guard), None);
+    pub fn wait<G: CondvarGuard>(&self, guard: &mut G) {
+        self.wait_until_internal(guard.wait_lock(), None);
     }
 
     /// Waits on this condition variable for a notification, timing out after
@@ -243,24 +659,28 @@ impl Condvar {
     /// This function will panic if another thread is waiting on the `Condvar`
     /// with a different `Mutex` object.
     #[inline]
-    pub fn wait_until<T: ?Sized>(
+    pub fn wait_until<G: CondvarGuard>(
         &self,
-        mutex_guard: &mut MutexGuard<T>,
+        guard: &mut G,
         timeout: Instant,
     ) -> WaitTimeoutResult {
-        self.wait_until_internal(guard_lock(mutex_guard), Some(timeout))
+        self.wait_until_internal(guard.wait_lock(), Some(timeout))
     }
 
     // This is a non-generic function to reduce the monomorphization cost of
     // using `wait_until`.
-    fn wait_until_internal(&self, mutex: &RawMutex, timeout: Option<Instant>) -> WaitTimeoutResult {
+    fn wait_until_internal(&self, lock: WaitLock, timeout: Option<Instant>) -> WaitTimeoutResult {
         unsafe {
             let result;
             let mut bad_mutex = false;
             let mut requeued = false;
+            let mut lock_count = 0;
             {
                 let addr = self as *const _ as usize;
-                let lock_addr = mutex as *const _ as *mut _;
+                let lock_addr = match lock.requeue_mutex() {
+                    Some(mutex) => mutex as *const _ as *mut _,
+                    None => self.no_requeue_state(),
+                };
                 let validate = || {
                     // Ensure we don't use two different mutexes with the same
                     // Condvar at the same time. This is done while locked to
@@ -276,7 +696,7 @@ impl Condvar {
                 };
                 let before_sleep = || {
                     // Unlock the mutex before sleeping...
-                    mutex.unlock(false);
+                    lock_count = lock.unlock();
                 };
                 let timed_out = |k, was_last_thread| {
                     // If we were requeued to a mutex, then we did not time out.
@@ -308,12 +728,9 @@ impl Condvar {
                 panic!("attempted to use a condition variable with more than one mutex");
             }
 
-            // ... and re-lock it once we are done sleeping
-            if result == ParkResult::Unparked(TOKEN_HANDOFF) {
-                deadlock::acquire_resource(mutex as *const _ as usize);
-            } else {
-                mutex.lock();
-            }
+            // ... and re-lock it once we are done sleeping. A mutex may have
+            // been handed off to us directly if we were requeued onto it.
+            lock.relock(lock_count, result == ParkResult::Unparked(TOKEN_HANDOFF));
 
             WaitTimeoutResult(!(result.is_unparked() || requeued))
         }
@@ -338,12 +755,167 @@ impl Condvar {
     /// Like `wait`, the lock specified will be re-acquired when this function
     /// returns, regardless of whether the timeout elapsed or not.
     #[inline]
-    pub fn wait_for<T: ?Sized>(
+    pub fn wait_for<G: CondvarGuard>(&self, guard: &mut G, timeout: Duration) -> WaitTimeoutResult {
+        self.wait_until(guard, Instant::now() + timeout)
+    }
+
+    /// Blocks the current thread until the given condition becomes false.
+    ///
+    /// The condition is checked with the lock held before the first wait and
+    /// again every time this thread is woken up. This is equivalent to calling
+    /// `wait` in a loop for as long as `condition` returns `true`.
+    ///
+    /// When this function returns, the lock is held and the condition is known
+    /// to be false.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the `Condvar`
+    /// with a different `Mutex` object.
+    #[inline]
+    pub fn wait_while<G, F>(&self, guard: &mut G, mut condition: F)
+    where
+        G: CondvarGuard + Deref,
+        F: FnMut(&G::Target) -> bool,
+    {
+        while condition(&**guard) {
+            self.wait(guard);
+        }
+    }
+
+    /// Blocks the current thread until the given condition becomes false or
+    /// the specified time instant is reached.
+    ///
+    /// The semantics of this function are equivalent to `wait_while()` except
+    /// that the thread will be blocked roughly until `timeout` is reached.
+    /// Wakeups which leave the condition true do not extend the wait past
+    /// `timeout`.
+    ///
+    /// The returned `WaitTimeoutResult` indicates whether the wait ended
+    /// because of the timeout, in which case the condition was still true when
+    /// it was last checked. Otherwise the condition is known to be false.
+    ///
+    /// Like `wait`, the lock specified will be re-acquired when this function
+    /// returns, regardless of whether the timeout elapsed or not.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if another thread is waiting on the `Condvar`
+    /// with a different `Mutex` object.
+    #[inline]
+    pub fn wait_while_until<G, F>(
+        &self,
+        guard: &mut G,
+        mut condition: F,
+        timeout: Instant,
+    ) -> WaitTimeoutResult
+    where
+        G: CondvarGuard + Deref,
+        F: FnMut(&G::Target) -> bool,
+    {
+        let mut result = WaitTimeoutResult(false);
+        while condition(&**guard) {
+            // Re-check the condition after a timeout, since it may have become
+            // false just as the wait timed out.
+            if result.timed_out() {
+                return result;
+            }
+            result = self.wait_until(guard, timeout);
+        }
+        WaitTimeoutResult(false)
+    }
+
+    /// Blocks the current thread until the given condition becomes false or
+    /// a specified duration has elapsed.
+    ///
+    /// The deadline is computed once when this function is called, so the
+    /// total time spent waiting is roughly no longer than `timeout` no matter
+    /// how many times the thread is woken up. See `wait_while_until` for
+    /// details.
+    #[inline]
+    pub fn wait_while_for<G, F>(
         &self,
-        guard: &mut MutexGuard<T>,
+        guard: &mut G,
+        condition: F,
         timeout: Duration,
-    ) -> WaitTimeoutResult {
-        self.wait_until(guard, Instant::now() + timeout)
+    ) -> WaitTimeoutResult
+    where
+        G: CondvarGuard + Deref,
+        F: FnMut(&G::Target) -> bool,
+    {
+        self.wait_while_until(guard, condition, Instant::now() + timeout)
+    }
+
+    /// Asynchronously waits on this condition variable for a notification.
+    ///
+    /// The returned future unlocks the `AsyncMutex` held by `guard` when it is
+    /// first polled, and resolves to a new guard once the task has received a
+    /// notification and the mutex has been re-acquired. Tasks are woken up by
+    /// `notify_one`, `notify_all` and `notify_n` just like blocked threads.
+    ///
+    /// If the future is dropped after the task was notified but before the
+    /// mutex was re-acquired, the notification is passed on to another waiter
+    /// so that it isn't lost.
+    ///
+    /// This function is only available with the `async` feature.
+    ///
+    /// # Panics
+    ///
+    /// The future will panic when first polled if another thread or task is
+    /// waiting on the `Condvar` with a different mutex object.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate parking_lot;
+    /// # #[cfg(feature = "async")]
+    /// # fn main() {
+    /// use parking_lot::{AsyncMutex, Condvar};
+    ///
+    /// let mutex = AsyncMutex::new(false);
+    /// let condvar = Condvar::new();
+    ///
+    /// // In an async function this would be
+    /// // `while !*guard { guard = condvar.wait_async(guard).await; }`
+    /// let guard = mutex.try_lock().unwrap();
+    /// let future = condvar.wait_async(guard);
+    /// # drop(future);
+    /// # }
+    /// # #[cfg(not(feature = "async"))]
+    /// # fn main() {}
+    /// ```
+    #[cfg(feature = "async")]
+    #[inline]
+    pub fn wait_async<'a, T: ?Sized>(
+        &'a self,
+        guard: AsyncMutexGuard<'a, T>,
+    ) -> CondvarWaitFuture<'a, T> {
+        CondvarWaitFuture {
+            condvar: self,
+            mutex: async_mutex::guard_mutex(&guard),
+            guard: Some(guard),
+            waiter: None,
+            notified: false,
+        }
+    }
+
+    // Queues a task waiting with the given mutex. Returns false if another
+    // mutex is already being used with this condvar.
+    #[cfg(feature = "async")]
+    fn park_async(&self, mutex: &RawMutex, waiter: &mut AsyncWaiter, waker: &Waker) -> bool {
+        let addr = self as *const _ as usize;
+        let mutex = mutex as *const _ as *mut _;
+        let validate = || {
+            // Same check as in wait_until_internal
+            let state = self.state.load(Ordering::Relaxed);
+            if state.is_null() {
+                self.state.store(mutex, Ordering::Relaxed);
+            } else if state != mutex {
+                return false;
+            }
+            true
+        };
+        unsafe { waiter.park(addr, validate, DEFAULT_PARK_TOKEN, waker) }
     }
 }
 
@@ -360,19 +932,202 @@ impl fmt::Debug for Condvar {
     }
 }
 
+/// A future which waits for a notification on a `Condvar` and then re-locks an
+/// `AsyncMutex`, returned by `Condvar::wait_async`.
+#[cfg(feature = "async")]
+#[must_use]
+pub struct CondvarWaitFuture<'a, T: ?Sized + 'a> {
+    condvar: &'a Condvar,
+    mutex: &'a AsyncMutex<T>,
+
+    // Guard passed to `wait_async`, released once the task is queued
+    guard: Option<AsyncMutexGuard<'a, T>>,
+    waiter: Option<AsyncWaiter>,
+
+    // Whether the task was notified and is now trying to lock the mutex
+    notified: bool,
+}
+
+#[cfg(feature = "async")]
+impl<'a, T: ?Sized + 'a> Future for CondvarWaitFuture<'a, T> {
+    type Output = AsyncMutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<AsyncMutexGuard<'a, T>> {
+        let this = self.get_mut();
+        let mutex = this.mutex.raw();
+
+        if let Some(guard) = this.guard.take() {
+            // Queue ourselves on the condvar before unlocking the mutex, so
+            // that any notification sent after the unlock will wake us up. If
+            // we panic here then the guard is dropped while unwinding.
+            let waiter = this.waiter.get_or_insert_with(AsyncWaiter::new);
+            if !this.condvar.park_async(mutex, waiter, cx.waker()) {
+                panic!("attempted to use a condition variable with more than one mutex");
+            }
+            drop(guard);
+            return Poll::Pending;
+        }
+
+        if !this.notified {
+            let waiter = this.waiter.as_mut().expect("polled after completion");
+            match waiter.poll(cx.waker()) {
+                None => return Poll::Pending,
+                // We were requeued onto the mutex and it was handed off to us
+                Some(TOKEN_HANDOFF) => return Poll::Ready(this.mutex.guard()),
+                Some(_) => this.notified = true,
+            }
+        }
+
+        match mutex.poll_lock(&mut this.waiter, cx.waker()) {
+            Poll::Ready(()) => Poll::Ready(this.mutex.guard()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a, T: ?Sized + 'a> Drop for CondvarWaitFuture<'a, T> {
+    fn drop(&mut self) {
+        let mutex = self.mutex.raw();
+        if self.notified {
+            mutex.cancel_lock(&mut self.waiter);
+            return;
+        }
+        let waiter = match self.waiter {
+            Some(ref mut waiter) if waiter.is_parked() => waiter,
+            _ => return,
+        };
+
+        let condvar = self.condvar;
+        let addr = condvar as *const _ as usize;
+        let timed_out = |key, was_last_thread| {
+            // We may have been requeued onto the mutex, in which case it is
+            // the mutex's parked bit that needs to be cleared instead.
+            if was_last_thread {
+                if key == addr {
+                    condvar.state.store(ptr::null_mut(), Ordering::Relaxed);
+                } else {
+                    mutex.clear_parked();
+                }
+            }
+        };
+        if let Some((key, token)) = unsafe { waiter.cancel(timed_out) } {
+            // We were woken up but never observed it. If we were woken up from
+            // the condvar then this may have been the only notification from
+            // `notify_one`, so pass it on to another waiter. Otherwise we were
+            // requeued onto the mutex and the wakeup came from unlocking it,
+            // in which case there is no notification to pass on. Either way,
+            // release the mutex if it was handed off to us.
+            mutex.abandon_unpark(token);
+            if key == addr {
+                condvar.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<'a, T: ?Sized + 'a> fmt::Debug for CondvarWaitFuture<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("CondvarWaitFuture { .. }")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::sync::mpsc::channel;
     use std::sync::Arc;
     use std::thread;
+    use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
     use std::time::{Duration, Instant};
-    use {Condvar, Mutex};
+    use lock_api::RawMutex;
+    use {Condvar, Mutex, ReentrantMutex, RwLock, RwLockUpgradableReadGuard, StampedRwLock};
+    #[cfg(feature = "async")]
+    use util::tests::{block_on, poll_once};
+    #[cfg(feature = "async")]
+    use AsyncMutex;
 
     #[test]
     fn smoke() {
         let c = Condvar::new();
-        c.notify_one();
-        c.notify_all();
+        assert!(!c.notify_one());
+        assert_eq!(c.notify_all(), 0);
+        assert_eq!(c.notify_n(1), 0);
+    }
+
+    #[test]
+    fn notify_counts() {
+        const N: usize = 4;
+
+        let data = Arc::new((Mutex::new(0), Condvar::new()));
+        let mut threads = Vec::new();
+        for _ in 0..N {
+            let data = data.clone();
+            threads.push(thread::spawn(move || {
+                let &(ref lock, ref cond) = &*data;
+                let mut waiting = lock.lock();
+                *waiting += 1;
+                cond.wait(&mut waiting);
+            }));
+        }
+
+        // A thread has been queued on the condvar by the time it unlocks the
+        // mutex, so once every thread has registered they are all waiting.
+        let &(ref lock, ref cond) = &*data;
+        while *lock.lock() != N {
+            thread::yield_now();
+        }
+        assert_eq!(cond.notify_n(0), 0);
+        assert_eq!(cond.notify_n(2), 2);
+        assert!(cond.notify_one());
+        assert_eq!(cond.notify_all(), 1);
+        assert_eq!(cond.notify_all(), 0);
+        assert!(!cond.notify_one());
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn notify_n_requeue() {
+        const N: usize = 4;
+
+        // The first number counts waiting threads, the second woken ones
+        let data = Arc::new((Mutex::new((0, 0)), Condvar::new()));
+        let mut threads = Vec::new();
+        for _ in 0..N {
+            let data = data.clone();
+            threads.push(thread::spawn(move || {
+                let &(ref lock, ref cond) = &*data;
+                let mut guard = lock.lock();
+                guard.0 += 1;
+                cond.wait(&mut guard);
+                guard.1 += 1;
+            }));
+        }
+
+        let &(ref lock, ref cond) = &*data;
+        while lock.lock().0 != N {
+            thread::yield_now();
+        }
+
+        // The notified threads are requeued onto the locked mutex, and acquire
+        // it once we release it.
+        {
+            let guard = lock.lock();
+            assert_eq!(cond.notify_n(2), 2);
+            assert_eq!(guard.1, 0);
+        }
+        while lock.lock().1 != 2 {
+            thread::yield_now();
+        }
+
+        // Only the remaining threads are still waiting on the condvar
+        assert_eq!(cond.notify_n(3), 2);
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(lock.lock().1, N);
     }
 
     #[test]
@@ -517,6 +1272,356 @@ mod tests {
         let _ = c.wait_for(&mut m3.lock(), Duration::from_millis(1));
     }
 
+    #[test]
+    fn wait_while() {
+        let pair = Arc::new((Mutex::new(0), Condvar::new()));
+        let pair2 = pair.clone();
+
+        let &(ref lock, ref cond) = &*pair;
+        let mut g = lock.lock();
+        let _t = thread::spawn(move || {
+            let &(ref lock, ref cond) = &*pair2;
+            for _ in 0..3 {
+                *lock.lock() += 1;
+                cond.notify_one();
+            }
+        });
+        cond.wait_while(&mut g, |count| *count < 3);
+        assert_eq!(*g, 3);
+    }
+
+    #[test]
+    fn wait_while_for() {
+        let pair = Arc::new((RwLock::new(false), Condvar::new()));
+        let pair2 = pair.clone();
+
+        let &(ref lock, ref cond) = &*pair;
+        let mut g = lock.read();
+        let result = cond.wait_while_for(&mut g, |done| !*done, Duration::from_millis(10));
+        assert!(result.timed_out());
+        let result = cond.wait_while_for(&mut g, |done| *done, Duration::from_millis(10));
+        assert!(!result.timed_out());
+
+        let _t = thread::spawn(move || {
+            let &(ref lock, ref cond) = &*pair2;
+            *lock.write() = true;
+            cond.notify_one();
+        });
+        let result = cond.wait_while_until(
+            &mut g,
+            |done| !*done,
+            Instant::now() + Duration::from_millis(u32::max_value() as u64),
+        );
+        assert!(!result.timed_out());
+        assert!(*g);
+    }
+
+    #[test]
+    fn wait_rwlock_write() {
+        let l = Arc::new(RwLock::new(false));
+        let l2 = l.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+
+        let mut g = l.write();
+        let _t = thread::spawn(move || {
+            *l2.write() = true;
+            c2.notify_one();
+        });
+        while !*g {
+            c.wait(&mut g);
+        }
+    }
+
+    #[test]
+    fn wait_stamped_rwlock_write() {
+        let l = Arc::new(StampedRwLock::new(false));
+        let l2 = l.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+
+        let mut g = l.write();
+        let _t = thread::spawn(move || {
+            *l2.write() = true;
+            c2.notify_one();
+        });
+        while !*g {
+            c.wait(&mut g);
+        }
+        drop(g);
+        let stamp = l.optimistic_stamp().unwrap();
+        assert!(l.validate(stamp));
+    }
+
+    #[test]
+    fn notify_all_custom_raw_mutex() {
+        const N: usize = 10;
+
+        struct RawSpinlock(AtomicBool);
+
+        unsafe impl RawMutex for RawSpinlock {
+            const INIT: RawSpinlock = RawSpinlock(ATOMIC_BOOL_INIT);
+
+            fn lock(&self) {
+                while !self.try_lock() {
+                    thread::yield_now();
+                }
+            }
+
+            fn try_lock(&self) -> bool {
+                !self.0.swap(true, Ordering::Acquire)
+            }
+
+            unsafe fn unlock(&self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+
+        let data = Arc::new((Mutex::<bool, RawSpinlock>::default(), Condvar::new()));
+        let (tx, rx) = channel();
+        for _ in 0..N {
+            let data = data.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let &(ref lock, ref cond) = &*data;
+                let mut done = lock.lock();
+                tx.send(()).unwrap();
+                while !*done {
+                    cond.wait(&mut done);
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+
+        let &(ref lock, ref cond) = &*data;
+        for _ in 0..N {
+            rx.recv().unwrap();
+        }
+        *lock.lock() = true;
+        assert_eq!(cond.notify_all(), N);
+
+        for _ in 0..N {
+            rx.recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn notify_all_rwlock_read() {
+        const N: usize = 10;
+
+        let data = Arc::new((RwLock::new(false), Condvar::new()));
+        let (tx, rx) = channel();
+        for _ in 0..N {
+            let data = data.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let &(ref lock, ref cond) = &*data;
+                let mut done = lock.read();
+                tx.send(()).unwrap();
+                while !*done {
+                    cond.wait(&mut done);
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+
+        let &(ref lock, ref cond) = &*data;
+        for _ in 0..N {
+            rx.recv().unwrap();
+        }
+        *lock.write() = true;
+        cond.notify_all();
+
+        for _ in 0..N {
+            rx.recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn wait_for_rwlock_upgradable() {
+        let l = RwLock::new(());
+        let c = Condvar::new();
+
+        let mut g = l.upgradable_read();
+        assert!(c.wait_for(&mut g, Duration::from_millis(1)).timed_out());
+        assert!(l.try_upgradable_read().is_none());
+        let _g = RwLockUpgradableReadGuard::upgrade(g);
+    }
+
+    #[test]
+    fn wait_reentrant_mutex() {
+        let m = Arc::new(ReentrantMutex::new(()));
+        let m2 = m.clone();
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+
+        let outer = m.lock();
+        let mut inner = m.lock();
+        let _t = thread::spawn(move || {
+            let _g = m2.lock();
+            c2.notify_one();
+        });
+        c.wait(&mut inner);
+        drop(inner);
+
+        // The outer guard should still hold the lock after the wait.
+        let m3 = m.clone();
+        assert!(thread::spawn(move || m3.try_lock().is_none()).join().unwrap());
+        drop(outer);
+        assert!(thread::spawn(move || m.try_lock().is_some()).join().unwrap());
+    }
+
+    #[test]
+    #[should_panic]
+    fn mutex_and_rwlock() {
+        let m = Arc::new(Mutex::new(()));
+        let m2 = m.clone();
+        let l = RwLock::new(());
+        let c = Arc::new(Condvar::new());
+        let c2 = c.clone();
+
+        // Make sure we don't leave the child thread dangling
+        struct PanicGuard<'a>(&'a Condvar);
+        impl<'a> Drop for PanicGuard<'a> {
+            fn drop(&mut self) {
+                self.0.notify_one();
+            }
+        }
+
+        let (tx, rx) = channel();
+        let g = m.lock();
+        let _t = thread::spawn(move || {
+            let mut g = m2.lock();
+            tx.send(()).unwrap();
+            c2.wait(&mut g);
+        });
+        drop(g);
+        rx.recv().unwrap();
+        let _g = m.lock();
+        let _guard = PanicGuard(&*c);
+        let _ = c.wait(&mut l.write());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn wait_async_notify_one() {
+        let m = AsyncMutex::new(false);
+        let c = Condvar::new();
+        let mut f = Box::pin(c.wait_async(m.try_lock().unwrap()));
+        assert!(poll_once(f.as_mut()).is_none());
+
+        // The mutex is released while waiting
+        *m.try_lock().unwrap() = true;
+        assert!(poll_once(f.as_mut()).is_none());
+        assert!(c.notify_one());
+        assert!(*poll_once(f.as_mut()).unwrap());
+        assert!(!c.notify_one());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn wait_async_with_threads() {
+        let m = Arc::new(AsyncMutex::new(0));
+        let c = Arc::new(Condvar::new());
+        let m2 = m.clone();
+        let c2 = c.clone();
+        let t = thread::spawn(move || {
+            let mut g = m2.blocking_lock();
+            *g += 1;
+            while *g < 10 {
+                c2.wait(&mut g);
+            }
+            *g += 1;
+        });
+        while *m.blocking_lock() == 0 {
+            thread::yield_now();
+        }
+
+        // Tasks and threads share the queue, and are all requeued onto the
+        // mutex by notify_all.
+        let mut f = Box::pin(c.wait_async(block_on(m.lock())));
+        assert!(poll_once(f.as_mut()).is_none());
+        let mut g = m.blocking_lock();
+        *g = 10;
+        assert_eq!(c.notify_all(), 2);
+        drop(g);
+        let mut g = block_on(f);
+        *g += 1;
+        drop(g);
+        t.join().unwrap();
+        assert_eq!(*m.blocking_lock(), 12);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn wait_async_cancel() {
+        let m = AsyncMutex::new(());
+        let c = Condvar::new();
+        let mut f1 = Box::pin(c.wait_async(m.try_lock().unwrap()));
+        assert!(poll_once(f1.as_mut()).is_none());
+        let mut f2 = Box::pin(c.wait_async(m.try_lock().unwrap()));
+        assert!(poll_once(f2.as_mut()).is_none());
+
+        // The first task was notified but is dropped without being polled, so
+        // the notification must be passed on to the second one.
+        assert!(c.notify_one());
+        drop(f1);
+        assert!(poll_once(f2.as_mut()).is_some());
+        drop(f2);
+
+        // A task requeued onto the mutex can be dropped as well
+        let mut f = Box::pin(c.wait_async(m.try_lock().unwrap()));
+        assert!(poll_once(f.as_mut()).is_none());
+        let g = m.try_lock().unwrap();
+        assert_eq!(c.notify_all(), 1);
+        drop(f);
+        drop(g);
+        assert!(m.try_lock().is_some());
+
+        // There are no more waiters, so another mutex can now be used
+        let m2 = AsyncMutex::new(());
+        let mut f = Box::pin(c.wait_async(m2.try_lock().unwrap()));
+        assert!(poll_once(f.as_mut()).is_none());
+        drop(f);
+        assert!(m2.try_lock().is_some());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn wait_async_cancel_requeued() {
+        let m = AsyncMutex::new(());
+        let c = Condvar::new();
+        let mut f1 = Box::pin(c.wait_async(m.try_lock().unwrap()));
+        assert!(poll_once(f1.as_mut()).is_none());
+        let g = m.try_lock().unwrap();
+        assert_eq!(c.notify_all(), 1);
+
+        // Waiting releases the mutex, which wakes up the requeued task. That
+        // task is dropped without being polled, but it wasn't notified by the
+        // condvar, so the second task must not be woken up in its place.
+        let mut f2 = Box::pin(c.wait_async(g));
+        assert!(poll_once(f2.as_mut()).is_none());
+        drop(f1);
+        assert!(poll_once(f2.as_mut()).is_none());
+        assert!(c.notify_one());
+        assert!(poll_once(f2.as_mut()).is_some());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    #[should_panic]
+    fn wait_async_two_mutexes() {
+        let m = AsyncMutex::new(());
+        let m2 = AsyncMutex::new(());
+        let c = Condvar::new();
+        let mut f = Box::pin(c.wait_async(m.try_lock().unwrap()));
+        assert!(poll_once(f.as_mut()).is_none());
+        let mut f2 = Box::pin(c.wait_async(m2.try_lock().unwrap()));
+        let _ = poll_once(f2.as_mut());
+    }
+
     #[test]
     fn test_debug_condvar() {
         let c = Condvar::new();
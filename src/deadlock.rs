@@ -1,6 +1,28 @@
 //! [Experimental] Deadlock detection
 //!
-//! This feature is optional and can be enabled via the `deadlock_detection` feature flag.
+//! Deadlock detection relies on tracking the locks held by each thread. This
+//! tracking is always compiled in but disabled by default, so it only costs an
+//! atomic load per lock operation. It can be switched on and off at runtime
+//! with `set_enabled`, or enabled at startup by setting the
+//! `PARKING_LOT_DEADLOCK_DETECTION` environment variable to `1`. While enabled,
+//! `held_resources` returns the locks held by the current thread and the
+//! ownership assertions of the lock types are exact. Locks acquired while
+//! tracking was disabled are not tracked.
+//!
+//! The detector itself, which reports cycles of threads waiting on each other,
+//! is optional and can be enabled via the `deadlock_detection` feature flag.
+//! This feature also turns tracking on by default, in which case it can be
+//! disabled by setting the environment variable to `0`.
+//!
+//! Separately, the `self_deadlock_detection` feature flag makes a thread which
+//! tries to acquire a lock it already holds panic instead of blocking forever.
+//! This happens even if the deadlock detector is enabled as well, so such a
+//! thread is never reported by `check_deadlock`.
+//! Each thread keeps a list of the locks it holds for this, which is much
+//! cheaper than the detector since other threads are never looked at. A lock
+//! which is unlocked with a raw unlock function on a different thread than the
+//! one which locked it stays in the list of the locking thread, which may then
+//! panic spuriously if it tries to lock it again.
 //!
 //! # Example
 //!
@@ -32,10 +54,229 @@
 //! });
 //! } // only for #[cfg]
 //! ```
+//!
+//! Instead of polling `check_deadlock`, a handler can be registered to report
+//! deadlocks as soon as the last thread of a cycle blocks:
+//!
+//! ```
+//! #[cfg(feature = "deadlock_detection")]
+//! { // only for #[cfg]
+//! use std::sync::{Arc, Barrier};
+//! # use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+//! use std::thread;
+//! use parking_lot::{deadlock, Mutex};
+//!
+//! # static REPORTED: AtomicBool = ATOMIC_BOOL_INIT;
+//! fn report(deadlocks: Vec<Vec<deadlock::DeadlockedThread>>) {
+//!     println!("{} deadlocks detected", deadlocks.len());
+//!     for (i, threads) in deadlocks.iter().enumerate() {
+//!         println!("Deadlock #{}", i);
+//!         for t in threads {
+//!             println!("Thread Id {:#?}", t.thread_id());
+//!             println!("{:#?}", t.backtrace());
+//!         }
+//!     }
+//! #   REPORTED.store(true, Ordering::SeqCst);
+//! }
+//! deadlock::set_deadlock_handler(Some(report));
+//!
+//! // Two threads locking two mutexes in opposite orders
+//! let m1 = Arc::new(Mutex::new(()));
+//! let m2 = Arc::new(Mutex::new(()));
+//! let b = Arc::new(Barrier::new(2));
+//! let (m1_, m2_, b_) = (m1.clone(), m2.clone(), b.clone());
+//! thread::spawn(move || {
+//!     let _g = m1_.lock();
+//!     b_.wait();
+//!     let _g = m2_.lock();
+//! });
+//! thread::spawn(move || {
+//!     let _g = m2.lock();
+//!     b.wait();
+//!     let _g = m1.lock();
+//! });
+//! # while !REPORTED.load(Ordering::SeqCst) {
+//! #     thread::yield_now();
+//! # }
+//! } // only for #[cfg]
+//! ```
+//!
+//! The same resource tracking can be used as a watchdog, to report locks which
+//! are held or waited on for too long. This is more expensive than deadlock
+//! detection since a backtrace is captured whenever a lock is acquired, so it
+//! has to be enabled explicitly with `set_watchdog_enabled`:
+//!
+//! ```
+//! #[cfg(feature = "deadlock_detection")]
+//! { // only for #[cfg]
+//! use std::sync::Arc;
+//! use std::thread;
+//! use std::time::Duration;
+//! use parking_lot::{deadlock, Mutex};
+//!
+//! deadlock::set_watchdog_enabled(true);
+//!
+//! // Create a background thread which reports slow locks every 10s
+//! thread::spawn(move || {
+//!     loop {
+//!         thread::sleep(Duration::from_secs(10));
+//!         for holder in deadlock::check_long_holds(Duration::from_secs(5)) {
+//!             println!("Thread Id {:#?} held a lock for {:?}", holder.thread_id(), holder.held_for());
+//!             println!("{:#?}", holder.backtrace());
+//!         }
+//!         for wait in deadlock::check_long_waits(Duration::from_secs(5)) {
+//!             println!("Thread Id {:#?} waited for a lock for {:?}", wait.thread_id(), wait.waited_for());
+//!             for holder in wait.holders() {
+//!                 println!("Held by thread Id {:#?}", holder.thread_id());
+//!                 println!("{:#?}", holder.backtrace());
+//!             }
+//!         }
+//!     }
+//! });
+//!
+//! let m = Arc::new(Mutex::new(()));
+//! let guard = m.lock();
+//! let m2 = m.clone();
+//! let t = thread::spawn(move || drop(m2.lock()));
+//! # while deadlock::check_long_waits(Duration::from_secs(0)).is_empty() {
+//! #     thread::yield_now();
+//! # }
+//! thread::sleep(Duration::from_millis(10));
+//! let holds = deadlock::check_long_holds(Duration::from_millis(10));
+//! assert_eq!(holds.len(), 1);
+//! let waits = deadlock::check_long_waits(Duration::from_millis(0));
+//! assert_eq!(waits.len(), 1);
+//! assert_eq!(waits[0].holders().len(), 1);
+//! drop(guard);
+//! t.join().unwrap();
+//! assert!(deadlock::check_long_holds(Duration::from_secs(0)).is_empty());
+//! } // only for #[cfg]
+//! ```
+//!
+//! Turning tracking off and back on at runtime:
+//!
+//! ```
+//! use parking_lot::{deadlock, Mutex};
+//!
+//! let m = Mutex::new(());
+//!
+//! deadlock::set_enabled(false);
+//! let guard = m.lock();
+//! assert!(deadlock::held_resources().is_empty());
+//! drop(guard);
+//!
+//! deadlock::set_enabled(true);
+//! let _guard = m.lock();
+//! assert_eq!(deadlock::held_resources().len(), 1);
+//! ```
 
+pub use parking_lot_core::deadlock::{held_resources, is_enabled, set_enabled};
 #[cfg(feature = "deadlock_detection")]
-pub use parking_lot_core::deadlock::check_deadlock;
-pub(crate) use parking_lot_core::deadlock::{acquire_resource, release_resource};
+pub use parking_lot_core::deadlock::{check_deadlock, check_long_holds, check_long_waits,
+                                     set_deadlock_handler, set_watchdog_enabled,
+                                     DeadlockedThread, LockHolder, LongWait};
+use parking_lot_core;
+
+// Keeps track of the locks held by each thread when the self_deadlock_detection
+// feature is enabled, so that a thread trying to acquire a lock it already holds
+// can be reported instead of hanging. This is much cheaper than the full
+// deadlock detector since it never looks at other threads.
+#[cfg(feature = "self_deadlock_detection")]
+mod held_locks {
+    use std::cell::RefCell;
+    #[cfg(not(feature = "nightly"))]
+    use std::panic;
+
+    thread_local!(static HELD_LOCKS: RefCell<Vec<usize>> = RefCell::new(Vec::new()));
+
+    // Runs the given function on the locks held by the current thread, or
+    // returns None if the TLS has already been destroyed.
+    #[cfg(feature = "nightly")]
+    fn with<F: FnOnce(&mut Vec<usize>) -> R, R>(f: F) -> Option<R> {
+        HELD_LOCKS.try_with(|x| f(&mut x.borrow_mut())).ok()
+    }
+    #[cfg(not(feature = "nightly"))]
+    fn with<F: FnOnce(&mut Vec<usize>) -> R, R>(f: F) -> Option<R> {
+        let f = panic::AssertUnwindSafe(f);
+        panic::catch_unwind(move || HELD_LOCKS.with(|x| (f.0)(&mut x.borrow_mut()))).ok()
+    }
+
+    pub fn acquire(key: usize) {
+        with(|locks| locks.push(key));
+    }
+
+    pub fn release(key: usize) {
+        with(|locks| {
+            if let Some(p) = locks.iter().rposition(|x| *x == key) {
+                locks.swap_remove(p);
+            }
+        });
+    }
+
+    pub fn is_held(key: usize) -> Option<bool> {
+        with(|locks| locks.contains(&key))
+    }
+}
+
+// Registers a resource as acquired by the current thread.
+// Note: Call after the resource is acquired
+#[inline]
+pub(crate) unsafe fn acquire_resource(key: usize) {
+    parking_lot_core::deadlock::acquire_resource(key);
+    #[cfg(feature = "self_deadlock_detection")]
+    held_locks::acquire(key);
+}
+
+// Registers a resource as released by the current thread.
+// Note: Call before the resource is released
+#[inline]
+pub(crate) unsafe fn release_resource(key: usize) {
+    parking_lot_core::deadlock::release_resource(key);
+    #[cfg(feature = "self_deadlock_detection")]
+    held_locks::release(key);
+}
+
+// Returns whether the resource identified by key is held by the current thread,
+// or None if the locks held by the current thread aren't being tracked.
+#[cfg(feature = "self_deadlock_detection")]
+#[inline]
+fn resource_held(key: usize) -> Option<bool> {
+    held_locks::is_held(key)
+}
+#[cfg(not(feature = "self_deadlock_detection"))]
+#[inline]
+fn resource_held(key: usize) -> Option<bool> {
+    if parking_lot_core::deadlock::is_enabled() {
+        Some(parking_lot_core::deadlock::is_resource_held(key))
+    } else {
+        None
+    }
+}
+
+// Returns whether the resource identified by key is held by the current thread.
+// If resources aren't being tracked we have to assume that the caller is the
+// owner.
+#[inline]
+pub(crate) fn is_resource_held(key: usize) -> bool {
+    resource_held(key).unwrap_or(true)
+}
+
+// Panics if the current thread is known to hold the resource identified by
+// key. Called before blocking on a resource to report a thread which would
+// otherwise wait on itself forever. This is only done with the
+// self_deadlock_detection feature. Without it the deadlock detector reports
+// such a thread through check_deadlock, but with both features the panic
+// happens first and the thread never blocks.
+#[cfg(feature = "self_deadlock_detection")]
+#[inline]
+pub(crate) fn check_not_held(key: usize, msg: &str) {
+    if resource_held(key) == Some(true) {
+        panic!("deadlock: {}", msg);
+    }
+}
+#[cfg(not(feature = "self_deadlock_detection"))]
+#[inline]
+pub(crate) fn check_not_held(_key: usize, _msg: &str) {}
 
 #[cfg(test)]
 #[cfg(feature = "deadlock_detection")]
@@ -93,7 +334,9 @@ mod tests {
         assert!(!check_deadlock());
     }
 
+    // With self_deadlock_detection the second lock panics instead of blocking
     #[test]
+    #[cfg(not(feature = "self_deadlock_detection"))]
     fn test_mutex_deadlock_reentrant() {
         let m1: Arc<Mutex<()>> = Default::default();
 
@@ -200,6 +443,69 @@ mod tests {
     }
 
     #[test]
+    fn test_held_resources() {
+        use super::held_resources;
+
+        let m1 = Mutex::new(());
+        let m2 = RwLock::new(());
+        let m3 = ReentrantMutex::new(());
+        let before = held_resources();
+
+        let g1 = m1.lock();
+        let g2 = m2.read();
+        let g3 = m3.lock();
+        let g4 = m3.lock();
+        assert_eq!(held_resources().len(), before.len() + 3);
+        m1.assert_held_by_current_thread();
+        m2.assert_read_held();
+        m3.assert_held_by_current_thread();
+
+        drop(g2);
+        drop(g4);
+        assert_eq!(held_resources().len(), before.len() + 2);
+        drop(g1);
+        drop(g3);
+        assert_eq!(held_resources(), before);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_assert_held_other_thread() {
+        let m: Arc<Mutex<()>> = Default::default();
+        let m2 = m.clone();
+        let _g = m.lock();
+        assert!(thread::spawn(move || m2.assert_held_by_current_thread()).join().is_err());
+        m.assert_held_by_current_thread();
+    }
+
+    #[test]
+    fn test_watchdog_release_while_disabled() {
+        use super::{check_long_holds, set_watchdog_enabled};
+
+        let m = Mutex::new(());
+        let key = &m as *const _ as usize;
+        let held = || {
+            check_long_holds(Duration::from_secs(0))
+                .iter()
+                .any(|holder| holder.key() == key)
+        };
+
+        set_watchdog_enabled(true);
+        let g = m.lock();
+        assert!(held());
+
+        // The release isn't seen by the watchdog, so the record has to be
+        // discarded when it is disabled.
+        set_watchdog_enabled(false);
+        drop(g);
+        set_watchdog_enabled(true);
+        assert!(!held());
+        set_watchdog_enabled(false);
+    }
+
+    // With self_deadlock_detection the second lock panics instead of blocking
+    #[test]
+    #[cfg(not(feature = "self_deadlock_detection"))]
     fn test_rwlock_deadlock_reentrant() {
         let m1: Arc<RwLock<()>> = Default::default();
 
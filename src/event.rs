@@ -0,0 +1,597 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::atomic::{fence, Ordering};
+#[cfg(feature = "nightly")]
+use std::sync::atomic::AtomicU8;
+#[cfg(feature = "nightly")]
+type U8 = u8;
+#[cfg(not(feature = "nightly"))]
+use std::sync::atomic::AtomicUsize as AtomicU8;
+#[cfg(not(feature = "nightly"))]
+type U8 = usize;
+use std::time::{Duration, Instant};
+use std::fmt;
+use parking_lot_core::{self, FilterOp, ParkResult, SpinWait, UnparkResult, DEFAULT_PARK_TOKEN,
+                       DEFAULT_UNPARK_TOKEN};
+
+const SET_BIT: U8 = 1;
+const PARKED_BIT: U8 = 2;
+
+/// An event which, once set, releases all waiting threads until it is reset.
+///
+/// This is equivalent to a Windows manual-reset event object. While the event
+/// is set, `wait` returns immediately. While it is reset, `wait` blocks the
+/// current thread until another thread calls `set`.
+///
+/// Unlike a `Mutex` and `Condvar` pair, the event only takes one byte of state
+/// (one word on stable Rust). Threads waiting on it are queued in the parking
+/// lot, and `set` wakes up all of the threads which were waiting at the time it
+/// was called, even if the event is reset again before they get to run.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::ManualResetEvent;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let ready = Arc::new(ManualResetEvent::new(false));
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let ready = ready.clone();
+///         thread::spawn(move || ready.wait())
+///     })
+///     .collect();
+///
+/// // Release all the threads at once
+/// ready.set();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// ```
+pub struct ManualResetEvent {
+    state: AtomicU8,
+}
+
+/// An event which releases a single waiting thread each time it is set.
+///
+/// This is equivalent to a Windows auto-reset event object. Setting the event
+/// wakes up exactly one waiting thread and the event is reset automatically. If
+/// no thread is waiting, the event stays set until the next thread calls
+/// `wait`, which then returns immediately and resets it. Setting an event which
+/// is already set has no effect.
+///
+/// The event only takes one byte of state (one word on stable Rust), and
+/// threads waiting on it are queued in the parking lot in FIFO order.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::AutoResetEvent;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let event = Arc::new(AutoResetEvent::new(false));
+/// let event2 = event.clone();
+/// let t = thread::spawn(move || {
+///     // Each wait consumes one signal
+///     event2.wait();
+///     event2.wait();
+/// });
+///
+/// event.set();
+/// while event.is_set() {
+///     thread::yield_now();
+/// }
+/// event.set();
+/// t.join().unwrap();
+/// ```
+pub struct AutoResetEvent {
+    state: AtomicU8,
+}
+
+impl ManualResetEvent {
+    /// Creates a new event, which is initially set if `set` is true.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(set: bool) -> ManualResetEvent {
+        ManualResetEvent {
+            state: AtomicU8::new(set as U8),
+        }
+    }
+
+    /// Creates a new event, which is initially set if `set` is true.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(set: bool) -> ManualResetEvent {
+        ManualResetEvent {
+            state: AtomicU8::new(set as U8),
+        }
+    }
+
+    /// Returns whether the event is currently set.
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Acquire) & SET_BIT != 0
+    }
+
+    /// Sets the event, waking up all the threads waiting on it.
+    ///
+    /// The event stays set until `reset` is called, so any thread calling
+    /// `wait` in the meantime returns immediately.
+    #[inline]
+    pub fn set(&self) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & PARKED_BIT != 0 {
+                return self.set_slow();
+            }
+            if state & SET_BIT != 0 {
+                return;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                SET_BIT,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    /// Resets the event, so that threads calling `wait` block until the next
+    /// call to `set`.
+    #[inline]
+    pub fn reset(&self) {
+        self.state.fetch_and(!SET_BIT, Ordering::Relaxed);
+    }
+
+    /// Blocks the current thread until the event is set.
+    #[inline]
+    pub fn wait(&self) {
+        if !self.is_set() {
+            self.wait_slow(None);
+        }
+    }
+
+    /// Blocks the current thread until the event is set or the specified time
+    /// instant is reached.
+    ///
+    /// Returns `true` if the event was set, or `false` if the timeout expired.
+    #[inline]
+    pub fn wait_until(&self, timeout: Instant) -> bool {
+        self.is_set() || self.wait_slow(Some(timeout))
+    }
+
+    /// Blocks the current thread until the event is set or the specified
+    /// duration has elapsed.
+    ///
+    /// Returns `true` if the event was set, or `false` if the timeout expired.
+    #[inline]
+    pub fn wait_for(&self, timeout: Duration) -> bool {
+        self.is_set() || self.wait_slow(Some(Instant::now() + timeout))
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn set_slow(&self) {
+        unsafe {
+            // Set the event and wake up all waiting threads while holding the
+            // queue lock, so that threads which start waiting after a
+            // subsequent reset aren't woken up by this call.
+            let addr = self as *const _ as usize;
+            let filter = |_| FilterOp::Unpark;
+            let callback = |_| {
+                self.state.store(SET_BIT, Ordering::Release);
+                DEFAULT_UNPARK_TOKEN
+            };
+            parking_lot_core::unpark_filter(addr, filter, callback);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wait_slow(&self, timeout: Option<Instant>) -> bool {
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & SET_BIT != 0 {
+                // Synchronize with the thread which set the event
+                fence(Ordering::Acquire);
+                return true;
+            }
+
+            // If there is no queue, try spinning a few times
+            if state & PARKED_BIT == 0 && spinwait.spin() {
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Set the parked bit
+            if state & PARKED_BIT == 0 {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | PARKED_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Park our thread until we are woken up by a call to set
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || self.state.load(Ordering::Relaxed) == PARKED_BIT;
+                let before_sleep = || {};
+                let timed_out = |_, was_last_thread| {
+                    // Clear the parked bit if we were the last parked thread
+                    if was_last_thread {
+                        self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                    }
+                };
+                match parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    DEFAULT_PARK_TOKEN,
+                    timeout,
+                ) {
+                    // The event was set while we were waiting. It may have
+                    // been reset since then, but we still count as released.
+                    ParkResult::Unparked(_) => return true,
+
+                    // The validation function failed, try again
+                    ParkResult::Invalid => (),
+
+                    // Timeout expired
+                    ParkResult::TimedOut => return false,
+                }
+            }
+
+            // Loop back and check the state again
+            spinwait.reset();
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for ManualResetEvent {
+    #[inline]
+    fn default() -> ManualResetEvent {
+        ManualResetEvent::new(false)
+    }
+}
+
+impl fmt::Debug for ManualResetEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ManualResetEvent")
+            .field("set", &self.is_set())
+            .finish()
+    }
+}
+
+impl AutoResetEvent {
+    /// Creates a new event, which is initially set if `set` is true.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(set: bool) -> AutoResetEvent {
+        AutoResetEvent {
+            state: AtomicU8::new(set as U8),
+        }
+    }
+
+    /// Creates a new event, which is initially set if `set` is true.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(set: bool) -> AutoResetEvent {
+        AutoResetEvent {
+            state: AtomicU8::new(set as U8),
+        }
+    }
+
+    /// Returns whether the event is currently set, which means that no thread
+    /// is waiting on it and the next call to `wait` will return immediately.
+    #[inline]
+    pub fn is_set(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & SET_BIT != 0
+    }
+
+    /// Sets the event.
+    ///
+    /// If threads are waiting on the event, the one which started waiting first
+    /// is woken up and the event is left reset. Otherwise the event stays set
+    /// until the next call to `wait`.
+    #[inline]
+    pub fn set(&self) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & PARKED_BIT != 0 {
+                return self.set_slow();
+            }
+            if state & SET_BIT != 0 {
+                return;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                SET_BIT,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    /// Resets the event if it is set, without waking up any thread.
+    #[inline]
+    pub fn reset(&self) {
+        self.state.fetch_and(!SET_BIT, Ordering::Relaxed);
+    }
+
+    /// Blocks the current thread until the event is set, and resets it.
+    #[inline]
+    pub fn wait(&self) {
+        if !self.try_wait_fast() {
+            self.wait_slow(None);
+        }
+    }
+
+    /// Blocks the current thread until the event is set, and resets it, or
+    /// until the specified time instant is reached.
+    ///
+    /// Returns `true` if the event was set, or `false` if the timeout expired.
+    #[inline]
+    pub fn wait_until(&self, timeout: Instant) -> bool {
+        self.try_wait_fast() || self.wait_slow(Some(timeout))
+    }
+
+    /// Blocks the current thread until the event is set, and resets it, or
+    /// until the specified duration has elapsed.
+    ///
+    /// Returns `true` if the event was set, or `false` if the timeout expired.
+    #[inline]
+    pub fn wait_for(&self, timeout: Duration) -> bool {
+        self.try_wait_fast() || self.wait_slow(Some(Instant::now() + timeout))
+    }
+
+    #[inline]
+    fn try_wait_fast(&self) -> bool {
+        self.state
+            .compare_exchange_weak(SET_BIT, 0, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn set_slow(&self) {
+        unsafe {
+            // Hand the signal off to the first waiting thread. If the thread
+            // that set the parked bit hasn't actually parked yet, set the event
+            // instead, which it will notice when it validates its state.
+            let addr = self as *const _ as usize;
+            let callback = |result: UnparkResult| {
+                if result.unparked_threads == 0 {
+                    self.state.store(SET_BIT, Ordering::Release);
+                } else if !result.have_more_threads {
+                    self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                }
+                DEFAULT_UNPARK_TOKEN
+            };
+            parking_lot_core::unpark_one(addr, callback);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wait_slow(&self, timeout: Option<Instant>) -> bool {
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            // Consume the signal if the event is set
+            if state & SET_BIT != 0 {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state & !SET_BIT,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+
+            // If there is no queue, try spinning a few times
+            if state & PARKED_BIT == 0 && spinwait.spin() {
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Set the parked bit
+            if state & PARKED_BIT == 0 {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | PARKED_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Park our thread until the signal is handed off to us
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || self.state.load(Ordering::Relaxed) == PARKED_BIT;
+                let before_sleep = || {};
+                let timed_out = |_, was_last_thread| {
+                    // Clear the parked bit if we were the last parked thread
+                    if was_last_thread {
+                        self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                    }
+                };
+                match parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    DEFAULT_PARK_TOKEN,
+                    timeout,
+                ) {
+                    // The signal was handed off to us directly
+                    ParkResult::Unparked(_) => return true,
+
+                    // The validation function failed, try again
+                    ParkResult::Invalid => (),
+
+                    // Timeout expired
+                    ParkResult::TimedOut => return false,
+                }
+            }
+
+            // Loop back and check the state again
+            spinwait.reset();
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for AutoResetEvent {
+    #[inline]
+    fn default() -> AutoResetEvent {
+        AutoResetEvent::new(false)
+    }
+}
+
+impl fmt::Debug for AutoResetEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AutoResetEvent")
+            .field("set", &self.is_set())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use {AutoResetEvent, ManualResetEvent};
+
+    #[test]
+    fn manual_smoke() {
+        let e = ManualResetEvent::new(false);
+        assert!(!e.is_set());
+        e.set();
+        assert!(e.is_set());
+        e.wait();
+        e.wait();
+        e.reset();
+        assert!(!e.is_set());
+        assert!(!e.wait_for(Duration::from_millis(10)));
+        assert!(ManualResetEvent::new(true).wait_for(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn manual_releases_all() {
+        const N: usize = 8;
+
+        let e = Arc::new(ManualResetEvent::new(false));
+        let threads: Vec<_> = (0..N)
+            .map(|_| {
+                let e = e.clone();
+                thread::spawn(move || e.wait())
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        e.set();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert!(e.is_set());
+    }
+
+    #[test]
+    fn manual_set_then_reset() {
+        // Threads which were waiting when the event was set are released even
+        // if it is reset straight away.
+        let e = Arc::new(ManualResetEvent::new(false));
+        let e2 = e.clone();
+        let t = thread::spawn(move || e2.wait());
+        thread::sleep(Duration::from_millis(50));
+        e.set();
+        e.reset();
+        t.join().unwrap();
+        assert!(!e.is_set());
+    }
+
+    #[test]
+    fn auto_smoke() {
+        let e = AutoResetEvent::new(true);
+        assert!(e.is_set());
+        e.wait();
+        assert!(!e.is_set());
+        assert!(!e.wait_for(Duration::from_millis(10)));
+
+        // Setting twice only releases one wait
+        e.set();
+        e.set();
+        assert!(e.wait_for(Duration::from_millis(10)));
+        assert!(!e.wait_until(Instant::now() + Duration::from_millis(10)));
+        e.set();
+        e.reset();
+        assert!(!e.is_set());
+    }
+
+    #[test]
+    fn auto_releases_one() {
+        const N: usize = 4;
+
+        let e = Arc::new(AutoResetEvent::new(false));
+        let released = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..N)
+            .map(|_| {
+                let e = e.clone();
+                let released = released.clone();
+                thread::spawn(move || {
+                    e.wait();
+                    released.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        thread::sleep(Duration::from_millis(50));
+        for i in 0..N {
+            e.set();
+            while released.load(Ordering::SeqCst) == i {
+                thread::yield_now();
+            }
+        }
+        thread::sleep(Duration::from_millis(10));
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(released.load(Ordering::SeqCst), N);
+        assert!(!e.is_set());
+    }
+
+    #[test]
+    fn test_debug_event() {
+        let e = ManualResetEvent::new(true);
+        assert_eq!(format!("{:?}", e), "ManualResetEvent { set: true }");
+        let e = AutoResetEvent::default();
+        assert_eq!(format!("{:?}", e), "AutoResetEvent { set: false }");
+    }
+}
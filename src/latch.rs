@@ -0,0 +1,610 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{fmt, thread};
+use parking_lot_core::{self, FilterOp, ParkResult, SpinWait, DEFAULT_PARK_TOKEN,
+                       DEFAULT_UNPARK_TOKEN};
+
+// This bit is set in the state if there are threads waiting for the count to
+// reach zero.
+const PARKED_BIT: usize = 1;
+
+// This bit is set while the threads which were waiting for the count to reach
+// zero are being released. New waiters must not be queued until this is done,
+// otherwise they could be woken up by a release which happened before the
+// count was increased again.
+const RELEASING_BIT: usize = 2;
+
+// The count is stored in the remaining bits.
+const COUNT_SHIFT: usize = 2;
+const COUNT_ONE: usize = 1 << COUNT_SHIFT;
+const MAX_COUNT: usize = !0 >> COUNT_SHIFT;
+
+// Counter shared by `Latch` and `WaitGroup`, whose waiters are released when
+// the count reaches zero.
+struct Counter {
+    state: AtomicUsize,
+}
+
+impl Counter {
+    #[cfg(feature = "nightly")]
+    #[inline]
+    const fn new(count: usize) -> Counter {
+        Counter {
+            state: AtomicUsize::new(count << COUNT_SHIFT),
+        }
+    }
+
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    fn new(count: usize) -> Counter {
+        assert!(count <= MAX_COUNT, "counter overflow");
+        Counter {
+            state: AtomicUsize::new(count << COUNT_SHIFT),
+        }
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.state.load(Ordering::Acquire) >> COUNT_SHIFT
+    }
+
+    #[inline]
+    fn add(&self, n: usize) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            assert!(
+                n <= MAX_COUNT - (state >> COUNT_SHIFT),
+                "counter overflow"
+            );
+            match self.state.compare_exchange_weak(
+                state,
+                state + (n << COUNT_SHIFT),
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    // Decrements the count, waking up all waiting threads if it reaches zero.
+    // Returns false if the count was already zero.
+    #[inline]
+    fn decrement(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state >> COUNT_SHIFT == 0 {
+                return false;
+            }
+
+            // Replace the parked bit with the releasing bit when the count
+            // reaches zero, the waiting threads are then released by
+            // release_slow. A release from a previous round may still be in
+            // progress, in which case its releasing bit is kept.
+            let new_state = if state >> COUNT_SHIFT != 1 {
+                state - COUNT_ONE
+            } else if state & PARKED_BIT != 0 {
+                RELEASING_BIT
+            } else {
+                state & RELEASING_BIT
+            };
+            match self.state.compare_exchange_weak(
+                state,
+                new_state,
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(x) => state = x,
+            }
+        }
+
+        if state == COUNT_ONE | PARKED_BIT {
+            self.release_slow();
+        }
+        true
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn release_slow(&self) {
+        unsafe {
+            // Clear the releasing bit while the queue is still locked, after
+            // all the threads waiting for this round have been dequeued. The
+            // count may already have been increased for the next round, so
+            // only this bit is touched.
+            let addr = self as *const _ as usize;
+            let filter = |_| FilterOp::Unpark;
+            let callback = |_| {
+                self.state.fetch_and(!RELEASING_BIT, Ordering::Relaxed);
+                DEFAULT_UNPARK_TOKEN
+            };
+            parking_lot_core::unpark_filter(addr, filter, callback);
+        }
+    }
+
+    #[inline]
+    fn wait_until(&self, timeout: Option<Instant>) -> bool {
+        self.count() == 0 || self.wait_slow(timeout)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wait_slow(&self, timeout: Option<Instant>) -> bool {
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state >> COUNT_SHIFT == 0 {
+                // Synchronize with the thread which decremented the count
+                fence(Ordering::Acquire);
+                return true;
+            }
+
+            // Wait for the previous round of waiters to be released before
+            // queueing ourselves. This only takes as long as unparking them.
+            if state & RELEASING_BIT != 0 {
+                thread::yield_now();
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // If there is no queue, try spinning a few times
+            if state & PARKED_BIT == 0 && spinwait.spin() {
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Set the parked bit
+            if state & PARKED_BIT == 0 {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | PARKED_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Park our thread until the count reaches zero
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || {
+                    let state = self.state.load(Ordering::Relaxed);
+                    state >> COUNT_SHIFT != 0 && state & (PARKED_BIT | RELEASING_BIT) == PARKED_BIT
+                };
+                let before_sleep = || {};
+                let timed_out = |_, was_last_thread| {
+                    // Clear the parked bit if we were the last parked thread
+                    if was_last_thread {
+                        self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                    }
+                };
+                match parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    DEFAULT_PARK_TOKEN,
+                    timeout,
+                ) {
+                    // The count reached zero
+                    ParkResult::Unparked(_) => return true,
+
+                    // The validation function failed, try again
+                    ParkResult::Invalid => (),
+
+                    // Timeout expired
+                    ParkResult::TimedOut => return false,
+                }
+            }
+
+            // Loop back and check the count again
+            spinwait.reset();
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+}
+
+/// A synchronization primitive which releases waiting threads once a number of
+/// operations have completed.
+///
+/// A latch is created with an initial count, which is decremented by each call
+/// to `count_down`. Threads calling `wait` block until the count reaches zero,
+/// after which all current and future waits return immediately. The count can't
+/// be increased again, see `WaitGroup` for a counter which can be reused.
+///
+/// The latch only takes one word of space, and waiting threads are queued in
+/// the parking lot instead of using a `Mutex` and `Condvar` pair.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Latch;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let latch = Arc::new(Latch::new(3));
+/// for _ in 0..3 {
+///     let latch = latch.clone();
+///     thread::spawn(move || {
+///         // ... do some work ...
+///         latch.count_down();
+///     });
+/// }
+///
+/// // Wait for all 3 threads to finish their work
+/// latch.wait();
+/// assert_eq!(latch.count(), 0);
+/// ```
+pub struct Latch {
+    counter: Counter,
+}
+
+impl Latch {
+    /// Creates a new latch with the given initial count.
+    ///
+    /// The count must not be greater than `usize::MAX / 4`.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(count: usize) -> Latch {
+        Latch {
+            counter: Counter::new(count),
+        }
+    }
+
+    /// Creates a new latch with the given initial count.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `count` is greater than `usize::MAX / 4`.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(count: usize) -> Latch {
+        Latch {
+            counter: Counter::new(count),
+        }
+    }
+
+    /// Returns the current count.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.counter.count()
+    }
+
+    /// Decrements the count, releasing all waiting threads if it reaches zero.
+    ///
+    /// This has no effect if the count is already zero.
+    #[inline]
+    pub fn count_down(&self) {
+        self.counter.decrement();
+    }
+
+    /// Blocks the current thread until the count reaches zero.
+    #[inline]
+    pub fn wait(&self) {
+        self.counter.wait_until(None);
+    }
+
+    /// Blocks the current thread until the count reaches zero or the specified
+    /// time instant is reached.
+    ///
+    /// Returns `true` if the count reached zero, or `false` if the timeout
+    /// expired.
+    #[inline]
+    pub fn wait_until(&self, timeout: Instant) -> bool {
+        self.counter.wait_until(Some(timeout))
+    }
+
+    /// Blocks the current thread until the count reaches zero or the specified
+    /// duration has elapsed.
+    ///
+    /// Returns `true` if the count reached zero, or `false` if the timeout
+    /// expired.
+    #[inline]
+    pub fn wait_for(&self, timeout: Duration) -> bool {
+        self.counter.wait_until(Some(Instant::now() + timeout))
+    }
+}
+
+impl fmt::Debug for Latch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Latch")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+/// A synchronization primitive which waits for a group of operations to
+/// complete.
+///
+/// A wait group holds a count of pending operations, which is incremented with
+/// `add` before starting operations and decremented with `done` as each of them
+/// completes. Threads calling `wait` block until the count reaches zero.
+///
+/// Unlike a `Latch`, a wait group can be reused once the count has reached zero.
+/// Threads which were waiting for the previous set of operations are still
+/// released even if `add` is called for the next set before they wake up, and
+/// threads waiting for the next set are only released once its count reaches
+/// zero.
+///
+/// The wait group only takes one word of space, and waiting threads are queued
+/// in the parking lot instead of using a `Mutex` and `Condvar` pair.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::WaitGroup;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let wg = Arc::new(WaitGroup::new());
+/// for _ in 0..4 {
+///     wg.add(1);
+///     let wg = wg.clone();
+///     thread::spawn(move || {
+///         // ... do some work ...
+///         wg.done();
+///     });
+/// }
+///
+/// // Wait for all the threads to call done
+/// wg.wait();
+/// assert_eq!(wg.count(), 0);
+/// ```
+pub struct WaitGroup {
+    counter: Counter,
+}
+
+impl WaitGroup {
+    /// Creates a new wait group with a count of zero.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new() -> WaitGroup {
+        WaitGroup {
+            counter: Counter::new(0),
+        }
+    }
+
+    /// Creates a new wait group with a count of zero.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            counter: Counter::new(0),
+        }
+    }
+
+    /// Returns the current count.
+    #[inline]
+    pub fn count(&self) -> usize {
+        self.counter.count()
+    }
+
+    /// Adds `n` to the count.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the count would exceed `usize::MAX / 4`.
+    #[inline]
+    pub fn add(&self, n: usize) {
+        self.counter.add(n);
+    }
+
+    /// Decrements the count, releasing all waiting threads if it reaches zero.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the count is already zero.
+    #[inline]
+    pub fn done(&self) {
+        if !self.counter.decrement() {
+            panic!("WaitGroup::done called more times than WaitGroup::add");
+        }
+    }
+
+    /// Blocks the current thread until the count reaches zero.
+    #[inline]
+    pub fn wait(&self) {
+        self.counter.wait_until(None);
+    }
+
+    /// Blocks the current thread until the count reaches zero or the specified
+    /// time instant is reached.
+    ///
+    /// Returns `true` if the count reached zero, or `false` if the timeout
+    /// expired.
+    #[inline]
+    pub fn wait_until(&self, timeout: Instant) -> bool {
+        self.counter.wait_until(Some(timeout))
+    }
+
+    /// Blocks the current thread until the count reaches zero or the specified
+    /// duration has elapsed.
+    ///
+    /// Returns `true` if the count reached zero, or `false` if the timeout
+    /// expired.
+    #[inline]
+    pub fn wait_for(&self, timeout: Duration) -> bool {
+        self.counter.wait_until(Some(Instant::now() + timeout))
+    }
+}
+
+impl Default for WaitGroup {
+    #[inline]
+    fn default() -> WaitGroup {
+        WaitGroup::new()
+    }
+}
+
+impl fmt::Debug for WaitGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WaitGroup")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use {Latch, WaitGroup};
+
+    #[test]
+    fn latch_smoke() {
+        let l = Latch::new(2);
+        assert_eq!(l.count(), 2);
+        assert!(!l.wait_for(Duration::from_millis(10)));
+        l.count_down();
+        l.count_down();
+        assert_eq!(l.count(), 0);
+        l.wait();
+        l.count_down();
+        assert_eq!(l.count(), 0);
+        assert!(Latch::new(0).wait_until(Instant::now()));
+    }
+
+    #[test]
+    fn latch_releases_all() {
+        const N: usize = 4;
+
+        let l = Arc::new(Latch::new(N));
+        let released = Arc::new(AtomicUsize::new(0));
+        let waiters: Vec<_> = (0..N)
+            .map(|_| {
+                let l = l.clone();
+                let released = released.clone();
+                thread::spawn(move || {
+                    l.wait();
+                    released.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+        for _ in 0..N - 1 {
+            l.count_down();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(released.load(Ordering::SeqCst), 0);
+        l.count_down();
+        for t in waiters {
+            t.join().unwrap();
+        }
+        assert_eq!(released.load(Ordering::SeqCst), N);
+    }
+
+    #[test]
+    fn wait_group() {
+        const N: usize = 8;
+
+        let wg = Arc::new(WaitGroup::new());
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            // The wait group can be reused once the count reaches zero
+            for _ in 0..N {
+                wg.add(1);
+                let wg = wg.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    done.fetch_add(1, Ordering::SeqCst);
+                    wg.done();
+                });
+            }
+            wg.wait();
+        }
+        assert_eq!(done.load(Ordering::SeqCst), 2 * N);
+        assert_eq!(wg.count(), 0);
+    }
+
+    #[test]
+    fn wait_group_reuse() {
+        const ROUNDS: usize = 1000;
+        const N: usize = 4;
+
+        // The main thread starts each round as soon as it sees the previous
+        // one complete, possibly before the previous waiters have been
+        // released. A waiter must never return before its own round has
+        // completed.
+        let wg = Arc::new(WaitGroup::new());
+        let started = Arc::new(AtomicUsize::new(0));
+        let completed = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..N)
+            .map(|i| {
+                let wg = wg.clone();
+                let started = started.clone();
+                let completed = completed.clone();
+                thread::spawn(move || {
+                    for round in 0..ROUNDS {
+                        while started.load(Ordering::SeqCst) <= round {
+                            thread::yield_now();
+                        }
+                        if i == 0 {
+                            completed.store(round + 1, Ordering::SeqCst);
+                            wg.done();
+                        } else {
+                            wg.wait();
+                            assert!(completed.load(Ordering::SeqCst) > round);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for round in 0..ROUNDS {
+            wg.add(1);
+            started.store(round + 1, Ordering::SeqCst);
+            wg.wait();
+            assert!(completed.load(Ordering::SeqCst) > round);
+        }
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(wg.count(), 0);
+    }
+
+    #[test]
+    fn wait_group_timeout() {
+        let wg = WaitGroup::new();
+        assert!(wg.wait_for(Duration::from_millis(10)));
+        wg.add(2);
+        wg.done();
+        assert!(!wg.wait_for(Duration::from_millis(10)));
+        wg.done();
+        assert!(wg.wait_for(Duration::from_millis(10)));
+    }
+
+    #[test]
+    #[should_panic]
+    fn wait_group_too_many_done() {
+        let wg = WaitGroup::new();
+        wg.add(1);
+        wg.done();
+        wg.done();
+    }
+
+    #[cfg(not(feature = "nightly"))]
+    #[test]
+    #[should_panic]
+    fn latch_count_overflow() {
+        Latch::new(!0 >> 1);
+    }
+
+    #[test]
+    fn test_debug_latch() {
+        let l = Latch::new(3);
+        assert_eq!(format!("{:?}", l), "Latch { count: 3 }");
+        let wg = WaitGroup::new();
+        wg.add(2);
+        assert_eq!(format!("{:?}", wg), "WaitGroup { count: 2 }");
+    }
+}
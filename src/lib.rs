@@ -19,27 +19,60 @@ extern crate owning_ref;
 
 extern crate parking_lot_core;
 
+#[macro_use]
 mod util;
 mod elision;
 mod raw_mutex;
 mod raw_remutex;
 mod raw_rwlock;
+mod raw_stamped_rwlock;
 mod condvar;
+mod multi_condvar;
 mod mutex;
 mod remutex;
 mod rwlock;
 mod once;
+mod once_cell;
+mod poison;
+mod semaphore;
+mod barrier;
+mod event;
+mod latch;
+#[cfg(feature = "async")]
+mod async_mutex;
+#[cfg(feature = "async")]
+mod async_rwlock;
+
+pub mod lock_api;
 
-#[cfg(feature = "deadlock_detection")]
 pub mod deadlock;
-#[cfg(not(feature = "deadlock_detection"))]
-mod deadlock;
 
 pub use once::{Once, OnceState, ONCE_INIT};
-pub use mutex::{Mutex, MutexGuard};
-pub use remutex::{ReentrantMutex, ReentrantMutexGuard};
-pub use condvar::{Condvar, WaitTimeoutResult};
-pub use rwlock::{RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard};
+pub use once_cell::{Lazy, OnceCell};
+pub use mutex::{ArcMutexGuard, MappedMutexGuard, Mutex, MutexGuard, SendArcMutexGuard,
+                SendMutexGuard};
+pub use remutex::{MappedReentrantMutexGuard, ReentrantMutex, ReentrantMutexGuard};
+pub use condvar::{Condvar, CondvarGuard, WaitTimeoutResult};
+#[cfg(feature = "async")]
+pub use condvar::CondvarWaitFuture;
+pub use multi_condvar::MultiCondvar;
+pub use rwlock::{ArcRwLockReadGuard, ArcRwLockUpgradableReadGuard, ArcRwLockWriteGuard,
+                 MappedRwLockReadGuard, MappedRwLockUpgradableReadGuard, MappedRwLockWriteGuard,
+                 RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard,
+                 SendArcRwLockWriteGuard, SendRwLockWriteGuard, StampedRwLock};
+#[cfg(feature = "async")]
+pub use async_mutex::{AsyncMutex, AsyncMutexGuard, AsyncMutexLockFuture};
+#[cfg(feature = "async")]
+pub use async_rwlock::{AsyncRwLock, AsyncRwLockReadFuture, AsyncRwLockReadGuard,
+                       AsyncRwLockWriteFuture, AsyncRwLockWriteGuard};
+pub use poison::{PoisonMutex, PoisonMutexGuard, PoisonRwLock, PoisonRwLockWriteGuard};
+pub use semaphore::{Semaphore, SemaphorePermit};
+pub use barrier::{Barrier, BarrierBrokenError, BarrierWaitResult};
+pub use event::{AutoResetEvent, ManualResetEvent};
+pub use latch::{Latch, WaitGroup};
+pub use raw_mutex::RawMutex;
+pub use raw_rwlock::RawRwLock;
+pub use raw_stamped_rwlock::RawStampedRwLock;
 
 #[cfg(feature = "owning_ref")]
 use owning_ref::OwningRef;
@@ -0,0 +1,450 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Traits for raw locks which can be used to build custom lock types.
+//!
+//! `Mutex`, `ReentrantMutex` and `RwLock` are generic over the raw lock which
+//! they use, and default to the raw locks provided by this crate. Implementing
+//! these traits for another raw lock, such as a spinlock or a lock placed in
+//! shared memory, allows it to be used with the same wrapper and guard types.
+//! Optional functionality such as fair unlocking or timeouts is only available
+//! on the wrapper types when the raw lock implements the corresponding trait.
+//!
+//! # Example
+//!
+//! ```
+//! use parking_lot::Mutex;
+//! use parking_lot::lock_api::RawMutex;
+//! use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+//!
+//! // 1. Define our raw lock type
+//! pub struct RawSpinlock(AtomicBool);
+//!
+//! // 2. Implement RawMutex for this type
+//! unsafe impl RawMutex for RawSpinlock {
+//!     const INIT: RawSpinlock = RawSpinlock(ATOMIC_BOOL_INIT);
+//!
+//!     fn lock(&self) {
+//!         // Note: This isn't the best way of implementing a spinlock, but it
+//!         // suffices for the sake of this example.
+//!         while !self.try_lock() {}
+//!     }
+//!
+//!     fn try_lock(&self) -> bool {
+//!         !self.0.swap(true, Ordering::Acquire)
+//!     }
+//!
+//!     unsafe fn unlock(&self) {
+//!         self.0.store(false, Ordering::Release);
+//!     }
+//! }
+//!
+//! // 3. Export the wrappers. These are the types that your users will actually use.
+//! pub type Spinlock<T> = Mutex<T, RawSpinlock>;
+//!
+//! let lock: Spinlock<i32> = Spinlock::default();
+//! *lock.lock() += 1;
+//! assert_eq!(*lock.lock(), 1);
+//! ```
+
+use std::time::{Duration, Instant};
+
+/// Basic operations for a mutex.
+///
+/// Types implementing this trait can be used by `Mutex` to form a safe and
+/// fully-functioning mutex type.
+///
+/// # Safety
+///
+/// Implementations of this trait must ensure that the mutex is actually
+/// exclusive: a lock can't be acquired while the mutex is already locked.
+pub unsafe trait RawMutex {
+    /// Initial value for an unlocked mutex.
+    const INIT: Self;
+
+    /// Acquires this mutex, blocking the current thread until it is able to do so.
+    fn lock(&self);
+
+    /// Attempts to acquire this mutex without blocking.
+    fn try_lock(&self) -> bool;
+
+    /// Unlocks this mutex.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if the mutex is held by the current
+    /// thread.
+    unsafe fn unlock(&self);
+}
+
+/// Additional methods for mutexes which support fair unlocking.
+///
+/// Fair unlocking means that a lock is handed directly over to the next waiting
+/// thread if there is one, without giving other threads the opportunity to
+/// "steal" the lock in the meantime. This is typically slower than unfair
+/// unlocking, but may be necessary in certain circumstances.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawMutex`.
+pub unsafe trait RawMutexFair: RawMutex {
+    /// Unlocks this mutex using a fair unlock protocol.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if the mutex is held by the current
+    /// thread.
+    unsafe fn unlock_fair(&self);
+
+    /// Temporarily yields the mutex to a waiting thread if there is one.
+    ///
+    /// This method is functionally equivalent to calling `unlock_fair` followed
+    /// by `lock`, however it can be much more efficient in the case where there
+    /// are no waiting threads.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if the mutex is held by the current
+    /// thread.
+    unsafe fn bump(&self) {
+        self.unlock_fair();
+        self.lock();
+    }
+}
+
+/// Additional methods for mutexes which support locking with timeouts.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawMutex`.
+pub unsafe trait RawMutexTimed: RawMutex {
+    /// Attempts to acquire this lock until a timeout is reached.
+    fn try_lock_for(&self, timeout: Duration) -> bool;
+
+    /// Attempts to acquire this lock until a timeout is reached.
+    fn try_lock_until(&self, timeout: Instant) -> bool;
+}
+
+/// Basic operations for a reader-writer lock.
+///
+/// Types implementing this trait can be used by `RwLock` to form a safe and
+/// fully-functioning `RwLock` type.
+///
+/// # Safety
+///
+/// Implementations of this trait must ensure that the `RwLock` is actually
+/// exclusive: an exclusive lock can't be acquired while an exclusive or shared
+/// lock exists, and a shared lock can't be acquired while an exclusive lock
+/// exists.
+pub unsafe trait RawRwLock {
+    /// Initial value for an unlocked `RwLock`.
+    const INIT: Self;
+
+    /// Acquires a shared lock, blocking the current thread until it is able to do so.
+    fn lock_shared(&self);
+
+    /// Attempts to acquire a shared lock without blocking.
+    fn try_lock_shared(&self) -> bool;
+
+    /// Releases a shared lock.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if a shared lock is held by the current
+    /// thread.
+    unsafe fn unlock_shared(&self);
+
+    /// Acquires an exclusive lock, blocking the current thread until it is able to do so.
+    fn lock_exclusive(&self);
+
+    /// Attempts to acquire an exclusive lock without blocking.
+    fn try_lock_exclusive(&self) -> bool;
+
+    /// Releases an exclusive lock.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an exclusive lock is held by the
+    /// current thread.
+    unsafe fn unlock_exclusive(&self);
+}
+
+/// Additional methods for `RwLock`s which support fair unlocking.
+///
+/// Fair unlocking means that a lock is handed directly over to the next waiting
+/// thread if there is one, without giving other threads the opportunity to
+/// "steal" the lock in the meantime. This is typically slower than unfair
+/// unlocking, but may be necessary in certain circumstances.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockFair: RawRwLock {
+    /// Releases a shared lock using a fair unlock protocol.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if a shared lock is held by the current
+    /// thread.
+    unsafe fn unlock_shared_fair(&self);
+
+    /// Releases an exclusive lock using a fair unlock protocol.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an exclusive lock is held by the
+    /// current thread.
+    unsafe fn unlock_exclusive_fair(&self);
+
+    /// Temporarily yields a shared lock to a waiting thread if there is one.
+    ///
+    /// This method is functionally equivalent to calling `unlock_shared_fair`
+    /// followed by `lock_shared`, however it can be much more efficient in the
+    /// case where there are no waiting threads.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if a shared lock is held by the current
+    /// thread.
+    unsafe fn bump_shared(&self) {
+        self.unlock_shared_fair();
+        self.lock_shared();
+    }
+
+    /// Temporarily yields an exclusive lock to a waiting thread if there is
+    /// one.
+    ///
+    /// This method is functionally equivalent to calling
+    /// `unlock_exclusive_fair` followed by `lock_exclusive`, however it can be
+    /// much more efficient in the case where there are no waiting threads.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an exclusive lock is held by the
+    /// current thread.
+    unsafe fn bump_exclusive(&self) {
+        self.unlock_exclusive_fair();
+        self.lock_exclusive();
+    }
+}
+
+/// Additional methods for `RwLock`s which support atomically downgrading an
+/// exclusive lock to a shared lock.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockDowngrade: RawRwLock {
+    /// Atomically downgrades an exclusive lock into a shared lock without
+    /// allowing any thread to take an exclusive lock in the meantime.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an exclusive lock is held by the
+    /// current thread.
+    unsafe fn downgrade(&self);
+}
+
+/// Additional methods for `RwLock`s which support locking with timeouts.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockTimed: RawRwLock {
+    /// Attempts to acquire a shared lock until a timeout is reached.
+    fn try_lock_shared_for(&self, timeout: Duration) -> bool;
+
+    /// Attempts to acquire a shared lock until a timeout is reached.
+    fn try_lock_shared_until(&self, timeout: Instant) -> bool;
+
+    /// Attempts to acquire an exclusive lock until a timeout is reached.
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool;
+
+    /// Attempts to acquire an exclusive lock until a timeout is reached.
+    fn try_lock_exclusive_until(&self, timeout: Instant) -> bool;
+}
+
+/// Additional methods for `RwLock`s which support recursive read locks.
+///
+/// These are guaranteed to succeed without blocking if another read lock is
+/// held at the time of the call. This allows a thread to recursively lock a
+/// `RwLock`. However using this method can cause writers to starve since
+/// readers no longer block if a writer is waiting for the lock.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockRecursive: RawRwLock {
+    /// Acquires a shared lock without deadlocking in case of a recursive lock.
+    fn lock_shared_recursive(&self);
+
+    /// Attempts to acquire a shared lock without deadlocking in case of a recursive lock.
+    fn try_lock_shared_recursive(&self) -> bool;
+}
+
+/// Additional methods for `RwLock`s which support recursive read locks and timeouts.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockRecursiveTimed: RawRwLockRecursive + RawRwLockTimed {
+    /// Attempts to acquire a shared lock until a timeout is reached, without
+    /// deadlocking in case of a recursive lock.
+    fn try_lock_shared_recursive_for(&self, timeout: Duration) -> bool;
+
+    /// Attempts to acquire a shared lock until a timeout is reached, without
+    /// deadlocking in case of a recursive lock.
+    fn try_lock_shared_recursive_until(&self, timeout: Instant) -> bool;
+}
+
+/// Additional methods for `RwLock`s which support atomically upgrading a shared
+/// lock to an exclusive lock.
+///
+/// This requires acquiring a special "upgradable read lock" instead of a
+/// normal shared lock. There may only be one upgradable lock at any time,
+/// otherwise deadlocks could occur when upgrading.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockUpgrade: RawRwLock {
+    /// Acquires an upgradable lock, blocking the current thread until it is able to do so.
+    fn lock_upgradable(&self);
+
+    /// Attempts to acquire an upgradable lock without blocking.
+    fn try_lock_upgradable(&self) -> bool;
+
+    /// Releases an upgradable lock.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn unlock_upgradable(&self);
+
+    /// Upgrades an upgradable lock to an exclusive lock.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn upgrade(&self);
+
+    /// Attempts to upgrade an upgradable lock to an exclusive lock without
+    /// blocking.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn try_upgrade(&self) -> bool;
+}
+
+/// Additional methods for `RwLock`s which support upgradable locks and fair
+/// unlocking.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockUpgradeFair: RawRwLockUpgrade + RawRwLockFair {
+    /// Releases an upgradable lock using a fair unlock protocol.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn unlock_upgradable_fair(&self);
+
+    /// Temporarily yields an upgradable lock to a waiting thread if there is
+    /// one.
+    ///
+    /// This method is functionally equivalent to calling
+    /// `unlock_upgradable_fair` followed by `lock_upgradable`, however it can
+    /// be much more efficient in the case where there are no waiting threads.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn bump_upgradable(&self) {
+        self.unlock_upgradable_fair();
+        self.lock_upgradable();
+    }
+}
+
+/// Additional methods for `RwLock`s which support upgradable locks and lock
+/// downgrading.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockUpgradeDowngrade: RawRwLockUpgrade + RawRwLockDowngrade {
+    /// Downgrades an upgradable lock to a shared lock.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn downgrade_upgradable(&self);
+}
+
+/// Additional methods for `RwLock`s which support upgradable locks and locking
+/// with timeouts.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+pub unsafe trait RawRwLockUpgradeTimed: RawRwLockUpgrade + RawRwLockTimed {
+    /// Attempts to acquire an upgradable lock until a timeout is reached.
+    fn try_lock_upgradable_for(&self, timeout: Duration) -> bool;
+
+    /// Attempts to acquire an upgradable lock until a timeout is reached.
+    fn try_lock_upgradable_until(&self, timeout: Instant) -> bool;
+
+    /// Attempts to upgrade an upgradable lock to an exclusive lock until a
+    /// timeout is reached.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn try_upgrade_for(&self, timeout: Duration) -> bool;
+
+    /// Attempts to upgrade an upgradable lock to an exclusive lock until a
+    /// timeout is reached.
+    ///
+    /// # Safety
+    ///
+    /// This method may only be called if an upgradable lock is held by the
+    /// current thread.
+    unsafe fn try_upgrade_until(&self, timeout: Instant) -> bool;
+}
+
+/// Additional methods for `RwLock`s which support optimistic reads.
+///
+/// An optimistic read doesn't acquire the lock at all. Instead the reader takes
+/// a stamp, reads the protected data speculatively, and then validates the
+/// stamp to check that no exclusive lock was acquired in the meantime. If the
+/// validation fails, the data that was read may be inconsistent and must be
+/// discarded.
+///
+/// # Safety
+///
+/// Implementations of this trait must uphold the same guarantees as `RawRwLock`.
+/// In addition, `validate` must only return `true` if no exclusive lock was
+/// held at any point between the call to `optimistic_stamp` which returned the
+/// stamp and the call to `validate`. Memory reads performed before `validate`
+/// must not be reordered after it.
+pub unsafe trait RawRwLockOptimistic: RawRwLock {
+    /// Returns a stamp for an optimistic read, or `None` if an exclusive lock
+    /// is currently held.
+    fn optimistic_stamp(&self) -> Option<usize>;
+
+    /// Checks whether no exclusive lock has been acquired since `stamp` was
+    /// returned by `optimistic_stamp`.
+    fn validate(&self, stamp: usize) -> bool;
+}
@@ -0,0 +1,464 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::ops::Deref;
+use std::time::{Duration, Instant};
+use std::fmt;
+use parking_lot_core::{self, ParkResult, ParkToken, RequeueOp, UnparkResult};
+use condvar::{CondvarGuard, WaitTimeoutResult};
+use raw_mutex::{RawMutex, TOKEN_HANDOFF, TOKEN_NORMAL};
+
+// ParkToken used by threads waiting with a lock which they can't be requeued
+// onto. Other threads use the address of their mutex as their ParkToken.
+const TOKEN_NO_REQUEUE: ParkToken = ParkToken(0);
+
+/// A condition variable which can be used with several mutexes at the same
+/// time.
+///
+/// A `Condvar` only allows one mutex to be used by waiting threads at any
+/// given time, since `notify_all` requeues all of them onto that mutex.
+/// `MultiCondvar` lifts this restriction: each waiting thread remembers the
+/// lock it is using and `notify_all` requeues every thread onto its own mutex.
+/// This is useful when a single condition is spread across a set of sharded
+/// mutexes.
+///
+/// This makes `notify_all` more expensive, since it needs to lock the queues
+/// of all the mutexes used by the waiting threads at once. Otherwise this type
+/// behaves like `Condvar`: it accepts the same guards, doesn't produce spurious
+/// wakeups and only requires 1 word of space.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::{Mutex, MultiCondvar};
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let shared = Arc::new(([Mutex::new(false), Mutex::new(false)], MultiCondvar::new()));
+///
+/// // Wait on each shard from a separate thread
+/// let waiters: Vec<_> = (0..2)
+///     .map(|i| {
+///         let shared = shared.clone();
+///         thread::spawn(move || {
+///             let (ref shards, ref cvar) = *shared;
+///             let mut done = shards[i].lock();
+///             cvar.wait_while(&mut done, |done| !*done);
+///         })
+///     })
+///     .collect();
+///
+/// // Update every shard, then wake up all waiting threads at once
+/// let (ref shards, ref cvar) = *shared;
+/// for shard in shards {
+///     *shard.lock() = true;
+/// }
+/// cvar.notify_all();
+///
+/// for waiter in waiters {
+///     waiter.join().unwrap();
+/// }
+/// ```
+pub struct MultiCondvar {
+    // Number of threads waiting on the condvar. This is only modified while
+    // the queue of the condvar is locked.
+    waiters: AtomicUsize,
+}
+
+impl MultiCondvar {
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new() -> MultiCondvar {
+        MultiCondvar {
+            waiters: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Creates a new condition variable which is ready to be waited on and
+    /// notified.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new() -> MultiCondvar {
+        MultiCondvar {
+            waiters: ATOMIC_USIZE_INIT,
+        }
+    }
+
+    /// Wakes up one blocked thread on this condvar.
+    ///
+    /// Returns whether a thread was woken up.
+    ///
+    /// See `Condvar::notify_one`.
+    #[inline]
+    pub fn notify_one(&self) -> bool {
+        // Nothing to do if there are no waiting threads
+        if self.waiters.load(Ordering::Relaxed) == 0 {
+            return false;
+        }
+
+        self.notify_one_slow()
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn notify_one_slow(&self) -> bool {
+        unsafe {
+            // Unpark one thread
+            let addr = self as *const _ as usize;
+            let callback = |result: UnparkResult| {
+                self.waiters
+                    .fetch_sub(result.unparked_threads, Ordering::Relaxed);
+                TOKEN_NORMAL
+            };
+            let res = parking_lot_core::unpark_one(addr, callback);
+
+            res.unparked_threads != 0
+        }
+    }
+
+    /// Wakes up all blocked threads on this condvar.
+    ///
+    /// For each distinct mutex used by the waiting threads, only one thread is
+    /// woken up and the rest are requeued to wait for that mutex. Threads
+    /// waiting with a `RwLock` guard or a guard of a lock built on another
+    /// raw mutex than `RawMutex` are all woken up, see `Condvar::notify_all`.
+    ///
+    /// Returns the number of threads which were woken up or requeued onto
+    /// their mutex.
+    #[inline]
+    pub fn notify_all(&self) -> usize {
+        // Nothing to do if there are no waiting threads
+        if self.waiters.load(Ordering::Relaxed) == 0 {
+            return 0;
+        }
+
+        self.notify_all_slow()
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn notify_all_slow(&self) -> usize {
+        unsafe {
+            // Requeue each thread onto its own mutex, or unpark it if it is
+            // waiting with a lock that it can't be requeued onto.
+            let addr = self as *const _ as usize;
+            let destination = |token: ParkToken| {
+                if token == TOKEN_NO_REQUEUE {
+                    None
+                } else {
+                    Some(token.0)
+                }
+            };
+            let validate = |to, num_threads| {
+                // This is only called if a thread waiting with the mutex was
+                // found, which means that the mutex is still alive.
+                //
+                // Unpark one thread if the mutex is unlocked, otherwise just
+                // requeue everything to the mutex. See Condvar::notify_all.
+                let mutex = to as *const RawMutex;
+                if (*mutex).mark_parked_if_locked() {
+                    RequeueOp::RequeueAll
+                } else {
+                    // The other threads are going to be requeued onto the
+                    // mutex, so mark it as having parked threads. The queue of
+                    // the mutex is locked, so nobody can look for them before
+                    // they are there.
+                    if num_threads > 1 {
+                        (*mutex).mark_parked();
+                    }
+                    RequeueOp::UnparkOneRequeueRest
+                }
+            };
+            let callback = |result: UnparkResult| {
+                self.waiters.fetch_sub(
+                    result.unparked_threads + result.requeued_threads,
+                    Ordering::Relaxed,
+                );
+                TOKEN_NORMAL
+            };
+            let res =
+                parking_lot_core::unpark_requeue_multi(addr, destination, validate, callback);
+
+            res.unparked_threads + res.requeued_threads
+        }
+    }
+
+    /// Blocks the current thread until this condition variable receives a
+    /// notification.
+    ///
+    /// See `Condvar::wait`. Unlike with `Condvar`, other threads may be waiting
+    /// with different locks at the same time.
+    #[inline]
+    pub fn wait<G: CondvarGuard>(&self, guard: &mut G) {
+        self.wait_until_internal(guard, None);
+    }
+
+    /// Waits on this condition variable for a notification, timing out after
+    /// the specified time instant.
+    ///
+    /// See `Condvar::wait_until`.
+    #[inline]
+    pub fn wait_until<G: CondvarGuard>(
+        &self,
+        guard: &mut G,
+        timeout: Instant,
+    ) -> WaitTimeoutResult {
+        self.wait_until_internal(guard, Some(timeout))
+    }
+
+    fn wait_until_internal<G: CondvarGuard>(
+        &self,
+        guard: &mut G,
+        timeout: Option<Instant>,
+    ) -> WaitTimeoutResult {
+        let lock = guard.wait_lock();
+        unsafe {
+            let result;
+            let mut requeued = false;
+            let mut lock_count = 0;
+            {
+                let addr = self as *const _ as usize;
+                let park_token = match lock.requeue_mutex() {
+                    Some(mutex) => ParkToken(mutex as *const _ as usize),
+                    None => TOKEN_NO_REQUEUE,
+                };
+                let validate = || {
+                    self.waiters.fetch_add(1, Ordering::Relaxed);
+                    true
+                };
+                let before_sleep = || {
+                    // Unlock the mutex before sleeping...
+                    lock_count = lock.unlock();
+                };
+                let timed_out = |k, _| {
+                    // If we were requeued to a mutex, then we did not time out.
+                    // We'll just park ourselves on the mutex again when we try
+                    // to lock it later.
+                    requeued = k != addr;
+                    if !requeued {
+                        self.waiters.fetch_sub(1, Ordering::Relaxed);
+                    }
+                };
+                result = parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    park_token,
+                    timeout,
+                );
+            }
+
+            // ... and re-lock it once we are done sleeping
+            lock.relock(lock_count, result == ParkResult::Unparked(TOKEN_HANDOFF));
+
+            WaitTimeoutResult(!(result.is_unparked() || requeued))
+        }
+    }
+
+    /// Waits on this condition variable for a notification, timing out after a
+    /// specified duration.
+    ///
+    /// See `Condvar::wait_for`.
+    #[inline]
+    pub fn wait_for<G: CondvarGuard>(&self, guard: &mut G, timeout: Duration) -> WaitTimeoutResult {
+        self.wait_until(guard, Instant::now() + timeout)
+    }
+
+    /// Blocks the current thread until the given condition becomes false.
+    ///
+    /// See `Condvar::wait_while`.
+    #[inline]
+    pub fn wait_while<G, F>(&self, guard: &mut G, mut condition: F)
+    where
+        G: CondvarGuard + Deref,
+        F: FnMut(&G::Target) -> bool,
+    {
+        while condition(&**guard) {
+            self.wait(guard);
+        }
+    }
+
+    /// Blocks the current thread until the given condition becomes false or
+    /// the specified time instant is reached.
+    ///
+    /// See `Condvar::wait_while_until`.
+    #[inline]
+    pub fn wait_while_until<G, F>(
+        &self,
+        guard: &mut G,
+        mut condition: F,
+        timeout: Instant,
+    ) -> WaitTimeoutResult
+    where
+        G: CondvarGuard + Deref,
+        F: FnMut(&G::Target) -> bool,
+    {
+        let mut result = WaitTimeoutResult(false);
+        while condition(&**guard) {
+            if result.timed_out() {
+                return result;
+            }
+            result = self.wait_until(guard, timeout);
+        }
+        WaitTimeoutResult(false)
+    }
+
+    /// Blocks the current thread until the given condition becomes false or
+    /// a specified duration has elapsed.
+    ///
+    /// See `Condvar::wait_while_for`.
+    #[inline]
+    pub fn wait_while_for<G, F>(
+        &self,
+        guard: &mut G,
+        condition: F,
+        timeout: Duration,
+    ) -> WaitTimeoutResult
+    where
+        G: CondvarGuard + Deref,
+        F: FnMut(&G::Target) -> bool,
+    {
+        self.wait_while_until(guard, condition, Instant::now() + timeout)
+    }
+}
+
+impl Default for MultiCondvar {
+    #[inline]
+    fn default() -> MultiCondvar {
+        MultiCondvar::new()
+    }
+}
+
+impl fmt::Debug for MultiCondvar {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad("MultiCondvar { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use {Mutex, MultiCondvar, ReentrantMutex, RwLock};
+
+    #[test]
+    fn smoke() {
+        let c = MultiCondvar::new();
+        assert!(!c.notify_one());
+        assert_eq!(c.notify_all(), 0);
+    }
+
+    #[test]
+    fn notify_all_sharded() {
+        const SHARDS: usize = 4;
+        const N: usize = 3;
+
+        let data = Arc::new((
+            (0..SHARDS).map(|_| Mutex::new(false)).collect::<Vec<_>>(),
+            MultiCondvar::new(),
+        ));
+        let (tx, rx) = channel();
+        for i in 0..SHARDS * N {
+            let data = data.clone();
+            let tx = tx.clone();
+            thread::spawn(move || {
+                let (ref shards, ref cond) = *data;
+                let mut done = shards[i % SHARDS].lock();
+                tx.send(()).unwrap();
+                while !*done {
+                    cond.wait(&mut done);
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        drop(tx);
+
+        let (ref shards, ref cond) = *data;
+        for _ in 0..SHARDS * N {
+            rx.recv().unwrap();
+        }
+
+        // Hold one of the mutexes so that its waiters are all requeued
+        let mut locked = shards[0].lock();
+        for shard in &shards[1..] {
+            *shard.lock() = true;
+        }
+        *locked = true;
+        while cond.waiters.load(::std::sync::atomic::Ordering::Relaxed) != SHARDS * N {
+            thread::yield_now();
+        }
+        assert_eq!(cond.notify_all(), SHARDS * N);
+        drop(locked);
+
+        for _ in 0..SHARDS * N {
+            rx.recv().unwrap();
+        }
+        assert_eq!(cond.notify_all(), 0);
+    }
+
+    #[test]
+    fn mixed_locks() {
+        let m = Arc::new(Mutex::new(()));
+        let r = Arc::new(ReentrantMutex::new(()));
+        let l = Arc::new(RwLock::new(()));
+        let c = Arc::new(MultiCondvar::new());
+        let (tx, rx) = channel();
+
+        let (m2, c2, tx2) = (m.clone(), c.clone(), tx.clone());
+        let t1 = thread::spawn(move || {
+            let mut g = m2.lock();
+            tx2.send(()).unwrap();
+            assert!(!c2.wait_for(&mut g, Duration::from_secs(60)).timed_out());
+        });
+        let (r2, c2, tx2) = (r.clone(), c.clone(), tx.clone());
+        let t2 = thread::spawn(move || {
+            let _outer = r2.lock();
+            let mut g = r2.lock();
+            tx2.send(()).unwrap();
+            assert!(!c2.wait_for(&mut g, Duration::from_secs(60)).timed_out());
+        });
+        let (l2, c2) = (l.clone(), c.clone());
+        let t3 = thread::spawn(move || {
+            let mut g = l2.read();
+            tx.send(()).unwrap();
+            assert!(!c2.wait_for(&mut g, Duration::from_secs(60)).timed_out());
+        });
+
+        for _ in 0..3 {
+            rx.recv().unwrap();
+        }
+        // Each thread is waiting once its lock is released
+        drop(m.lock());
+        drop(r.lock());
+        drop(l.write());
+        assert_eq!(c.notify_all(), 3);
+        t1.join().unwrap();
+        t2.join().unwrap();
+        t3.join().unwrap();
+    }
+
+    #[test]
+    fn wait_for_timeout() {
+        let m = Mutex::new(());
+        let c = MultiCondvar::new();
+        let mut g = m.lock();
+        assert!(c.wait_for(&mut g, Duration::from_millis(1)).timed_out());
+        assert!(!c.notify_one());
+    }
+
+    #[test]
+    fn test_debug_multi_condvar() {
+        let c = MultiCondvar::new();
+        assert_eq!(format!("{:?}", c), "MultiCondvar { .. }");
+    }
+}
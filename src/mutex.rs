@@ -10,8 +10,11 @@ use std::ops::{Deref, DerefMut};
 use std::time::{Duration, Instant};
 use std::fmt;
 use std::mem;
+use std::ptr;
+use std::sync::Arc;
 use std::marker::PhantomData;
 use raw_mutex::RawMutex;
+use lock_api::{RawMutex as RawMutexTrait, RawMutexFair, RawMutexTimed};
 
 #[cfg(feature = "owning_ref")]
 use owning_ref::StableAddress;
@@ -45,9 +48,18 @@ use owning_ref::StableAddress;
 /// You can also force a fair unlock by calling `MutexGuard::unlock_fair` when
 /// unlocking a mutex instead of simply dropping the `MutexGuard`.
 ///
+/// # Custom raw mutexes
+///
+/// The type parameter `R` selects the raw mutex which is used to implement the
+/// lock. It defaults to the mutex provided by this crate, but any type which
+/// implements `lock_api::RawMutex` can be used instead. Fair unlocking and
+/// timed locking are only available if the raw mutex also implements
+/// `lock_api::RawMutexFair` and `lock_api::RawMutexTimed` respectively.
+///
 /// # Differences from the standard library `Mutex`
 ///
-/// - No poisoning, the lock is released normally on panic.
+/// - No poisoning, the lock is released normally on panic. `PoisonMutex` can be
+///   used if poisoning is needed.
 /// - Only requires 1 byte of space, whereas the standard library boxes the
 ///   `Mutex` due to platform limitations.
 /// - Can be statically constructed (requires the `const_fn` nightly feature).
@@ -93,13 +105,13 @@ use owning_ref::StableAddress;
 ///
 /// rx.recv().unwrap();
 /// ```
-pub struct Mutex<T: ?Sized> {
-    raw: RawMutex,
+pub struct Mutex<T: ?Sized, R = RawMutex> {
+    raw: R,
     data: UnsafeCell<T>,
 }
 
-unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
-unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+unsafe impl<T: ?Sized + Send, R: RawMutexTrait + Send> Send for Mutex<T, R> {}
+unsafe impl<T: ?Sized + Send, R: RawMutexTrait + Sync> Sync for Mutex<T, R> {}
 
 /// An RAII implementation of a "scoped lock" of a mutex. When this structure is
 /// dropped (falls out of scope), the lock will be unlocked.
@@ -107,13 +119,80 @@ unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
 /// The data protected by the mutex can be accessed through this guard via its
 /// `Deref` and `DerefMut` implementations.
 #[must_use]
-pub struct MutexGuard<'a, T: ?Sized + 'a> {
+pub struct MutexGuard<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a = RawMutex> {
+    raw: &'a R,
+    data: *mut T,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawMutexTrait + Sync + 'a> Sync
+    for MutexGuard<'a, T, R> {}
+
+/// An RAII mutex guard returned by `MutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedMutexGuard` and `MutexGuard` is that the
+/// former can't be used with a `Condvar`, since the guard no longer refers to
+/// the whole of the protected data.
+#[must_use]
+pub struct MappedMutexGuard<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a = RawMutex> {
+    raw: &'a R,
+    data: *mut T,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawMutexTrait + Sync + 'a> Sync
+    for MappedMutexGuard<'a, T, R> {}
+
+/// An RAII mutex guard returned by `Mutex::lock_arc`.
+///
+/// This is similar to `MutexGuard`, except that it holds a reference to the
+/// `Arc` containing the mutex instead of borrowing it. This allows the guard
+/// to be stored in other structures without a lifetime.
+///
+/// Like `MutexGuard`, the lock is attributed to the thread which locked it, so
+/// the guard can't be sent to another thread. With the default raw mutex it
+/// can be converted into a `SendArcMutexGuard` for that.
+#[must_use]
+pub struct ArcMutexGuard<T: ?Sized, R: RawMutexTrait = RawMutex> {
+    mutex: Arc<Mutex<T, R>>,
+    marker: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, R: RawMutexTrait + Sync> Sync for ArcMutexGuard<T, R> {}
+
+/// An `Arc` mutex guard which can be sent to another thread, created by
+/// `ArcMutexGuard::into_send`.
+///
+/// This is the owned counterpart of `SendMutexGuard`, and likewise isn't
+/// attributed to any thread while it exists.
+#[must_use]
+pub struct SendArcMutexGuard<T: ?Sized> {
+    mutex: Arc<Mutex<T>>,
+    marker: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for SendArcMutexGuard<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SendArcMutexGuard<T> {}
+
+/// A mutex guard which can be sent to another thread, created by
+/// `MutexGuard::into_send`.
+///
+/// This allows one thread to lock a mutex and another thread to finish the
+/// critical section and unlock it. While this guard exists the lock is not
+/// attributed to any thread, so the deadlock detector and the debug checks for
+/// recursive locking ignore it. Converting it back with
+/// `SendMutexGuard::into_guard` attributes the lock to the current thread
+/// again.
+#[must_use]
+pub struct SendMutexGuard<'a, T: ?Sized + 'a> {
     raw: &'a RawMutex,
     data: *mut T,
     marker: PhantomData<&'a mut T>,
 }
 
-unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for MutexGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Send + 'a> Send for SendMutexGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for SendMutexGuard<'a, T> {}
 
 impl<T> Mutex<T> {
     /// Creates a new mutex in an unlocked state ready for use.
@@ -122,7 +201,7 @@ impl<T> Mutex<T> {
     pub const fn new(val: T) -> Mutex<T> {
         Mutex {
             data: UnsafeCell::new(val),
-            raw: RawMutex::new(),
+            raw: <RawMutex as RawMutexTrait>::INIT,
         }
     }
 
@@ -132,7 +211,22 @@ impl<T> Mutex<T> {
     pub fn new(val: T) -> Mutex<T> {
         Mutex {
             data: UnsafeCell::new(val),
-            raw: RawMutex::new(),
+            raw: <RawMutex as RawMutexTrait>::INIT,
+        }
+    }
+}
+
+impl<T, R: RawMutexTrait> Mutex<T, R> {
+    /// Creates a new mutex using the given raw mutex, which must be in an
+    /// unlocked state.
+    ///
+    /// This allows using raw mutexes which can't be statically initialized,
+    /// for example because they need to be set up at runtime.
+    #[inline]
+    pub fn with_raw_mutex(raw: R, val: T) -> Mutex<T, R> {
+        Mutex {
+            data: UnsafeCell::new(val),
+            raw,
         }
     }
 
@@ -143,9 +237,9 @@ impl<T> Mutex<T> {
     }
 }
 
-impl<T: ?Sized> Mutex<T> {
+impl<T: ?Sized, R: RawMutexTrait> Mutex<T, R> {
     #[inline]
-    fn guard(&self) -> MutexGuard<T> {
+    fn guard(&self) -> MutexGuard<T, R> {
         MutexGuard {
             raw: &self.raw,
             data: self.data.get(),
@@ -161,9 +255,10 @@ impl<T: ?Sized> Mutex<T> {
     /// the guard goes out of scope, the mutex will be unlocked.
     ///
     /// Attempts to lock a mutex in the thread which already holds the lock will
-    /// result in a deadlock.
+    /// result in a deadlock. When the `self_deadlock_detection` feature is
+    /// enabled, this is detected and results in a panic instead.
     #[inline]
-    pub fn lock(&self) -> MutexGuard<T> {
+    pub fn lock(&self) -> MutexGuard<T, R> {
         self.raw.lock();
         self.guard()
     }
@@ -176,7 +271,7 @@ impl<T: ?Sized> Mutex<T> {
     ///
     /// This function does not block.
     #[inline]
-    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+    pub fn try_lock(&self) -> Option<MutexGuard<T, R>> {
         if self.raw.try_lock() {
             Some(self.guard())
         } else {
@@ -184,29 +279,39 @@ impl<T: ?Sized> Mutex<T> {
         }
     }
 
-    /// Attempts to acquire this lock until a timeout is reached.
-    ///
-    /// If the lock could not be acquired before the timeout expired, then
-    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
-    /// be unlocked when the guard is dropped.
     #[inline]
-    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<T>> {
-        if self.raw.try_lock_for(timeout) {
-            Some(self.guard())
-        } else {
-            None
+    fn arc_guard(this: &Arc<Self>) -> ArcMutexGuard<T, R> {
+        ArcMutexGuard {
+            mutex: this.clone(),
+            marker: PhantomData,
         }
     }
 
-    /// Attempts to acquire this lock until a timeout is reached.
+    /// Acquires a mutex through an `Arc`, blocking the current thread until it
+    /// is able to do so.
     ///
-    /// If the lock could not be acquired before the timeout expired, then
-    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
-    /// be unlocked when the guard is dropped.
+    /// This is similar to `lock`, except that the returned guard keeps a clone
+    /// of the `Arc` instead of borrowing the mutex, and therefore has a
+    /// `'static` lifetime.
+    ///
+    /// This is an associated function that needs to be used as
+    /// `Mutex::lock_arc(&mutex)`.
     #[inline]
-    pub fn try_lock_until(&self, timeout: Instant) -> Option<MutexGuard<T>> {
-        if self.raw.try_lock_until(timeout) {
-            Some(self.guard())
+    pub fn lock_arc(this: &Arc<Self>) -> ArcMutexGuard<T, R> {
+        this.raw.lock();
+        Mutex::arc_guard(this)
+    }
+
+    /// Attempts to acquire a mutex through an `Arc`.
+    ///
+    /// This is similar to `try_lock`, except that the returned guard keeps a
+    /// clone of the `Arc` instead of borrowing the mutex.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_lock_arc(this: &Arc<Self>) -> Option<ArcMutexGuard<T, R>> {
+        if this.raw.try_lock() {
+            Some(Mutex::arc_guard(this))
         } else {
             None
         }
@@ -230,9 +335,11 @@ impl<T: ?Sized> Mutex<T> {
     /// leaked (e.g. with `mem::forget`). The mutex must be locked.
     #[inline]
     pub unsafe fn raw_unlock(&self) {
-        self.raw.unlock(false);
+        self.raw.unlock();
     }
+}
 
+impl<T: ?Sized, R: RawMutexFair> Mutex<T, R> {
     /// Releases the mutex using a fair unlock protocol.
     ///
     /// See `MutexGuard::unlock_fair`.
@@ -244,10 +351,112 @@ impl<T: ?Sized> Mutex<T> {
     /// leaked (e.g. with `mem::forget`). The mutex must be locked.
     #[inline]
     pub unsafe fn raw_unlock_fair(&self) {
-        self.raw.unlock(true);
+        self.raw.unlock_fair();
+    }
+}
+
+impl<T: ?Sized, R: RawMutexTimed> Mutex<T, R> {
+    /// Attempts to acquire this lock until a timeout is reached.
+    ///
+    /// If the lock could not be acquired before the timeout expired, then
+    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
+    /// be unlocked when the guard is dropped.
+    #[inline]
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<T, R>> {
+        if self.raw.try_lock_for(timeout) {
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this lock until a timeout is reached.
+    ///
+    /// If the lock could not be acquired before the timeout expired, then
+    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
+    /// be unlocked when the guard is dropped.
+    #[inline]
+    pub fn try_lock_until(&self, timeout: Instant) -> Option<MutexGuard<T, R>> {
+        if self.raw.try_lock_until(timeout) {
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire a mutex through an `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_lock_for`, except that the returned guard keeps
+    /// a clone of the `Arc` instead of borrowing the mutex.
+    #[inline]
+    pub fn try_lock_arc_for(this: &Arc<Self>, timeout: Duration) -> Option<ArcMutexGuard<T, R>> {
+        if this.raw.try_lock_for(timeout) {
+            Some(Mutex::arc_guard(this))
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire a mutex through an `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_lock_until`, except that the returned guard
+    /// keeps a clone of the `Arc` instead of borrowing the mutex.
+    #[inline]
+    pub fn try_lock_arc_until(this: &Arc<Self>, timeout: Instant) -> Option<ArcMutexGuard<T, R>> {
+        if this.raw.try_lock_until(timeout) {
+            Some(Mutex::arc_guard(this))
+        } else {
+            None
+        }
     }
 }
-impl Mutex<()> {
+
+impl<T: ?Sized> Mutex<T> {
+    /// Asserts that the mutex is held by the current thread.
+    ///
+    /// This is intended for functions which require their caller to hold a
+    /// particular lock. The check is only performed in debug builds and does
+    /// nothing otherwise.
+    ///
+    /// Which thread holds the mutex is only known while the locks held by each
+    /// thread are being tracked. Tracking is enabled at runtime with
+    /// `deadlock::set_enabled`, and by default with the `deadlock_detection` or
+    /// `self_deadlock_detection` features. Otherwise this only checks that the
+    /// mutex is locked.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, this function will panic if the mutex is not held by
+    /// the current thread.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::{deadlock, Mutex};
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// deadlock::set_enabled(true);
+    ///
+    /// let mutex = Arc::new(Mutex::new(0));
+    /// let _guard = mutex.lock();
+    /// mutex.assert_held_by_current_thread();
+    ///
+    /// // The assertion fails on any other thread
+    /// let mutex2 = mutex.clone();
+    /// let result = thread::spawn(move || mutex2.assert_held_by_current_thread()).join();
+    /// assert_eq!(result.is_err(), cfg!(debug_assertions));
+    /// ```
+    #[inline]
+    pub fn assert_held_by_current_thread(&self) {
+        debug_assert!(
+            self.raw.is_held_by_current_thread(),
+            "mutex is not held by the current thread"
+        );
+    }
+}
+
+impl<R: RawMutexTrait> Mutex<(), R> {
     /// Acquires a mutex, blocking the current thread until it is able to do so.
     ///
     /// This is similar to `lock`, except that a `MutexGuard` is not returned.
@@ -268,14 +477,14 @@ impl Mutex<()> {
     }
 }
 
-impl<T: ?Sized + Default> Default for Mutex<T> {
+impl<T: Default, R: RawMutexTrait> Default for Mutex<T, R> {
     #[inline]
-    fn default() -> Mutex<T> {
-        Mutex::new(Default::default())
+    fn default() -> Mutex<T, R> {
+        Mutex::with_raw_mutex(R::INIT, Default::default())
     }
 }
 
-impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+impl<T: ?Sized + fmt::Debug, R: RawMutexTrait> fmt::Debug for Mutex<T, R> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.try_lock() {
             Some(guard) => f.debug_struct("Mutex")
@@ -286,7 +495,77 @@ impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> MutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> MutexGuard<'a, T, R> {
+    /// Make a new `MappedMutexGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MutexGuard` passed
+    /// in already locked the mutex.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MutexGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedMutexGuard<'a, U, R>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let raw = orig.raw;
+        let data = f(unsafe { &mut *orig.data });
+        mem::forget(orig);
+        MappedMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedMutexGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MutexGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedMutexGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &mut *s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Temporarily unlocks the mutex to execute the given function.
+    ///
+    /// This is safe because `&mut` guarantees that there exist no other
+    /// references to the data protected by the mutex. The mutex is locked again
+    /// before this function returns, even if `f` panics.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MutexGuard::unlocked(&mut guard, ...)`. A method would interfere
+    /// with methods of the same name on the contents of the locked data.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock() };
+        defer!(raw.lock());
+        f()
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexFair + 'a> MutexGuard<'a, T, R> {
     /// Unlocks the mutex using a fair unlock protocol.
     ///
     /// By default, mutexes are unfair and allow the current thread to re-lock
@@ -301,26 +580,186 @@ impl<'a, T: ?Sized + 'a> MutexGuard<'a, T> {
     /// using this method instead of dropping the `MutexGuard` normally.
     #[inline]
     pub fn unlock_fair(self) {
-        self.raw.unlock(true);
+        unsafe { self.raw.unlock_fair() };
         mem::forget(self);
     }
 
-    /// Make a new `MutexGuard` for a component of the locked data.
+    /// Temporarily unlocks the mutex to execute the given function.
     ///
-    /// This operation cannot fail as the `MutexGuard` passed
+    /// The mutex is unlocked using a fair unlock protocol. This is otherwise
+    /// identical to `MutexGuard::unlocked`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_fair() };
+        defer!(raw.lock());
+        f()
+    }
+
+    /// Temporarily yields the mutex to a waiting thread if there is one.
+    ///
+    /// This method is functionally equivalent to calling `unlock_fair` followed
+    /// by `lock`, however it can be much more efficient in the case where there
+    /// are no waiting threads.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MutexGuard::bump(&mut guard)`. A method would interfere with
+    /// methods of the same name on the contents of the locked data.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.raw.bump() };
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Deref for MutexGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> DerefMut for MutexGuard<'a, T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Drop for MutexGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawMutexTrait> StableAddress for MutexGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> MappedMutexGuard<'a, T, R> {
+    /// Make a new `MappedMutexGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedMutexGuard` passed
     /// in already locked the mutex.
     ///
     /// This is an associated function that needs to be
-    /// used as `MutexGuard::map(...)`. A method would interfere with methods of
+    /// used as `MappedMutexGuard::map(...)`. A method would interfere with methods of
     /// the same name on the contents of the locked data.
     #[inline]
-    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MutexGuard<'a, U>
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedMutexGuard<'a, U, R>
     where
         F: FnOnce(&mut T) -> &mut U,
     {
         let raw = orig.raw;
         let data = f(unsafe { &mut *orig.data });
         mem::forget(orig);
+        MappedMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedMutexGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedMutexGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedMutexGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &mut *s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexFair + 'a> MappedMutexGuard<'a, T, R> {
+    /// Unlocks the `Mutex` using a fair unlock protocol.
+    ///
+    /// See `MutexGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.raw.unlock_fair() };
+        mem::forget(self);
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Deref for MappedMutexGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> DerefMut for MappedMutexGuard<'a, T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Drop for MappedMutexGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawMutexTrait> StableAddress for MappedMutexGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a> MutexGuard<'a, T> {
+    /// Converts this guard into one which can be sent to another thread.
+    ///
+    /// The lock stays held, but is no longer attributed to the current thread.
+    /// See `SendMutexGuard` for details.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MutexGuard::into_send(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn into_send(s: Self) -> SendMutexGuard<'a, T> {
+        unsafe { s.raw.detach() };
+        let raw = s.raw;
+        let data = s.data;
+        mem::forget(s);
+        SendMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> SendMutexGuard<'a, T> {
+    /// Converts this guard back into a `MutexGuard` held by the current thread.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `SendMutexGuard::into_guard(...)`. A method would interfere with
+    /// methods of the same name on the contents of the locked data.
+    #[inline]
+    pub fn into_guard(s: Self) -> MutexGuard<'a, T> {
+        unsafe { s.raw.attach() };
+        let raw = s.raw;
+        let data = s.data;
+        mem::forget(s);
         MutexGuard {
             raw,
             data,
@@ -329,7 +768,7 @@ impl<'a, T: ?Sized + 'a> MutexGuard<'a, T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> Deref for MutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a> Deref for SendMutexGuard<'a, T> {
     type Target = T;
     #[inline]
     fn deref(&self) -> &T {
@@ -337,36 +776,192 @@ impl<'a, T: ?Sized + 'a> Deref for MutexGuard<'a, T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> DerefMut for MutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a> DerefMut for SendMutexGuard<'a, T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut T {
         unsafe { &mut *self.data }
     }
 }
 
-impl<'a, T: ?Sized + 'a> Drop for MutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a> Drop for SendMutexGuard<'a, T> {
     #[inline]
     fn drop(&mut self) {
-        self.raw.unlock(false);
+        // Attribute the lock to the unlocking thread first so that the release
+        // is balanced for the deadlock detector.
+        unsafe {
+            self.raw.attach();
+            self.raw.unlock();
+        }
     }
 }
 
 #[cfg(feature = "owning_ref")]
-unsafe impl<'a, T: ?Sized> StableAddress for MutexGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized> StableAddress for SendMutexGuard<'a, T> {}
+
+impl<T: ?Sized, R: RawMutexTrait> ArcMutexGuard<T, R> {
+    /// Returns the `Arc` containing the mutex which this guard locks.
+    ///
+    /// This is an associated function that needs to be used as
+    /// `ArcMutexGuard::mutex(&guard)`.
+    #[inline]
+    pub fn mutex(this: &Self) -> &Arc<Mutex<T, R>> {
+        &this.mutex
+    }
+
+    // Takes the `Arc` out of the guard without unlocking the mutex.
+    #[inline]
+    fn into_arc(self) -> Arc<Mutex<T, R>> {
+        let mutex = unsafe { ptr::read(&self.mutex) };
+        mem::forget(self);
+        mutex
+    }
+
+    /// Temporarily unlocks the mutex to execute the given function.
+    ///
+    /// See `MutexGuard::unlocked`.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.mutex.raw.unlock() };
+        defer!(s.mutex.raw.lock());
+        f()
+    }
+}
+
+impl<T: ?Sized, R: RawMutexFair> ArcMutexGuard<T, R> {
+    /// Unlocks the mutex using a fair unlock protocol.
+    ///
+    /// See `MutexGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.mutex.raw.unlock_fair() };
+        drop(self.into_arc());
+    }
+    /// Temporarily unlocks the mutex to execute the given function.
+    ///
+    /// See `MutexGuard::unlocked_fair`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.mutex.raw.unlock_fair() };
+        defer!(s.mutex.raw.lock());
+        f()
+    }
+
+    /// Temporarily yields the mutex to a waiting thread if there is one.
+    ///
+    /// See `MutexGuard::bump`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.mutex.raw.bump() };
+    }
+}
+
+impl<T: ?Sized, R: RawMutexTrait> Deref for ArcMutexGuard<T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized, R: RawMutexTrait> DerefMut for ArcMutexGuard<T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized, R: RawMutexTrait> Drop for ArcMutexGuard<T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.mutex.raw.unlock() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<T: ?Sized, R: RawMutexTrait> StableAddress for ArcMutexGuard<T, R> {}
+
+impl<T: ?Sized> ArcMutexGuard<T> {
+    /// Converts this guard into one which can be sent to another thread.
+    ///
+    /// See `MutexGuard::into_send`.
+    #[inline]
+    pub fn into_send(s: Self) -> SendArcMutexGuard<T> {
+        unsafe { s.mutex.raw.detach() };
+        SendArcMutexGuard {
+            mutex: s.into_arc(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> SendArcMutexGuard<T> {
+    /// Converts this guard back into an `ArcMutexGuard` held by the current
+    /// thread.
+    ///
+    /// See `SendMutexGuard::into_guard`.
+    #[inline]
+    pub fn into_guard(s: Self) -> ArcMutexGuard<T> {
+        unsafe { s.mutex.raw.attach() };
+        let mutex = unsafe { ptr::read(&s.mutex) };
+        mem::forget(s);
+        ArcMutexGuard {
+            mutex,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for SendArcMutexGuard<T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SendArcMutexGuard<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SendArcMutexGuard<T> {
+    #[inline]
+    fn drop(&mut self) {
+        // See SendMutexGuard
+        unsafe {
+            self.mutex.raw.attach();
+            self.mutex.raw.unlock();
+        }
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<T: ?Sized> StableAddress for SendArcMutexGuard<T> {}
 
 // Helper function used by Condvar, not publicly exported
 #[inline]
-pub(crate) fn guard_lock<'a, T: ?Sized>(guard: &MutexGuard<'a, T>) -> &'a RawMutex {
-    &guard.raw
+pub(crate) fn guard_lock<'a, T: ?Sized, R: RawMutexTrait>(guard: &MutexGuard<'a, T, R>) -> &'a R {
+    guard.raw
 }
 
 #[cfg(test)]
 mod tests {
+    use std::panic::{self, AssertUnwindSafe};
     use std::sync::mpsc::channel;
     use std::sync::Arc;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::thread;
-    use {Condvar, Mutex};
+    use std::time::Duration;
+    use {ArcMutexGuard, Condvar, MappedMutexGuard, Mutex, MutexGuard, SendArcMutexGuard,
+         SendMutexGuard};
 
     struct Packet<T>(Arc<(Mutex<T>, Condvar)>);
 
@@ -535,6 +1130,180 @@ mod tests {
         sync(mutex.lock());
     }
 
+    #[test]
+    fn test_mutex_assert_held() {
+        let m = Mutex::new(());
+        let _g = m.lock();
+        m.assert_held_by_current_thread();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn test_mutex_assert_held_unlocked() {
+        let m = Mutex::new(());
+        m.assert_held_by_current_thread();
+    }
+
+    #[test]
+    #[cfg(feature = "self_deadlock_detection")]
+    #[should_panic(expected = "already held by the current thread")]
+    fn test_mutex_relock() {
+        let m = Mutex::new(());
+        let _g = m.lock();
+        let _g2 = m.lock();
+    }
+
+    #[test]
+    fn test_mutex_relock_timeout() {
+        let m = Mutex::new(());
+        let _g = m.lock();
+        assert!(m.try_lock_for(Duration::from_millis(1)).is_none());
+    }
+
+    #[test]
+    fn test_mutex_timeout_behind_waiter() {
+        // A timed out thread at the back of the queue must not forget about
+        // the thread waiting ahead of it.
+        let m = Arc::new(Mutex::new(()));
+        let g = m.lock();
+        let m2 = m.clone();
+        let t = thread::spawn(move || drop(m2.lock()));
+        thread::sleep(Duration::from_millis(50));
+        let m3 = m.clone();
+        thread::spawn(move || assert!(m3.try_lock_for(Duration::from_millis(50)).is_none()))
+            .join()
+            .unwrap();
+        drop(g);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn test_lock_arc() {
+        struct Holder {
+            guard: ArcMutexGuard<i32>,
+        }
+
+        let m = Arc::new(Mutex::new(1));
+        let mut holder = Holder {
+            guard: Mutex::lock_arc(&m),
+        };
+        *holder.guard += 1;
+        assert!(Mutex::try_lock_arc(&m).is_none());
+        assert!(Arc::ptr_eq(ArcMutexGuard::mutex(&holder.guard), &m));
+        m.assert_held_by_current_thread();
+        drop(holder);
+
+        let guard = Mutex::try_lock_arc(&m).unwrap();
+        drop(m);
+        assert_eq!(*guard, 2);
+        guard.unlock_fair();
+    }
+
+    #[test]
+    #[cfg(feature = "self_deadlock_detection")]
+    #[should_panic(expected = "already held by the current thread")]
+    fn test_lock_arc_relock() {
+        let m = Arc::new(Mutex::new(()));
+        let _g = Mutex::lock_arc(&m);
+        let _g2 = m.lock();
+    }
+
+    #[test]
+    fn test_lock_arc_send() {
+        let m = Arc::new(Mutex::new(1));
+        let guard = ArcMutexGuard::into_send(Mutex::lock_arc(&m));
+        thread::spawn(move || {
+            let mut guard = SendArcMutexGuard::into_guard(guard);
+            *guard += 1;
+            let guard = ArcMutexGuard::into_send(guard);
+            drop(guard);
+        }).join().unwrap();
+        assert_eq!(*m.lock(), 2);
+
+        let guard = ArcMutexGuard::into_send(Mutex::lock_arc(&m));
+        let guard = thread::spawn(move || guard).join().unwrap();
+        let guard = SendArcMutexGuard::into_guard(guard);
+        m.assert_held_by_current_thread();
+        drop(guard);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_mutex_map() {
+        let m = Mutex::new((1, vec![2, 3]));
+        {
+            let mut guard = MutexGuard::map(m.lock(), |x| &mut x.1);
+            guard.push(4);
+            assert!(m.try_lock().is_none());
+            let mut guard = MappedMutexGuard::map(guard, |x| &mut x[0]);
+            *guard += 1;
+        }
+        assert_eq!(*m.lock(), (1, vec![3, 3, 4]));
+
+        let guard = MutexGuard::try_map(m.lock(), |x| x.1.get_mut(5)).err().unwrap();
+        assert_eq!(guard.0, 1);
+        drop(guard);
+        let guard = MutexGuard::try_map(m.lock(), |x| x.1.get_mut(2)).ok().unwrap();
+        assert_eq!(*guard, 4);
+        let guard = MappedMutexGuard::try_map(guard, |_| None::<&mut i32>).err().unwrap();
+        guard.unlock_fair();
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn test_mutex_unlocked() {
+        let m = Arc::new(Mutex::new(0));
+        let mut guard = m.lock();
+        MutexGuard::unlocked(&mut guard, || {
+            let m2 = m.clone();
+            thread::spawn(move || *m2.lock() += 1).join().unwrap();
+        });
+        assert_eq!(*guard, 1);
+        MutexGuard::unlocked_fair(&mut guard, || assert!(m.try_lock().is_some()));
+        assert!(m.try_lock().is_none());
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            MutexGuard::unlocked(&mut guard, || panic!());
+        }));
+        assert!(result.is_err());
+        assert!(m.try_lock().is_none());
+    }
+
+    #[test]
+    fn test_mutex_bump() {
+        let m = Arc::new(Mutex::new(0));
+        let mut guard = m.lock();
+        MutexGuard::bump(&mut guard);
+
+        let m2 = m.clone();
+        let child = thread::spawn(move || *m2.lock() += 1);
+        while *guard == 0 {
+            MutexGuard::bump(&mut guard);
+            thread::yield_now();
+        }
+        drop(guard);
+        child.join().unwrap();
+    }
+
+    #[test]
+    fn test_mutex_send_guard() {
+        // Leak the mutex so that the guard can be sent to another thread
+        let m: &'static Mutex<i32> = unsafe { &*Box::into_raw(Box::new(Mutex::new(0))) };
+        let guard = MutexGuard::into_send(m.lock());
+        thread::spawn(move || {
+            let mut guard = SendMutexGuard::into_guard(guard);
+            *guard += 1;
+            m.assert_held_by_current_thread();
+        }).join().unwrap();
+
+        let guard = MutexGuard::into_send(m.lock());
+        thread::spawn(move || {
+            assert_eq!(*guard, 1);
+        }).join().unwrap();
+        assert_eq!(*m.lock(), 1);
+    }
+
     #[test]
     fn test_mutex_debug() {
         let mutex = Mutex::new(vec![0u8, 10]);
@@ -18,8 +18,10 @@ use std::sync::atomic::ATOMIC_USIZE_INIT as ATOMIC_U8_INIT;
 type U8 = usize;
 use std::mem;
 use std::fmt;
-use parking_lot_core::{self, SpinWait, DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
+use std::time::{Duration, Instant};
+use parking_lot_core::{self, ParkResult, SpinWait, DEFAULT_PARK_TOKEN, DEFAULT_UNPARK_TOKEN};
 use util::UncheckedOptionExt;
+use deadlock;
 
 const DONE_BIT: U8 = 1;
 const POISON_BIT: U8 = 2;
@@ -173,6 +175,10 @@ impl Once {
     /// concurrently amongst many threads. If that closure panics, however, then
     /// it will *poison* this `Once` instance, causing all future invocations of
     /// `call_once` to also panic.
+    ///
+    /// Calling `call_once` recursively from within the closure results in a
+    /// deadlock. When the `self_deadlock_detection` feature is enabled, this is
+    /// detected and results in a panic instead.
     #[inline]
     pub fn call_once<F>(&self, f: F)
     where
@@ -183,7 +189,10 @@ impl Once {
         }
 
         let mut f = Some(f);
-        self.call_once_slow(false, &mut |_| unsafe { f.take().unchecked_unwrap()() });
+        self.call_once_slow(false, &mut |_| {
+            unsafe { f.take().unchecked_unwrap()() };
+            true
+        });
     }
 
     /// Performs the same function as `call_once` except ignores poisoning.
@@ -205,11 +214,224 @@ impl Once {
         }
 
         let mut f = Some(f);
-        self.call_once_slow(true, &mut |state| unsafe {
-            f.take().unchecked_unwrap()(state)
+        self.call_once_slow(true, &mut |state| {
+            unsafe { f.take().unchecked_unwrap()(state) };
+            true
         });
     }
 
+    /// Performs a fallible initialization routine once and only once.
+    ///
+    /// This behaves like `call_once`, except that the closure may report a
+    /// failure by returning an error. In that case the `Once` is not poisoned:
+    /// it returns to the `New` state, the error is passed on to the caller, and
+    /// any threads blocked waiting for the initialization are woken up so that
+    /// one of them can attempt it again.
+    ///
+    /// If some initialization has already completed, the closure is not run and
+    /// `Ok(())` is returned.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::{Once, OnceState};
+    ///
+    /// let once = Once::new();
+    ///
+    /// let r: Result<(), &str> = once.try_call_once(|| Err("not ready yet"));
+    /// assert_eq!(r, Err("not ready yet"));
+    /// assert_eq!(once.state(), OnceState::New);
+    ///
+    /// let r: Result<(), &str> = once.try_call_once(|| Ok(()));
+    /// assert_eq!(r, Ok(()));
+    /// assert_eq!(once.state(), OnceState::Done);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If the closure panics, this `Once` is poisoned just like with
+    /// `call_once`, and this function panics if it is called on a poisoned
+    /// `Once`.
+    ///
+    /// Calling `try_call_once` recursively from within the closure results in
+    /// a deadlock. When the `self_deadlock_detection` feature is enabled, this
+    /// is detected and results in a panic instead.
+    #[inline]
+    pub fn try_call_once<F, E>(&self, f: F) -> Result<(), E>
+    where
+        F: FnOnce() -> Result<(), E>,
+    {
+        self.try_call_once_internal(false, |_| f())
+    }
+
+    // Shared implementation of `try_call_once` and `OnceCell::get_or_try_init`.
+    #[inline]
+    pub(crate) fn try_call_once_internal<F, E>(&self, ignore_poison: bool, f: F) -> Result<(), E>
+    where
+        F: FnOnce(OnceState) -> Result<(), E>,
+    {
+        if self.0.load(Ordering::Acquire) == DONE_BIT {
+            return Ok(());
+        }
+
+        let mut f = Some(f);
+        let mut result = Ok(());
+        self.call_once_slow(ignore_poison, &mut |state| {
+            match unsafe { f.take().unchecked_unwrap()(state) } {
+                Ok(()) => true,
+                Err(e) => {
+                    result = Err(e);
+                    false
+                }
+            }
+        });
+        result
+    }
+
+    /// Blocks the current thread until an initialization routine has either
+    /// completed or panicked, without ever running one itself.
+    ///
+    /// Returns `OnceState::Done` or `OnceState::Poisoned`. If the `Once` is
+    /// still new, this waits for some other thread to initialize it. A routine
+    /// which fails through `try_call_once` returns the `Once` to the new state,
+    /// so waiting threads keep waiting for the next attempt.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::{Once, OnceState};
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// let once = Arc::new(Once::new());
+    /// let once2 = once.clone();
+    /// thread::spawn(move || {
+    ///     once2.call_once(|| {
+    ///         // run initialization here
+    ///     });
+    /// });
+    ///
+    /// assert_eq!(once.wait(), OnceState::Done);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Calling `wait` from within an initialization routine of the same `Once`
+    /// results in a deadlock. When the `self_deadlock_detection` feature is
+    /// enabled, this is detected and results in a panic instead.
+    #[inline]
+    pub fn wait(&self) -> OnceState {
+        if self.0.load(Ordering::Acquire) == DONE_BIT {
+            return OnceState::Done;
+        }
+        unsafe { self.wait_slow(None).unchecked_unwrap() }
+    }
+
+    /// Blocks the current thread until an initialization routine has either
+    /// completed or panicked, or until the specified time instant is reached.
+    ///
+    /// Returns `Some(OnceState::Done)` or `Some(OnceState::Poisoned)`, or
+    /// `None` if the timeout expired first.
+    #[inline]
+    pub fn wait_until(&self, timeout: Instant) -> Option<OnceState> {
+        if self.0.load(Ordering::Acquire) == DONE_BIT {
+            return Some(OnceState::Done);
+        }
+        self.wait_slow(Some(timeout))
+    }
+
+    /// Blocks the current thread until an initialization routine has either
+    /// completed or panicked, or until the specified duration has elapsed.
+    ///
+    /// Returns `Some(OnceState::Done)` or `Some(OnceState::Poisoned)`, or
+    /// `None` if the timeout expired first.
+    #[inline]
+    pub fn wait_for(&self, timeout: Duration) -> Option<OnceState> {
+        if self.0.load(Ordering::Acquire) == DONE_BIT {
+            return Some(OnceState::Done);
+        }
+        self.wait_slow(Some(Instant::now() + timeout))
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn wait_slow(&self, timeout: Option<Instant>) -> Option<OnceState> {
+        let mut spinwait = SpinWait::new();
+        let mut state = self.0.load(Ordering::Relaxed);
+        loop {
+            // Return once a closure has completed or panicked. The poison bit
+            // is cleared when a new closure starts running, so it is only set
+            // while the `Once` is unlocked.
+            if state & DONE_BIT != 0 {
+                fence(Ordering::Acquire);
+                return Some(OnceState::Done);
+            }
+            if state & POISON_BIT != 0 {
+                fence(Ordering::Acquire);
+                return Some(OnceState::Poisoned);
+            }
+
+            // If a closure is running and there is no queue, try spinning a few
+            // times
+            if state & LOCKED_BIT != 0 && state & PARKED_BIT == 0 && spinwait.spin() {
+                state = self.0.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Make sure the closure isn't running on our own thread before
+            // waiting for it to complete.
+            deadlock::check_not_held(
+                self as *const _ as usize,
+                "Once::wait called from its own closure",
+            );
+
+            // Set the parked bit
+            if state & PARKED_BIT == 0 {
+                if let Err(x) = self.0.compare_exchange_weak(
+                    state,
+                    state | PARKED_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Park our thread until we are woken up by a thread which ran a
+            // closure.
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || {
+                    let state = self.0.load(Ordering::Relaxed);
+                    state & (DONE_BIT | POISON_BIT) == 0 && state & PARKED_BIT != 0
+                };
+                let before_sleep = || {};
+                let timed_out = |_, was_last_thread| {
+                    // Clear the parked bit if we were the last parked thread
+                    if was_last_thread {
+                        self.0.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                    }
+                };
+                if let ParkResult::TimedOut = parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    DEFAULT_PARK_TOKEN,
+                    timeout,
+                ) {
+                    return None;
+                }
+            }
+
+            // Loop back and check the state again, a failed closure leaves
+            // the `Once` in the new state.
+            spinwait.reset();
+            state = self.0.load(Ordering::Relaxed);
+        }
+    }
+
     // This is a non-generic function to reduce the monomorphization cost of
     // using `call_once` (this isn't exactly a trivial or small implementation).
     //
@@ -221,9 +443,12 @@ impl Once {
     // Finally, this takes an `FnMut` instead of a `FnOnce` because there's
     // currently no way to take an `FnOnce` and call it via virtual dispatch
     // without some allocation overhead.
+    //
+    // The closure returns false if the initialization failed without
+    // panicking, in which case the state is reset to `New` instead of `Done`.
     #[cold]
     #[inline(never)]
-    fn call_once_slow(&self, ignore_poison: bool, f: &mut FnMut(OnceState)) {
+    fn call_once_slow(&self, ignore_poison: bool, f: &mut FnMut(OnceState) -> bool) {
         let mut spinwait = SpinWait::new();
         let mut state = self.0.load(Ordering::Relaxed);
         loop {
@@ -252,7 +477,10 @@ impl Once {
                     Ordering::Acquire,
                     Ordering::Relaxed,
                 ) {
-                    Ok(_) => break,
+                    Ok(_) => {
+                        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+                        break;
+                    }
                     Err(x) => state = x,
                 }
                 continue;
@@ -264,6 +492,13 @@ impl Once {
                 continue;
             }
 
+            // Make sure the closure isn't running on our own thread before
+            // waiting for it to complete.
+            deadlock::check_not_held(
+                self as *const _ as usize,
+                "Once::call_once called recursively from its own closure",
+            );
+
             // Set the parked bit
             if state & PARKED_BIT == 0 {
                 if let Err(x) = self.0.compare_exchange_weak(
@@ -304,6 +539,7 @@ impl Once {
             fn drop(&mut self) {
                 // Mark the state as poisoned, unlock it and unpark all threads.
                 let once = self.0;
+                unsafe { deadlock::release_resource(once as *const _ as usize) };
                 let state = once.0.swap(POISON_BIT, Ordering::Release);
                 if state & PARKED_BIT != 0 {
                     unsafe {
@@ -322,11 +558,13 @@ impl Once {
         } else {
             OnceState::New
         };
-        f(once_state);
+        let new_state = if f(once_state) { DONE_BIT } else { 0 };
         mem::forget(guard);
 
-        // Now unlock the state, set the done bit and unpark all threads
-        let state = self.0.swap(DONE_BIT, Ordering::Release);
+        // Now unlock the state, set the done bit (unless the closure failed, in
+        // which case another thread may retry) and unpark all threads
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        let state = self.0.swap(new_state, Ordering::Release);
         if state & PARKED_BIT != 0 {
             unsafe {
                 let addr = self as *const _ as usize;
@@ -353,11 +591,11 @@ impl fmt::Debug for Once {
 
 #[cfg(test)]
 mod tests {
-    #[cfg(feature = "nightly")]
-    use std::panic;
+    use std::panic::{self, AssertUnwindSafe};
     use std::sync::mpsc::channel;
     use std::thread;
-    use {Once, ONCE_INIT};
+    use std::time::{Duration, Instant};
+    use {Once, OnceState, ONCE_INIT};
 
     #[test]
     fn smoke_once() {
@@ -473,6 +711,124 @@ mod tests {
         assert!(t2.join().is_ok());
     }
 
+    #[test]
+    fn try_call_once() {
+        static O: Once = ONCE_INIT;
+
+        assert_eq!(O.try_call_once(|| Err(1)), Err(1));
+        assert_eq!(O.state(), OnceState::New);
+        assert_eq!(O.try_call_once(|| Ok::<_, i32>(())), Ok(()));
+        assert_eq!(O.state(), OnceState::Done);
+
+        // the closure isn't called once initialization has succeeded
+        assert_eq!(O.try_call_once(|| Err(2)), Ok(()));
+    }
+
+    #[test]
+    fn try_call_once_wakes_waiters() {
+        static O: Once = ONCE_INIT;
+
+        // fail the initialization while another thread is waiting for it
+        let (tx1, rx1) = channel();
+        let (tx2, rx2) = channel();
+        let t1 = thread::spawn(move || {
+            O.try_call_once(|| {
+                tx1.send(()).unwrap();
+                rx2.recv().unwrap();
+                Err(())
+            })
+        });
+
+        rx1.recv().unwrap();
+
+        // the waiter runs its own closure after the failure
+        let t2 = thread::spawn(|| {
+            let mut called = false;
+            O.call_once(|| {
+                called = true;
+            });
+            called
+        });
+
+        tx2.send(()).unwrap();
+
+        assert_eq!(t1.join().unwrap(), Err(()));
+        assert!(t2.join().unwrap());
+        assert_eq!(O.state(), OnceState::Done);
+    }
+
+    #[test]
+    fn wait() {
+        static O: Once = ONCE_INIT;
+
+        let (tx, rx) = channel();
+        let waiters: Vec<_> = (0..4)
+            .map(|_| {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    tx.send(()).unwrap();
+                    O.wait()
+                })
+            })
+            .collect();
+        for _ in 0..4 {
+            rx.recv().unwrap();
+        }
+
+        // a failed initialization doesn't release the waiters
+        assert_eq!(O.try_call_once(|| Err(())), Err(()));
+        assert_eq!(O.wait_for(Duration::from_millis(10)), None);
+
+        O.call_once(|| {});
+        for t in waiters {
+            assert_eq!(t.join().unwrap(), OnceState::Done);
+        }
+        assert_eq!(O.wait(), OnceState::Done);
+        assert_eq!(O.wait_for(Duration::from_millis(0)), Some(OnceState::Done));
+    }
+
+    #[test]
+    fn wait_timeout() {
+        let once = Once::new();
+        let start = Instant::now();
+        assert_eq!(once.wait_for(Duration::from_millis(50)), None);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(once.wait_until(Instant::now()), None);
+        assert_eq!(once.state(), OnceState::New);
+    }
+
+    #[test]
+    fn wait_poisoned() {
+        let once = Once::new();
+        let t = panic::catch_unwind(AssertUnwindSafe(|| {
+            once.call_once(|| panic!());
+        }));
+        assert!(t.is_err());
+        assert_eq!(once.wait(), OnceState::Poisoned);
+        assert_eq!(
+            once.wait_for(Duration::from_millis(0)),
+            Some(OnceState::Poisoned)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "self_deadlock_detection")]
+    #[should_panic(expected = "called from its own closure")]
+    fn wait_from_call_once() {
+        static O: Once = ONCE_INIT;
+        O.call_once(|| {
+            O.wait();
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "self_deadlock_detection")]
+    #[should_panic(expected = "called recursively")]
+    fn recursive_call_once() {
+        static O: Once = ONCE_INIT;
+        O.call_once(|| O.call_once(|| {}));
+    }
+
     #[test]
     fn test_once_debug() {
         static O: Once = ONCE_INIT;
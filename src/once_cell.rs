@@ -0,0 +1,419 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::{Cell, UnsafeCell};
+use std::ops::Deref;
+use std::fmt;
+use once::{Once, OnceState};
+use util::UncheckedOptionExt;
+
+/// A cell which can be written to only once.
+///
+/// This provides the storage that `Once` lacks: the value produced by the
+/// one-time initialization is kept in the cell, and shared references to it
+/// can be obtained once it is initialized. The initialization state is tracked
+/// by a `Once`, so a cell which isn't initialized yet only takes 1 byte besides
+/// the value itself.
+///
+/// If an initialization closure panics, the cell is left uninitialized and the
+/// next call to `get_or_init` or `set` attempts the initialization again, like
+/// `Once::call_once_force`.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::OnceCell;
+///
+/// let cell = OnceCell::new();
+/// assert!(cell.get().is_none());
+///
+/// let value: &String = cell.get_or_init(|| "Hello, World!".to_string());
+/// assert_eq!(value, "Hello, World!");
+///
+/// // The cell is already initialized, so this doesn't change it
+/// assert!(cell.set("Goodbye".to_string()).is_err());
+/// assert_eq!(cell.get().unwrap(), "Hello, World!");
+/// ```
+pub struct OnceCell<T> {
+    once: Once,
+    value: UnsafeCell<Option<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceCell<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> OnceCell<T> {
+    /// Creates a new uninitialized cell.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new() -> OnceCell<T> {
+        OnceCell {
+            once: Once::new(),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Creates a new uninitialized cell.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new() -> OnceCell<T> {
+        OnceCell {
+            once: Once::new(),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Returns a reference to the value, or `None` if the cell is not
+    /// initialized yet.
+    ///
+    /// This never blocks: `None` is also returned while another thread is
+    /// initializing the cell.
+    #[inline]
+    pub fn get(&self) -> Option<&T> {
+        if self.once.state().done() {
+            unsafe { Some((*self.value.get()).as_ref().unchecked_unwrap()) }
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the value, or `None` if the cell is not
+    /// initialized yet.
+    ///
+    /// Since this call borrows the `OnceCell` mutably, no synchronization needs
+    /// to take place.
+    #[inline]
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        unsafe { (*self.value.get()).as_mut() }
+    }
+
+    /// Initializes the cell with `value`.
+    ///
+    /// Returns `Err(value)` if the cell was already initialized. If another
+    /// thread is currently initializing the cell, this blocks until it is done.
+    #[inline]
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.initialize(|| unsafe { value.take().unchecked_unwrap() });
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// Returns a reference to the value, initializing the cell with `f` if it
+    /// is not initialized yet.
+    ///
+    /// Only one closure is executed even if this is called concurrently from
+    /// many threads. The other threads block until the initialization has
+    /// completed, and then return a reference to the same value.
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the panic is propagated to the caller and the cell stays
+    /// uninitialized, so that a later call can retry the initialization.
+    ///
+    /// Calling `get_or_init` recursively from within `f` results in a deadlock.
+    /// When the `self_deadlock_detection` feature is enabled, this is detected
+    /// and results in a panic instead.
+    #[inline]
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        if let Some(value) = self.get() {
+            return value;
+        }
+        self.initialize(f);
+        unsafe { (*self.value.get()).as_ref().unchecked_unwrap() }
+    }
+
+    /// Returns a reference to the value, initializing the cell with `f` if it
+    /// is not initialized yet. If `f` fails, its error is returned.
+    ///
+    /// A failed initialization leaves the cell uninitialized, and threads which
+    /// were blocked waiting for it are woken up so that one of them can attempt
+    /// the initialization again with its own closure.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::OnceCell;
+    ///
+    /// let cell = OnceCell::new();
+    /// assert_eq!(cell.get_or_try_init(|| "oops".parse::<i32>()).is_err(), true);
+    /// assert!(cell.get().is_none());
+    /// assert_eq!(cell.get_or_try_init(|| "42".parse::<i32>()), Ok(&42));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If `f` panics, the panic is propagated to the caller and the cell stays
+    /// uninitialized, as with `get_or_init`.
+    #[inline]
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        let value = &self.value;
+        self.once.try_call_once_internal(true, |_| {
+            unsafe { *value.get() = Some(f()?) };
+            Ok(())
+        })?;
+        unsafe { Ok((*self.value.get()).as_ref().unchecked_unwrap()) }
+    }
+
+    /// Consumes the cell, returning the value if it was initialized.
+    #[inline]
+    pub fn into_inner(self) -> Option<T> {
+        unsafe { self.value.into_inner() }
+    }
+
+    // Runs `f` to initialize the cell unless it is already initialized. A
+    // previous initialization which panicked doesn't prevent this one.
+    #[inline]
+    fn initialize<F>(&self, f: F)
+    where
+        F: FnOnce() -> T,
+    {
+        let value = &self.value;
+        self.once.call_once_force(|_: OnceState| unsafe {
+            *value.get() = Some(f());
+        });
+    }
+}
+
+impl<T> Default for OnceCell<T> {
+    #[inline]
+    fn default() -> OnceCell<T> {
+        OnceCell::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_struct("OnceCell").field("value", value).finish(),
+            None => f.pad("OnceCell { <uninit> }"),
+        }
+    }
+}
+
+/// A value which is initialized on first access.
+///
+/// The initialization function is run the first time the `Lazy` is
+/// dereferenced, and the result is stored in a `OnceCell` for all later
+/// accesses. If several threads access the value at the same time, only one of
+/// them runs the initialization function and the others block until it is done.
+///
+/// If the initialization function panics, the `Lazy` is poisoned and all later
+/// accesses panic as well, since the function has been consumed.
+///
+/// Creating a `Lazy` in a `static` requires the `nightly` feature, which makes
+/// `Lazy::new` a `const fn`.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Lazy;
+/// use std::collections::HashMap;
+///
+/// let map: Lazy<HashMap<u32, &str>> = Lazy::new(|| {
+///     let mut map = HashMap::new();
+///     map.insert(1, "one");
+///     map.insert(2, "two");
+///     map
+/// });
+/// assert_eq!(map.get(&2), Some(&"two"));
+/// ```
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    init: Cell<Option<F>>,
+}
+
+// The initialization function is only accessed from within the `Once`, so
+// sharing a `Lazy` only requires it to be `Send`.
+unsafe impl<T, F: Send> Sync for Lazy<T, F>
+where
+    OnceCell<T>: Sync,
+{
+}
+
+impl<T, F> Lazy<T, F> {
+    /// Creates a new lazy value with the given initialization function.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(f: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(f)),
+        }
+    }
+
+    /// Creates a new lazy value with the given initialization function.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(f: F) -> Lazy<T, F> {
+        Lazy {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(f)),
+        }
+    }
+
+    /// Consumes this `Lazy`, returning the value if it was initialized.
+    #[inline]
+    pub fn into_inner(this: Lazy<T, F>) -> Option<T> {
+        this.cell.into_inner()
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Forces the evaluation of this lazy value and returns a reference to the
+    /// result. This is equivalent to the `Deref` implementation.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the initialization function panicked during a
+    /// previous access.
+    #[inline]
+    pub fn force(this: &Lazy<T, F>) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+impl<T: Default> Default for Lazy<T> {
+    #[inline]
+    fn default() -> Lazy<T> {
+        Lazy::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.cell.get() {
+            Some(value) => f.debug_struct("Lazy").field("value", value).finish(),
+            None => f.pad("Lazy { <uninit> }"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+    use std::sync::Arc;
+    use std::thread;
+    use {Lazy, OnceCell};
+
+    #[test]
+    fn smoke() {
+        let cell = OnceCell::new();
+        assert!(cell.get().is_none());
+        assert_eq!(cell.set(1), Ok(()));
+        assert_eq!(cell.set(2), Err(2));
+        assert_eq!(*cell.get_or_init(|| 3), 1);
+        assert_eq!(cell.get(), Some(&1));
+        assert_eq!(cell.into_inner(), Some(1));
+        assert_eq!(OnceCell::<i32>::new().into_inner(), None);
+    }
+
+    #[test]
+    fn get_mut() {
+        let mut cell = OnceCell::new();
+        assert!(cell.get_mut().is_none());
+        cell.set(vec![1]).unwrap();
+        cell.get_mut().unwrap().push(2);
+        assert_eq!(cell.get().unwrap(), &[1, 2]);
+    }
+
+    #[test]
+    fn stampede() {
+        static CALLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        let cell = Arc::new(OnceCell::new());
+        let threads: Vec<_> = (0..10)
+            .map(|i| {
+                let cell = cell.clone();
+                thread::spawn(move || {
+                    *cell.get_or_init(|| {
+                        CALLS.fetch_add(1, Ordering::SeqCst);
+                        i
+                    })
+                })
+            })
+            .collect();
+        let values: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert!(values.iter().all(|&v| v == values[0]));
+    }
+
+    #[test]
+    fn retry_after_panic() {
+        let cell = OnceCell::new();
+        let r = panic::catch_unwind(AssertUnwindSafe(|| cell.get_or_init(|| panic!())));
+        assert!(r.is_err());
+        assert!(cell.get().is_none());
+        assert_eq!(*cell.get_or_init(|| 1), 1);
+    }
+
+    #[test]
+    fn get_or_try_init() {
+        let cell = OnceCell::new();
+        assert_eq!(cell.get_or_try_init(|| Err(1)), Err(1));
+        assert!(cell.get().is_none());
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, i32>(2)), Ok(&2));
+        assert_eq!(cell.get_or_try_init(|| Err(3)), Ok(&2));
+    }
+
+    #[test]
+    fn lazy() {
+        static CALLS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        let lazy = Lazy::new(|| {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            vec![1, 2, 3]
+        });
+        assert_eq!(CALLS.load(Ordering::SeqCst), 0);
+        assert_eq!(lazy.len(), 3);
+        assert_eq!(*Lazy::force(&lazy), [1, 2, 3]);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(Lazy::into_inner(lazy), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn lazy_poisoned() {
+        let lazy: Lazy<i32> = Lazy::new(|| panic!());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| *lazy)).is_err());
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| *lazy)).is_err());
+    }
+
+    #[test]
+    fn test_debug_once_cell() {
+        let cell = OnceCell::new();
+        assert_eq!(format!("{:?}", cell), "OnceCell { <uninit> }");
+        cell.set(1).unwrap();
+        assert_eq!(format!("{:?}", cell), "OnceCell { value: 1 }");
+        let lazy: Lazy<i32> = Lazy::default();
+        assert_eq!(format!("{:?}", lazy), "Lazy { <uninit> }");
+        assert_eq!(*lazy, 0);
+        assert_eq!(format!("{:?}", lazy), "Lazy { value: 0 }");
+    }
+}
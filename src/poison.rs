@@ -0,0 +1,753 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LockResult, PoisonError, TryLockError, TryLockResult};
+use std::ops::{Deref, DerefMut};
+use std::panic::{RefUnwindSafe, UnwindSafe};
+use std::time::{Duration, Instant};
+use std::thread;
+use std::fmt;
+use mutex::{Mutex, MutexGuard};
+use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use raw_mutex::RawMutex;
+use raw_rwlock::RawRwLock;
+use lock_api::{RawMutex as RawMutexTrait, RawMutexTimed, RawRwLock as RawRwLockTrait,
+               RawRwLockTimed};
+
+// Poison flag shared by the poisoning lock types. A lock is poisoned when a
+// guard granting write access is dropped while its thread is panicking, unless
+// that thread was already panicking when the guard was created.
+struct PoisonFlag(AtomicBool);
+
+impl PoisonFlag {
+    #[cfg(feature = "nightly")]
+    #[inline]
+    const fn new() -> PoisonFlag {
+        PoisonFlag(AtomicBool::new(false))
+    }
+
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    fn new() -> PoisonFlag {
+        PoisonFlag(AtomicBool::new(false))
+    }
+
+    #[inline]
+    fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    #[inline]
+    fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn done(&self, panicking: bool) {
+        if !panicking && thread::panicking() {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    fn result<G>(&self, guard: G) -> LockResult<G> {
+        if self.get() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+}
+
+/// A mutex which is poisoned if a thread panics while holding it.
+///
+/// This is a wrapper around `Mutex` which restores the poisoning behavior of
+/// the standard library `Mutex`. If a thread panics while holding the lock,
+/// the mutex is marked as poisoned and all subsequent attempts to lock it
+/// return a `PoisonError`. The error still contains the guard, so the data can
+/// be inspected and repaired, after which `clear_poison` can be used to make
+/// the mutex usable normally again.
+///
+/// Poisoning is opt-in: the plain `Mutex` type is unaffected and keeps its
+/// size and performance. This type needs an additional byte to hold the poison
+/// flag.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::PoisonMutex;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// let lock = Arc::new(PoisonMutex::new(0));
+/// let lock2 = lock.clone();
+///
+/// let _ = thread::spawn(move || {
+///     let _guard = lock2.lock().unwrap();
+///     panic!();
+/// }).join();
+///
+/// assert!(lock.is_poisoned());
+/// let mut guard = match lock.lock() {
+///     Ok(guard) => guard,
+///     Err(poisoned) => poisoned.into_inner(),
+/// };
+/// *guard += 1;
+/// drop(guard);
+///
+/// lock.clear_poison();
+/// assert_eq!(*lock.lock().unwrap(), 1);
+/// ```
+pub struct PoisonMutex<T: ?Sized, R = RawMutex> {
+    poison: PoisonFlag,
+    inner: Mutex<T, R>,
+}
+
+/// An RAII implementation of a "scoped lock" of a `PoisonMutex`. When this
+/// structure is dropped (falls out of scope), the lock will be unlocked, and
+/// the mutex will be poisoned if the current thread is panicking.
+///
+/// The data protected by the mutex can be accessed through this guard via its
+/// `Deref` and `DerefMut` implementations.
+#[must_use]
+pub struct PoisonMutexGuard<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a = RawMutex> {
+    poison: &'a PoisonFlag,
+    panicking: bool,
+    guard: MutexGuard<'a, T, R>,
+}
+
+impl<T> PoisonMutex<T> {
+    /// Creates a new poisoning mutex in an unlocked state ready for use.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> PoisonMutex<T> {
+        PoisonMutex {
+            poison: PoisonFlag::new(),
+            inner: Mutex::new(val),
+        }
+    }
+
+    /// Creates a new poisoning mutex in an unlocked state ready for use.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> PoisonMutex<T> {
+        PoisonMutex {
+            poison: PoisonFlag::new(),
+            inner: Mutex::new(val),
+        }
+    }
+}
+
+impl<T, R: RawMutexTrait> PoisonMutex<T, R> {
+    /// Creates a new poisoning mutex using the given raw mutex, which must be
+    /// in an unlocked state.
+    #[inline]
+    pub fn with_raw_mutex(raw: R, val: T) -> PoisonMutex<T, R> {
+        PoisonMutex {
+            poison: PoisonFlag::new(),
+            inner: Mutex::with_raw_mutex(raw, val),
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    ///
+    /// If the mutex is poisoned then an error is returned, which still
+    /// contains the data.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let data = self.inner.into_inner();
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawMutexTrait> PoisonMutex<T, R> {
+    #[inline]
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, T, R>) -> PoisonMutexGuard<'a, T, R> {
+        PoisonMutexGuard {
+            poison: &self.poison,
+            panicking: thread::panicking(),
+            guard,
+        }
+    }
+
+    /// Acquires the mutex, blocking the current thread until it is able to do
+    /// so.
+    ///
+    /// If the mutex is poisoned then the lock is still acquired, but an error
+    /// containing the guard is returned.
+    ///
+    /// See `Mutex::lock` for details about locking behavior.
+    #[inline]
+    pub fn lock(&self) -> LockResult<PoisonMutexGuard<T, R>> {
+        let guard = self.inner.lock();
+        self.poison.result(self.guard(guard))
+    }
+
+    /// Attempts to acquire this lock.
+    ///
+    /// If the lock could not be acquired at this time, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// mutex is poisoned then `TryLockError::Poisoned` is returned, which
+    /// contains the guard.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_lock(&self) -> TryLockResult<PoisonMutexGuard<T, R>> {
+        match self.inner.try_lock() {
+            Some(guard) => self.poison.result(self.guard(guard)).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the mutex mutably, no actual locking needs to
+    /// take place. An error containing the reference is returned if the mutex
+    /// is poisoned.
+    #[inline]
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        let data = self.inner.get_mut();
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Returns whether the mutex is poisoned.
+    ///
+    /// Another thread may poison the mutex at any time, so this should only be
+    /// used as a hint.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poisoned state of the mutex.
+    ///
+    /// This should be called once the protected data has been restored to a
+    /// consistent state, after which locking the mutex no longer returns an
+    /// error.
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+}
+
+impl<T: ?Sized, R: RawMutexTimed> PoisonMutex<T, R> {
+    /// Attempts to acquire this lock until a timeout is reached.
+    ///
+    /// If the lock could not be acquired before the timeout expired, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// mutex is poisoned then `TryLockError::Poisoned` is returned.
+    #[inline]
+    pub fn try_lock_for(&self, timeout: Duration) -> TryLockResult<PoisonMutexGuard<T, R>> {
+        match self.inner.try_lock_for(timeout) {
+            Some(guard) => self.poison.result(self.guard(guard)).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Attempts to acquire this lock until a timeout is reached.
+    ///
+    /// If the lock could not be acquired before the timeout expired, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// mutex is poisoned then `TryLockError::Poisoned` is returned.
+    #[inline]
+    pub fn try_lock_until(&self, timeout: Instant) -> TryLockResult<PoisonMutexGuard<T, R>> {
+        match self.inner.try_lock_until(timeout) {
+            Some(guard) => self.poison.result(self.guard(guard)).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+}
+
+// As with the standard library `Mutex`, a panic while the lock is held poisons
+// it, so the protected data can't be observed in a broken state by accident.
+impl<T: ?Sized, R: RawMutexTrait + UnwindSafe> UnwindSafe for PoisonMutex<T, R> {}
+impl<T: ?Sized, R: RawMutexTrait + RefUnwindSafe> RefUnwindSafe for PoisonMutex<T, R> {}
+
+impl<T: Default, R: RawMutexTrait> Default for PoisonMutex<T, R> {
+    #[inline]
+    fn default() -> PoisonMutex<T, R> {
+        PoisonMutex::with_raw_mutex(R::INIT, Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, R: RawMutexTrait> fmt::Debug for PoisonMutex<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner.try_lock() {
+            Some(guard) => f.debug_struct("PoisonMutex")
+                .field("data", &&*guard)
+                .field("poisoned", &self.poison.get())
+                .finish(),
+            None => f.pad("PoisonMutex { <locked> }"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Deref for PoisonMutexGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> DerefMut for PoisonMutexGuard<'a, T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Drop for PoisonMutexGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        // The inner guard is dropped after this, so the flag is always set
+        // before the mutex is unlocked.
+        self.poison.done(self.panicking);
+    }
+}
+
+/// A reader-writer lock which is poisoned if a thread panics while holding
+/// write access.
+///
+/// This is a wrapper around `RwLock` which restores the poisoning behavior of
+/// the standard library `RwLock`. Only writers poison the lock: a panic while
+/// holding shared read access leaves it untouched. Once poisoned, all attempts
+/// to lock the rwlock return a `PoisonError` containing the guard until
+/// `clear_poison` is called.
+///
+/// Poisoning is opt-in: the plain `RwLock` type is unaffected and keeps its
+/// size and performance.
+pub struct PoisonRwLock<T: ?Sized, R = RawRwLock> {
+    poison: PoisonFlag,
+    inner: RwLock<T, R>,
+}
+
+/// RAII structure used to release the exclusive write access of a
+/// `PoisonRwLock` when dropped. The rwlock will be poisoned if the current
+/// thread is panicking.
+#[must_use]
+pub struct PoisonRwLockWriteGuard<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a = RawRwLock> {
+    poison: &'a PoisonFlag,
+    panicking: bool,
+    guard: RwLockWriteGuard<'a, T, R>,
+}
+
+impl<T> PoisonRwLock<T> {
+    /// Creates a new poisoning rwlock in an unlocked state ready for use.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> PoisonRwLock<T> {
+        PoisonRwLock {
+            poison: PoisonFlag::new(),
+            inner: RwLock::new(val),
+        }
+    }
+
+    /// Creates a new poisoning rwlock in an unlocked state ready for use.
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> PoisonRwLock<T> {
+        PoisonRwLock {
+            poison: PoisonFlag::new(),
+            inner: RwLock::new(val),
+        }
+    }
+}
+
+impl<T, R: RawRwLockTrait> PoisonRwLock<T, R> {
+    /// Creates a new poisoning rwlock using the given raw rwlock, which must be
+    /// in an unlocked state.
+    #[inline]
+    pub fn with_raw_rwlock(raw: R, val: T) -> PoisonRwLock<T, R> {
+        PoisonRwLock {
+            poison: PoisonFlag::new(),
+            inner: RwLock::with_raw_rwlock(raw, val),
+        }
+    }
+
+    /// Consumes this rwlock, returning the underlying data.
+    ///
+    /// If the rwlock is poisoned then an error is returned, which still
+    /// contains the data.
+    #[inline]
+    pub fn into_inner(self) -> LockResult<T> {
+        let poisoned = self.poison.get();
+        let data = self.inner.into_inner();
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTrait> PoisonRwLock<T, R> {
+    #[inline]
+    fn write_guard<'a>(
+        &'a self,
+        guard: RwLockWriteGuard<'a, T, R>,
+    ) -> PoisonRwLockWriteGuard<'a, T, R> {
+        PoisonRwLockWriteGuard {
+            poison: &self.poison,
+            panicking: thread::panicking(),
+            guard,
+        }
+    }
+
+    /// Locks this rwlock with shared read access, blocking the current thread
+    /// until it can be acquired.
+    ///
+    /// If the rwlock is poisoned then the lock is still acquired, but an error
+    /// containing the guard is returned.
+    ///
+    /// See `RwLock::read` for details about locking behavior.
+    #[inline]
+    pub fn read(&self) -> LockResult<RwLockReadGuard<T, R>> {
+        self.poison.result(self.inner.read())
+    }
+
+    /// Attempts to acquire this rwlock with shared read access.
+    ///
+    /// If the access could not be granted at this time, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// rwlock is poisoned then `TryLockError::Poisoned` is returned.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<T, R>> {
+        match self.inner.try_read() {
+            Some(guard) => self.poison.result(guard).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Locks this rwlock with exclusive write access, blocking the current
+    /// thread until it can be acquired.
+    ///
+    /// If the rwlock is poisoned then the lock is still acquired, but an error
+    /// containing the guard is returned.
+    ///
+    /// See `RwLock::write` for details about locking behavior.
+    #[inline]
+    pub fn write(&self) -> LockResult<PoisonRwLockWriteGuard<T, R>> {
+        let guard = self.inner.write();
+        self.poison.result(self.write_guard(guard))
+    }
+
+    /// Attempts to lock this rwlock with exclusive write access.
+    ///
+    /// If the access could not be granted at this time, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// rwlock is poisoned then `TryLockError::Poisoned` is returned.
+    ///
+    /// This function does not block.
+    #[inline]
+    pub fn try_write(&self) -> TryLockResult<PoisonRwLockWriteGuard<T, R>> {
+        match self.inner.try_write() {
+            Some(guard) => self.poison.result(self.write_guard(guard)).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the rwlock mutably, no actual locking needs to
+    /// take place. An error containing the reference is returned if the rwlock
+    /// is poisoned.
+    #[inline]
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let poisoned = self.poison.get();
+        let data = self.inner.get_mut();
+        if poisoned {
+            Err(PoisonError::new(data))
+        } else {
+            Ok(data)
+        }
+    }
+
+    /// Returns whether the rwlock is poisoned.
+    ///
+    /// Another thread may poison the rwlock at any time, so this should only
+    /// be used as a hint.
+    #[inline]
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Clears the poisoned state of the rwlock.
+    ///
+    /// This should be called once the protected data has been restored to a
+    /// consistent state, after which locking the rwlock no longer returns an
+    /// error.
+    #[inline]
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTimed> PoisonRwLock<T, R> {
+    /// Attempts to acquire this rwlock with shared read access until a timeout
+    /// is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// rwlock is poisoned then `TryLockError::Poisoned` is returned.
+    #[inline]
+    pub fn try_read_for(&self, timeout: Duration) -> TryLockResult<RwLockReadGuard<T, R>> {
+        match self.inner.try_read_for(timeout) {
+            Some(guard) => self.poison.result(guard).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Attempts to acquire this rwlock with shared read access until a timeout
+    /// is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// rwlock is poisoned then `TryLockError::Poisoned` is returned.
+    #[inline]
+    pub fn try_read_until(&self, timeout: Instant) -> TryLockResult<RwLockReadGuard<T, R>> {
+        match self.inner.try_read_until(timeout) {
+            Some(guard) => self.poison.result(guard).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Attempts to acquire this rwlock with exclusive write access until a
+    /// timeout is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// rwlock is poisoned then `TryLockError::Poisoned` is returned.
+    #[inline]
+    pub fn try_write_for(&self, timeout: Duration) -> TryLockResult<PoisonRwLockWriteGuard<T, R>> {
+        match self.inner.try_write_for(timeout) {
+            Some(guard) => self.poison.result(self.write_guard(guard)).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+
+    /// Attempts to acquire this rwlock with exclusive write access until a
+    /// timeout is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// `TryLockError::WouldBlock` is returned. If the lock was acquired but the
+    /// rwlock is poisoned then `TryLockError::Poisoned` is returned.
+    #[inline]
+    pub fn try_write_until(&self, timeout: Instant) -> TryLockResult<PoisonRwLockWriteGuard<T, R>> {
+        match self.inner.try_write_until(timeout) {
+            Some(guard) => self.poison.result(self.write_guard(guard)).map_err(TryLockError::from),
+            None => Err(TryLockError::WouldBlock),
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTrait + UnwindSafe> UnwindSafe for PoisonRwLock<T, R> {}
+impl<T: ?Sized, R: RawRwLockTrait + RefUnwindSafe> RefUnwindSafe for PoisonRwLock<T, R> {}
+
+impl<T: Default, R: RawRwLockTrait> Default for PoisonRwLock<T, R> {
+    #[inline]
+    fn default() -> PoisonRwLock<T, R> {
+        PoisonRwLock::with_raw_rwlock(R::INIT, Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, R: RawRwLockTrait> fmt::Debug for PoisonRwLock<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.inner.try_read() {
+            Some(guard) => f.debug_struct("PoisonRwLock")
+                .field("data", &&*guard)
+                .field("poisoned", &self.poison.get())
+                .finish(),
+            None => f.pad("PoisonRwLock { <locked> }"),
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Deref for PoisonRwLockWriteGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> DerefMut for PoisonRwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Drop for PoisonRwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        // The inner guard is dropped after this, so the flag is always set
+        // before the rwlock is unlocked.
+        self.poison.done(self.panicking);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::panic;
+    use std::sync::{Arc, TryLockError};
+    use std::thread;
+    use std::time::Duration;
+    use {PoisonMutex, PoisonRwLock};
+
+    #[test]
+    fn smoke() {
+        let m = PoisonMutex::new(1);
+        *m.lock().unwrap() += 1;
+        assert!(!m.is_poisoned());
+        assert_eq!(m.into_inner().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_mutex_poison() {
+        let m = Arc::new(PoisonMutex::new(1));
+        let m2 = m.clone();
+        let result = thread::spawn(move || {
+            let _lock = m2.lock().unwrap();
+            panic!("test panic in inner thread to poison mutex");
+        }).join();
+        assert!(result.is_err());
+        assert!(m.is_poisoned());
+
+        match m.lock() {
+            Ok(_) => panic!("lock of poisoned mutex succeeded"),
+            Err(e) => assert_eq!(*e.into_inner(), 1),
+        }
+        match m.try_lock() {
+            Err(TryLockError::Poisoned(e)) => assert_eq!(*e.into_inner(), 1),
+            _ => panic!("try_lock of poisoned mutex succeeded"),
+        }
+
+        m.clear_poison();
+        assert!(!m.is_poisoned());
+        assert_eq!(*m.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_unwind_safe() {
+        // The locks can be used across catch_unwind without AssertUnwindSafe,
+        // even if the data they protect isn't unwind safe.
+        let m = PoisonMutex::new(Cell::new(1));
+        let rw = PoisonRwLock::new(Cell::new(1));
+        let result = panic::catch_unwind(|| {
+            let _lock = m.lock().unwrap();
+            let _write = rw.write().unwrap();
+            panic!("test panic to poison both locks");
+        });
+        assert!(result.is_err());
+        assert!(m.is_poisoned());
+        assert!(rw.is_poisoned());
+    }
+
+    #[test]
+    fn test_mutex_into_inner_poison() {
+        let m = Arc::new(PoisonMutex::new(10));
+        let m2 = m.clone();
+        let _ = thread::spawn(move || {
+            let _lock = m2.lock().unwrap();
+            panic!("test panic in inner thread to poison mutex");
+        }).join();
+
+        let mut m = Arc::try_unwrap(m).ok().unwrap();
+        assert_eq!(*m.get_mut().unwrap_err().into_inner(), 10);
+        assert_eq!(m.into_inner().unwrap_err().into_inner(), 10);
+    }
+
+    #[test]
+    fn test_mutex_try_lock_would_block() {
+        let m = PoisonMutex::new(());
+        let _lock = m.lock().unwrap();
+        match m.try_lock() {
+            Err(TryLockError::WouldBlock) => {}
+            _ => panic!("try_lock of locked mutex succeeded"),
+        }
+        match m.try_lock_for(Duration::from_millis(10)) {
+            Err(TryLockError::WouldBlock) => {}
+            _ => panic!("try_lock_for of locked mutex succeeded"),
+        };
+    }
+
+    #[test]
+    fn test_mutex_panic_while_panicking() {
+        // A guard created during unwinding does not poison the mutex.
+        struct Unwinding<'a>(&'a PoisonMutex<()>);
+        impl<'a> Drop for Unwinding<'a> {
+            fn drop(&mut self) {
+                let _lock = self.0.lock().unwrap();
+            }
+        }
+
+        let m = Arc::new(PoisonMutex::new(()));
+        let m2 = m.clone();
+        let _ = thread::spawn(move || {
+            let _unwinding = Unwinding(&m2);
+            panic!("test panic in inner thread");
+        }).join();
+        assert!(!m.is_poisoned());
+    }
+
+    #[test]
+    fn test_rwlock_write_poison() {
+        let l = Arc::new(PoisonRwLock::new(1));
+        let l2 = l.clone();
+        let _ = thread::spawn(move || {
+            let _lock = l2.write().unwrap();
+            panic!("test panic in inner thread to poison rwlock");
+        }).join();
+        assert!(l.is_poisoned());
+        assert!(l.read().is_err());
+        assert!(l.write().is_err());
+        match l.try_read() {
+            Err(TryLockError::Poisoned(_)) => {}
+            _ => panic!("try_read of poisoned rwlock succeeded"),
+        }
+
+        l.clear_poison();
+        assert_eq!(*l.read().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_rwlock_read_no_poison() {
+        let l = Arc::new(PoisonRwLock::new(1));
+        let l2 = l.clone();
+        let _ = thread::spawn(move || {
+            let _lock = l2.read().unwrap();
+            panic!("test panic in inner thread");
+        }).join();
+        assert!(!l.is_poisoned());
+        assert_eq!(*l.write().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_rwlock_try_write_would_block() {
+        let l = PoisonRwLock::new(());
+        let _lock = l.read().unwrap();
+        match l.try_write() {
+            Err(TryLockError::WouldBlock) => {}
+            _ => panic!("try_write of read locked rwlock succeeded"),
+        };
+    }
+}
@@ -17,8 +17,13 @@ use std::sync::atomic::ATOMIC_USIZE_INIT as ATOMIC_U8_INIT;
 #[cfg(not(feature = "nightly"))]
 type U8 = usize;
 use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use std::task::{Poll, Waker};
+#[cfg(feature = "async")]
+use parking_lot_core::AsyncWaiter;
 use parking_lot_core::{self, ParkResult, SpinWait, UnparkResult, UnparkToken, DEFAULT_PARK_TOKEN};
 use deadlock;
+use lock_api::{RawMutex as RawMutexTrait, RawMutexFair, RawMutexTimed};
 
 // UnparkToken used to indicate that that the target thread should attempt to
 // lock the mutex again as soon as it is unparked.
@@ -31,39 +36,86 @@ pub const TOKEN_HANDOFF: UnparkToken = UnparkToken(1);
 const LOCKED_BIT: U8 = 1;
 const PARKED_BIT: U8 = 2;
 
+/// Raw mutex type backed by the parking lot.
 pub struct RawMutex {
     state: AtomicU8,
 }
 
-impl RawMutex {
-    #[cfg(feature = "nightly")]
+unsafe impl RawMutexTrait for RawMutex {
+    const INIT: RawMutex = RawMutex {
+        state: ATOMIC_U8_INIT,
+    };
+
     #[inline]
-    pub const fn new() -> RawMutex {
-        RawMutex {
-            state: ATOMIC_U8_INIT,
+    fn lock(&self) {
+        if self.state
+            .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_slow(None);
         }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
     }
-    #[cfg(not(feature = "nightly"))]
+
     #[inline]
-    pub fn new() -> RawMutex {
-        RawMutex {
-            state: ATOMIC_U8_INIT,
+    fn try_lock(&self) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & LOCKED_BIT != 0 {
+                return false;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state | LOCKED_BIT,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+                    return true;
+                }
+                Err(x) => state = x,
+            }
         }
     }
 
     #[inline]
-    pub fn lock(&self) {
+    unsafe fn unlock(&self) {
+        deadlock::release_resource(self as *const _ as usize);
         if self.state
-            .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
+            .compare_exchange_weak(LOCKED_BIT, 0, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
         {
-            self.lock_slow(None);
+            return;
         }
-        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        self.unlock_slow(false);
+    }
+}
+
+unsafe impl RawMutexFair for RawMutex {
+    #[inline]
+    unsafe fn unlock_fair(&self) {
+        deadlock::release_resource(self as *const _ as usize);
+        if self.state
+            .compare_exchange_weak(LOCKED_BIT, 0, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+        self.unlock_slow(true);
     }
 
     #[inline]
-    pub fn try_lock_until(&self, timeout: Instant) -> bool {
+    unsafe fn bump(&self) {
+        if self.state.load(Ordering::Relaxed) & PARKED_BIT != 0 {
+            self.bump_slow();
+        }
+    }
+}
+
+unsafe impl RawMutexTimed for RawMutex {
+    #[inline]
+    fn try_lock_until(&self, timeout: Instant) -> bool {
         let result = if self.state
             .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
@@ -79,7 +131,7 @@ impl RawMutex {
     }
 
     #[inline]
-    pub fn try_lock_for(&self, timeout: Duration) -> bool {
+    fn try_lock_for(&self, timeout: Duration) -> bool {
         let result = if self.state
             .compare_exchange_weak(0, LOCKED_BIT, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
@@ -93,39 +145,29 @@ impl RawMutex {
         }
         result
     }
+}
 
+impl RawMutex {
+    // Checks whether the mutex is locked by the current thread. If locks aren't
+    // being tracked we can only check whether the mutex is locked at all.
     #[inline]
-    pub fn try_lock(&self) -> bool {
-        let mut state = self.state.load(Ordering::Relaxed);
-        loop {
-            if state & LOCKED_BIT != 0 {
-                return false;
-            }
-            match self.state.compare_exchange_weak(
-                state,
-                state | LOCKED_BIT,
-                Ordering::Acquire,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => {
-                    unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-                    return true;
-                }
-                Err(x) => state = x,
-            }
-        }
+    pub(crate) fn is_held_by_current_thread(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & LOCKED_BIT != 0
+            && deadlock::is_resource_held(self as *const _ as usize)
     }
 
+    // Stops attributing a lock held by the current thread to it, so that the
+    // lock can be released by another thread after calling `attach` there.
     #[inline]
-    pub fn unlock(&self, force_fair: bool) {
-        unsafe { deadlock::release_resource(self as *const _ as usize) };
-        if self.state
-            .compare_exchange_weak(LOCKED_BIT, 0, Ordering::Release, Ordering::Relaxed)
-            .is_ok()
-        {
-            return;
-        }
-        self.unlock_slow(force_fair);
+    pub(crate) unsafe fn detach(&self) {
+        deadlock::release_resource(self as *const _ as usize);
+    }
+
+    // Attributes a lock previously detached with `detach` to the current
+    // thread.
+    #[inline]
+    pub(crate) unsafe fn attach(&self) {
+        deadlock::acquire_resource(self as *const _ as usize);
     }
 
     // Used by Condvar when requeuing threads to us, must be called while
@@ -159,6 +201,13 @@ impl RawMutex {
     #[cold]
     #[inline(never)]
     fn lock_slow(&self, timeout: Option<Instant>) -> bool {
+        if timeout.is_none() {
+            deadlock::check_not_held(
+                self as *const _ as usize,
+                "attempted to lock a mutex which is already held by the current thread",
+            );
+        }
+
         let mut spinwait = SpinWait::new();
         let mut state = self.state.load(Ordering::Relaxed);
         loop {
@@ -235,6 +284,14 @@ impl RawMutex {
         }
     }
 
+    #[cold]
+    #[inline(never)]
+    fn bump_slow(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.unlock_slow(true);
+        self.lock();
+    }
+
     #[cold]
     #[inline(never)]
     fn unlock_slow(&self, force_fair: bool) {
@@ -275,3 +332,116 @@ impl RawMutex {
         }
     }
 }
+
+#[cfg(feature = "async")]
+impl RawMutex {
+    // Attempts to lock the mutex on behalf of an asynchronous task. If the
+    // mutex is locked then the waiter is queued, creating it if necessary, and
+    // the waker is notified once the task should poll again. The lock is not
+    // attributed to any thread, in the same way as with `detach`.
+    pub(crate) fn poll_lock(&self, waiter: &mut Option<AsyncWaiter>, waker: &Waker) -> Poll<()> {
+        // Check whether we were unparked since the last poll
+        if let Some(ref mut waiter) = *waiter {
+            if waiter.is_parked() {
+                match waiter.poll(waker) {
+                    // The thread that unparked us passed the lock on to us
+                    // directly without unlocking it.
+                    Some(TOKEN_HANDOFF) => return Poll::Ready(()),
+
+                    // We were unparked normally, try acquiring the lock again
+                    Some(_) => (),
+
+                    // Still waiting
+                    None => return Poll::Pending,
+                }
+            }
+        }
+
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            // Grab the lock if it isn't locked, even if there is a queue on it
+            if state & LOCKED_BIT == 0 {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state | LOCKED_BIT,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Poll::Ready(()),
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+
+            // Set the parked bit. Unlike threads, tasks never spin since that
+            // would block the executor.
+            if state & PARKED_BIT == 0 {
+                if let Err(x) = self.state.compare_exchange_weak(
+                    state,
+                    state | PARKED_BIT,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    state = x;
+                    continue;
+                }
+            }
+
+            // Queue the task until it is woken up by an unlock
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || self.state.load(Ordering::Relaxed) == LOCKED_BIT | PARKED_BIT;
+                let waiter = waiter.get_or_insert_with(AsyncWaiter::new);
+                if waiter.park(addr, validate, DEFAULT_PARK_TOKEN, waker) {
+                    return Poll::Pending;
+                }
+            }
+
+            // The validation function failed, try locking again
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    // Abandons a lock attempt started with `poll_lock`, for example because
+    // the future waiting for the lock was dropped.
+    pub(crate) fn cancel_lock(&self, waiter: &mut Option<AsyncWaiter>) {
+        let waiter = match *waiter {
+            Some(ref mut waiter) if waiter.is_parked() => waiter,
+            _ => return,
+        };
+        let timed_out = |_, was_last_thread| {
+            // Clear the parked bit if we were the last parked thread
+            if was_last_thread {
+                self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+            }
+        };
+        if let Some((_, token)) = unsafe { waiter.cancel(timed_out) } {
+            self.abandon_unpark(token);
+        }
+    }
+
+    // Called when a task which was unparked from our queue gives up on
+    // locking the mutex, given the token it was unparked with.
+    pub(crate) fn abandon_unpark(&self, token: UnparkToken) {
+        if token == TOKEN_HANDOFF {
+            // The lock was handed off to us, so pass it on to the next waiter
+            unsafe {
+                self.attach();
+                self.unlock();
+            }
+        } else if self.try_lock() {
+            // We were unparked in order to retry locking the mutex. Since we
+            // won't do that, make sure that the wakeup isn't lost for the other
+            // waiters: if the mutex is still unlocked then bounce it to wake
+            // up the next one, otherwise the current owner will do so.
+            unsafe { self.unlock() };
+        }
+    }
+
+    // Used by Condvar when a task which was requeued to us is removed from the
+    // queue, must be called while holding the queue lock.
+    #[inline]
+    pub(crate) fn clear_parked(&self) {
+        self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+    }
+}
@@ -5,10 +5,10 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::time::{Duration, Instant};
 use std::cell::Cell;
-use raw_mutex::RawMutex;
+use lock_api::{RawMutex, RawMutexFair, RawMutexTimed};
 
 // Helper function to get a thread id
 fn get_thread_id() -> usize {
@@ -18,31 +18,27 @@ fn get_thread_id() -> usize {
     KEY.with(|x| x as *const _ as usize)
 }
 
-pub struct RawReentrantMutex {
+pub struct RawReentrantMutex<R> {
     owner: AtomicUsize,
     lock_count: Cell<usize>,
-    mutex: RawMutex,
+    mutex: R,
 }
 
-unsafe impl Sync for RawReentrantMutex {}
+unsafe impl<R: RawMutex + Sync> Sync for RawReentrantMutex<R> {}
+
+impl<R: RawMutex> RawReentrantMutex<R> {
+    pub const INIT: RawReentrantMutex<R> = RawReentrantMutex {
+        owner: ATOMIC_USIZE_INIT,
+        lock_count: Cell::new(0),
+        mutex: R::INIT,
+    };
 
-impl RawReentrantMutex {
-    #[cfg(feature = "nightly")]
-    #[inline]
-    pub const fn new() -> RawReentrantMutex {
-        RawReentrantMutex {
-            owner: AtomicUsize::new(0),
-            lock_count: Cell::new(0),
-            mutex: RawMutex::new(),
-        }
-    }
-    #[cfg(not(feature = "nightly"))]
     #[inline]
-    pub fn new() -> RawReentrantMutex {
+    pub fn new(mutex: R) -> RawReentrantMutex<R> {
         RawReentrantMutex {
-            owner: AtomicUsize::new(0),
+            owner: ATOMIC_USIZE_INIT,
             lock_count: Cell::new(0),
-            mutex: RawMutex::new(),
+            mutex,
         }
     }
 
@@ -75,28 +71,83 @@ impl RawReentrantMutex {
     }
 
     #[inline]
-    pub fn try_lock_until(&self, timeout: Instant) -> bool {
-        self.lock_internal(|| self.mutex.try_lock_until(timeout))
+    pub fn try_lock(&self) -> bool {
+        self.lock_internal(|| self.mutex.try_lock())
     }
 
     #[inline]
-    pub fn try_lock_for(&self, timeout: Duration) -> bool {
-        self.lock_internal(|| self.mutex.try_lock_for(timeout))
+    pub fn is_held_by_current_thread(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == get_thread_id()
     }
 
+    // Returns the underlying mutex. Used by Condvar, which requeues waiting
+    // threads directly onto it.
     #[inline]
-    pub fn try_lock(&self) -> bool {
-        self.lock_internal(|| self.mutex.try_lock())
+    pub fn mutex(&self) -> &R {
+        &self.mutex
+    }
+
+    // Releases the underlying mutex regardless of how many times it has been
+    // locked recursively, and returns the recursion count. Used by Condvar.
+    #[inline]
+    pub unsafe fn unlock_all(&self) -> usize {
+        let lock_count = self.lock_count.get();
+        self.owner.store(0, Ordering::Relaxed);
+        self.mutex.unlock();
+        lock_count
+    }
+
+    // Restores the ownership state saved by `unlock_all` after the underlying
+    // mutex has been re-acquired by the current thread.
+    #[inline]
+    pub unsafe fn restore(&self, lock_count: usize) {
+        self.owner.store(get_thread_id(), Ordering::Relaxed);
+        self.lock_count.set(lock_count);
     }
 
     #[inline]
-    pub fn unlock(&self, force_fair: bool) {
+    pub unsafe fn unlock(&self) {
         let lock_count = self.lock_count.get() - 1;
         if lock_count == 0 {
             self.owner.store(0, Ordering::Relaxed);
-            self.mutex.unlock(force_fair);
+            self.mutex.unlock();
         } else {
             self.lock_count.set(lock_count);
         }
     }
 }
+
+impl<R: RawMutexFair> RawReentrantMutex<R> {
+    #[inline]
+    pub unsafe fn unlock_fair(&self) {
+        let lock_count = self.lock_count.get() - 1;
+        if lock_count == 0 {
+            self.owner.store(0, Ordering::Relaxed);
+            self.mutex.unlock_fair();
+        } else {
+            self.lock_count.set(lock_count);
+        }
+    }
+
+    #[inline]
+    pub unsafe fn bump(&self) {
+        if self.lock_count.get() == 1 {
+            let id = self.owner.load(Ordering::Relaxed);
+            self.owner.store(0, Ordering::Relaxed);
+            self.mutex.bump();
+            self.owner.store(id, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<R: RawMutexTimed> RawReentrantMutex<R> {
+    #[inline]
+    pub fn try_lock_until(&self, timeout: Instant) -> bool {
+        self.lock_internal(|| self.mutex.try_lock_until(timeout))
+    }
+
+    #[inline]
+    pub fn try_lock_for(&self, timeout: Duration) -> bool {
+        self.lock_internal(|| self.mutex.try_lock_for(timeout))
+    }
+}
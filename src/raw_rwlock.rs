@@ -5,13 +5,20 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use std::cell::Cell;
 use std::time::{Duration, Instant};
+#[cfg(feature = "async")]
+use std::task::{Poll, Waker};
+#[cfg(feature = "async")]
+use parking_lot_core::AsyncWaiter;
 use parking_lot_core::{self, FilterOp, ParkResult, ParkToken, SpinWait, UnparkResult};
 use elision::{have_elision, AtomicElisionExt};
 use raw_mutex::{TOKEN_HANDOFF, TOKEN_NORMAL};
 use deadlock;
+use lock_api::{RawRwLock as RawRwLockTrait, RawRwLockDowngrade, RawRwLockFair,
+               RawRwLockRecursive, RawRwLockRecursiveTimed, RawRwLockTimed, RawRwLockUpgrade,
+               RawRwLockUpgradeDowngrade, RawRwLockUpgradeFair, RawRwLockUpgradeTimed};
 
 const USABLE_BITS_MASK: usize = {
     #[cfg(feature = "nightly")]
@@ -63,40 +70,140 @@ fn checked_add(left: usize, right: usize) -> Option<usize> {
     }
 }
 
+/// Raw reader-writer lock type backed by the parking lot.
 pub struct RawRwLock {
     state: AtomicUsize,
 }
 
-impl RawRwLock {
-    #[cfg(feature = "nightly")]
+unsafe impl RawRwLockTrait for RawRwLock {
+    const INIT: RawRwLock = RawRwLock {
+        state: ATOMIC_USIZE_INIT,
+    };
+
     #[inline]
-    pub const fn new() -> RawRwLock {
-        RawRwLock {
-            state: AtomicUsize::new(0),
+    fn lock_exclusive(&self) {
+        if self.state
+            .compare_exchange_weak(0, EXCLUSIVE_GUARD, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            let result = self.lock_exclusive_slow(None);
+            debug_assert!(result);
         }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
     }
-    #[cfg(not(feature = "nightly"))]
+
     #[inline]
-    pub fn new() -> RawRwLock {
-        RawRwLock {
-            state: AtomicUsize::new(0),
+    fn try_lock_exclusive(&self) -> bool {
+        if self.state
+            .compare_exchange(0, EXCLUSIVE_GUARD, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+        {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            true
+        } else {
+            false
         }
     }
 
     #[inline]
-    pub fn lock_exclusive(&self) {
+    unsafe fn unlock_exclusive(&self) {
+        deadlock::release_resource(self as *const _ as usize);
         if self.state
-            .compare_exchange_weak(0, EXCLUSIVE_GUARD, Ordering::Acquire, Ordering::Relaxed)
-            .is_err()
+            .compare_exchange_weak(EXCLUSIVE_GUARD, 0, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
         {
-            let result = self.lock_exclusive_slow(None);
+            return;
+        }
+        self.unlock_exclusive_slow(false);
+    }
+
+    #[inline]
+    fn lock_shared(&self) {
+        if !self.try_lock_shared_fast(false) {
+            let result = self.lock_shared_slow(false, None);
             debug_assert!(result);
         }
         unsafe { deadlock::acquire_resource(self as *const _ as usize) };
     }
 
     #[inline]
-    pub fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
+    fn try_lock_shared(&self) -> bool {
+        let result = if self.try_lock_shared_fast(false) {
+            true
+        } else {
+            self.try_lock_shared_slow(false)
+        };
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    unsafe fn unlock_shared(&self) {
+        self.unlock_shared_internal(false);
+    }
+}
+
+unsafe impl RawRwLockFair for RawRwLock {
+    #[inline]
+    unsafe fn unlock_shared_fair(&self) {
+        self.unlock_shared_internal(true);
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive_fair(&self) {
+        deadlock::release_resource(self as *const _ as usize);
+        if self.state
+            .compare_exchange_weak(EXCLUSIVE_GUARD, 0, Ordering::Release, Ordering::Relaxed)
+            .is_ok()
+        {
+            return;
+        }
+        self.unlock_exclusive_slow(true);
+    }
+
+    #[inline]
+    unsafe fn bump_shared(&self) {
+        if self.state.load(Ordering::Relaxed) & PARKED_BIT != 0 {
+            self.bump_shared_slow();
+        }
+    }
+
+    #[inline]
+    unsafe fn bump_exclusive(&self) {
+        if self.state.load(Ordering::Relaxed) & PARKED_BIT != 0 {
+            self.bump_exclusive_slow();
+        }
+    }
+}
+
+unsafe impl RawRwLockDowngrade for RawRwLock {
+    #[inline]
+    unsafe fn downgrade(&self) {
+        let state = self.state
+            .fetch_sub(EXCLUSIVE_GUARD - SHARED_GUARD, Ordering::Release);
+
+        // Wake up parked shared and upgradable threads if there are any
+        if state & PARKED_BIT != 0 {
+            self.exclusive_to_shared_slow();
+        }
+    }
+}
+
+unsafe impl RawRwLockTimed for RawRwLock {
+    #[inline]
+    fn try_lock_shared_until(&self, timeout: Instant) -> bool {
+        self.try_lock_shared_until_internal(false, timeout)
+    }
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Duration) -> bool {
+        self.try_lock_shared_until_internal(false, Instant::now() + timeout)
+    }
+
+    #[inline]
+    fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
         let result = if self.state
             .compare_exchange_weak(0, EXCLUSIVE_GUARD, Ordering::Acquire, Ordering::Relaxed)
             .is_ok()
@@ -112,57 +219,188 @@ impl RawRwLock {
     }
 
     #[inline]
-    pub fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
-        let result = if self.state
-            .compare_exchange_weak(0, EXCLUSIVE_GUARD, Ordering::Acquire, Ordering::Relaxed)
-            .is_ok()
-        {
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        self.try_lock_exclusive_until(Instant::now() + timeout)
+    }
+}
+
+unsafe impl RawRwLockRecursive for RawRwLock {
+    #[inline]
+    fn lock_shared_recursive(&self) {
+        if !self.try_lock_shared_fast(true) {
+            let result = self.lock_shared_slow(true, None);
+            debug_assert!(result);
+        }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive(&self) -> bool {
+        let result = if self.try_lock_shared_fast(true) {
             true
         } else {
-            self.lock_exclusive_slow(Some(Instant::now() + timeout))
+            self.try_lock_shared_slow(true)
         };
         if result {
             unsafe { deadlock::acquire_resource(self as *const _ as usize) };
         }
         result
     }
+}
+
+unsafe impl RawRwLockRecursiveTimed for RawRwLock {
+    #[inline]
+    fn try_lock_shared_recursive_until(&self, timeout: Instant) -> bool {
+        self.try_lock_shared_until_internal(true, timeout)
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive_for(&self, timeout: Duration) -> bool {
+        self.try_lock_shared_until_internal(true, Instant::now() + timeout)
+    }
+}
 
+unsafe impl RawRwLockUpgrade for RawRwLock {
     #[inline]
-    pub fn try_lock_exclusive(&self) -> bool {
+    fn lock_upgradable(&self) {
+        if !self.try_lock_upgradable_fast() {
+            let result = self.lock_upgradable_slow(None);
+            debug_assert!(result);
+        }
+        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+    }
+
+    #[inline]
+    fn try_lock_upgradable(&self) -> bool {
+        let result = if self.try_lock_upgradable_fast() {
+            true
+        } else {
+            self.try_lock_upgradable_slow()
+        };
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    unsafe fn unlock_upgradable(&self) {
+        deadlock::release_resource(self as *const _ as usize);
         if self.state
-            .compare_exchange(0, EXCLUSIVE_GUARD, Ordering::Acquire, Ordering::Relaxed)
+            .compare_exchange_weak(UPGRADABLE_GUARD, 0, Ordering::Release, Ordering::Relaxed)
             .is_ok()
         {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-            true
-        } else {
-            false
+            return;
         }
+        self.unlock_upgradable_slow(false);
     }
 
     #[inline]
-    pub fn unlock_exclusive(&self, force_fair: bool) {
-        unsafe { deadlock::release_resource(self as *const _ as usize) };
+    unsafe fn upgrade(&self) {
         if self.state
-            .compare_exchange_weak(EXCLUSIVE_GUARD, 0, Ordering::Release, Ordering::Relaxed)
+            .compare_exchange_weak(
+                UPGRADABLE_GUARD,
+                EXCLUSIVE_GUARD,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            let result = self.upgradable_to_exclusive_slow(None);
+            debug_assert!(result);
+        }
+    }
+
+    #[inline]
+    unsafe fn try_upgrade(&self) -> bool {
+        self.state
+            .compare_exchange(
+                UPGRADABLE_GUARD,
+                EXCLUSIVE_GUARD,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+}
+
+unsafe impl RawRwLockUpgradeFair for RawRwLock {
+    #[inline]
+    unsafe fn unlock_upgradable_fair(&self) {
+        deadlock::release_resource(self as *const _ as usize);
+        if self.state
+            .compare_exchange_weak(UPGRADABLE_GUARD, 0, Ordering::Release, Ordering::Relaxed)
             .is_ok()
         {
             return;
         }
-        self.unlock_exclusive_slow(force_fair);
+        self.unlock_upgradable_slow(true);
     }
 
     #[inline]
-    pub fn exclusive_to_shared(&self) {
+    unsafe fn bump_upgradable(&self) {
+        if self.state.load(Ordering::Relaxed) & PARKED_BIT != 0 {
+            self.bump_upgradable_slow();
+        }
+    }
+}
+
+unsafe impl RawRwLockUpgradeDowngrade for RawRwLock {
+    #[inline]
+    unsafe fn downgrade_upgradable(&self) {
         let state = self.state
-            .fetch_sub(EXCLUSIVE_GUARD - SHARED_GUARD, Ordering::Release);
+            .fetch_sub(UPGRADABLE_GUARD - SHARED_GUARD, Ordering::Relaxed);
 
         // Wake up parked shared and upgradable threads if there are any
         if state & PARKED_BIT != 0 {
-            self.exclusive_to_shared_slow();
+            self.upgradable_to_shared_slow(state);
         }
     }
+}
 
+unsafe impl RawRwLockUpgradeTimed for RawRwLock {
+    #[inline]
+    fn try_lock_upgradable_until(&self, timeout: Instant) -> bool {
+        let result = if self.try_lock_upgradable_fast() {
+            true
+        } else {
+            self.lock_upgradable_slow(Some(timeout))
+        };
+        if result {
+            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        }
+        result
+    }
+
+    #[inline]
+    fn try_lock_upgradable_for(&self, timeout: Duration) -> bool {
+        self.try_lock_upgradable_until(Instant::now() + timeout)
+    }
+
+    #[inline]
+    unsafe fn try_upgrade_until(&self, timeout: Instant) -> bool {
+        if self.state
+            .compare_exchange_weak(
+                UPGRADABLE_GUARD,
+                EXCLUSIVE_GUARD,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+        {
+            true
+        } else {
+            self.upgradable_to_exclusive_slow(Some(timeout))
+        }
+    }
+
+    #[inline]
+    unsafe fn try_upgrade_for(&self, timeout: Duration) -> bool {
+        self.try_upgrade_until(Instant::now() + timeout)
+    }
+}
+
+impl RawRwLock {
     #[inline(always)]
     fn try_lock_shared_fast(&self, recursive: bool) -> bool {
         let state = self.state.load(Ordering::Relaxed);
@@ -188,16 +426,7 @@ impl RawRwLock {
     }
 
     #[inline]
-    pub fn lock_shared(&self, recursive: bool) {
-        if !self.try_lock_shared_fast(recursive) {
-            let result = self.lock_shared_slow(recursive, None);
-            debug_assert!(result);
-        }
-        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-    }
-
-    #[inline]
-    pub fn try_lock_shared_until(&self, recursive: bool, timeout: Instant) -> bool {
+    fn try_lock_shared_until_internal(&self, recursive: bool, timeout: Instant) -> bool {
         let result = if self.try_lock_shared_fast(recursive) {
             true
         } else {
@@ -210,34 +439,8 @@ impl RawRwLock {
     }
 
     #[inline]
-    pub fn try_lock_shared_for(&self, recursive: bool, timeout: Duration) -> bool {
-        let result = if self.try_lock_shared_fast(recursive) {
-            true
-        } else {
-            self.lock_shared_slow(recursive, Some(Instant::now() + timeout))
-        };
-        if result {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-        }
-        result
-    }
-
-    #[inline]
-    pub fn try_lock_shared(&self, recursive: bool) -> bool {
-        let result = if self.try_lock_shared_fast(recursive) {
-            true
-        } else {
-            self.try_lock_shared_slow(recursive)
-        };
-        if result {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-        }
-        result
-    }
-
-    #[inline]
-    pub fn unlock_shared(&self, force_fair: bool) {
-        unsafe { deadlock::release_resource(self as *const _ as usize) };
+    unsafe fn unlock_shared_internal(&self, force_fair: bool) {
+        deadlock::release_resource(self as *const _ as usize);
         let state = self.state.load(Ordering::Relaxed);
         if state & PARKED_BIT == 0
             || (state & UPGRADING_BIT == 0 && state & GUARD_COUNT_MASK != SHARED_GUARD)
@@ -272,155 +475,69 @@ impl RawRwLock {
 
         // We can't allow grabbing an upgradable lock while there are parked threads
         // since that could lead to writer starvation.
-        if state & PARKED_BIT != 0 {
-            return false;
-        }
-
-        if let Some(new_state) = checked_add(state, UPGRADABLE_GUARD) {
-            self.state
-                .compare_exchange_weak(state, new_state, Ordering::Acquire, Ordering::Relaxed)
-                .is_ok()
-        } else {
-            false
-        }
-    }
-
-    #[inline]
-    pub fn lock_upgradable(&self) {
-        if !self.try_lock_upgradable_fast() {
-            let result = self.lock_upgradable_slow(None);
-            debug_assert!(result);
-        }
-        unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-    }
-
-    #[inline]
-    pub fn try_lock_upgradable_until(&self, timeout: Instant) -> bool {
-        let result = if self.try_lock_upgradable_fast() {
-            true
-        } else {
-            self.lock_upgradable_slow(Some(timeout))
-        };
-        if result {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
-        }
-        result
-    }
-
-    #[inline]
-    pub fn try_lock_upgradable_for(&self, timeout: Duration) -> bool {
-        let result = if self.try_lock_upgradable_fast() {
-            true
-        } else {
-            self.lock_upgradable_slow(Some(Instant::now() + timeout))
-        };
-        if result {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+        if state & PARKED_BIT != 0 {
+            return false;
         }
-        result
-    }
 
-    #[inline]
-    pub fn try_lock_upgradable(&self) -> bool {
-        let result = if self.try_lock_upgradable_fast() {
-            true
+        if let Some(new_state) = checked_add(state, UPGRADABLE_GUARD) {
+            self.state
+                .compare_exchange_weak(state, new_state, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
         } else {
-            self.try_lock_upgradable_slow()
-        };
-        if result {
-            unsafe { deadlock::acquire_resource(self as *const _ as usize) };
+            false
         }
-        result
     }
 
+    // Checks whether the current thread holds any kind of lock on the rwlock.
+    // If locks aren't being tracked we can only check whether the rwlock is
+    // locked at all.
     #[inline]
-    pub fn unlock_upgradable(&self, force_fair: bool) {
-        unsafe { deadlock::release_resource(self as *const _ as usize) };
-        if self.state
-            .compare_exchange_weak(UPGRADABLE_GUARD, 0, Ordering::Release, Ordering::Relaxed)
-            .is_ok()
-        {
-            return;
-        }
-        self.unlock_upgradable_slow(force_fair);
+    pub(crate) fn is_held_by_current_thread(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & GUARD_COUNT_MASK != 0
+            && deadlock::is_resource_held(self as *const _ as usize)
     }
 
+    // Stops attributing a lock held by the current thread to it, so that the
+    // lock can be released by another thread after calling `attach` there.
     #[inline]
-    pub fn upgradable_to_shared(&self) {
-        let state = self.state
-            .fetch_sub(UPGRADABLE_GUARD - SHARED_GUARD, Ordering::Relaxed);
-
-        // Wake up parked shared and upgradable threads if there are any
-        if state & PARKED_BIT != 0 {
-            self.upgradable_to_shared_slow(state);
-        }
+    pub(crate) unsafe fn detach(&self) {
+        deadlock::release_resource(self as *const _ as usize);
     }
 
+    // Attributes a lock previously detached with `detach` to the current
+    // thread.
     #[inline]
-    pub fn upgradable_to_exclusive(&self) {
-        if self.state
-            .compare_exchange_weak(
-                UPGRADABLE_GUARD,
-                EXCLUSIVE_GUARD,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            )
-            .is_err()
-        {
-            let result = self.upgradable_to_exclusive_slow(None);
-            debug_assert!(result);
-        }
+    pub(crate) unsafe fn attach(&self) {
+        deadlock::acquire_resource(self as *const _ as usize);
     }
 
+    // Checks whether the current thread holds an exclusive lock on the rwlock.
     #[inline]
-    pub fn try_upgradable_to_exclusive_until(&self, timeout: Instant) -> bool {
-        if self.state
-            .compare_exchange_weak(
-                UPGRADABLE_GUARD,
-                EXCLUSIVE_GUARD,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            )
-            .is_ok()
-        {
-            true
-        } else {
-            self.upgradable_to_exclusive_slow(Some(timeout))
-        }
+    pub(crate) fn is_exclusive_held_by_current_thread(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & GUARD_COUNT_MASK == EXCLUSIVE_GUARD
+            && deadlock::is_resource_held(self as *const _ as usize)
     }
 
+    // Panics if the current thread holds an exclusive lock on the rwlock. Since
+    // exclusive locks can't coexist with other locks, any lock held by the
+    // current thread while the rwlock is exclusively locked must be that one.
     #[inline]
-    pub fn try_upgradable_to_exclusive_for(&self, timeout: Duration) -> bool {
-        if self.state
-            .compare_exchange_weak(
-                UPGRADABLE_GUARD,
-                EXCLUSIVE_GUARD,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            )
-            .is_ok()
-        {
-            true
-        } else {
-            self.upgradable_to_exclusive_slow(Some(Instant::now() + timeout))
+    fn check_not_write_locked_by_current_thread(&self, msg: &str) {
+        if self.state.load(Ordering::Relaxed) & GUARD_COUNT_MASK == EXCLUSIVE_GUARD {
+            deadlock::check_not_held(self as *const _ as usize, msg);
         }
     }
 
-    #[inline]
-    pub fn try_upgradable_to_exclusive(&self) -> bool {
-        self.state
-            .compare_exchange(
-                UPGRADABLE_GUARD,
-                EXCLUSIVE_GUARD,
-                Ordering::Relaxed,
-                Ordering::Relaxed,
-            )
-            .is_ok()
-    }
-
     #[cold]
     #[inline(never)]
     fn lock_exclusive_slow(&self, timeout: Option<Instant>) -> bool {
+        if timeout.is_none() {
+            deadlock::check_not_held(
+                self as *const _ as usize,
+                "attempted to write lock a rwlock which is already locked by the current thread",
+            );
+        }
+
         let mut spinwait = SpinWait::new();
         let mut state = self.state.load(Ordering::Relaxed);
         loop {
@@ -513,6 +630,14 @@ impl RawRwLock {
         }
     }
 
+    #[cold]
+    #[inline(never)]
+    fn bump_exclusive_slow(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.unlock_exclusive_slow(true);
+        self.lock_exclusive();
+    }
+
     #[cold]
     #[inline(never)]
     fn unlock_exclusive_slow(&self, force_fair: bool) {
@@ -593,6 +718,12 @@ impl RawRwLock {
     #[cold]
     #[inline(never)]
     fn lock_shared_slow(&self, recursive: bool, timeout: Option<Instant>) -> bool {
+        if timeout.is_none() {
+            self.check_not_write_locked_by_current_thread(
+                "attempted to read lock a rwlock which is write locked by the current thread",
+            );
+        }
+
         let mut spinwait = SpinWait::new();
         let mut spinwait_shared = SpinWait::new();
         let mut state = self.state.load(Ordering::Relaxed);
@@ -742,6 +873,14 @@ impl RawRwLock {
         }
     }
 
+    #[cold]
+    #[inline(never)]
+    fn bump_shared_slow(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.unlock_shared_slow(true);
+        self.lock_shared();
+    }
+
     #[cold]
     #[inline(never)]
     fn unlock_shared_slow(&self, force_fair: bool) {
@@ -851,6 +990,12 @@ impl RawRwLock {
     #[cold]
     #[inline(never)]
     fn lock_upgradable_slow(&self, timeout: Option<Instant>) -> bool {
+        if timeout.is_none() {
+            self.check_not_write_locked_by_current_thread(
+                "attempted to upgradable read lock a rwlock which is write locked by the current thread",
+            );
+        }
+
         let mut spinwait = SpinWait::new();
         let mut spinwait_shared = SpinWait::new();
         let mut state = self.state.load(Ordering::Relaxed);
@@ -984,6 +1129,14 @@ impl RawRwLock {
         }
     }
 
+    #[cold]
+    #[inline(never)]
+    fn bump_upgradable_slow(&self) {
+        unsafe { deadlock::release_resource(self as *const _ as usize) };
+        self.unlock_upgradable_slow(true);
+        self.lock_upgradable();
+    }
+
     #[cold]
     #[inline(never)]
     fn unlock_upgradable_slow(&self, force_fair: bool) {
@@ -1180,3 +1333,249 @@ impl RawRwLock {
         }
     }
 }
+
+#[cfg(feature = "async")]
+impl RawRwLock {
+    // Attempts to acquire an exclusive lock on behalf of an asynchronous task.
+    // If the rwlock is locked then the waiter is queued, creating it if
+    // necessary, and the waker is notified once the task should poll again.
+    // The lock is not attributed to any thread, in the same way as with
+    // `detach`.
+    pub(crate) fn poll_lock_exclusive(
+        &self,
+        waiter: &mut Option<AsyncWaiter>,
+        waker: &Waker,
+    ) -> Poll<()> {
+        // Check whether we were unparked since the last poll
+        if let Some(ref mut waiter) = *waiter {
+            if waiter.is_parked() {
+                match waiter.poll(waker) {
+                    // The thread that unparked us passed the lock on to us
+                    // directly without unlocking it.
+                    Some(TOKEN_HANDOFF) => return Poll::Ready(()),
+
+                    // We were unparked normally, try acquiring the lock again
+                    Some(_) => (),
+
+                    // Still waiting
+                    None => return Poll::Pending,
+                }
+            }
+        }
+
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            // Grab the lock if it isn't locked, even if there are other
+            // threads parked.
+            if let Some(new_state) = checked_add(state, EXCLUSIVE_GUARD) {
+                match self.state.compare_exchange_weak(
+                    state,
+                    new_state,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Poll::Ready(()),
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+
+            // Queue the task until it is woken up by an unlock. Unlike threads,
+            // tasks never spin since that would block the executor.
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || {
+                    let mut state = self.state.load(Ordering::Relaxed);
+                    loop {
+                        // If the rwlock is free, abort the park and try to grab
+                        // it immediately.
+                        if state & GUARD_COUNT_MASK == 0 {
+                            return false;
+                        }
+
+                        // Nothing to do if the parked bit is already set
+                        if state & PARKED_BIT != 0 {
+                            return true;
+                        }
+
+                        // Set the parked bit
+                        match self.state.compare_exchange_weak(
+                            state,
+                            state | PARKED_BIT,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        ) {
+                            Ok(_) => return true,
+                            Err(x) => state = x,
+                        }
+                    }
+                };
+                let waiter = waiter.get_or_insert_with(AsyncWaiter::new);
+                if waiter.park(addr, validate, TOKEN_EXCLUSIVE, waker) {
+                    return Poll::Pending;
+                }
+            }
+
+            // The validation function failed, try locking again
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    // Attempts to acquire a shared lock on behalf of an asynchronous task. See
+    // `poll_lock_exclusive`.
+    pub(crate) fn poll_lock_shared(
+        &self,
+        waiter: &mut Option<AsyncWaiter>,
+        waker: &Waker,
+    ) -> Poll<()> {
+        // Check whether we were unparked since the last poll
+        let mut unparked = false;
+        if let Some(ref mut waiter) = *waiter {
+            if waiter.is_parked() {
+                match waiter.poll(waker) {
+                    // The thread that unparked us passed the lock on to us
+                    // directly without unlocking it.
+                    Some(TOKEN_HANDOFF) => return Poll::Ready(()),
+
+                    // We were unparked normally, try acquiring the lock again
+                    Some(_) => unparked = true,
+
+                    // Still waiting
+                    None => return Poll::Pending,
+                }
+            }
+        }
+
+        // Note that hardware lock elision is never used here since the lock
+        // may be released on a different thread.
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            // Grab the lock if there are no exclusive threads locked or
+            // waiting. However if we were unparked then we are allowed to grab
+            // the lock even if there are pending exclusive threads.
+            if unparked || state & PARKED_BIT == 0 {
+                if let Some(new_state) = checked_add(state, SHARED_GUARD) {
+                    match self.state.compare_exchange_weak(
+                        state,
+                        new_state,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => return Poll::Ready(()),
+                        Err(x) => state = x,
+                    }
+                    continue;
+                }
+            }
+
+            // Queue the task until it is woken up by an unlock
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || {
+                    let mut state = self.state.load(Ordering::Relaxed);
+                    loop {
+                        // Nothing to do if the parked bit is already set
+                        if state & PARKED_BIT != 0 {
+                            return true;
+                        }
+
+                        // If the parked bit is not set then it means we are at
+                        // the front of the queue. If there is space for another
+                        // lock then we should abort the park and try acquiring
+                        // the lock again.
+                        if state & GUARD_COUNT_MASK != GUARD_COUNT_MASK {
+                            return false;
+                        }
+
+                        // Set the parked bit
+                        match self.state.compare_exchange_weak(
+                            state,
+                            state | PARKED_BIT,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        ) {
+                            Ok(_) => return true,
+                            Err(x) => state = x,
+                        }
+                    }
+                };
+                let waiter = waiter.get_or_insert_with(AsyncWaiter::new);
+                if waiter.park(addr, validate, TOKEN_SHARED, waker) {
+                    return Poll::Pending;
+                }
+            }
+
+            // The validation function failed, try locking again
+            state = self.state.load(Ordering::Relaxed);
+            unparked = true;
+        }
+    }
+
+    // Abandons an exclusive lock attempt started with `poll_lock_exclusive`,
+    // for example because the future waiting for the lock was dropped.
+    pub(crate) fn cancel_lock_exclusive(&self, waiter: &mut Option<AsyncWaiter>) {
+        if self.cancel_lock(waiter) {
+            unsafe {
+                self.attach();
+                self.unlock_exclusive();
+            }
+        }
+    }
+
+    // Abandons a shared lock attempt started with `poll_lock_shared`.
+    pub(crate) fn cancel_lock_shared(&self, waiter: &mut Option<AsyncWaiter>) {
+        if self.cancel_lock(waiter) {
+            unsafe {
+                self.attach();
+                self.unlock_shared();
+            }
+        }
+    }
+
+    // Removes a waiting task from the queue. Returns true if the lock was
+    // already handed off to it, in which case the caller must release it.
+    fn cancel_lock(&self, waiter: &mut Option<AsyncWaiter>) -> bool {
+        let waiter = match *waiter {
+            Some(ref mut waiter) if waiter.is_parked() => waiter,
+            _ => return false,
+        };
+        let timed_out = |_, was_last_thread| {
+            // Clear the parked bit if we were the last parked thread
+            if was_last_thread {
+                self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+            }
+        };
+        match unsafe { waiter.cancel(timed_out) } {
+            // We were still in the queue, nothing else to do
+            None => false,
+
+            // The lock was handed off to us
+            Some((_, TOKEN_HANDOFF)) => true,
+
+            // We were unparked in order to retry locking the rwlock. Since we
+            // won't do that, make sure that the wakeup isn't lost for the other
+            // waiters: if the rwlock is still unlocked then bounce it to wake
+            // up the next ones, otherwise the current owners will do so. Note
+            // that try_lock_exclusive can't be used since it fails while the
+            // parked bit is set, which is the case if there are other waiters.
+            Some(_) => {
+                let mut state = self.state.load(Ordering::Relaxed);
+                while let Some(new_state) = checked_add(state, EXCLUSIVE_GUARD) {
+                    match self.state.compare_exchange_weak(
+                        state,
+                        new_state,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    ) {
+                        Ok(_) => {
+                            self.unlock_exclusive_slow(false);
+                            break;
+                        }
+                        Err(x) => state = x,
+                    }
+                }
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::sync::atomic::{fence, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::time::{Duration, Instant};
+use raw_rwlock::RawRwLock;
+use lock_api::{RawRwLock as RawRwLockTrait, RawRwLockDowngrade, RawRwLockFair,
+               RawRwLockOptimistic, RawRwLockRecursive, RawRwLockRecursiveTimed, RawRwLockTimed,
+               RawRwLockUpgrade, RawRwLockUpgradeDowngrade, RawRwLockUpgradeFair,
+               RawRwLockUpgradeTimed};
+
+/// Raw reader-writer lock type which supports optimistic reads.
+///
+/// This wraps a `RawRwLock` and adds a version counter which is incremented
+/// when an exclusive lock is acquired and again when it is released, so it is
+/// odd exactly while a writer holds the lock. An optimistic reader takes the
+/// current (even) version as its stamp, and the stamp is still valid as long
+/// as the version hasn't changed.
+///
+/// Keeping the version in a separate word means that `RawRwLock` itself stays
+/// 1 word in size and writers which don't need optimistic reads don't pay for
+/// updating the counter. This lock takes 2 words.
+pub struct RawStampedRwLock {
+    lock: RawRwLock,
+    version: AtomicUsize,
+}
+
+unsafe impl RawRwLockTrait for RawStampedRwLock {
+    const INIT: RawStampedRwLock = RawStampedRwLock {
+        lock: <RawRwLock as RawRwLockTrait>::INIT,
+        version: ATOMIC_USIZE_INIT,
+    };
+
+    #[inline]
+    fn lock_exclusive(&self) {
+        self.lock.lock_exclusive();
+        self.begin_write();
+    }
+
+    #[inline]
+    fn try_lock_exclusive(&self) -> bool {
+        if self.lock.try_lock_exclusive() {
+            self.begin_write();
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive(&self) {
+        self.end_write();
+        self.lock.unlock_exclusive();
+    }
+
+    #[inline]
+    fn lock_shared(&self) {
+        self.lock.lock_shared();
+    }
+
+    #[inline]
+    fn try_lock_shared(&self) -> bool {
+        self.lock.try_lock_shared()
+    }
+
+    #[inline]
+    unsafe fn unlock_shared(&self) {
+        self.lock.unlock_shared();
+    }
+}
+
+unsafe impl RawRwLockFair for RawStampedRwLock {
+    #[inline]
+    unsafe fn unlock_shared_fair(&self) {
+        self.lock.unlock_shared_fair();
+    }
+
+    #[inline]
+    unsafe fn unlock_exclusive_fair(&self) {
+        self.end_write();
+        self.lock.unlock_exclusive_fair();
+    }
+
+    #[inline]
+    unsafe fn bump_shared(&self) {
+        self.lock.bump_shared();
+    }
+
+    #[inline]
+    unsafe fn bump_exclusive(&self) {
+        // Other threads may take the lock while it is bumped, so this counts
+        // as releasing and re-acquiring it.
+        self.end_write();
+        self.lock.bump_exclusive();
+        self.begin_write();
+    }
+}
+
+unsafe impl RawRwLockDowngrade for RawStampedRwLock {
+    #[inline]
+    unsafe fn downgrade(&self) {
+        self.end_write();
+        self.lock.downgrade();
+    }
+}
+
+unsafe impl RawRwLockTimed for RawStampedRwLock {
+    #[inline]
+    fn try_lock_shared_until(&self, timeout: Instant) -> bool {
+        self.lock.try_lock_shared_until(timeout)
+    }
+
+    #[inline]
+    fn try_lock_shared_for(&self, timeout: Duration) -> bool {
+        self.lock.try_lock_shared_for(timeout)
+    }
+
+    #[inline]
+    fn try_lock_exclusive_until(&self, timeout: Instant) -> bool {
+        if self.lock.try_lock_exclusive_until(timeout) {
+            self.begin_write();
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    fn try_lock_exclusive_for(&self, timeout: Duration) -> bool {
+        self.try_lock_exclusive_until(Instant::now() + timeout)
+    }
+}
+
+unsafe impl RawRwLockRecursive for RawStampedRwLock {
+    #[inline]
+    fn lock_shared_recursive(&self) {
+        self.lock.lock_shared_recursive();
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive(&self) -> bool {
+        self.lock.try_lock_shared_recursive()
+    }
+}
+
+unsafe impl RawRwLockRecursiveTimed for RawStampedRwLock {
+    #[inline]
+    fn try_lock_shared_recursive_until(&self, timeout: Instant) -> bool {
+        self.lock.try_lock_shared_recursive_until(timeout)
+    }
+
+    #[inline]
+    fn try_lock_shared_recursive_for(&self, timeout: Duration) -> bool {
+        self.lock.try_lock_shared_recursive_for(timeout)
+    }
+}
+
+unsafe impl RawRwLockUpgrade for RawStampedRwLock {
+    #[inline]
+    fn lock_upgradable(&self) {
+        self.lock.lock_upgradable();
+    }
+
+    #[inline]
+    fn try_lock_upgradable(&self) -> bool {
+        self.lock.try_lock_upgradable()
+    }
+
+    #[inline]
+    unsafe fn unlock_upgradable(&self) {
+        self.lock.unlock_upgradable();
+    }
+
+    #[inline]
+    unsafe fn upgrade(&self) {
+        self.lock.upgrade();
+        self.begin_write();
+    }
+
+    #[inline]
+    unsafe fn try_upgrade(&self) -> bool {
+        if self.lock.try_upgrade() {
+            self.begin_write();
+            true
+        } else {
+            false
+        }
+    }
+}
+
+unsafe impl RawRwLockUpgradeFair for RawStampedRwLock {
+    #[inline]
+    unsafe fn unlock_upgradable_fair(&self) {
+        self.lock.unlock_upgradable_fair();
+    }
+
+    #[inline]
+    unsafe fn bump_upgradable(&self) {
+        self.lock.bump_upgradable();
+    }
+}
+
+unsafe impl RawRwLockUpgradeDowngrade for RawStampedRwLock {
+    #[inline]
+    unsafe fn downgrade_upgradable(&self) {
+        self.lock.downgrade_upgradable();
+    }
+}
+
+unsafe impl RawRwLockUpgradeTimed for RawStampedRwLock {
+    #[inline]
+    fn try_lock_upgradable_until(&self, timeout: Instant) -> bool {
+        self.lock.try_lock_upgradable_until(timeout)
+    }
+
+    #[inline]
+    fn try_lock_upgradable_for(&self, timeout: Duration) -> bool {
+        self.lock.try_lock_upgradable_for(timeout)
+    }
+
+    #[inline]
+    unsafe fn try_upgrade_until(&self, timeout: Instant) -> bool {
+        if self.lock.try_upgrade_until(timeout) {
+            self.begin_write();
+            true
+        } else {
+            false
+        }
+    }
+
+    #[inline]
+    unsafe fn try_upgrade_for(&self, timeout: Duration) -> bool {
+        self.try_upgrade_until(Instant::now() + timeout)
+    }
+}
+
+unsafe impl RawRwLockOptimistic for RawStampedRwLock {
+    #[inline]
+    fn optimistic_stamp(&self) -> Option<usize> {
+        let version = self.version.load(Ordering::Acquire);
+        if version & 1 == 0 {
+            Some(version)
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    fn validate(&self, stamp: usize) -> bool {
+        // Make sure the speculative reads of the data happen before we check
+        // the version. This pairs with the release fence in begin_write.
+        fence(Ordering::Acquire);
+        self.version.load(Ordering::Relaxed) == stamp
+    }
+}
+
+impl RawStampedRwLock {
+    // Called after acquiring an exclusive lock, before the data is modified.
+    #[inline]
+    fn begin_write(&self) {
+        // Only the thread holding the exclusive lock modifies the version, so
+        // there is no need for an atomic increment. The fence ensures that an
+        // optimistic reader which sees any of our writes to the data also sees
+        // the odd version when it validates its stamp.
+        let version = self.version.load(Ordering::Relaxed);
+        self.version.store(version.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+    }
+
+    // Called before releasing an exclusive lock, after the data was modified.
+    #[inline]
+    fn end_write(&self) {
+        let version = self.version.load(Ordering::Relaxed);
+        self.version.store(version.wrapping_add(1), Ordering::Release);
+    }
+}
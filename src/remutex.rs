@@ -12,6 +12,8 @@ use std::fmt;
 use std::mem;
 use std::marker::PhantomData;
 use raw_remutex::RawReentrantMutex;
+use raw_mutex::RawMutex;
+use lock_api::{RawMutex as RawMutexTrait, RawMutexFair, RawMutexTimed};
 
 #[cfg(feature = "owning_ref")]
 use owning_ref::StableAddress;
@@ -27,14 +29,15 @@ use owning_ref::StableAddress;
 /// - `ReentrantMutexGuard` is not `Send`.
 ///
 /// See [`Mutex`](struct.Mutex.html) for more details about the underlying mutex
-/// primitive.
-pub struct ReentrantMutex<T: ?Sized> {
-    raw: RawReentrantMutex,
+/// primitive. Like `Mutex`, the type parameter `R` selects the raw mutex which
+/// is used to implement the lock.
+pub struct ReentrantMutex<T: ?Sized, R = RawMutex> {
+    raw: RawReentrantMutex<R>,
     data: UnsafeCell<T>,
 }
 
-unsafe impl<T: ?Sized + Send> Send for ReentrantMutex<T> {}
-unsafe impl<T: ?Sized + Send> Sync for ReentrantMutex<T> {}
+unsafe impl<T: ?Sized + Send, R: RawMutexTrait + Send> Send for ReentrantMutex<T, R> {}
+unsafe impl<T: ?Sized + Send, R: RawMutexTrait + Sync> Sync for ReentrantMutex<T, R> {}
 
 /// An RAII implementation of a "scoped lock" of a reentrant mutex. When this structure
 /// is dropped (falls out of scope), the lock will be unlocked.
@@ -42,13 +45,30 @@ unsafe impl<T: ?Sized + Send> Sync for ReentrantMutex<T> {}
 /// The data protected by the mutex can be accessed through this guard via its
 /// `Deref` implementation.
 #[must_use]
-pub struct ReentrantMutexGuard<'a, T: ?Sized + 'a> {
-    raw: &'a RawReentrantMutex,
+pub struct ReentrantMutexGuard<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a = RawMutex> {
+    raw: &'a RawReentrantMutex<R>,
     data: *const T,
     marker: PhantomData<&'a T>,
 }
 
-unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for ReentrantMutexGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawMutexTrait + Sync + 'a> Sync
+    for ReentrantMutexGuard<'a, T, R> {}
+
+/// An RAII mutex guard returned by `ReentrantMutexGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedReentrantMutexGuard` and
+/// `ReentrantMutexGuard` is that the former can't be used with a `Condvar`,
+/// since the guard no longer refers to the whole of the protected data.
+#[must_use]
+pub struct MappedReentrantMutexGuard<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a = RawMutex> {
+    raw: &'a RawReentrantMutex<R>,
+    data: *const T,
+    marker: PhantomData<&'a T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawMutexTrait + Sync + 'a> Sync
+    for MappedReentrantMutexGuard<'a, T, R> {}
 
 impl<T> ReentrantMutex<T> {
     /// Creates a new reentrant mutex in an unlocked state ready for use.
@@ -57,7 +77,7 @@ impl<T> ReentrantMutex<T> {
     pub const fn new(val: T) -> ReentrantMutex<T> {
         ReentrantMutex {
             data: UnsafeCell::new(val),
-            raw: RawReentrantMutex::new(),
+            raw: RawReentrantMutex::INIT,
         }
     }
 
@@ -67,7 +87,19 @@ impl<T> ReentrantMutex<T> {
     pub fn new(val: T) -> ReentrantMutex<T> {
         ReentrantMutex {
             data: UnsafeCell::new(val),
-            raw: RawReentrantMutex::new(),
+            raw: RawReentrantMutex::INIT,
+        }
+    }
+}
+
+impl<T, R: RawMutexTrait> ReentrantMutex<T, R> {
+    /// Creates a new reentrant mutex using the given raw mutex, which must be
+    /// in an unlocked state.
+    #[inline]
+    pub fn with_raw_mutex(raw: R, val: T) -> ReentrantMutex<T, R> {
+        ReentrantMutex {
+            data: UnsafeCell::new(val),
+            raw: RawReentrantMutex::new(raw),
         }
     }
 
@@ -78,9 +110,9 @@ impl<T> ReentrantMutex<T> {
     }
 }
 
-impl<T: ?Sized> ReentrantMutex<T> {
+impl<T: ?Sized, R: RawMutexTrait> ReentrantMutex<T, R> {
     #[inline]
-    fn guard(&self) -> ReentrantMutexGuard<T> {
+    fn guard(&self) -> ReentrantMutexGuard<T, R> {
         ReentrantMutexGuard {
             raw: &self.raw,
             data: self.data.get(),
@@ -99,7 +131,7 @@ impl<T: ?Sized> ReentrantMutex<T> {
     /// returned to allow scoped unlock of the lock. When the guard goes out of
     /// scope, the mutex will be unlocked.
     #[inline]
-    pub fn lock(&self) -> ReentrantMutexGuard<T> {
+    pub fn lock(&self) -> ReentrantMutexGuard<T, R> {
         self.raw.lock();
         self.guard()
     }
@@ -112,7 +144,7 @@ impl<T: ?Sized> ReentrantMutex<T> {
     ///
     /// This function does not block.
     #[inline]
-    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<T>> {
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<T, R>> {
         if self.raw.try_lock() {
             Some(self.guard())
         } else {
@@ -120,34 +152,6 @@ impl<T: ?Sized> ReentrantMutex<T> {
         }
     }
 
-    /// Attempts to acquire this lock until a timeout is reached.
-    ///
-    /// If the lock could not be acquired before the timeout expired, then
-    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
-    /// be unlocked when the guard is dropped.
-    #[inline]
-    pub fn try_lock_for(&self, timeout: Duration) -> Option<ReentrantMutexGuard<T>> {
-        if self.raw.try_lock_for(timeout) {
-            Some(self.guard())
-        } else {
-            None
-        }
-    }
-
-    /// Attempts to acquire this lock until a timeout is reached.
-    ///
-    /// If the lock could not be acquired before the timeout expired, then
-    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
-    /// be unlocked when the guard is dropped.
-    #[inline]
-    pub fn try_lock_until(&self, timeout: Instant) -> Option<ReentrantMutexGuard<T>> {
-        if self.raw.try_lock_until(timeout) {
-            Some(self.guard())
-        } else {
-            None
-        }
-    }
-
     /// Returns a mutable reference to the underlying data.
     ///
     /// Since this call borrows the `ReentrantMutex` mutably, no actual locking needs to
@@ -157,6 +161,24 @@ impl<T: ?Sized> ReentrantMutex<T> {
         unsafe { &mut *self.data.get() }
     }
 
+    /// Asserts that the mutex is held by the current thread.
+    ///
+    /// This is intended for functions which require their caller to hold a
+    /// particular lock. The check is only performed in debug builds and does
+    /// nothing otherwise.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, this function will panic if the mutex is not held by
+    /// the current thread.
+    #[inline]
+    pub fn assert_held_by_current_thread(&self) {
+        debug_assert!(
+            self.raw.is_held_by_current_thread(),
+            "reentrant mutex is not held by the current thread"
+        );
+    }
+
     /// Releases the mutex.
     ///
     /// # Safety
@@ -166,9 +188,11 @@ impl<T: ?Sized> ReentrantMutex<T> {
     /// leaked (e.g. with `mem::forget`). The mutex must be locked.
     #[inline]
     pub unsafe fn raw_unlock(&self) {
-        self.raw.unlock(false);
+        self.raw.unlock();
     }
+}
 
+impl<T: ?Sized, R: RawMutexFair> ReentrantMutex<T, R> {
     /// Releases the mutex using a fair unlock protocol.
     ///
     /// See `ReentrantMutexGuard::unlock_fair`.
@@ -180,10 +204,42 @@ impl<T: ?Sized> ReentrantMutex<T> {
     /// leaked (e.g. with `mem::forget`). The mutex must be locked.
     #[inline]
     pub unsafe fn raw_unlock_fair(&self) {
-        self.raw.unlock(true);
+        self.raw.unlock_fair();
+    }
+}
+
+impl<T: ?Sized, R: RawMutexTimed> ReentrantMutex<T, R> {
+
+    /// Attempts to acquire this lock until a timeout is reached.
+    ///
+    /// If the lock could not be acquired before the timeout expired, then
+    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
+    /// be unlocked when the guard is dropped.
+    #[inline]
+    pub fn try_lock_for(&self, timeout: Duration) -> Option<ReentrantMutexGuard<T, R>> {
+        if self.raw.try_lock_for(timeout) {
+            Some(self.guard())
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this lock until a timeout is reached.
+    ///
+    /// If the lock could not be acquired before the timeout expired, then
+    /// `None` is returned. Otherwise, an RAII guard is returned. The lock will
+    /// be unlocked when the guard is dropped.
+    #[inline]
+    pub fn try_lock_until(&self, timeout: Instant) -> Option<ReentrantMutexGuard<T, R>> {
+        if self.raw.try_lock_until(timeout) {
+            Some(self.guard())
+        } else {
+            None
+        }
     }
 }
-impl ReentrantMutex<()> {
+
+impl<R: RawMutexTrait> ReentrantMutex<(), R> {
     /// Acquires a mutex, blocking the current thread until it is able to do so.
     ///
     /// This is similar to `lock`, except that a `ReentrantMutexGuard` is not returned.
@@ -204,14 +260,14 @@ impl ReentrantMutex<()> {
     }
 }
 
-impl<T: ?Sized + Default> Default for ReentrantMutex<T> {
+impl<T: Default, R: RawMutexTrait> Default for ReentrantMutex<T, R> {
     #[inline]
-    fn default() -> ReentrantMutex<T> {
-        ReentrantMutex::new(Default::default())
+    fn default() -> ReentrantMutex<T, R> {
+        ReentrantMutex::with_raw_mutex(R::INIT, Default::default())
     }
 }
 
-impl<T: ?Sized + fmt::Debug> fmt::Debug for ReentrantMutex<T> {
+impl<T: ?Sized + fmt::Debug, R: RawMutexTrait> fmt::Debug for ReentrantMutex<T, R> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.try_lock() {
             Some(guard) => f.debug_struct("ReentrantMutex")
@@ -222,7 +278,79 @@ impl<T: ?Sized + fmt::Debug> fmt::Debug for ReentrantMutex<T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> ReentrantMutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> ReentrantMutexGuard<'a, T, R> {
+    /// Make a new `MappedReentrantMutexGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `ReentrantMutexGuard` passed
+    /// in already locked the mutex.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ReentrantMutexGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedReentrantMutexGuard<'a, U, R>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let raw = orig.raw;
+        let data = f(unsafe { &*orig.data });
+        mem::forget(orig);
+        MappedReentrantMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedReentrantMutexGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ReentrantMutexGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedReentrantMutexGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &*s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedReentrantMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Temporarily unlocks the mutex to execute the given function.
+    ///
+    /// This only releases the lock held by this guard: if the current thread
+    /// has locked the mutex recursively then it remains locked while `f` runs.
+    /// The lock is re-acquired before this function returns, even if `f`
+    /// panics.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ReentrantMutexGuard::unlocked(&mut guard, ...)`. A method would
+    /// interfere with methods of the same name on the contents of the locked
+    /// data.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock() };
+        defer!(raw.lock());
+        f()
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexFair + 'a> ReentrantMutexGuard<'a, T, R> {
     /// Unlocks the mutex using a fair unlock protocol.
     ///
     /// By default, mutexes are unfair and allow the current thread to re-lock
@@ -237,35 +365,122 @@ impl<'a, T: ?Sized + 'a> ReentrantMutexGuard<'a, T> {
     /// using this method instead of dropping the `ReentrantMutexGuard` normally.
     #[inline]
     pub fn unlock_fair(self) {
-        self.raw.unlock(true);
+        unsafe { self.raw.unlock_fair() };
         mem::forget(self);
     }
+    /// Temporarily unlocks the mutex to execute the given function.
+    ///
+    /// The mutex is unlocked using a fair unlock protocol. This is otherwise
+    /// identical to `ReentrantMutexGuard::unlocked`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_fair() };
+        defer!(raw.lock());
+        f()
+    }
 
-    /// Make a new `ReentrantMutexGuard` for a component of the locked data.
+    /// Temporarily yields the mutex to a waiting thread if there is one.
     ///
-    /// This operation cannot fail as the `ReentrantMutexGuard` passed
+    /// This has no effect if the current thread has locked the mutex
+    /// recursively, since the mutex can't be handed off in that case.
+    /// Otherwise it is functionally equivalent to calling `unlock_fair`
+    /// followed by `lock`, however it can be much more efficient in the case
+    /// where there are no waiting threads.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `ReentrantMutexGuard::bump(&mut guard)`. A method would
+    /// interfere with methods of the same name on the contents of the locked
+    /// data.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.raw.bump() };
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Deref for ReentrantMutexGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Drop for ReentrantMutexGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawMutexTrait> StableAddress for ReentrantMutexGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> MappedReentrantMutexGuard<'a, T, R> {
+    /// Make a new `MappedReentrantMutexGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedReentrantMutexGuard` passed
     /// in already locked the mutex.
     ///
     /// This is an associated function that needs to be
-    /// used as `ReentrantMutexGuard::map(...)`. A method would interfere with
-    /// methods of the same name on the contents of the locked data.
+    /// used as `MappedReentrantMutexGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
     #[inline]
-    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> ReentrantMutexGuard<'a, U>
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedReentrantMutexGuard<'a, U, R>
     where
         F: FnOnce(&T) -> &U,
     {
         let raw = orig.raw;
         let data = f(unsafe { &*orig.data });
         mem::forget(orig);
-        ReentrantMutexGuard {
+        MappedReentrantMutexGuard {
             raw,
             data,
             marker: PhantomData,
         }
     }
+
+    /// Attempts to make a new `MappedReentrantMutexGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedReentrantMutexGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedReentrantMutexGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &*s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedReentrantMutexGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawMutexFair + 'a> MappedReentrantMutexGuard<'a, T, R> {
+    /// Unlocks the `ReentrantMutex` using a fair unlock protocol.
+    ///
+    /// See `ReentrantMutexGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.raw.unlock_fair() };
+        mem::forget(self);
+    }
 }
 
-impl<'a, T: ?Sized + 'a> Deref for ReentrantMutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Deref for MappedReentrantMutexGuard<'a, T, R> {
     type Target = T;
     #[inline]
     fn deref(&self) -> &T {
@@ -273,22 +488,31 @@ impl<'a, T: ?Sized + 'a> Deref for ReentrantMutexGuard<'a, T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> Drop for ReentrantMutexGuard<'a, T> {
+impl<'a, T: ?Sized + 'a, R: RawMutexTrait + 'a> Drop for MappedReentrantMutexGuard<'a, T, R> {
     #[inline]
     fn drop(&mut self) {
-        self.raw.unlock(false);
+        unsafe { self.raw.unlock() };
     }
 }
 
 #[cfg(feature = "owning_ref")]
-unsafe impl<'a, T: ?Sized> StableAddress for ReentrantMutexGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized, R: RawMutexTrait> StableAddress
+    for MappedReentrantMutexGuard<'a, T, R> {}
+
+// Helper function used by Condvar, not publicly exported
+#[inline]
+pub(crate) fn guard_lock<'a, T: ?Sized, R: RawMutexTrait>(
+    guard: &ReentrantMutexGuard<'a, T, R>,
+) -> &'a RawReentrantMutex<R> {
+    guard.raw
+}
 
 #[cfg(test)]
 mod tests {
     use std::cell::RefCell;
     use std::sync::Arc;
     use std::thread;
-    use ReentrantMutex;
+    use {MappedReentrantMutexGuard, ReentrantMutex, ReentrantMutexGuard};
 
     #[test]
     fn smoke() {
@@ -338,6 +562,51 @@ mod tests {
         let _lock3 = m.try_lock();
     }
 
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_reentrant_mutex_assert_held() {
+        let m = Arc::new(ReentrantMutex::new(()));
+        let m2 = m.clone();
+        let _g = m.lock();
+        m.assert_held_by_current_thread();
+        assert!(thread::spawn(move || m2.assert_held_by_current_thread()).join().is_err());
+    }
+
+    #[test]
+    fn test_reentrant_mutex_map() {
+        let m = ReentrantMutex::new((1, RefCell::new(2)));
+        let guard = ReentrantMutexGuard::map(m.lock(), |x| &x.1);
+        *guard.borrow_mut() += 1;
+        let guard2 = ReentrantMutexGuard::try_map(m.lock(), |x| Some(&x.0)).ok().unwrap();
+        let guard2 = MappedReentrantMutexGuard::try_map(guard2, |_| None::<&i32>)
+            .err()
+            .unwrap();
+        assert_eq!(*guard2, 1);
+        drop(guard2);
+        let guard = MappedReentrantMutexGuard::map(guard, |x| x);
+        assert_eq!(*guard.borrow(), 3);
+    }
+
+    #[test]
+    fn test_reentrant_mutex_unlocked() {
+        let m = Arc::new(ReentrantMutex::new(0));
+        let outer = m.lock();
+        let mut inner = m.lock();
+        ReentrantMutexGuard::unlocked(&mut inner, || {
+            let m2 = m.clone();
+            assert!(thread::spawn(move || m2.try_lock().is_none()).join().unwrap());
+        });
+        ReentrantMutexGuard::bump(&mut inner);
+        drop(outer);
+
+        ReentrantMutexGuard::unlocked_fair(&mut inner, || {
+            let m2 = m.clone();
+            assert!(thread::spawn(move || m2.try_lock().is_some()).join().unwrap());
+        });
+        let m2 = m.clone();
+        assert!(thread::spawn(move || m2.try_lock().is_none()).join().unwrap());
+    }
+
     #[test]
     fn test_reentrant_mutex_debug() {
         let mutex = ReentrantMutex::new(vec![0u8, 10]);
@@ -10,8 +10,15 @@ use std::ops::{Deref, DerefMut};
 use std::time::{Duration, Instant};
 use std::fmt;
 use std::mem;
+use std::ptr;
+use std::sync::Arc;
 use std::marker::PhantomData;
 use raw_rwlock::RawRwLock;
+use raw_stamped_rwlock::RawStampedRwLock;
+use lock_api::{RawRwLock as RawRwLockTrait, RawRwLockDowngrade, RawRwLockFair,
+               RawRwLockOptimistic, RawRwLockRecursive, RawRwLockRecursiveTimed,
+               RawRwLockTimed, RawRwLockUpgrade, RawRwLockUpgradeDowngrade,
+               RawRwLockUpgradeFair, RawRwLockUpgradeTimed};
 
 #[cfg(feature = "owning_ref")]
 use owning_ref::StableAddress;
@@ -56,11 +63,21 @@ use owning_ref::StableAddress;
 /// or `RwLockWriteGuard::unlock_fair` when unlocking a mutex instead of simply
 /// dropping the guard.
 ///
+/// # Custom raw rwlocks
+///
+/// The type parameter `R` selects the raw rwlock which is used to implement the
+/// lock. It defaults to the rwlock provided by this crate, but any type which
+/// implements `lock_api::RawRwLock` can be used instead. Methods such as
+/// `upgradable_read`, `read_recursive` or the timed and fair variants are only
+/// available if the raw rwlock implements the corresponding trait from
+/// `lock_api`.
+///
 /// # Differences from the standard library `RwLock`
 ///
 /// - Supports atomically downgrading a write lock into a read lock.
 /// - Task-fair locking policy instead of an unspecified platform default.
-/// - No poisoning, the lock is released normally on panic.
+/// - No poisoning, the lock is released normally on panic. `PoisonRwLock` can be
+///   used if poisoning is needed.
 /// - Only requires 1 word of space, whereas the standard library boxes the
 ///   `RwLock` due to platform limitations.
 /// - Can be statically constructed (requires the `const_fn` nightly feature).
@@ -94,46 +111,222 @@ use owning_ref::StableAddress;
 ///     assert_eq!(*w, 6);
 /// } // write lock is dropped here
 /// ```
-pub struct RwLock<T: ?Sized> {
-    raw: RawRwLock,
+pub struct RwLock<T: ?Sized, R = RawRwLock> {
+    raw: R,
     data: UnsafeCell<T>,
 }
 
-unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
-unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+unsafe impl<T: ?Sized + Send, R: RawRwLockTrait + Send> Send for RwLock<T, R> {}
+unsafe impl<T: ?Sized + Send + Sync, R: RawRwLockTrait + Sync> Sync for RwLock<T, R> {}
+
+/// A reader-writer lock which supports optimistic reads.
+///
+/// This wraps an `RwLock` using `RawStampedRwLock` as its raw lock, and
+/// dereferences to it for all the usual locking methods. In addition, readers
+/// can take a stamp with `optimistic_stamp`, read the data without acquiring
+/// the lock, and then `validate` the stamp to check that no writer intervened.
+/// This avoids touching the shared reader count, which makes read-mostly data
+/// scale better across cores. `read_optimistic` wraps this pattern and falls
+/// back to `read` if the validation fails.
+///
+/// Unlike `RwLock`, this takes 2 words of space and writers have to update a
+/// version counter when locking and unlocking.
+///
+/// # Examples
+///
+/// Reading the data without the lock is only sound if it can't race with a
+/// writer, so the parts of the data which are read optimistically must be
+/// atomics which writers only modify through a shared reference:
+///
+/// ```
+/// use parking_lot::StampedRwLock;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// let lock = StampedRwLock::new([AtomicUsize::new(0), AtomicUsize::new(0)]);
+/// {
+///     let pair = lock.write();
+///     pair[0].store(5, Ordering::Relaxed);
+///     pair[1].store(5, Ordering::Relaxed);
+/// }
+///
+/// let stamp = lock.optimistic_stamp().unwrap();
+/// let pair = unsafe { &*lock.data_ptr() };
+/// let (a, b) = (pair[0].load(Ordering::Relaxed), pair[1].load(Ordering::Relaxed));
+/// let (a, b) = if lock.validate(stamp) {
+///     (a, b)
+/// } else {
+///     let pair = lock.read();
+///     (pair[0].load(Ordering::Relaxed), pair[1].load(Ordering::Relaxed))
+/// };
+/// assert_eq!(a + b, 10);
+/// ```
+pub struct StampedRwLock<T: ?Sized> {
+    lock: RwLock<T, RawStampedRwLock>,
+}
 
 /// RAII structure used to release the shared read access of a lock when
 /// dropped.
 #[must_use]
-pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
-    raw: &'a RawRwLock,
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a = RawRwLock> {
+    raw: &'a R,
     data: *const T,
     marker: PhantomData<&'a T>,
 }
 
-unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for RwLockReadGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawRwLockTrait + Sync + 'a> Sync
+    for RwLockReadGuard<'a, T, R> {}
+
+/// An RAII rwlock guard returned by `RwLockReadGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedRwLockReadGuard` and `RwLockReadGuard`
+/// is that the former can't be used with a `Condvar`, since the guard no
+/// longer refers to the whole of the protected data.
+#[must_use]
+pub struct MappedRwLockReadGuard<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a = RawRwLock> {
+    raw: &'a R,
+    data: *const T,
+    marker: PhantomData<&'a T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawRwLockTrait + Sync + 'a> Sync
+    for MappedRwLockReadGuard<'a, T, R> {}
 
 /// RAII structure used to release the exclusive write access of a lock when
 /// dropped.
 #[must_use]
-pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
-    raw: &'a RawRwLock,
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a = RawRwLock> {
+    raw: &'a R,
     data: *mut T,
     marker: PhantomData<&'a mut T>,
 }
 
-unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for RwLockWriteGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawRwLockTrait + Sync + 'a> Sync
+    for RwLockWriteGuard<'a, T, R> {}
+
+/// An RAII rwlock guard returned by `RwLockWriteGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedRwLockWriteGuard` and `RwLockWriteGuard`
+/// is that the former doesn't support downgrading or being used with a
+/// `Condvar`, since the guard no longer refers to the whole of the protected
+/// data.
+#[must_use]
+pub struct MappedRwLockWriteGuard<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a = RawRwLock> {
+    raw: &'a R,
+    data: *mut T,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawRwLockTrait + Sync + 'a> Sync
+    for MappedRwLockWriteGuard<'a, T, R> {}
 
 /// RAII structure used to release the upgradable read access of a lock when
 /// dropped.
 #[must_use]
-pub struct RwLockUpgradableReadGuard<'a, T: ?Sized + 'a> {
-    raw: &'a RawRwLock,
+pub struct RwLockUpgradableReadGuard<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a = RawRwLock> {
+    raw: &'a R,
     data: *mut T,
     marker: PhantomData<&'a T>,
 }
 
-unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for RwLockUpgradableReadGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawRwLockUpgrade + Sync + 'a> Sync
+    for RwLockUpgradableReadGuard<'a, T, R> {}
+
+/// An RAII rwlock guard returned by `RwLockUpgradableReadGuard::map`, which can point to a
+/// subfield of the protected data.
+///
+/// The main difference between `MappedRwLockUpgradableReadGuard` and
+/// `RwLockUpgradableReadGuard` is that the former doesn't support upgrading
+/// or downgrading, since the guard no longer refers to the whole of the
+/// protected data.
+#[must_use]
+pub struct MappedRwLockUpgradableReadGuard<
+    'a,
+    T: ?Sized + 'a,
+    R: RawRwLockUpgrade + 'a = RawRwLock,
+> {
+    raw: &'a R,
+    data: *const T,
+    marker: PhantomData<&'a T>,
+}
+
+unsafe impl<'a, T: ?Sized + Sync + 'a, R: RawRwLockUpgrade + Sync + 'a> Sync
+    for MappedRwLockUpgradableReadGuard<'a, T, R> {}
+
+/// A write guard which can be sent to another thread, created by
+/// `RwLockWriteGuard::into_send`.
+///
+/// This allows one thread to lock a rwlock and another thread to finish the
+/// critical section and unlock it. While this guard exists the lock is not
+/// attributed to any thread, so the deadlock detector and the debug checks for
+/// recursive locking ignore it. Converting it back with
+/// `SendRwLockWriteGuard::into_guard` attributes the lock to the current
+/// thread again.
+#[must_use]
+pub struct SendRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    raw: &'a RawRwLock,
+    data: *mut T,
+    marker: PhantomData<&'a mut T>,
+}
+
+unsafe impl<'a, T: ?Sized + Send + 'a> Send for SendRwLockWriteGuard<'a, T> {}
+unsafe impl<'a, T: ?Sized + Sync + 'a> Sync for SendRwLockWriteGuard<'a, T> {}
+
+/// An RAII rwlock guard returned by `RwLock::read_arc`.
+///
+/// This is similar to `RwLockReadGuard`, except that it holds a reference to
+/// the `Arc` containing the rwlock instead of borrowing it.
+#[must_use]
+pub struct ArcRwLockReadGuard<T: ?Sized, R: RawRwLockTrait = RawRwLock> {
+    rwlock: Arc<RwLock<T, R>>,
+    marker: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, R: RawRwLockTrait + Sync> Sync for ArcRwLockReadGuard<T, R> {}
+
+/// An RAII rwlock guard returned by `RwLock::write_arc`.
+///
+/// This is similar to `RwLockWriteGuard`, except that it holds a reference to
+/// the `Arc` containing the rwlock instead of borrowing it.
+///
+/// Like `RwLockWriteGuard`, the lock is attributed to the thread which locked
+/// it, so the guard can't be sent to another thread. With the default raw
+/// rwlock it can be converted into a `SendArcRwLockWriteGuard` for that.
+#[must_use]
+pub struct ArcRwLockWriteGuard<T: ?Sized, R: RawRwLockTrait = RawRwLock> {
+    rwlock: Arc<RwLock<T, R>>,
+    marker: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, R: RawRwLockTrait + Sync> Sync for ArcRwLockWriteGuard<T, R> {}
+
+/// An `Arc` rwlock write guard which can be sent to another thread, created by
+/// `ArcRwLockWriteGuard::into_send`.
+///
+/// This is the owned counterpart of `SendRwLockWriteGuard`, and likewise isn't
+/// attributed to any thread while it exists.
+#[must_use]
+pub struct SendArcRwLockWriteGuard<T: ?Sized> {
+    rwlock: Arc<RwLock<T>>,
+    marker: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Send + Sync> Send for SendArcRwLockWriteGuard<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for SendArcRwLockWriteGuard<T> {}
+
+/// An RAII rwlock guard returned by `RwLock::upgradable_read_arc`.
+///
+/// This is similar to `RwLockUpgradableReadGuard`, except that it holds a
+/// reference to the `Arc` containing the rwlock instead of borrowing it.
+#[must_use]
+pub struct ArcRwLockUpgradableReadGuard<T: ?Sized, R: RawRwLockUpgrade = RawRwLock> {
+    rwlock: Arc<RwLock<T, R>>,
+    marker: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, R: RawRwLockUpgrade + Sync> Sync
+    for ArcRwLockUpgradableReadGuard<T, R> {}
 
 impl<T> RwLock<T> {
     /// Creates a new instance of an `RwLock<T>` which is unlocked.
@@ -150,7 +343,7 @@ impl<T> RwLock<T> {
     pub const fn new(val: T) -> RwLock<T> {
         RwLock {
             data: UnsafeCell::new(val),
-            raw: RawRwLock::new(),
+            raw: <RawRwLock as RawRwLockTrait>::INIT,
         }
     }
 
@@ -168,30 +361,117 @@ impl<T> RwLock<T> {
     pub fn new(val: T) -> RwLock<T> {
         RwLock {
             data: UnsafeCell::new(val),
-            raw: RawRwLock::new(),
+            raw: <RawRwLock as RawRwLockTrait>::INIT,
         }
     }
+}
 
-    /// Consumes this `RwLock`, returning the underlying data.
+impl<T> StampedRwLock<T> {
+    /// Creates a new instance of a `StampedRwLock<T>` which is unlocked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::StampedRwLock;
+    ///
+    /// let lock = StampedRwLock::new(5);
+    /// ```
+    #[cfg(feature = "nightly")]
+    #[inline]
+    pub const fn new(val: T) -> StampedRwLock<T> {
+        StampedRwLock {
+            lock: RwLock {
+                data: UnsafeCell::new(val),
+                raw: <RawStampedRwLock as RawRwLockTrait>::INIT,
+            },
+        }
+    }
+
+    /// Creates a new instance of a `StampedRwLock<T>` which is unlocked.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::StampedRwLock;
+    ///
+    /// let lock = StampedRwLock::new(5);
+    /// ```
+    #[cfg(not(feature = "nightly"))]
+    #[inline]
+    pub fn new(val: T) -> StampedRwLock<T> {
+        StampedRwLock {
+            lock: RwLock {
+                data: UnsafeCell::new(val),
+                raw: <RawStampedRwLock as RawRwLockTrait>::INIT,
+            },
+        }
+    }
+
+    /// Consumes this `StampedRwLock`, returning the underlying data.
     #[inline]
     pub fn into_inner(self) -> T {
-        unsafe { self.data.into_inner() }
+        self.lock.into_inner()
     }
 }
 
-impl<T: ?Sized> RwLock<T> {
+impl<T: ?Sized> Deref for StampedRwLock<T> {
+    type Target = RwLock<T, RawStampedRwLock>;
     #[inline]
-    fn read_guard(&self) -> RwLockReadGuard<T> {
-        RwLockReadGuard {
-            raw: &self.raw,
-            data: self.data.get(),
-            marker: PhantomData,
+    fn deref(&self) -> &RwLock<T, RawStampedRwLock> {
+        &self.lock
+    }
+}
+
+impl<T: ?Sized> DerefMut for StampedRwLock<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut RwLock<T, RawStampedRwLock> {
+        &mut self.lock
+    }
+}
+
+impl<T: Default> Default for StampedRwLock<T> {
+    #[inline]
+    fn default() -> StampedRwLock<T> {
+        StampedRwLock::new(Default::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for StampedRwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_struct("StampedRwLock")
+                .field("data", &&*guard)
+                .finish(),
+            None => f.pad("StampedRwLock { <locked> }"),
         }
     }
+}
 
+impl<T, R: RawRwLockTrait> RwLock<T, R> {
+    /// Creates a new instance of an `RwLock<T>` using the given raw rwlock,
+    /// which must be in an unlocked state.
+    ///
+    /// This allows using raw rwlocks which can't be statically initialized,
+    /// for example because they need to be set up at runtime.
     #[inline]
-    fn write_guard(&self) -> RwLockWriteGuard<T> {
-        RwLockWriteGuard {
+    pub fn with_raw_rwlock(raw: R, val: T) -> RwLock<T, R> {
+        RwLock {
+            data: UnsafeCell::new(val),
+            raw,
+        }
+    }
+
+    /// Consumes this `RwLock`, returning the underlying data.
+    #[inline]
+    pub fn into_inner(self) -> T {
+        unsafe { self.data.into_inner() }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTrait> RwLock<T, R> {
+    #[inline]
+    fn read_guard(&self) -> RwLockReadGuard<T, R> {
+        RwLockReadGuard {
             raw: &self.raw,
             data: self.data.get(),
             marker: PhantomData,
@@ -199,8 +479,8 @@ impl<T: ?Sized> RwLock<T> {
     }
 
     #[inline]
-    fn upgradable_guard(&self) -> RwLockUpgradableReadGuard<T> {
-        RwLockUpgradableReadGuard {
+    fn write_guard(&self) -> RwLockWriteGuard<T, R> {
+        RwLockWriteGuard {
             raw: &self.raw,
             data: self.data.get(),
             marker: PhantomData,
@@ -215,13 +495,16 @@ impl<T: ?Sized> RwLock<T> {
     /// this method returns.
     ///
     /// Note that attempts to recursively acquire a read lock on a `RwLock` when
-    /// the current thread already holds one may result in a deadlock.
+    /// the current thread already holds one may result in a deadlock. Attempts
+    /// to acquire a read lock while the current thread holds the write lock
+    /// always result in a deadlock, which is detected and results in a panic
+    /// when the `self_deadlock_detection` feature is enabled.
     ///
     /// Returns an RAII guard which will release this thread's shared access
     /// once it is dropped.
     #[inline]
-    pub fn read(&self) -> RwLockReadGuard<T> {
-        self.raw.lock_shared(false);
+    pub fn read(&self) -> RwLockReadGuard<T, R> {
+        self.raw.lock_shared();
         self.read_guard()
     }
 
@@ -233,143 +516,241 @@ impl<T: ?Sized> RwLock<T> {
     ///
     /// This function does not block.
     #[inline]
-    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
-        if self.raw.try_lock_shared(false) {
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T, R>> {
+        if self.raw.try_lock_shared() {
             Some(self.read_guard())
         } else {
             None
         }
     }
 
-    /// Attempts to acquire this rwlock with shared read access until a timeout
-    /// is reached.
+    /// Locks this rwlock with exclusive write access, blocking the current
+    /// thread until it can be acquired.
     ///
-    /// If the access could not be granted before the timeout expires, then
-    /// `None` is returned. Otherwise, an RAII guard is returned which will
-    /// release the shared access when it is dropped.
+    /// This function will not return while other writers or other readers
+    /// currently have access to the lock.
+    ///
+    /// Attempts to write lock a rwlock in the thread which already holds any
+    /// lock on it will result in a deadlock. When the `self_deadlock_detection`
+    /// feature is enabled, this is detected and results in a panic instead.
+    ///
+    /// Returns an RAII guard which will drop the write access of this rwlock
+    /// when dropped.
     #[inline]
-    pub fn try_read_for(&self, timeout: Duration) -> Option<RwLockReadGuard<T>> {
-        if self.raw.try_lock_shared_for(false, timeout) {
-            Some(self.read_guard())
-        } else {
-            None
-        }
+    pub fn write(&self) -> RwLockWriteGuard<T, R> {
+        self.raw.lock_exclusive();
+        self.write_guard()
     }
 
-    /// Attempts to acquire this rwlock with shared read access until a timeout
-    /// is reached.
+    /// Attempts to lock this rwlock with exclusive write access.
     ///
-    /// If the access could not be granted before the timeout expires, then
-    /// `None` is returned. Otherwise, an RAII guard is returned which will
-    /// release the shared access when it is dropped.
+    /// If the lock could not be acquired at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned which will release the lock when
+    /// it is dropped.
+    ///
+    /// This function does not block.
     #[inline]
-    pub fn try_read_until(&self, timeout: Instant) -> Option<RwLockReadGuard<T>> {
-        if self.raw.try_lock_shared_until(false, timeout) {
-            Some(self.read_guard())
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T, R>> {
+        if self.raw.try_lock_exclusive() {
+            Some(self.write_guard())
         } else {
             None
         }
     }
 
-    /// Locks this rwlock with shared read access, blocking the current thread
-    /// until it can be acquired.
+    /// Returns a mutable reference to the underlying data.
     ///
-    /// The calling thread will be blocked until there are no more writers which
-    /// hold the lock. There may be other readers currently inside the lock when
-    /// this method returns.
+    /// Since this call borrows the `RwLock` mutably, no actual locking needs to
+    /// take place---the mutable borrow statically guarantees no locks exist.
+    #[inline]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data.get() }
+    }
+
+    /// Releases shared read access of the rwlock.
     ///
-    /// Unlike `read`, this method is guaranteed to succeed without blocking if
-    /// another read lock is held at the time of the call. This allows a thread
-    /// to recursively lock a `RwLock`. However using this method can cause
-    /// writers to starve since readers no longer block if a writer is waiting
-    /// for the lock.
+    /// # Safety
     ///
-    /// Returns an RAII guard which will release this thread's shared access
-    /// once it is dropped.
+    /// This function must only be called if the rwlock was locked using
+    /// `raw_read` or `raw_try_read`, or if an `RwLockReadGuard` from this
+    /// rwlock was leaked (e.g. with `mem::forget`). The rwlock must be locked
+    /// with shared read access.
     #[inline]
-    pub fn read_recursive(&self) -> RwLockReadGuard<T> {
-        self.raw.lock_shared(true);
-        self.read_guard()
+    pub unsafe fn raw_unlock_read(&self) {
+        self.raw.unlock_shared();
     }
 
-    /// Attempts to acquire this rwlock with shared read access.
-    ///
-    /// If the access could not be granted at this time, then `None` is returned.
-    /// Otherwise, an RAII guard is returned which will release the shared access
-    /// when it is dropped.
+    /// Releases exclusive write access of the rwlock.
     ///
-    /// This method is guaranteed to succeed if another read lock is held at the
-    /// time of the call. See the documentation for `read_recursive` for details.
+    /// # Safety
     ///
-    /// This function does not block.
+    /// This function must only be called if the rwlock was locked using
+    /// `raw_write` or `raw_try_write`, or if an `RwLockWriteGuard` from this
+    /// rwlock was leaked (e.g. with `mem::forget`). The rwlock must be locked
+    /// with exclusive write access.
     #[inline]
-    pub fn try_read_recursive(&self) -> Option<RwLockReadGuard<T>> {
-        if self.raw.try_lock_shared(true) {
-            Some(self.read_guard())
-        } else {
-            None
+    pub unsafe fn raw_unlock_write(&self) {
+        self.raw.unlock_exclusive();
+    }
+
+    #[inline]
+    fn read_arc_guard(this: &Arc<Self>) -> ArcRwLockReadGuard<T, R> {
+        ArcRwLockReadGuard {
+            rwlock: this.clone(),
+            marker: PhantomData,
         }
     }
 
-    /// Attempts to acquire this rwlock with shared read access until a timeout
-    /// is reached.
+    #[inline]
+    fn write_arc_guard(this: &Arc<Self>) -> ArcRwLockWriteGuard<T, R> {
+        ArcRwLockWriteGuard {
+            rwlock: this.clone(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Locks this rwlock with shared read access through an `Arc`, blocking
+    /// the current thread until it can be acquired.
     ///
-    /// If the access could not be granted before the timeout expires, then
-    /// `None` is returned. Otherwise, an RAII guard is returned which will
-    /// release the shared access when it is dropped.
+    /// This is similar to `read`, except that the returned guard keeps a clone
+    /// of the `Arc` instead of borrowing the rwlock, and therefore has a
+    /// `'static` lifetime.
     ///
-    /// This method is guaranteed to succeed without blocking if another read
-    /// lock is held at the time of the call. See the documentation for
-    /// `read_recursive` for details.
+    /// This is an associated function that needs to be used as
+    /// `RwLock::read_arc(&rwlock)`.
     #[inline]
-    pub fn try_read_recursive_for(&self, timeout: Duration) -> Option<RwLockReadGuard<T>> {
-        if self.raw.try_lock_shared_for(true, timeout) {
-            Some(self.read_guard())
-        } else {
-            None
-        }
+    pub fn read_arc(this: &Arc<Self>) -> ArcRwLockReadGuard<T, R> {
+        this.raw.lock_shared();
+        RwLock::read_arc_guard(this)
     }
 
-    /// Attempts to acquire this rwlock with shared read access until a timeout
-    /// is reached.
+    /// Attempts to acquire this rwlock with shared read access through an
+    /// `Arc`.
     ///
-    /// If the access could not be granted before the timeout expires, then
-    /// `None` is returned. Otherwise, an RAII guard is returned which will
-    /// release the shared access when it is dropped.
+    /// This is similar to `try_read`, except that the returned guard keeps a
+    /// clone of the `Arc` instead of borrowing the rwlock.
+    ///
+    /// This function does not block.
     #[inline]
-    pub fn try_read_recursive_until(&self, timeout: Instant) -> Option<RwLockReadGuard<T>> {
-        if self.raw.try_lock_shared_until(true, timeout) {
-            Some(self.read_guard())
+    pub fn try_read_arc(this: &Arc<Self>) -> Option<ArcRwLockReadGuard<T, R>> {
+        if this.raw.try_lock_shared() {
+            Some(RwLock::read_arc_guard(this))
         } else {
             None
         }
     }
 
-    /// Locks this rwlock with exclusive write access, blocking the current
-    /// thread until it can be acquired.
+    /// Locks this rwlock with exclusive write access through an `Arc`,
+    /// blocking the current thread until it can be acquired.
     ///
-    /// This function will not return while other writers or other readers
-    /// currently have access to the lock.
+    /// This is similar to `write`, except that the returned guard keeps a clone
+    /// of the `Arc` instead of borrowing the rwlock, and therefore has a
+    /// `'static` lifetime.
     ///
-    /// Returns an RAII guard which will drop the write access of this rwlock
-    /// when dropped.
+    /// This is an associated function that needs to be used as
+    /// `RwLock::write_arc(&rwlock)`.
     #[inline]
-    pub fn write(&self) -> RwLockWriteGuard<T> {
-        self.raw.lock_exclusive();
-        self.write_guard()
+    pub fn write_arc(this: &Arc<Self>) -> ArcRwLockWriteGuard<T, R> {
+        this.raw.lock_exclusive();
+        RwLock::write_arc_guard(this)
     }
 
-    /// Attempts to lock this rwlock with exclusive write access.
+    /// Attempts to lock this rwlock with exclusive write access through an
+    /// `Arc`.
     ///
-    /// If the lock could not be acquired at this time, then `None` is returned.
-    /// Otherwise, an RAII guard is returned which will release the lock when
-    /// it is dropped.
+    /// This is similar to `try_write`, except that the returned guard keeps a
+    /// clone of the `Arc` instead of borrowing the rwlock.
     ///
     /// This function does not block.
     #[inline]
-    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
-        if self.raw.try_lock_exclusive() {
-            Some(self.write_guard())
+    pub fn try_write_arc(this: &Arc<Self>) -> Option<ArcRwLockWriteGuard<T, R>> {
+        if this.raw.try_lock_exclusive() {
+            Some(RwLock::write_arc_guard(this))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockFair> RwLock<T, R> {
+    /// Releases shared read access of the rwlock using a fair unlock protocol.
+    ///
+    /// See `RwLockReadGuard::unlock_fair`.
+    ///
+    /// # Safety
+    ///
+    /// This function must only be called if the rwlock was locked using
+    /// `raw_write` or `raw_try_write`, a raw upgradable read lock was upgraded
+    /// using `raw_upgrade` or `raw_try_upgrade`, or if an `RwLockWriteGuard`
+    /// from this rwlock was leaked (e.g. with `mem::forget`). The rwlock must
+    /// be locked with exclusive write access.
+    #[inline]
+    pub unsafe fn raw_unlock_read_fair(&self) {
+        self.raw.unlock_shared_fair();
+    }
+
+    /// Releases exclusive write access of the rwlock using a fair unlock
+    /// protocol.
+    ///
+    /// See `RwLockWriteGuard::unlock_fair`.
+    ///
+    /// # Safety
+    ///
+    /// This function must only be called if the rwlock was locked using
+    /// `raw_write` or `raw_try_write`, a raw upgradable read lock was upgraded
+    /// using `raw_upgrade` or `raw_try_upgrade`, or if an `RwLockWriteGuard`
+    /// from this rwlock was leaked (e.g. with `mem::forget`). The rwlock must
+    /// be locked with exclusive write access.
+    #[inline]
+    pub unsafe fn raw_unlock_write_fair(&self) {
+        self.raw.unlock_exclusive_fair();
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockDowngrade> RwLock<T, R> {
+    /// Atomically downgrades a write lock into a shared read lock without
+    /// allowing any writers to take exclusive access of the lock in the meantime.
+    ///
+    /// See `RwLockWriteGuard::downgrade`.
+    ///
+    /// # Safety
+    ///
+    /// This function must only be called if the rwlock was locked using
+    /// `raw_write` or `raw_try_write`, or if an `RwLockWriteGuard` from this
+    /// rwlock was leaked (e.g. with `mem::forget`). The rwlock must be locked
+    /// with exclusive write access.
+    #[inline]
+    pub unsafe fn raw_downgrade(&self) {
+        self.raw.downgrade();
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTimed> RwLock<T, R> {
+    /// Attempts to acquire this rwlock with shared read access until a timeout
+    /// is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// `None` is returned. Otherwise, an RAII guard is returned which will
+    /// release the shared access when it is dropped.
+    #[inline]
+    pub fn try_read_for(&self, timeout: Duration) -> Option<RwLockReadGuard<T, R>> {
+        if self.raw.try_lock_shared_for(timeout) {
+            Some(self.read_guard())
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this rwlock with shared read access until a timeout
+    /// is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// `None` is returned. Otherwise, an RAII guard is returned which will
+    /// release the shared access when it is dropped.
+    #[inline]
+    pub fn try_read_until(&self, timeout: Instant) -> Option<RwLockReadGuard<T, R>> {
+        if self.raw.try_lock_shared_until(timeout) {
+            Some(self.read_guard())
         } else {
             None
         }
@@ -382,7 +763,7 @@ impl<T: ?Sized> RwLock<T> {
     /// `None` is returned. Otherwise, an RAII guard is returned which will
     /// release the exclusive access when it is dropped.
     #[inline]
-    pub fn try_write_for(&self, timeout: Duration) -> Option<RwLockWriteGuard<T>> {
+    pub fn try_write_for(&self, timeout: Duration) -> Option<RwLockWriteGuard<T, R>> {
         if self.raw.try_lock_exclusive_for(timeout) {
             Some(self.write_guard())
         } else {
@@ -397,7 +778,7 @@ impl<T: ?Sized> RwLock<T> {
     /// `None` is returned. Otherwise, an RAII guard is returned which will
     /// release the exclusive access when it is dropped.
     #[inline]
-    pub fn try_write_until(&self, timeout: Instant) -> Option<RwLockWriteGuard<T>> {
+    pub fn try_write_until(&self, timeout: Instant) -> Option<RwLockWriteGuard<T, R>> {
         if self.raw.try_lock_exclusive_until(timeout) {
             Some(self.write_guard())
         } else {
@@ -405,106 +786,192 @@ impl<T: ?Sized> RwLock<T> {
         }
     }
 
-    /// Locks this rwlock with upgradable read access, blocking the current thread
+    /// Attempts to acquire this rwlock with shared read access through an
+    /// `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_read_for`, except that the returned guard keeps
+    /// a clone of the `Arc` instead of borrowing the rwlock.
+    #[inline]
+    pub fn try_read_arc_for(
+        this: &Arc<Self>,
+        timeout: Duration,
+    ) -> Option<ArcRwLockReadGuard<T, R>> {
+        if this.raw.try_lock_shared_for(timeout) {
+            Some(RwLock::read_arc_guard(this))
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this rwlock with shared read access through an
+    /// `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_read_until`, except that the returned guard
+    /// keeps a clone of the `Arc` instead of borrowing the rwlock.
+    #[inline]
+    pub fn try_read_arc_until(
+        this: &Arc<Self>,
+        timeout: Instant,
+    ) -> Option<ArcRwLockReadGuard<T, R>> {
+        if this.raw.try_lock_shared_until(timeout) {
+            Some(RwLock::read_arc_guard(this))
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this rwlock with exclusive write access through an
+    /// `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_write_for`, except that the returned guard keeps
+    /// a clone of the `Arc` instead of borrowing the rwlock.
+    #[inline]
+    pub fn try_write_arc_for(
+        this: &Arc<Self>,
+        timeout: Duration,
+    ) -> Option<ArcRwLockWriteGuard<T, R>> {
+        if this.raw.try_lock_exclusive_for(timeout) {
+            Some(RwLock::write_arc_guard(this))
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this rwlock with exclusive write access through an
+    /// `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_write_until`, except that the returned guard
+    /// keeps a clone of the `Arc` instead of borrowing the rwlock.
+    #[inline]
+    pub fn try_write_arc_until(
+        this: &Arc<Self>,
+        timeout: Instant,
+    ) -> Option<ArcRwLockWriteGuard<T, R>> {
+        if this.raw.try_lock_exclusive_until(timeout) {
+            Some(RwLock::write_arc_guard(this))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockRecursive> RwLock<T, R> {
+    /// Locks this rwlock with shared read access, blocking the current thread
     /// until it can be acquired.
     ///
-    /// The calling thread will be blocked until there are no more writers or other
-    /// upgradable reads which hold the lock. There may be other readers currently
-    /// inside the lock when this method returns.
+    /// The calling thread will be blocked until there are no more writers which
+    /// hold the lock. There may be other readers currently inside the lock when
+    /// this method returns.
+    ///
+    /// Unlike `read`, this method is guaranteed to succeed without blocking if
+    /// another read lock is held at the time of the call. This allows a thread
+    /// to recursively lock a `RwLock`. However using this method can cause
+    /// writers to starve since readers no longer block if a writer is waiting
+    /// for the lock.
     ///
     /// Returns an RAII guard which will release this thread's shared access
     /// once it is dropped.
     #[inline]
-    pub fn upgradable_read(&self) -> RwLockUpgradableReadGuard<T> {
-        self.raw.lock_upgradable();
-        self.upgradable_guard()
+    pub fn read_recursive(&self) -> RwLockReadGuard<T, R> {
+        self.raw.lock_shared_recursive();
+        self.read_guard()
     }
 
-    /// Attempts to acquire this rwlock with upgradable read access.
+    /// Attempts to acquire this rwlock with shared read access.
     ///
     /// If the access could not be granted at this time, then `None` is returned.
     /// Otherwise, an RAII guard is returned which will release the shared access
     /// when it is dropped.
     ///
+    /// This method is guaranteed to succeed if another read lock is held at the
+    /// time of the call. See the documentation for `read_recursive` for details.
+    ///
     /// This function does not block.
     #[inline]
-    pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableReadGuard<T>> {
-        if self.raw.try_lock_upgradable() {
-            Some(self.upgradable_guard())
+    pub fn try_read_recursive(&self) -> Option<RwLockReadGuard<T, R>> {
+        if self.raw.try_lock_shared_recursive() {
+            Some(self.read_guard())
         } else {
             None
         }
     }
+}
 
-    /// Attempts to acquire this rwlock with upgradable read access until a timeout
+impl<T: ?Sized, R: RawRwLockRecursiveTimed> RwLock<T, R> {
+    /// Attempts to acquire this rwlock with shared read access until a timeout
     /// is reached.
     ///
     /// If the access could not be granted before the timeout expires, then
     /// `None` is returned. Otherwise, an RAII guard is returned which will
     /// release the shared access when it is dropped.
+    ///
+    /// This method is guaranteed to succeed without blocking if another read
+    /// lock is held at the time of the call. See the documentation for
+    /// `read_recursive` for details.
     #[inline]
-    pub fn try_upgradable_read_for(
-        &self,
-        timeout: Duration,
-    ) -> Option<RwLockUpgradableReadGuard<T>> {
-        if self.raw.try_lock_upgradable_for(timeout) {
-            Some(self.upgradable_guard())
+    pub fn try_read_recursive_for(&self, timeout: Duration) -> Option<RwLockReadGuard<T, R>> {
+        if self.raw.try_lock_shared_recursive_for(timeout) {
+            Some(self.read_guard())
         } else {
             None
         }
     }
 
-    /// Attempts to acquire this rwlock with upgradable read access until a timeout
+    /// Attempts to acquire this rwlock with shared read access until a timeout
     /// is reached.
     ///
     /// If the access could not be granted before the timeout expires, then
     /// `None` is returned. Otherwise, an RAII guard is returned which will
     /// release the shared access when it is dropped.
     #[inline]
-    pub fn try_upgradable_read_until(
-        &self,
-        timeout: Instant,
-    ) -> Option<RwLockUpgradableReadGuard<T>> {
-        if self.raw.try_lock_upgradable_until(timeout) {
-            Some(self.upgradable_guard())
+    pub fn try_read_recursive_until(&self, timeout: Instant) -> Option<RwLockReadGuard<T, R>> {
+        if self.raw.try_lock_shared_recursive_until(timeout) {
+            Some(self.read_guard())
         } else {
             None
         }
     }
+}
 
-    /// Returns a mutable reference to the underlying data.
-    ///
-    /// Since this call borrows the `RwLock` mutably, no actual locking needs to
-    /// take place---the mutable borrow statically guarantees no locks exist.
+impl<T: ?Sized, R: RawRwLockUpgrade> RwLock<T, R> {
     #[inline]
-    pub fn get_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.data.get() }
+    fn upgradable_guard(&self) -> RwLockUpgradableReadGuard<T, R> {
+        RwLockUpgradableReadGuard {
+            raw: &self.raw,
+            data: self.data.get(),
+            marker: PhantomData,
+        }
     }
 
-    /// Releases shared read access of the rwlock.
+    /// Locks this rwlock with upgradable read access, blocking the current thread
+    /// until it can be acquired.
     ///
-    /// # Safety
+    /// The calling thread will be blocked until there are no more writers or other
+    /// upgradable reads which hold the lock. There may be other readers currently
+    /// inside the lock when this method returns.
     ///
-    /// This function must only be called if the rwlock was locked using
-    /// `raw_read` or `raw_try_read`, or if an `RwLockReadGuard` from this
-    /// rwlock was leaked (e.g. with `mem::forget`). The rwlock must be locked
-    /// with shared read access.
+    /// Returns an RAII guard which will release this thread's shared access
+    /// once it is dropped.
     #[inline]
-    pub unsafe fn raw_unlock_read(&self) {
-        self.raw.unlock_shared(false);
+    pub fn upgradable_read(&self) -> RwLockUpgradableReadGuard<T, R> {
+        self.raw.lock_upgradable();
+        self.upgradable_guard()
     }
 
-    /// Releases exclusive write access of the rwlock.
+    /// Attempts to acquire this rwlock with upgradable read access.
     ///
-    /// # Safety
+    /// If the access could not be granted at this time, then `None` is returned.
+    /// Otherwise, an RAII guard is returned which will release the shared access
+    /// when it is dropped.
     ///
-    /// This function must only be called if the rwlock was locked using
-    /// `raw_write` or `raw_try_write`, or if an `RwLockWriteGuard` from this
-    /// rwlock was leaked (e.g. with `mem::forget`). The rwlock must be locked
-    /// with exclusive write access.
+    /// This function does not block.
     #[inline]
-    pub unsafe fn raw_unlock_write(&self) {
-        self.raw.unlock_exclusive(false);
+    pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableReadGuard<T, R>> {
+        if self.raw.try_lock_upgradable() {
+            Some(self.upgradable_guard())
+        } else {
+            None
+        }
     }
 
     /// Releases upgradable read access of the rwlock.
@@ -517,42 +984,50 @@ impl<T: ?Sized> RwLock<T> {
     /// `mem::forget`). The rwlock must be locked with upgradable read access.
     #[inline]
     pub unsafe fn raw_unlock_upgradable_read(&self) {
-        self.raw.unlock_upgradable(false);
+        unsafe { self.raw.unlock_upgradable() };
     }
 
-    /// Releases shared read access of the rwlock using a fair unlock protocol.
-    ///
-    /// See `RwLockReadGuard::unlock_fair`.
+    #[inline]
+    fn upgradable_arc_guard(this: &Arc<Self>) -> ArcRwLockUpgradableReadGuard<T, R> {
+        ArcRwLockUpgradableReadGuard {
+            rwlock: this.clone(),
+            marker: PhantomData,
+        }
+    }
+
+    /// Locks this rwlock with upgradable read access through an `Arc`,
+    /// blocking the current thread until it can be acquired.
     ///
-    /// # Safety
+    /// This is similar to `upgradable_read`, except that the returned guard
+    /// keeps a clone of the `Arc` instead of borrowing the rwlock, and
+    /// therefore has a `'static` lifetime.
     ///
-    /// This function must only be called if the rwlock was locked using
-    /// `raw_write` or `raw_try_write`, a raw upgradable read lock was upgraded
-    /// using `raw_upgrade` or `raw_try_upgrade`, or if an `RwLockWriteGuard`
-    /// from this rwlock was leaked (e.g. with `mem::forget`). The rwlock must
-    /// be locked with exclusive write access.
+    /// This is an associated function that needs to be used as
+    /// `RwLock::upgradable_read_arc(&rwlock)`.
     #[inline]
-    pub unsafe fn raw_unlock_read_fair(&self) {
-        self.raw.unlock_shared(true);
+    pub fn upgradable_read_arc(this: &Arc<Self>) -> ArcRwLockUpgradableReadGuard<T, R> {
+        this.raw.lock_upgradable();
+        RwLock::upgradable_arc_guard(this)
     }
 
-    /// Releases exclusive write access of the rwlock using a fair unlock
-    /// protocol.
-    ///
-    /// See `RwLockWriteGuard::unlock_fair`.
+    /// Attempts to acquire this rwlock with upgradable read access through an
+    /// `Arc`.
     ///
-    /// # Safety
+    /// This is similar to `try_upgradable_read`, except that the returned
+    /// guard keeps a clone of the `Arc` instead of borrowing the rwlock.
     ///
-    /// This function must only be called if the rwlock was locked using
-    /// `raw_write` or `raw_try_write`, a raw upgradable read lock was upgraded
-    /// using `raw_upgrade` or `raw_try_upgrade`, or if an `RwLockWriteGuard`
-    /// from this rwlock was leaked (e.g. with `mem::forget`). The rwlock must
-    /// be locked with exclusive write access.
+    /// This function does not block.
     #[inline]
-    pub unsafe fn raw_unlock_write_fair(&self) {
-        self.raw.unlock_exclusive(true);
+    pub fn try_upgradable_read_arc(this: &Arc<Self>) -> Option<ArcRwLockUpgradableReadGuard<T, R>> {
+        if this.raw.try_lock_upgradable() {
+            Some(RwLock::upgradable_arc_guard(this))
+        } else {
+            None
+        }
     }
+}
 
+impl<T: ?Sized, R: RawRwLockUpgradeFair> RwLock<T, R> {
     /// Releases upgradable read access of the rwlock using a fair unlock
     /// protocol.
     ///
@@ -564,25 +1039,11 @@ impl<T: ?Sized> RwLock<T> {
     /// `mem::forget`). The rwlock must be locked with upgradable read access.
     #[inline]
     pub unsafe fn raw_unlock_upgradable_read_fair(&self) {
-        self.raw.unlock_upgradable(true);
-    }
-
-    /// Atomically downgrades a write lock into a shared read lock without
-    /// allowing any writers to take exclusive access of the lock in the meantime.
-    ///
-    /// See `RwLockWriteGuard::downgrade`.
-    ///
-    /// # Safety
-    ///
-    /// This function must only be called if the rwlock was locked using
-    /// `raw_write` or `raw_try_write`, or if an `RwLockWriteGuard` from this
-    /// rwlock was leaked (e.g. with `mem::forget`). The rwlock must be locked
-    /// with exclusive write access.
-    #[inline]
-    pub unsafe fn raw_downgrade(&self) {
-        self.raw.exclusive_to_shared();
+        self.raw.unlock_upgradable_fair();
     }
+}
 
+impl<T: ?Sized, R: RawRwLockUpgradeDowngrade> RwLock<T, R> {
     /// Atomically downgrades an upgradable read lock into a shared read lock
     /// without allowing any writers to take exclusive access of the lock in
     /// the meantime.
@@ -597,51 +1058,263 @@ impl<T: ?Sized> RwLock<T> {
     /// `mem::forget`). The rwlock must be locked with upgradable read access.
     #[inline]
     pub unsafe fn raw_downgrade_upgradable_read(&self) {
-        self.raw.upgradable_to_shared();
+        self.raw.downgrade_upgradable();
     }
 }
 
-impl RwLock<()> {
-    /// Locks this rwlock with shared read access, blocking the current thread
-    /// until it can be acquired.
+impl<T: ?Sized, R: RawRwLockUpgradeTimed> RwLock<T, R> {
+    /// Attempts to acquire this rwlock with upgradable read access until a timeout
+    /// is reached.
     ///
-    /// This is similar to `read`, except that a `RwLockReadGuard` is not
-    /// returned. Instead you will need to call `raw_unlock` to release the
-    /// rwlock.
-    #[inline]
-    pub fn raw_read(&self) {
-        self.raw.lock_shared(false);
+    /// If the access could not be granted before the timeout expires, then
+    /// `None` is returned. Otherwise, an RAII guard is returned which will
+    /// release the shared access when it is dropped.
+    #[inline]
+    pub fn try_upgradable_read_for(
+        &self,
+        timeout: Duration,
+    ) -> Option<RwLockUpgradableReadGuard<T, R>> {
+        if self.raw.try_lock_upgradable_for(timeout) {
+            Some(self.upgradable_guard())
+        } else {
+            None
+        }
     }
 
-    /// Attempts to acquire this rwlock with shared read access.
+    /// Attempts to acquire this rwlock with upgradable read access until a timeout
+    /// is reached.
     ///
-    /// This is similar to `try_read`, except that a `RwLockReadGuard` is not
-    /// returned. Instead you will need to call `raw_unlock` to release the
+    /// If the access could not be granted before the timeout expires, then
+    /// `None` is returned. Otherwise, an RAII guard is returned which will
+    /// release the shared access when it is dropped.
+    #[inline]
+    pub fn try_upgradable_read_until(
+        &self,
+        timeout: Instant,
+    ) -> Option<RwLockUpgradableReadGuard<T, R>> {
+        if self.raw.try_lock_upgradable_until(timeout) {
+            Some(self.upgradable_guard())
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this rwlock with upgradable read access through an
+    /// `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_upgradable_read_for`, except that the returned
+    /// guard keeps a clone of the `Arc` instead of borrowing the rwlock.
+    #[inline]
+    pub fn try_upgradable_read_arc_for(
+        this: &Arc<Self>,
+        timeout: Duration,
+    ) -> Option<ArcRwLockUpgradableReadGuard<T, R>> {
+        if this.raw.try_lock_upgradable_for(timeout) {
+            Some(RwLock::upgradable_arc_guard(this))
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire this rwlock with upgradable read access through an
+    /// `Arc` until a timeout is reached.
+    ///
+    /// This is similar to `try_upgradable_read_until`, except that the
+    /// returned guard keeps a clone of the `Arc` instead of borrowing the
     /// rwlock.
     #[inline]
-    pub fn raw_try_read(&self) -> bool {
-        self.raw.try_lock_shared(false)
+    pub fn try_upgradable_read_arc_until(
+        this: &Arc<Self>,
+        timeout: Instant,
+    ) -> Option<ArcRwLockUpgradableReadGuard<T, R>> {
+        if this.raw.try_lock_upgradable_until(timeout) {
+            Some(RwLock::upgradable_arc_guard(this))
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockOptimistic> RwLock<T, R> {
+    /// Returns a stamp for an optimistic read, or `None` if the rwlock is
+    /// currently locked with exclusive write access.
+    ///
+    /// This doesn't lock the rwlock. After reading the data through
+    /// `data_ptr`, pass the stamp to `validate` to check that no writer
+    /// modified the data in the meantime.
+    #[inline]
+    pub fn optimistic_stamp(&self) -> Option<usize> {
+        self.raw.optimistic_stamp()
+    }
+
+    /// Checks whether the rwlock has not been locked with exclusive write
+    /// access since `stamp` was returned by `optimistic_stamp`.
+    ///
+    /// If this returns `false`, any data read since the stamp was taken may be
+    /// inconsistent and must be discarded.
+    #[inline]
+    pub fn validate(&self, stamp: usize) -> bool {
+        self.raw.validate(stamp)
+    }
+
+    /// Returns a raw pointer to the underlying data, for use with optimistic
+    /// reads.
+    ///
+    /// Dereferencing the pointer without holding the lock is unsafe, since a
+    /// writer may modify the data concurrently. To avoid a data race, only read
+    /// the data through atomics which writers only modify through a shared
+    /// reference, as described in `read_optimistic`. The values read may only
+    /// be relied upon after a successful call to `validate`.
+    #[inline]
+    pub fn data_ptr(&self) -> *const T {
+        self.data.get()
+    }
+
+    /// Reads the data with an optimistic read, falling back to a shared lock
+    /// if a writer interferes.
+    ///
+    /// `f` is first called with a pointer to the data without locking the
+    /// rwlock. If a writer held or acquired the lock in the meantime, the
+    /// result is discarded without being dropped and `f` is called again while
+    /// holding a shared lock.
+    ///
+    /// # Safety
+    ///
+    /// The first call to `f` may run while a writer holds the lock, and the
+    /// caller must ensure that this isn't a data race. `f` must only read
+    /// parts of the data which are atomics, using atomic loads, and writers
+    /// must only modify those parts with atomic operations through a shared
+    /// reference (for example through `Deref` on `RwLockWriteGuard`, never
+    /// through `DerefMut`).
+    ///
+    /// `f` may also observe partially modified data the first time it is
+    /// called, so it must not rely on any invariants between the values it
+    /// reads.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::StampedRwLock;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// let lock = StampedRwLock::new((AtomicUsize::new(1), AtomicUsize::new(2)));
+    /// let (a, b) = unsafe {
+    ///     lock.read_optimistic(|p| {
+    ///         let pair = &*p;
+    ///         (pair.0.load(Ordering::Relaxed), pair.1.load(Ordering::Relaxed))
+    ///     })
+    /// };
+    /// assert_eq!(a + b, 3);
+    /// ```
+    #[inline]
+    pub unsafe fn read_optimistic<U, F>(&self, mut f: F) -> U
+    where
+        F: FnMut(*const T) -> U,
+    {
+        if let Some(stamp) = self.raw.optimistic_stamp() {
+            let result = f(self.data.get());
+            if self.raw.validate(stamp) {
+                return result;
+            }
+
+            // The result may have been built from inconsistent data, so don't
+            // run its destructor.
+            mem::forget(result);
+        }
+        let guard = self.read();
+        f(&*guard)
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Asserts that the current thread holds the rwlock with exclusive write
+    /// access.
+    ///
+    /// This is intended for functions which require their caller to hold a
+    /// particular lock. The check is only performed in debug builds and does
+    /// nothing otherwise.
+    ///
+    /// Which thread holds the rwlock is only known while the locks held by
+    /// each thread are being tracked. Tracking is enabled at runtime with
+    /// `deadlock::set_enabled`, and by default with the `deadlock_detection` or
+    /// `self_deadlock_detection` features. Otherwise this only checks that the
+    /// rwlock is locked with exclusive write access.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, this function will panic if the current thread does
+    /// not hold a write lock on the rwlock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use parking_lot::{deadlock, RwLock};
+    /// use std::sync::Arc;
+    /// use std::thread;
+    ///
+    /// deadlock::set_enabled(true);
+    ///
+    /// let lock = Arc::new(RwLock::new(0));
+    /// let _guard = lock.write();
+    /// lock.assert_write_held();
+    ///
+    /// // The assertion fails on any other thread
+    /// let lock2 = lock.clone();
+    /// let result = thread::spawn(move || lock2.assert_write_held()).join();
+    /// assert_eq!(result.is_err(), cfg!(debug_assertions));
+    /// ```
+    #[inline]
+    pub fn assert_write_held(&self) {
+        debug_assert!(
+            self.raw.is_exclusive_held_by_current_thread(),
+            "rwlock is not write locked by the current thread"
+        );
+    }
+
+    /// Asserts that the current thread holds the rwlock with shared read,
+    /// upgradable read or exclusive write access.
+    ///
+    /// This is intended for functions which require their caller to hold a
+    /// particular lock. The check is only performed in debug builds and does
+    /// nothing otherwise.
+    ///
+    /// Which threads hold the rwlock is only known while the locks held by
+    /// each thread are being tracked, see `assert_write_held`. Otherwise this
+    /// only checks that the rwlock is locked.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, this function will panic if the current thread does
+    /// not hold a lock on the rwlock.
+    #[inline]
+    pub fn assert_read_held(&self) {
+        debug_assert!(
+            self.raw.is_held_by_current_thread(),
+            "rwlock is not locked by the current thread"
+        );
     }
+}
 
+impl<R: RawRwLockTrait> RwLock<(), R> {
     /// Locks this rwlock with shared read access, blocking the current thread
     /// until it can be acquired.
     ///
-    /// This is similar to `read_recursive`, except that a `RwLockReadGuard` is
-    /// not returned. Instead you will need to call `raw_unlock` to release the
+    /// This is similar to `read`, except that a `RwLockReadGuard` is not
+    /// returned. Instead you will need to call `raw_unlock` to release the
     /// rwlock.
     #[inline]
-    pub fn raw_read_recursive(&self) {
-        self.raw.lock_shared(true);
+    pub fn raw_read(&self) {
+        self.raw.lock_shared();
     }
 
     /// Attempts to acquire this rwlock with shared read access.
     ///
-    /// This is similar to `try_read_recursive`, except that a `RwLockReadGuard` is not
+    /// This is similar to `try_read`, except that a `RwLockReadGuard` is not
     /// returned. Instead you will need to call `raw_unlock` to release the
     /// rwlock.
     #[inline]
-    pub fn raw_try_read_recursive(&self) -> bool {
-        self.raw.try_lock_shared(true)
+    pub fn raw_try_read(&self) -> bool {
+        self.raw.try_lock_shared()
     }
 
     /// Locks this rwlock with exclusive write access, blocking the current
@@ -664,7 +1337,32 @@ impl RwLock<()> {
     pub fn raw_try_write(&self) -> bool {
         self.raw.try_lock_exclusive()
     }
+}
+
+impl<R: RawRwLockRecursive> RwLock<(), R> {
+    /// Locks this rwlock with shared read access, blocking the current thread
+    /// until it can be acquired.
+    ///
+    /// This is similar to `read_recursive`, except that a `RwLockReadGuard` is
+    /// not returned. Instead you will need to call `raw_unlock` to release the
+    /// rwlock.
+    #[inline]
+    pub fn raw_read_recursive(&self) {
+        self.raw.lock_shared_recursive();
+    }
+
+    /// Attempts to acquire this rwlock with shared read access.
+    ///
+    /// This is similar to `try_read_recursive`, except that a `RwLockReadGuard` is not
+    /// returned. Instead you will need to call `raw_unlock` to release the
+    /// rwlock.
+    #[inline]
+    pub fn raw_try_read_recursive(&self) -> bool {
+        self.raw.try_lock_shared_recursive()
+    }
+}
 
+impl<R: RawRwLockUpgrade> RwLock<(), R> {
     /// Locks this rwlock with upgradable read access, blocking the current thread
     /// until it can be acquired.
     ///
@@ -699,7 +1397,7 @@ impl RwLock<()> {
     /// `mem::forget`). The rwlock must be locked with upgradable read access.
     #[inline]
     pub unsafe fn raw_upgrade(&self) {
-        self.raw.upgradable_to_exclusive();
+        self.raw.upgrade();
     }
 
     /// Attempts to upgrade this rwlock from upgradable read access to exclusive
@@ -715,18 +1413,18 @@ impl RwLock<()> {
     /// `mem::forget`). The rwlock must be locked with upgradable read access.
     #[inline]
     pub unsafe fn raw_try_upgrade(&self) -> bool {
-        self.raw.try_upgradable_to_exclusive()
+        self.raw.try_upgrade()
     }
 }
 
-impl<T: ?Sized + Default> Default for RwLock<T> {
+impl<T: Default, R: RawRwLockTrait> Default for RwLock<T, R> {
     #[inline]
-    fn default() -> RwLock<T> {
-        RwLock::new(Default::default())
+    fn default() -> RwLock<T, R> {
+        RwLock::with_raw_rwlock(R::INIT, Default::default())
     }
 }
 
-impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+impl<T: ?Sized + fmt::Debug, R: RawRwLockTrait> fmt::Debug for RwLock<T, R> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self.try_read() {
             Some(guard) => f.debug_struct("RwLock")
@@ -737,7 +1435,77 @@ impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
     }
 }
 
-impl<'a, T: ?Sized + 'a> RwLockReadGuard<'a, T> {
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> RwLockReadGuard<'a, T, R> {
+    /// Make a new `MappedRwLockReadGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `RwLockReadGuard` passed
+    /// in already locked the rwlock.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockReadGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedRwLockReadGuard<'a, U, R>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let raw = orig.raw;
+        let data = f(unsafe { &*orig.data });
+        mem::forget(orig);
+        MappedRwLockReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedRwLockReadGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockReadGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedRwLockReadGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &*s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedRwLockReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// The read lock is released while `f` runs and is re-acquired before this
+    /// function returns, even if `f` panics.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockReadGuard::unlocked(&mut guard, ...)`. A method would
+    /// interfere with methods of the same name on the contents of the locked
+    /// data.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_shared() };
+        defer!(raw.lock_shared());
+        f()
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockFair + 'a> RwLockReadGuard<'a, T, R> {
     /// Unlocks the `RwLock` using a fair unlock protocol.
     ///
     /// By default, `RwLock` is unfair and allow the current thread to re-lock
@@ -752,191 +1520,1079 @@ impl<'a, T: ?Sized + 'a> RwLockReadGuard<'a, T> {
     /// using this method instead of dropping the `RwLockReadGuard` normally.
     #[inline]
     pub fn unlock_fair(self) {
-        self.raw.unlock_shared(true);
+        unsafe { self.raw.unlock_shared_fair() };
         mem::forget(self);
     }
 
-    /// Make a new `RwLockReadGuard` for a component of the locked data.
+    /// Temporarily unlocks the `RwLock` to execute the given function.
     ///
-    /// This operation cannot fail as the `RwLockReadGuard` passed
-    /// in already locked the data.
+    /// The `RwLock` is unlocked using a fair unlock protocol. This is otherwise
+    /// identical to `RwLockReadGuard::unlocked`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_shared_fair() };
+        defer!(raw.lock_shared());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// This method is functionally equivalent to calling `unlock_fair` followed
+    /// by re-acquiring the read lock, however it can be much more efficient
+    /// in the case where there are no waiting threads.
     ///
     /// This is an associated function that needs to be
-    /// used as `RwLockReadGuard::map(...)`. A method would interfere with methods of
+    /// used as `RwLockReadGuard::bump(&mut guard)`. A method would interfere
+    /// with methods of the same name on the contents of the locked data.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.raw.bump_shared() };
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Deref for RwLockReadGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Drop for RwLockReadGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_shared() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawRwLockTrait> StableAddress for RwLockReadGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> MappedRwLockReadGuard<'a, T, R> {
+    /// Make a new `MappedRwLockReadGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedRwLockReadGuard` passed
+    /// in already locked the rwlock.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedRwLockReadGuard::map(...)`. A method would interfere with methods of
     /// the same name on the contents of the locked data.
     #[inline]
-    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> RwLockReadGuard<'a, U>
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedRwLockReadGuard<'a, U, R>
     where
         F: FnOnce(&T) -> &U,
     {
-        let raw = orig.raw;
-        let data = f(unsafe { &*orig.data });
-        mem::forget(orig);
-        RwLockReadGuard {
-            raw,
-            data,
-            marker: PhantomData,
-        }
+        let raw = orig.raw;
+        let data = f(unsafe { &*orig.data });
+        mem::forget(orig);
+        MappedRwLockReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedRwLockReadGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedRwLockReadGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedRwLockReadGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &*s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedRwLockReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockFair + 'a> MappedRwLockReadGuard<'a, T, R> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// See `RwLockReadGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.raw.unlock_shared_fair() };
+        mem::forget(self);
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Deref for MappedRwLockReadGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Drop for MappedRwLockReadGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_shared() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawRwLockTrait> StableAddress for MappedRwLockReadGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> RwLockWriteGuard<'a, T, R> {
+    /// Make a new `MappedRwLockWriteGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `RwLockWriteGuard` passed
+    /// in already locked the rwlock.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockWriteGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedRwLockWriteGuard<'a, U, R>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let raw = orig.raw;
+        let data = f(unsafe { &mut *orig.data });
+        mem::forget(orig);
+        MappedRwLockWriteGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedRwLockWriteGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockWriteGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedRwLockWriteGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &mut *s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedRwLockWriteGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// The write lock is released while `f` runs and is re-acquired before this
+    /// function returns, even if `f` panics.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockWriteGuard::unlocked(&mut guard, ...)`. A method would
+    /// interfere with methods of the same name on the contents of the locked
+    /// data.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_exclusive() };
+        defer!(raw.lock_exclusive());
+        f()
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockDowngrade + 'a> RwLockWriteGuard<'a, T, R> {
+    /// Atomically downgrades a write lock into a read lock without allowing any
+    /// writers to take exclusive access of the lock in the meantime.
+    ///
+    /// Note that if there are any writers currently waiting to take the lock
+    /// then other readers may not be able to acquire the lock even if it was
+    /// downgraded.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T, R> {
+        unsafe { self.raw.downgrade() };
+        let raw = self.raw;
+        // Reborrow the value to avoid moving self.borrow,
+        // which isn't allow for types with destructors
+        let data = unsafe { &*self.data };
+        mem::forget(self);
+        RwLockReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockFair + 'a> RwLockWriteGuard<'a, T, R> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// By default, `RwLock` is unfair and allow the current thread to re-lock
+    /// the rwlock before another has the chance to acquire the lock, even if
+    /// that thread has been blocked on the `RwLock` for a long time. This is
+    /// the default because it allows much higher throughput as it avoids
+    /// forcing a context switch on every rwlock unlock. This can result in one
+    /// thread acquiring a `RwLock` many more times than other threads.
+    ///
+    /// However in some cases it can be beneficial to ensure fairness by forcing
+    /// the lock to pass on to a waiting thread if there is one. This is done by
+    /// using this method instead of dropping the `RwLockWriteGuard` normally.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.raw.unlock_exclusive_fair() };
+        mem::forget(self);
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// The `RwLock` is unlocked using a fair unlock protocol. This is otherwise
+    /// identical to `RwLockWriteGuard::unlocked`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_exclusive_fair() };
+        defer!(raw.lock_exclusive());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// This method is functionally equivalent to calling `unlock_fair` followed
+    /// by re-acquiring the write lock, however it can be much more efficient
+    /// in the case where there are no waiting threads.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockWriteGuard::bump(&mut guard)`. A method would interfere
+    /// with methods of the same name on the contents of the locked data.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.raw.bump_exclusive() };
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Deref for RwLockWriteGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> DerefMut for RwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Drop for RwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_exclusive() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawRwLockTrait> StableAddress for RwLockWriteGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> MappedRwLockWriteGuard<'a, T, R> {
+    /// Make a new `MappedRwLockWriteGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedRwLockWriteGuard` passed
+    /// in already locked the rwlock.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedRwLockWriteGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedRwLockWriteGuard<'a, U, R>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let raw = orig.raw;
+        let data = f(unsafe { &mut *orig.data });
+        mem::forget(orig);
+        MappedRwLockWriteGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedRwLockWriteGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedRwLockWriteGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(s: Self, f: F) -> Result<MappedRwLockWriteGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &mut *s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedRwLockWriteGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockFair + 'a> MappedRwLockWriteGuard<'a, T, R> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// See `RwLockWriteGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.raw.unlock_exclusive_fair() };
+        mem::forget(self);
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Deref for MappedRwLockWriteGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> DerefMut for MappedRwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockTrait + 'a> Drop for MappedRwLockWriteGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_exclusive() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawRwLockTrait> StableAddress for MappedRwLockWriteGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a> RwLockWriteGuard<'a, T> {
+    /// Converts this guard into one which can be sent to another thread.
+    ///
+    /// The lock stays held, but is no longer attributed to the current thread.
+    /// See `SendRwLockWriteGuard` for details.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockWriteGuard::into_send(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn into_send(s: Self) -> SendRwLockWriteGuard<'a, T> {
+        unsafe { s.raw.detach() };
+        let raw = s.raw;
+        let data = s.data;
+        mem::forget(s);
+        SendRwLockWriteGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> SendRwLockWriteGuard<'a, T> {
+    /// Converts this guard back into a `RwLockWriteGuard` held by the current
+    /// thread.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `SendRwLockWriteGuard::into_guard(...)`. A method would interfere
+    /// with methods of the same name on the contents of the locked data.
+    #[inline]
+    pub fn into_guard(s: Self) -> RwLockWriteGuard<'a, T> {
+        unsafe { s.raw.attach() };
+        let raw = s.raw;
+        let data = s.data;
+        mem::forget(s);
+        RwLockWriteGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Deref for SendRwLockWriteGuard<'a, T> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> DerefMut for SendRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a> Drop for SendRwLockWriteGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        // Attribute the lock to the unlocking thread first so that the release
+        // is balanced for the deadlock detector.
+        unsafe {
+            self.raw.attach();
+            self.raw.unlock_exclusive();
+        }
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized> StableAddress for SendRwLockWriteGuard<'a, T> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> RwLockUpgradableReadGuard<'a, T, R> {
+    /// Make a new `MappedRwLockUpgradableReadGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `RwLockUpgradableReadGuard` passed
+    /// in already locked the rwlock.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockUpgradableReadGuard::map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedRwLockUpgradableReadGuard<'a, U, R>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let raw = orig.raw;
+        let data = f(unsafe { &*orig.data });
+        mem::forget(orig);
+        MappedRwLockUpgradableReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedRwLockUpgradableReadGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockUpgradableReadGuard::try_map(...)`. A method would interfere with methods of
+    /// the same name on the contents of the locked data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(
+        s: Self,
+        f: F,
+    ) -> Result<MappedRwLockUpgradableReadGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &*s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedRwLockUpgradableReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+
+    /// Atomically upgrades an upgradable read lock lock into a exclusive write lock,
+    /// blocking the current thread until it can be aquired.
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T, R> {
+        unsafe { self.raw.upgrade() };
+        let raw = self.raw;
+        // Reborrow the value to avoid moving self.borrow,
+        // which isn't allow for types with destructors
+        let data = unsafe { &mut *self.data };
+        mem::forget(self);
+        RwLockWriteGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Tries to atomically upgrade an upgradable read lock into a exclusive write lock.
+    ///
+    /// If the access could not be granted at this time, then the current guard is returned.
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T, R>, Self> {
+        if unsafe { self.raw.try_upgrade() } {
+            let raw = self.raw;
+            // Reborrow the value to avoid moving self.borrow,
+            // which isn't allow for types with destructors
+            let data = unsafe { &mut *self.data };
+            mem::forget(self);
+            Ok(RwLockWriteGuard {
+                raw,
+                data,
+                marker: PhantomData,
+            })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// The upgradable read lock is released while `f` runs and is re-acquired before this
+    /// function returns, even if `f` panics.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockUpgradableReadGuard::unlocked(&mut guard, ...)`. A method would
+    /// interfere with methods of the same name on the contents of the locked
+    /// data.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_upgradable() };
+        defer!(raw.lock_upgradable());
+        f()
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgradeDowngrade + 'a> RwLockUpgradableReadGuard<'a, T, R> {
+    /// Atomically downgrades an upgradable read lock lock into a shared read lock
+    /// without allowing any writers to take exclusive access of the lock in the
+    /// meantime.
+    ///
+    /// Note that if there are any writers currently waiting to take the lock
+    /// then other readers may not be able to acquire the lock even if it was
+    /// downgraded.
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T, R> {
+        unsafe { self.raw.downgrade_upgradable() };
+        let raw = self.raw;
+        // Reborrow the value to avoid moving self.borrow,
+        // which isn't allow for types with destructors
+        let data = unsafe { &*self.data };
+        mem::forget(self);
+        RwLockReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgradeTimed + 'a> RwLockUpgradableReadGuard<'a, T, R> {
+    /// Tries to atomically upgrade an upgradable read lock into a exclusive
+    /// write lock, until a timeout is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// the current guard is returned.
+    pub fn try_upgrade_for(self, timeout: Duration) -> Result<RwLockWriteGuard<'a, T, R>, Self> {
+        if unsafe { self.raw.try_upgrade_for(timeout) } {
+            let raw = self.raw;
+            // Reborrow the value to avoid moving self.borrow,
+            // which isn't allow for types with destructors
+            let data = unsafe { &mut *self.data };
+            mem::forget(self);
+            Ok(RwLockWriteGuard {
+                raw,
+                data,
+                marker: PhantomData,
+            })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Tries to atomically upgrade an upgradable read lock into a exclusive
+    /// write lock, until a timeout is reached.
+    ///
+    /// If the access could not be granted before the timeout expires, then
+    /// the current guard is returned.
+    #[inline]
+    pub fn try_upgrade_until(self, timeout: Instant) -> Result<RwLockWriteGuard<'a, T, R>, Self> {
+        if unsafe { self.raw.try_upgrade_until(timeout) } {
+            let raw = self.raw;
+            // Reborrow the value to avoid moving self.borrow,
+            // which isn't allow for types with destructors
+            let data = unsafe { &mut *self.data };
+            mem::forget(self);
+            Ok(RwLockWriteGuard {
+                raw,
+                data,
+                marker: PhantomData,
+            })
+        } else {
+            Err(self)
+        }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgradeFair + 'a> RwLockUpgradableReadGuard<'a, T, R> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// By default, `RwLock` is unfair and allow the current thread to re-lock
+    /// the rwlock before another has the chance to acquire the lock, even if
+    /// that thread has been blocked on the `RwLock` for a long time. This is
+    /// the default because it allows much higher throughput as it avoids
+    /// forcing a context switch on every rwlock unlock. This can result in one
+    /// thread acquiring a `RwLock` many more times than other threads.
+    ///
+    /// However in some cases it can be beneficial to ensure fairness by forcing
+    /// the lock to pass on to a waiting thread if there is one. This is done by
+    /// using this method instead of dropping the `RwLockUpgradableReadGuard` normally.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.raw.unlock_upgradable_fair() };
+        mem::forget(self);
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// The `RwLock` is unlocked using a fair unlock protocol. This is otherwise
+    /// identical to `RwLockUpgradableReadGuard::unlocked`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        let raw = s.raw;
+        unsafe { raw.unlock_upgradable_fair() };
+        defer!(raw.lock_upgradable());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// This method is functionally equivalent to calling `unlock_fair` followed
+    /// by re-acquiring the upgradable read lock, however it can be much more efficient
+    /// in the case where there are no waiting threads.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `RwLockUpgradableReadGuard::bump(&mut guard)`. A method would interfere
+    /// with methods of the same name on the contents of the locked data.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.raw.bump_upgradable() };
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> Deref for RwLockUpgradableReadGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> Drop for RwLockUpgradableReadGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_upgradable() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawRwLockUpgrade> StableAddress
+    for RwLockUpgradableReadGuard<'a, T, R> {}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> MappedRwLockUpgradableReadGuard<'a, T, R> {
+    /// Make a new `MappedRwLockUpgradableReadGuard` for a component of the locked data.
+    ///
+    /// This operation cannot fail as the `MappedRwLockUpgradableReadGuard` passed
+    /// in already locked the rwlock.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedRwLockUpgradableReadGuard::map(...)`. A method would
+    /// interfere with methods of the same name on the contents of the locked
+    /// data.
+    #[inline]
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> MappedRwLockUpgradableReadGuard<'a, U, R>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let raw = orig.raw;
+        let data = f(unsafe { &*orig.data });
+        mem::forget(orig);
+        MappedRwLockUpgradableReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        }
+    }
+
+    /// Attempts to make a new `MappedRwLockUpgradableReadGuard` for a component of the
+    /// locked data. The original guard is returned if the closure returns
+    /// `None`.
+    ///
+    /// This is an associated function that needs to be
+    /// used as `MappedRwLockUpgradableReadGuard::try_map(...)`. A method would
+    /// interfere with methods of the same name on the contents of the locked
+    /// data.
+    #[inline]
+    pub fn try_map<U: ?Sized, F>(
+        s: Self,
+        f: F,
+    ) -> Result<MappedRwLockUpgradableReadGuard<'a, U, R>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        let raw = s.raw;
+        let data = match f(unsafe { &*s.data }) {
+            Some(data) => data,
+            None => return Err(s),
+        };
+        mem::forget(s);
+        Ok(MappedRwLockUpgradableReadGuard {
+            raw,
+            data,
+            marker: PhantomData,
+        })
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgradeFair + 'a> MappedRwLockUpgradableReadGuard<'a, T, R> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// See `RwLockUpgradableReadGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.raw.unlock_upgradable_fair() };
+        mem::forget(self);
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> Deref
+    for MappedRwLockUpgradableReadGuard<'a, T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.data }
+    }
+}
+
+impl<'a, T: ?Sized + 'a, R: RawRwLockUpgrade + 'a> Drop
+    for MappedRwLockUpgradableReadGuard<'a, T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.raw.unlock_upgradable() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<'a, T: ?Sized, R: RawRwLockUpgrade> StableAddress
+    for MappedRwLockUpgradableReadGuard<'a, T, R> {}
+
+impl<T: ?Sized, R: RawRwLockTrait> ArcRwLockReadGuard<T, R> {
+    /// Returns the `Arc` containing the rwlock which this guard locks.
+    ///
+    /// This is an associated function that needs to be used as
+    /// `ArcRwLockReadGuard::rwlock(&guard)`.
+    #[inline]
+    pub fn rwlock(this: &Self) -> &Arc<RwLock<T, R>> {
+        &this.rwlock
+    }
+
+    // Takes the `Arc` out of the guard without unlocking the rwlock.
+    #[inline]
+    fn into_arc(self) -> Arc<RwLock<T, R>> {
+        let rwlock = unsafe { ptr::read(&self.rwlock) };
+        mem::forget(self);
+        rwlock
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// See `RwLockReadGuard::unlocked`.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.rwlock.raw.unlock_shared() };
+        defer!(s.rwlock.raw.lock_shared());
+        f()
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockFair> ArcRwLockReadGuard<T, R> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// See `RwLockReadGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.rwlock.raw.unlock_shared_fair() };
+        drop(self.into_arc());
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// See `RwLockReadGuard::unlocked_fair`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.rwlock.raw.unlock_shared_fair() };
+        defer!(s.rwlock.raw.lock_shared());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// See `RwLockReadGuard::bump`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.rwlock.raw.bump_shared() };
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTrait> Deref for ArcRwLockReadGuard<T, R> {
+    type Target = T;
+    #[inline]
+    fn deref(&self) -> &T {
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTrait> Drop for ArcRwLockReadGuard<T, R> {
+    #[inline]
+    fn drop(&mut self) {
+        unsafe { self.rwlock.raw.unlock_shared() };
+    }
+}
+
+#[cfg(feature = "owning_ref")]
+unsafe impl<T: ?Sized, R: RawRwLockTrait> StableAddress for ArcRwLockReadGuard<T, R> {}
+
+impl<T: ?Sized, R: RawRwLockTrait> ArcRwLockWriteGuard<T, R> {
+    /// Returns the `Arc` containing the rwlock which this guard locks.
+    ///
+    /// This is an associated function that needs to be used as
+    /// `ArcRwLockWriteGuard::rwlock(&guard)`.
+    #[inline]
+    pub fn rwlock(this: &Self) -> &Arc<RwLock<T, R>> {
+        &this.rwlock
+    }
+
+    // Takes the `Arc` out of the guard without unlocking the rwlock.
+    #[inline]
+    fn into_arc(self) -> Arc<RwLock<T, R>> {
+        let rwlock = unsafe { ptr::read(&self.rwlock) };
+        mem::forget(self);
+        rwlock
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// See `RwLockWriteGuard::unlocked`.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.rwlock.raw.unlock_exclusive() };
+        defer!(s.rwlock.raw.lock_exclusive());
+        f()
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockDowngrade> ArcRwLockWriteGuard<T, R> {
+    /// Atomically downgrades a write lock into a read lock without allowing any
+    /// writers to take exclusive access of the lock in the meantime.
+    ///
+    /// See `RwLockWriteGuard::downgrade`.
+    pub fn downgrade(self) -> ArcRwLockReadGuard<T, R> {
+        unsafe { self.rwlock.raw.downgrade() };
+        ArcRwLockReadGuard {
+            rwlock: self.into_arc(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockFair> ArcRwLockWriteGuard<T, R> {
+    /// Unlocks the `RwLock` using a fair unlock protocol.
+    ///
+    /// See `RwLockWriteGuard::unlock_fair`.
+    #[inline]
+    pub fn unlock_fair(self) {
+        unsafe { self.rwlock.raw.unlock_exclusive_fair() };
+        drop(self.into_arc());
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// See `RwLockWriteGuard::unlocked_fair`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.rwlock.raw.unlock_exclusive_fair() };
+        defer!(s.rwlock.raw.lock_exclusive());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// See `RwLockWriteGuard::bump`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.rwlock.raw.bump_exclusive() };
     }
 }
 
-impl<'a, T: ?Sized + 'a> Deref for RwLockReadGuard<'a, T> {
+impl<T: ?Sized, R: RawRwLockTrait> Deref for ArcRwLockWriteGuard<T, R> {
     type Target = T;
     #[inline]
     fn deref(&self) -> &T {
-        unsafe { &*self.data }
+        unsafe { &*self.rwlock.data.get() }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockTrait> DerefMut for ArcRwLockWriteGuard<T, R> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.rwlock.data.get() }
     }
 }
 
-impl<'a, T: ?Sized + 'a> Drop for RwLockReadGuard<'a, T> {
+impl<T: ?Sized, R: RawRwLockTrait> Drop for ArcRwLockWriteGuard<T, R> {
     #[inline]
     fn drop(&mut self) {
-        self.raw.unlock_shared(false);
+        unsafe { self.rwlock.raw.unlock_exclusive() };
     }
 }
 
 #[cfg(feature = "owning_ref")]
-unsafe impl<'a, T: ?Sized> StableAddress for RwLockReadGuard<'a, T> {}
+unsafe impl<T: ?Sized, R: RawRwLockTrait> StableAddress for ArcRwLockWriteGuard<T, R> {}
 
-impl<'a, T: ?Sized + 'a> RwLockWriteGuard<'a, T> {
-    /// Atomically downgrades a write lock into a read lock without allowing any
-    /// writers to take exclusive access of the lock in the meantime.
+impl<T: ?Sized> ArcRwLockWriteGuard<T> {
+    /// Converts this guard into one which can be sent to another thread.
     ///
-    /// Note that if there are any writers currently waiting to take the lock
-    /// then other readers may not be able to acquire the lock even if it was
-    /// downgraded.
-    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
-        self.raw.exclusive_to_shared();
-        let raw = self.raw;
-        // Reborrow the value to avoid moving self.borrow,
-        // which isn't allow for types with destructors
-        let data = unsafe { &*self.data };
-        mem::forget(self);
-        RwLockReadGuard {
-            raw,
-            data,
+    /// See `RwLockWriteGuard::into_send`.
+    #[inline]
+    pub fn into_send(s: Self) -> SendArcRwLockWriteGuard<T> {
+        unsafe { s.rwlock.raw.detach() };
+        SendArcRwLockWriteGuard {
+            rwlock: s.into_arc(),
             marker: PhantomData,
         }
     }
+}
 
-    /// Make a new `RwLockWriteGuard` for a component of the locked data.
-    ///
-    /// This operation cannot fail as the `RwLockWriteGuard` passed
-    /// in already locked the data.
+impl<T: ?Sized> SendArcRwLockWriteGuard<T> {
+    /// Converts this guard back into an `ArcRwLockWriteGuard` held by the
+    /// current thread.
     ///
-    /// This is an associated function that needs to be
-    /// used as `RwLockWriteGuard::map(...)`. A method would interfere with methods of
-    /// the same name on the contents of the locked data.
+    /// See `SendRwLockWriteGuard::into_guard`.
     #[inline]
-    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> RwLockWriteGuard<'a, U>
-    where
-        F: FnOnce(&mut T) -> &mut U,
-    {
-        let raw = orig.raw;
-        let data = f(unsafe { &mut *orig.data });
-        mem::forget(orig);
-        RwLockWriteGuard {
-            raw,
-            data,
+    pub fn into_guard(s: Self) -> ArcRwLockWriteGuard<T> {
+        unsafe { s.rwlock.raw.attach() };
+        let rwlock = unsafe { ptr::read(&s.rwlock) };
+        mem::forget(s);
+        ArcRwLockWriteGuard {
+            rwlock,
             marker: PhantomData,
         }
     }
-
-    /// Unlocks the `RwLock` using a fair unlock protocol.
-    ///
-    /// By default, `RwLock` is unfair and allow the current thread to re-lock
-    /// the rwlock before another has the chance to acquire the lock, even if
-    /// that thread has been blocked on the `RwLock` for a long time. This is
-    /// the default because it allows much higher throughput as it avoids
-    /// forcing a context switch on every rwlock unlock. This can result in one
-    /// thread acquiring a `RwLock` many more times than other threads.
-    ///
-    /// However in some cases it can be beneficial to ensure fairness by forcing
-    /// the lock to pass on to a waiting thread if there is one. This is done by
-    /// using this method instead of dropping the `RwLockWriteGuard` normally.
-    #[inline]
-    pub fn unlock_fair(self) {
-        self.raw.unlock_exclusive(true);
-        mem::forget(self);
-    }
 }
 
-impl<'a, T: ?Sized + 'a> Deref for RwLockWriteGuard<'a, T> {
+impl<T: ?Sized> Deref for SendArcRwLockWriteGuard<T> {
     type Target = T;
     #[inline]
     fn deref(&self) -> &T {
-        unsafe { &*self.data }
+        unsafe { &*self.rwlock.data.get() }
     }
 }
 
-impl<'a, T: ?Sized + 'a> DerefMut for RwLockWriteGuard<'a, T> {
+impl<T: ?Sized> DerefMut for SendArcRwLockWriteGuard<T> {
     #[inline]
     fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.data }
+        unsafe { &mut *self.rwlock.data.get() }
     }
 }
 
-impl<'a, T: ?Sized + 'a> Drop for RwLockWriteGuard<'a, T> {
+impl<T: ?Sized> Drop for SendArcRwLockWriteGuard<T> {
     #[inline]
     fn drop(&mut self) {
-        self.raw.unlock_exclusive(false);
+        // See SendRwLockWriteGuard
+        unsafe {
+            self.rwlock.raw.attach();
+            self.rwlock.raw.unlock_exclusive();
+        }
     }
 }
 
 #[cfg(feature = "owning_ref")]
-unsafe impl<'a, T: ?Sized> StableAddress for RwLockWriteGuard<'a, T> {}
+unsafe impl<T: ?Sized> StableAddress for SendArcRwLockWriteGuard<T> {}
 
-impl<'a, T: ?Sized + 'a> RwLockUpgradableReadGuard<'a, T> {
-    /// Atomically downgrades an upgradable read lock lock into a shared read lock
-    /// without allowing any writers to take exclusive access of the lock in the
-    /// meantime.
+impl<T: ?Sized, R: RawRwLockUpgrade> ArcRwLockUpgradableReadGuard<T, R> {
+    /// Returns the `Arc` containing the rwlock which this guard locks.
     ///
-    /// Note that if there are any writers currently waiting to take the lock
-    /// then other readers may not be able to acquire the lock even if it was
-    /// downgraded.
-    pub fn downgrade(self) -> RwLockReadGuard<'a, T> {
-        self.raw.upgradable_to_shared();
-        let raw = self.raw;
-        // Reborrow the value to avoid moving self.borrow,
-        // which isn't allow for types with destructors
-        let data = unsafe { &*self.data };
-        mem::forget(self);
-        RwLockReadGuard {
-            raw,
-            data,
-            marker: PhantomData,
-        }
+    /// This is an associated function that needs to be used as
+    /// `ArcRwLockUpgradableReadGuard::rwlock(&guard)`.
+    #[inline]
+    pub fn rwlock(this: &Self) -> &Arc<RwLock<T, R>> {
+        &this.rwlock
     }
 
-    /// Atomically upgrades an upgradable read lock lock into a exclusive write lock,
-    /// blocking the current thread until it can be aquired.
-    pub fn upgrade(self) -> RwLockWriteGuard<'a, T> {
-        self.raw.upgradable_to_exclusive();
-        let raw = self.raw;
-        // Reborrow the value to avoid moving self.borrow,
-        // which isn't allow for types with destructors
-        let data = unsafe { &mut *self.data };
+    // Takes the `Arc` out of the guard without unlocking the rwlock.
+    #[inline]
+    fn into_arc(self) -> Arc<RwLock<T, R>> {
+        let rwlock = unsafe { ptr::read(&self.rwlock) };
         mem::forget(self);
-        RwLockWriteGuard {
-            raw,
-            data,
+        rwlock
+    }
+
+    /// Atomically upgrades an upgradable read lock lock into a exclusive write
+    /// lock, blocking the current thread until it can be aquired.
+    pub fn upgrade(self) -> ArcRwLockWriteGuard<T, R> {
+        unsafe { self.rwlock.raw.upgrade() };
+        ArcRwLockWriteGuard {
+            rwlock: self.into_arc(),
             marker: PhantomData,
         }
     }
 
-    /// Tries to atomically upgrade an upgradable read lock into a exclusive write lock.
-    ///
-    /// If the access could not be granted at this time, then the current guard is returned.
-    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T>, Self> {
-        if self.raw.try_upgradable_to_exclusive() {
-            let raw = self.raw;
-            // Reborrow the value to avoid moving self.borrow,
-            // which isn't allow for types with destructors
-            let data = unsafe { &mut *self.data };
-            mem::forget(self);
-            Ok(RwLockWriteGuard {
-                raw,
-                data,
+    /// Tries to atomically upgrade an upgradable read lock into a exclusive
+    /// write lock.
+    ///
+    /// If the access could not be granted at this time, then the current guard
+    /// is returned.
+    pub fn try_upgrade(self) -> Result<ArcRwLockWriteGuard<T, R>, Self> {
+        if unsafe { self.rwlock.raw.try_upgrade() } {
+            Ok(ArcRwLockWriteGuard {
+                rwlock: self.into_arc(),
                 marker: PhantomData,
             })
         } else {
@@ -944,21 +2600,45 @@ impl<'a, T: ?Sized + 'a> RwLockUpgradableReadGuard<'a, T> {
         }
     }
 
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// See `RwLockUpgradableReadGuard::unlocked`.
+    #[inline]
+    pub fn unlocked<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.rwlock.raw.unlock_upgradable() };
+        defer!(s.rwlock.raw.lock_upgradable());
+        f()
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockUpgradeDowngrade> ArcRwLockUpgradableReadGuard<T, R> {
+    /// Atomically downgrades an upgradable read lock lock into a shared read
+    /// lock without allowing any writers to take exclusive access of the lock
+    /// in the meantime.
+    ///
+    /// See `RwLockUpgradableReadGuard::downgrade`.
+    pub fn downgrade(self) -> ArcRwLockReadGuard<T, R> {
+        unsafe { self.rwlock.raw.downgrade_upgradable() };
+        ArcRwLockReadGuard {
+            rwlock: self.into_arc(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, R: RawRwLockUpgradeTimed> ArcRwLockUpgradableReadGuard<T, R> {
     /// Tries to atomically upgrade an upgradable read lock into a exclusive
     /// write lock, until a timeout is reached.
     ///
     /// If the access could not be granted before the timeout expires, then
     /// the current guard is returned.
-    pub fn try_upgrade_for(self, timeout: Duration) -> Result<RwLockWriteGuard<'a, T>, Self> {
-        if self.raw.try_upgradable_to_exclusive_for(timeout) {
-            let raw = self.raw;
-            // Reborrow the value to avoid moving self.borrow,
-            // which isn't allow for types with destructors
-            let data = unsafe { &mut *self.data };
-            mem::forget(self);
-            Ok(RwLockWriteGuard {
-                raw,
-                data,
+    pub fn try_upgrade_for(self, timeout: Duration) -> Result<ArcRwLockWriteGuard<T, R>, Self> {
+        if unsafe { self.rwlock.raw.try_upgrade_for(timeout) } {
+            Ok(ArcRwLockWriteGuard {
+                rwlock: self.into_arc(),
                 marker: PhantomData,
             })
         } else {
@@ -971,60 +2651,90 @@ impl<'a, T: ?Sized + 'a> RwLockUpgradableReadGuard<'a, T> {
     ///
     /// If the access could not be granted before the timeout expires, then
     /// the current guard is returned.
-    #[inline]
-    pub fn try_upgrade_until(self, timeout: Instant) -> Result<RwLockWriteGuard<'a, T>, Self> {
-        if self.raw.try_upgradable_to_exclusive_until(timeout) {
-            let raw = self.raw;
-            // Reborrow the value to avoid moving self.borrow,
-            // which isn't allow for types with destructors
-            let data = unsafe { &mut *self.data };
-            mem::forget(self);
-            Ok(RwLockWriteGuard {
-                raw,
-                data,
+    pub fn try_upgrade_until(self, timeout: Instant) -> Result<ArcRwLockWriteGuard<T, R>, Self> {
+        if unsafe { self.rwlock.raw.try_upgrade_until(timeout) } {
+            Ok(ArcRwLockWriteGuard {
+                rwlock: self.into_arc(),
                 marker: PhantomData,
             })
         } else {
             Err(self)
         }
     }
+}
 
+impl<T: ?Sized, R: RawRwLockUpgradeFair> ArcRwLockUpgradableReadGuard<T, R> {
     /// Unlocks the `RwLock` using a fair unlock protocol.
     ///
-    /// By default, `RwLock` is unfair and allow the current thread to re-lock
-    /// the rwlock before another has the chance to acquire the lock, even if
-    /// that thread has been blocked on the `RwLock` for a long time. This is
-    /// the default because it allows much higher throughput as it avoids
-    /// forcing a context switch on every rwlock unlock. This can result in one
-    /// thread acquiring a `RwLock` many more times than other threads.
-    ///
-    /// However in some cases it can be beneficial to ensure fairness by forcing
-    /// the lock to pass on to a waiting thread if there is one. This is done by
-    /// using this method instead of dropping the `RwLockUpgradableReadGuard` normally.
+    /// See `RwLockUpgradableReadGuard::unlock_fair`.
     #[inline]
     pub fn unlock_fair(self) {
-        self.raw.unlock_upgradable(true);
-        mem::forget(self);
+        unsafe { self.rwlock.raw.unlock_upgradable_fair() };
+        drop(self.into_arc());
+    }
+
+    /// Temporarily unlocks the `RwLock` to execute the given function.
+    ///
+    /// See `RwLockUpgradableReadGuard::unlocked_fair`.
+    #[inline]
+    pub fn unlocked_fair<F, U>(s: &mut Self, f: F) -> U
+    where
+        F: FnOnce() -> U,
+    {
+        unsafe { s.rwlock.raw.unlock_upgradable_fair() };
+        defer!(s.rwlock.raw.lock_upgradable());
+        f()
+    }
+
+    /// Temporarily yields the `RwLock` to a waiting thread if there is one.
+    ///
+    /// See `RwLockUpgradableReadGuard::bump`.
+    #[inline]
+    pub fn bump(s: &mut Self) {
+        unsafe { s.rwlock.raw.bump_upgradable() };
     }
 }
 
-impl<'a, T: ?Sized + 'a> Deref for RwLockUpgradableReadGuard<'a, T> {
+impl<T: ?Sized, R: RawRwLockUpgrade> Deref for ArcRwLockUpgradableReadGuard<T, R> {
     type Target = T;
     #[inline]
     fn deref(&self) -> &T {
-        unsafe { &*self.data }
+        unsafe { &*self.rwlock.data.get() }
     }
 }
 
-impl<'a, T: ?Sized + 'a> Drop for RwLockUpgradableReadGuard<'a, T> {
+impl<T: ?Sized, R: RawRwLockUpgrade> Drop for ArcRwLockUpgradableReadGuard<T, R> {
     #[inline]
     fn drop(&mut self) {
-        self.raw.unlock_upgradable(false);
+        unsafe { self.rwlock.raw.unlock_upgradable() };
     }
 }
 
 #[cfg(feature = "owning_ref")]
-unsafe impl<'a, T: ?Sized> StableAddress for RwLockUpgradableReadGuard<'a, T> {}
+unsafe impl<T: ?Sized, R: RawRwLockUpgrade> StableAddress
+    for ArcRwLockUpgradableReadGuard<T, R> {}
+
+// Helper functions used by Condvar, not publicly exported
+#[inline]
+pub(crate) fn read_guard_lock<'a, T: ?Sized, R: RawRwLockTrait>(
+    guard: &RwLockReadGuard<'a, T, R>,
+) -> &'a R {
+    guard.raw
+}
+
+#[inline]
+pub(crate) fn write_guard_lock<'a, T: ?Sized, R: RawRwLockTrait>(
+    guard: &RwLockWriteGuard<'a, T, R>,
+) -> &'a R {
+    guard.raw
+}
+
+#[inline]
+pub(crate) fn upgradable_guard_lock<'a, T: ?Sized, R: RawRwLockUpgrade>(
+    guard: &RwLockUpgradableReadGuard<'a, T, R>,
+) -> &'a R {
+    guard.raw
+}
 
 #[cfg(test)]
 mod tests {
@@ -1035,7 +2745,9 @@ mod tests {
     use std::sync::Arc;
     use std::sync::atomic::{AtomicUsize, Ordering};
     use std::time::Duration;
-    use RwLock;
+    use {ArcRwLockReadGuard, ArcRwLockWriteGuard, MappedRwLockReadGuard, MappedRwLockWriteGuard,
+         RwLock, RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard,
+         SendArcRwLockWriteGuard, SendRwLockWriteGuard, StampedRwLock};
 
     #[derive(Eq, PartialEq, Debug)]
     struct NonCopy(i32);
@@ -1429,6 +3141,57 @@ mod tests {
         assert_eq!(*x.read(), 800);
     }
 
+    #[test]
+    fn test_rwlock_unlocked() {
+        let lock = Arc::new(RwLock::new(0));
+        let mut write = lock.write();
+        RwLockWriteGuard::unlocked(&mut write, || {
+            let lock2 = lock.clone();
+            thread::spawn(move || *lock2.write() += 1).join().unwrap();
+        });
+        assert_eq!(*write, 1);
+        RwLockWriteGuard::unlocked_fair(&mut write, || assert!(lock.try_read().is_some()));
+        assert!(lock.try_read().is_none());
+        drop(write);
+
+        let mut read = lock.read();
+        RwLockReadGuard::unlocked(&mut read, || assert!(lock.try_write().is_some()));
+        assert!(lock.try_write().is_none());
+        drop(read);
+
+        let mut upgradable = lock.upgradable_read();
+        RwLockUpgradableReadGuard::unlocked_fair(&mut upgradable, || {
+            assert!(lock.try_upgradable_read().is_some());
+        });
+        assert!(lock.try_upgradable_read().is_none());
+    }
+
+    #[test]
+    fn test_rwlock_bump() {
+        let lock = Arc::new(RwLock::new(0));
+        let mut read = lock.read();
+        RwLockReadGuard::bump(&mut read);
+
+        let lock2 = lock.clone();
+        let child = thread::spawn(move || *lock2.write() += 1);
+        while *read == 0 {
+            RwLockReadGuard::bump(&mut read);
+            thread::yield_now();
+        }
+        drop(read);
+        child.join().unwrap();
+
+        let mut write = lock.write();
+        let lock2 = lock.clone();
+        let child = thread::spawn(move || *lock2.write() += 1);
+        while *write == 1 {
+            RwLockWriteGuard::bump(&mut write);
+            thread::yield_now();
+        }
+        drop(write);
+        child.join().unwrap();
+    }
+
     #[test]
     fn test_rwlock_recursive() {
         let arc = Arc::new(RwLock::new(1));
@@ -1443,6 +3206,274 @@ mod tests {
         let _lock2 = arc.read_recursive();
     }
 
+    #[test]
+    fn test_rwlock_assert_held() {
+        let lock = RwLock::new(());
+        {
+            let _g = lock.write();
+            lock.assert_write_held();
+            lock.assert_read_held();
+        }
+        {
+            let _g = lock.read();
+            lock.assert_read_held();
+        }
+        {
+            let _g = lock.upgradable_read();
+            lock.assert_read_held();
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn test_rwlock_assert_write_held_read() {
+        let lock = RwLock::new(());
+        let _g = lock.read();
+        lock.assert_write_held();
+    }
+
+    #[test]
+    #[cfg(feature = "self_deadlock_detection")]
+    #[should_panic(expected = "already locked by the current thread")]
+    fn test_rwlock_write_while_reading() {
+        let lock = RwLock::new(());
+        let _g = lock.read();
+        let _g2 = lock.write();
+    }
+
+    #[test]
+    #[cfg(feature = "self_deadlock_detection")]
+    #[should_panic(expected = "write locked by the current thread")]
+    fn test_rwlock_read_while_writing() {
+        let lock = RwLock::new(());
+        let _g = lock.write();
+        let _g2 = lock.read();
+    }
+
+    #[test]
+    fn test_rwlock_recursive_read() {
+        let lock = RwLock::new(());
+        let _g = lock.read();
+        let _g2 = lock.read();
+        let _g3 = lock.read_recursive();
+    }
+
+    #[test]
+    fn test_rwlock_arc_guards() {
+        let lock = Arc::new(RwLock::new(1));
+
+        let read = RwLock::read_arc(&lock);
+        assert!(RwLock::try_write_arc(&lock).is_none());
+        assert!(RwLock::try_read_arc(&lock).is_some());
+        assert!(Arc::ptr_eq(ArcRwLockReadGuard::rwlock(&read), &lock));
+        drop(read);
+
+        let mut write = RwLock::write_arc(&lock);
+        *write += 1;
+        let read = write.downgrade();
+        assert_eq!(*read, 2);
+        assert!(RwLock::try_read_arc(&lock).is_some());
+        read.unlock_fair();
+
+        let guard = RwLock::try_write_arc(&lock).unwrap();
+        drop(lock);
+        assert_eq!(*guard, 2);
+    }
+
+    #[test]
+    fn test_rwlock_arc_write_send() {
+        let lock = Arc::new(RwLock::new(1));
+        let write = ArcRwLockWriteGuard::into_send(RwLock::write_arc(&lock));
+        thread::spawn(move || {
+            let mut write = write;
+            *write += 1;
+        }).join().unwrap();
+        assert_eq!(*lock.read(), 2);
+
+        let write = ArcRwLockWriteGuard::into_send(RwLock::write_arc(&lock));
+        let write = thread::spawn(move || write).join().unwrap();
+        let write = SendArcRwLockWriteGuard::into_guard(write);
+        assert!(lock.try_read().is_none());
+        drop(write);
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn test_rwlock_arc_upgradable() {
+        let lock = Arc::new(RwLock::new(1));
+
+        let upgradable = RwLock::upgradable_read_arc(&lock);
+        assert!(RwLock::try_upgradable_read_arc(&lock).is_none());
+        let read = RwLock::read_arc(&lock);
+        let upgradable = upgradable.try_upgrade().err().unwrap();
+        drop(read);
+
+        let mut write = upgradable.upgrade();
+        *write += 1;
+        assert!(RwLock::try_read_arc(&lock).is_none());
+        drop(write);
+
+        let upgradable = RwLock::try_upgradable_read_arc(&lock).unwrap();
+        let read = upgradable.downgrade();
+        assert!(RwLock::try_upgradable_read_arc(&lock).is_some());
+        assert_eq!(*read, 2);
+    }
+
+    #[test]
+    fn test_rwlock_map() {
+        let lock = RwLock::new((1, vec![2, 3]));
+        {
+            let mut guard = RwLockWriteGuard::map(lock.write(), |x| &mut x.1);
+            guard.push(4);
+            let mut guard = MappedRwLockWriteGuard::map(guard, |x| &mut x[0]);
+            *guard += 1;
+        }
+        {
+            let guard = RwLockReadGuard::map(lock.read(), |x| &x.1);
+            assert_eq!(*guard, [3, 3, 4]);
+            assert!(lock.try_write().is_none());
+            let guard = MappedRwLockReadGuard::try_map(guard, |x| x.get(5)).err().unwrap();
+            assert_eq!(guard.len(), 3);
+        }
+        {
+            let guard = RwLockUpgradableReadGuard::try_map(lock.upgradable_read(), |x| x.1.get(2))
+                .ok()
+                .unwrap();
+            assert_eq!(*guard, 4);
+            assert!(lock.try_upgradable_read().is_none());
+            assert!(lock.try_read().is_some());
+        }
+        let guard = RwLockWriteGuard::try_map(lock.write(), |x| x.1.get_mut(5)).err().unwrap();
+        guard.unlock_fair();
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn test_rwlock_send_guard() {
+        // Leak the rwlock so that the guard can be sent to another thread
+        let lock: &'static RwLock<i32> = unsafe { &*Box::into_raw(Box::new(RwLock::new(0))) };
+        let guard = RwLockWriteGuard::into_send(lock.write());
+        thread::spawn(move || {
+            let mut guard = SendRwLockWriteGuard::into_guard(guard);
+            *guard += 1;
+            lock.assert_write_held();
+        }).join().unwrap();
+
+        let guard = RwLockWriteGuard::into_send(lock.write());
+        thread::spawn(move || {
+            assert_eq!(*guard, 1);
+        }).join().unwrap();
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn test_rwlock_optimistic() {
+        let lock = StampedRwLock::new(1);
+        let stamp = lock.optimistic_stamp().unwrap();
+        assert!(lock.validate(stamp));
+
+        // Readers don't invalidate the stamp
+        drop(lock.read());
+        drop(lock.upgradable_read());
+        assert!(lock.validate(stamp));
+
+        // Writers do, and no stamp can be taken while one holds the lock
+        {
+            let _guard = lock.write();
+            assert!(lock.optimistic_stamp().is_none());
+            assert!(!lock.validate(stamp));
+        }
+        assert!(!lock.validate(stamp));
+
+        let stamp = lock.optimistic_stamp().unwrap();
+        let guard = RwLockUpgradableReadGuard::upgrade(lock.upgradable_read());
+        assert!(lock.optimistic_stamp().is_none());
+        let _guard = RwLockWriteGuard::downgrade(guard);
+        assert!(!lock.validate(stamp));
+        assert!(lock.optimistic_stamp().is_some());
+    }
+
+    #[test]
+    fn test_stamped_rwlock() {
+        let mut lock = StampedRwLock::new(1);
+        *lock.get_mut() += 1;
+        assert_eq!(*lock.read(), 2);
+        assert_eq!(format!("{:?}", lock), "StampedRwLock { data: 2 }");
+        {
+            let _guard = lock.write();
+            assert_eq!(format!("{:?}", lock), "StampedRwLock { <locked> }");
+        }
+        assert_eq!(lock.into_inner(), 2);
+        assert_eq!(*StampedRwLock::<i32>::default().read(), 0);
+    }
+
+    #[test]
+    fn test_rwlock_read_optimistic() {
+        let lock = StampedRwLock::new(AtomicUsize::new(1));
+        let mut calls = 0;
+        let value = unsafe {
+            lock.read_optimistic(|p| {
+                calls += 1;
+                (*p).load(Ordering::Relaxed)
+            })
+        };
+        assert_eq!((value, calls), (1, 1));
+
+        // A writer invalidates the optimistic read, so it is retried under a
+        // shared lock
+        let mut calls = 0;
+        let value = unsafe {
+            lock.read_optimistic(|p| {
+                calls += 1;
+                if calls == 1 {
+                    lock.write().store(2, Ordering::Relaxed);
+                } else {
+                    assert!(lock.try_write().is_none());
+                }
+                (*p).load(Ordering::Relaxed)
+            })
+        };
+        assert_eq!((value, calls), (2, 2));
+    }
+
+    #[test]
+    fn test_rwlock_read_optimistic_with_threads() {
+        const N: usize = 10;
+        const M: usize = 1000;
+
+        // The writers keep both halves of the pair equal
+        let lock = Arc::new(StampedRwLock::new((AtomicUsize::new(0), AtomicUsize::new(0))));
+        let threads: Vec<_> = (0..N)
+            .map(|i| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..M {
+                        if i % 2 == 0 {
+                            let guard = lock.write();
+                            guard.0.fetch_add(1, Ordering::Relaxed);
+                            guard.1.fetch_add(1, Ordering::Relaxed);
+                        } else {
+                            let (a, b) = unsafe {
+                                lock.read_optimistic(|p| {
+                                    let pair = &*p;
+                                    (pair.0.load(Ordering::Relaxed), pair.1.load(Ordering::Relaxed))
+                                })
+                            };
+                            assert_eq!(a, b);
+                        }
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        let guard = lock.read();
+        assert_eq!(guard.0.load(Ordering::Relaxed), N / 2 * M);
+        assert_eq!(guard.1.load(Ordering::Relaxed), N / 2 * M);
+    }
+
     #[test]
     fn test_rwlock_debug() {
         let x = RwLock::new(vec![0u8, 10]);
@@ -0,0 +1,548 @@
+// Copyright 2016 Amanieu d'Antras
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::fmt;
+use parking_lot_core::{self, FilterOp, ParkResult, ParkToken, SpinWait, UnparkResult,
+                       DEFAULT_UNPARK_TOKEN};
+
+// This bit is set in the state if there are threads waiting for permits. While
+// it is set, permits are only added or removed with the queue locked.
+const PARKED_BIT: usize = 1;
+
+// The number of available permits is stored in the remaining bits.
+const PERMIT_SHIFT: usize = 1;
+
+// The maximum number of permits that a `Semaphore` can hold.
+const MAX_PERMITS: usize = !0 >> PERMIT_SHIFT;
+
+/// A counting semaphore.
+///
+/// A semaphore holds a number of permits. Acquiring permits decrements the
+/// count, blocking the current thread until enough permits are available, and
+/// the permits are added back when the returned `SemaphorePermit` is dropped.
+/// This is typically used to limit the number of threads accessing a resource
+/// at the same time, such as a connection pool.
+///
+/// The state is a single word, and waiting threads are queued in the parking
+/// lot along with the number of permits that they requested. Waiters are
+/// served in FIFO order: released permits are handed off directly to the
+/// waiting threads at the front of the queue, as long as there are enough
+/// permits for them, and a thread which requested more permits than are
+/// available holds up the threads behind it. This means that large requests
+/// can't be starved by a stream of smaller ones.
+///
+/// # Examples
+///
+/// ```
+/// use parking_lot::Semaphore;
+/// use std::sync::Arc;
+/// use std::thread;
+///
+/// // Allow at most 2 threads to work at the same time
+/// let semaphore = Arc::new(Semaphore::new(2));
+/// let threads: Vec<_> = (0..8)
+///     .map(|_| {
+///         let semaphore = semaphore.clone();
+///         thread::spawn(move || {
+///             let _permit = semaphore.acquire();
+///             // ... at most 2 threads get here at a time ...
+///         })
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(semaphore.available_permits(), 2);
+/// ```
+pub struct Semaphore {
+    state: AtomicUsize,
+}
+
+/// An RAII guard holding permits acquired from a `Semaphore`. When this
+/// structure is dropped (falls out of scope), the permits are released.
+#[must_use]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl Semaphore {
+    /// Creates a new semaphore with the given number of available permits.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `permits` is greater than `usize::MAX / 2`.
+    #[inline]
+    pub fn new(permits: usize) -> Semaphore {
+        assert!(permits <= MAX_PERMITS, "semaphore permit count overflow");
+        Semaphore {
+            state: AtomicUsize::new(permits << PERMIT_SHIFT),
+        }
+    }
+
+    /// Returns the number of permits which are currently available.
+    ///
+    /// Since other threads may acquire or release permits concurrently, the
+    /// returned value should only be used as a hint.
+    #[inline]
+    pub fn available_permits(&self) -> usize {
+        self.state.load(Ordering::Relaxed) >> PERMIT_SHIFT
+    }
+
+    /// Acquires a single permit, blocking the current thread until one is
+    /// available.
+    ///
+    /// The permit is released when the returned guard is dropped.
+    #[inline]
+    pub fn acquire(&self) -> SemaphorePermit {
+        self.acquire_many(1)
+    }
+
+    /// Acquires `n` permits at once, blocking the current thread until they are
+    /// all available.
+    ///
+    /// The permits are released when the returned guard is dropped.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `n` is greater than `usize::MAX / 2`, since such
+    /// a request could never be satisfied.
+    #[inline]
+    pub fn acquire_many(&self, n: usize) -> SemaphorePermit {
+        if !self.try_acquire_fast(n) {
+            self.acquire_slow(n, None);
+        }
+        self.permit(n)
+    }
+
+    /// Attempts to acquire a single permit without blocking.
+    ///
+    /// Returns `None` if no permit is available, or if other threads are
+    /// already waiting for permits.
+    #[inline]
+    pub fn try_acquire(&self) -> Option<SemaphorePermit> {
+        self.try_acquire_many(1)
+    }
+
+    /// Attempts to acquire `n` permits at once without blocking.
+    ///
+    /// Returns `None` if not enough permits are available, or if other threads
+    /// are already waiting for permits.
+    #[inline]
+    pub fn try_acquire_many(&self, n: usize) -> Option<SemaphorePermit> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & PARKED_BIT != 0 || state >> PERMIT_SHIFT < n {
+                return None;
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state - (n << PERMIT_SHIFT),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(self.permit(n)),
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    /// Attempts to acquire a single permit until a timeout is reached.
+    ///
+    /// Returns `None` if the timeout expired before a permit became available.
+    #[inline]
+    pub fn try_acquire_until(&self, timeout: Instant) -> Option<SemaphorePermit> {
+        self.try_acquire_many_until(1, timeout)
+    }
+
+    /// Attempts to acquire a single permit until a timeout is reached.
+    ///
+    /// Returns `None` if the timeout expired before a permit became available.
+    #[inline]
+    pub fn try_acquire_for(&self, timeout: Duration) -> Option<SemaphorePermit> {
+        self.try_acquire_many_until(1, Instant::now() + timeout)
+    }
+
+    /// Attempts to acquire `n` permits at once until a timeout is reached.
+    ///
+    /// Returns `None` if the timeout expired before enough permits became
+    /// available. In that case none of the permits are acquired.
+    #[inline]
+    pub fn try_acquire_many_until(&self, n: usize, timeout: Instant) -> Option<SemaphorePermit> {
+        if self.try_acquire_fast(n) || self.acquire_slow(n, Some(timeout)) {
+            Some(self.permit(n))
+        } else {
+            None
+        }
+    }
+
+    /// Attempts to acquire `n` permits at once until a timeout is reached.
+    ///
+    /// Returns `None` if the timeout expired before enough permits became
+    /// available. In that case none of the permits are acquired.
+    #[inline]
+    pub fn try_acquire_many_for(&self, n: usize, timeout: Duration) -> Option<SemaphorePermit> {
+        self.try_acquire_many_until(n, Instant::now() + timeout)
+    }
+
+    /// Adds `n` permits to the semaphore.
+    ///
+    /// This wakes up the waiting threads whose requests can now be satisfied.
+    /// It is typically used together with `SemaphorePermit::forget`, or to
+    /// raise the number of permits of a semaphore after it was created.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if the number of available permits would exceed
+    /// `usize::MAX / 2`.
+    #[inline]
+    pub fn release(&self, n: usize) {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            assert!(
+                n <= MAX_PERMITS - (state >> PERMIT_SHIFT),
+                "semaphore permit count overflow"
+            );
+            if state & PARKED_BIT != 0 {
+                return self.release_slow(n);
+            }
+            match self.state.compare_exchange_weak(
+                state,
+                state + (n << PERMIT_SHIFT),
+                Ordering::Release,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    #[inline]
+    fn permit(&self, n: usize) -> SemaphorePermit {
+        SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        }
+    }
+
+    #[inline]
+    fn try_acquire_fast(&self, n: usize) -> bool {
+        let state = self.state.load(Ordering::Relaxed);
+        state & PARKED_BIT == 0 && state >> PERMIT_SHIFT >= n
+            && self.state
+                .compare_exchange_weak(
+                    state,
+                    state - (n << PERMIT_SHIFT),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn acquire_slow(&self, n: usize, timeout: Option<Instant>) -> bool {
+        assert!(n <= MAX_PERMITS, "requested more permits than a semaphore can hold");
+
+        let mut spinwait = SpinWait::new();
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            // Grab the permits if there are enough of them and nobody is
+            // waiting ahead of us.
+            if state & PARKED_BIT == 0 && state >> PERMIT_SHIFT >= n {
+                match self.state.compare_exchange_weak(
+                    state,
+                    state - (n << PERMIT_SHIFT),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return true,
+                    Err(x) => state = x,
+                }
+                continue;
+            }
+
+            // If there is no queue, try spinning a few times
+            if state & PARKED_BIT == 0 && spinwait.spin() {
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+
+            // Park our thread until enough permits are handed off to us
+            unsafe {
+                let addr = self as *const _ as usize;
+                let validate = || {
+                    // Set the parked bit, unless permits were released in the
+                    // meantime and nobody else is waiting for them.
+                    let mut state = self.state.load(Ordering::Relaxed);
+                    loop {
+                        if state & PARKED_BIT != 0 {
+                            return true;
+                        }
+                        if state >> PERMIT_SHIFT >= n {
+                            return false;
+                        }
+                        match self.state.compare_exchange_weak(
+                            state,
+                            state | PARKED_BIT,
+                            Ordering::Relaxed,
+                            Ordering::Relaxed,
+                        ) {
+                            Ok(_) => return true,
+                            Err(x) => state = x,
+                        }
+                    }
+                };
+                let before_sleep = || {};
+                let mut was_last = false;
+                let timed_out = |_, was_last_thread| {
+                    // Clear the parked bit if we were the last parked thread
+                    was_last = was_last_thread;
+                    if was_last_thread {
+                        self.state.fetch_and(!PARKED_BIT, Ordering::Relaxed);
+                    }
+                };
+                match parking_lot_core::park(
+                    addr,
+                    validate,
+                    before_sleep,
+                    timed_out,
+                    ParkToken(n),
+                    timeout,
+                ) {
+                    // The thread that unparked us handed the permits off to us
+                    ParkResult::Unparked(_) => return true,
+
+                    // The validation function failed, try acquiring again
+                    ParkResult::Invalid => (),
+
+                    // Timeout expired. If we were at the front of the queue
+                    // then the threads behind us may now be able to proceed.
+                    ParkResult::TimedOut => {
+                        if !was_last {
+                            self.release_slow(0);
+                        }
+                        return false;
+                    }
+                }
+            }
+
+            // Loop back and try acquiring again
+            spinwait.reset();
+            state = self.state.load(Ordering::Relaxed);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn release_slow(&self, n: usize) {
+        unsafe {
+            // Hand off permits to the waiting threads in queue order, stopping
+            // at the first one which requested more permits than are left.
+            // The number of permits can't change while there are parked threads
+            // since other threads only modify it with the queue locked.
+            let addr = self as *const _ as usize;
+            let mut available = None;
+            let handed_off = Cell::new(0);
+            let filter = |ParkToken(requested)| {
+                let available = available.get_or_insert_with(|| {
+                    (self.state.load(Ordering::Relaxed) >> PERMIT_SHIFT) + n
+                });
+                if requested > *available {
+                    return FilterOp::Stop;
+                }
+                *available -= requested;
+                handed_off.set(handed_off.get() + requested);
+                FilterOp::Unpark
+            };
+            let callback = |result: UnparkResult| {
+                // Add the released permits which weren't handed off, and clear
+                // the parked bit if there are no more parked threads. This is
+                // done with atomic operations since threads may acquire and
+                // release permits concurrently once the parked bit is clear.
+                let handed_off = handed_off.get();
+                if n >= handed_off {
+                    self.state.fetch_add((n - handed_off) << PERMIT_SHIFT, Ordering::Release);
+                } else {
+                    self.state.fetch_sub((handed_off - n) << PERMIT_SHIFT, Ordering::Relaxed);
+                }
+                if !result.have_more_threads {
+                    self.state.fetch_and(!PARKED_BIT, Ordering::Release);
+                }
+                DEFAULT_UNPARK_TOKEN
+            };
+            parking_lot_core::unpark_filter(addr, filter, callback);
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("permits", &self.available_permits())
+            .finish()
+    }
+}
+
+impl<'a> SemaphorePermit<'a> {
+    /// Returns the number of permits held by this guard.
+    #[inline]
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Consumes this guard without releasing its permits.
+    ///
+    /// The permits are permanently removed from the semaphore, unless they are
+    /// added back later with `Semaphore::release`.
+    #[inline]
+    pub fn forget(self) {
+        ::std::mem::forget(self);
+    }
+}
+
+impl<'a> Drop for SemaphorePermit<'a> {
+    #[inline]
+    fn drop(&mut self) {
+        self.semaphore.release(self.permits);
+    }
+}
+
+impl<'a> fmt::Debug for SemaphorePermit<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc::channel;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use Semaphore;
+
+    #[test]
+    fn smoke() {
+        let s = Semaphore::new(2);
+        let p1 = s.acquire();
+        let p2 = s.try_acquire().unwrap();
+        assert!(s.try_acquire().is_none());
+        assert_eq!(s.available_permits(), 0);
+        drop(p1);
+        drop(p2);
+        assert_eq!(s.available_permits(), 2);
+        assert_eq!(s.acquire_many(2).permits(), 2);
+        assert_eq!(s.acquire_many(0).permits(), 0);
+    }
+
+    #[test]
+    fn forget_and_release() {
+        let s = Semaphore::new(1);
+        s.acquire().forget();
+        assert_eq!(s.available_permits(), 0);
+        s.release(3);
+        assert_eq!(s.available_permits(), 3);
+    }
+
+    #[test]
+    fn limits_concurrency() {
+        const N: usize = 8;
+        const LIMIT: usize = 3;
+
+        let s = Arc::new(Semaphore::new(LIMIT));
+        let active = Arc::new(AtomicUsize::new(0));
+        let threads: Vec<_> = (0..N)
+            .map(|_| {
+                let s = s.clone();
+                let active = active.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        let _p = s.acquire();
+                        let n = active.fetch_add(1, Ordering::SeqCst);
+                        assert!(n < LIMIT);
+                        thread::yield_now();
+                        active.fetch_sub(1, Ordering::SeqCst);
+                    }
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(s.available_permits(), LIMIT);
+    }
+
+    #[test]
+    fn large_request_is_not_starved() {
+        let s = Arc::new(Semaphore::new(1));
+        let (tx, rx) = channel();
+        let s2 = s.clone();
+        let t = thread::spawn(move || {
+            let _p = s2.acquire_many(2);
+            tx.send(()).unwrap();
+        });
+
+        // The free permit can't be taken once the request for 2 permits is
+        // queued, since that would hold it up.
+        while s.try_acquire().is_some() {
+            thread::yield_now();
+        }
+        assert!(s.try_acquire_for(Duration::from_millis(10)).is_none());
+        s.release(1);
+        rx.recv().unwrap();
+        t.join().unwrap();
+        assert_eq!(s.available_permits(), 2);
+    }
+
+    #[test]
+    fn timeout_wakes_next_waiter() {
+        let s = Arc::new(Semaphore::new(1));
+
+        // A thread waiting for 2 permits times out, which must let the thread
+        // waiting behind it take the free permit.
+        let s2 = s.clone();
+        let t1 = thread::spawn(move || {
+            s2.try_acquire_many_for(2, Duration::from_millis(100)).is_none()
+        });
+        while s.try_acquire().is_some() {
+            thread::yield_now();
+        }
+        let s3 = s.clone();
+        let t2 = thread::spawn(move || s3.acquire().forget());
+        assert!(t1.join().unwrap());
+        t2.join().unwrap();
+        assert_eq!(s.available_permits(), 0);
+    }
+
+    #[test]
+    fn try_acquire_until() {
+        let s = Semaphore::new(0);
+        let start = Instant::now();
+        let timeout = start + Duration::from_millis(20);
+        assert!(s.try_acquire_until(timeout).is_none());
+        assert!(Instant::now() >= timeout);
+        s.release(1);
+        assert!(s.try_acquire_until(timeout).is_some());
+    }
+
+    #[test]
+    fn test_debug_semaphore() {
+        let s = Semaphore::new(3);
+        assert_eq!(format!("{:?}", s), "Semaphore { permits: 3 }");
+        let p = s.acquire_many(2);
+        assert_eq!(format!("{:?}", p), "SemaphorePermit { permits: 2 }");
+        assert_eq!(format!("{:?}", s), "Semaphore { permits: 1 }");
+    }
+}
@@ -5,6 +5,21 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+// Runs the given expression when the current scope is exited, including when
+// unwinding from a panic.
+macro_rules! defer {
+    ($e:expr) => {
+        struct DeferGuard<F: FnMut()>(F);
+        impl<F: FnMut()> Drop for DeferGuard<F> {
+            #[inline]
+            fn drop(&mut self) {
+                (self.0)();
+            }
+        }
+        let _guard = DeferGuard(|| $e);
+    };
+}
+
 // Option::unchecked_unwrap
 pub trait UncheckedOptionExt<T> {
     unsafe fn unchecked_unwrap(self) -> T;
@@ -30,3 +45,64 @@ unsafe fn unreachable() -> ! {
         match *(1 as *const Void) {}
     }
 }
+
+#[cfg(all(test, feature = "async"))]
+pub mod tests {
+    use std::future::Future;
+    use std::mem;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    use std::thread::{self, Thread};
+
+    // Waker which unparks the current thread. The data pointer is a boxed
+    // Thread handle.
+    static THREAD_WAKER_VTABLE: RawWakerVTable =
+        RawWakerVTable::new(clone_waker, wake, wake_by_ref, drop_waker);
+
+    fn raw_thread_waker(thread: Thread) -> RawWaker {
+        let data = Box::into_raw(Box::new(thread)) as *const ();
+        RawWaker::new(data, &THREAD_WAKER_VTABLE)
+    }
+
+    unsafe fn clone_waker(data: *const ()) -> RawWaker {
+        raw_thread_waker((*(data as *const Thread)).clone())
+    }
+
+    unsafe fn wake(data: *const ()) {
+        Box::from_raw(data as *mut Thread).unpark();
+    }
+
+    unsafe fn wake_by_ref(data: *const ()) {
+        (*(data as *const Thread)).unpark();
+    }
+
+    unsafe fn drop_waker(data: *const ()) {
+        mem::drop(Box::from_raw(data as *mut Thread));
+    }
+
+    fn thread_waker() -> Waker {
+        unsafe { Waker::from_raw(raw_thread_waker(thread::current())) }
+    }
+
+    // Runs a future to completion on the current thread
+    pub fn block_on<F: Future>(f: F) -> F::Output {
+        let mut f = Box::pin(f);
+        let waker = thread_waker();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(x) = f.as_mut().poll(&mut cx) {
+                return x;
+            }
+            thread::park();
+        }
+    }
+
+    // Polls a future once, returning its output if it is ready
+    pub fn poll_once<F: Future>(f: Pin<&mut F>) -> Option<F::Output> {
+        let waker = thread_waker();
+        match f.poll(&mut Context::from_waker(&waker)) {
+            Poll::Ready(x) => Some(x),
+            Poll::Pending => None,
+        }
+    }
+}